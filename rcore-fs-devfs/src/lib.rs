@@ -43,8 +43,13 @@ impl FileSystem for DevFS {
             files: 0,
             ffree: 0,
             namemax: 0,
+            inline_blocks: 0,
         }
     }
+
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
 }
 
 impl DevFS {
@@ -0,0 +1,86 @@
+use super::*;
+use rcore_fs::dev::Device;
+
+/// Major device number of loop devices
+pub const LOOP_MAJOR: usize = 7;
+
+/// A block device file backed by a `Device`, e.g. /dev/loop0
+pub struct BlockINode {
+    inode_id: usize,
+    fs: Weak<DevFS>,
+    device: Arc<dyn Device>,
+    rdev: usize,
+}
+
+impl BlockINode {
+    /// Create a device file to be added to `fs`
+    pub fn new(fs: &Arc<DevFS>, device: Arc<dyn Device>, rdev: usize) -> Self {
+        Self {
+            inode_id: DevFS::new_inode_id(),
+            fs: Arc::downgrade(fs),
+            device,
+            rdev,
+        }
+    }
+
+    /// Create the loop device file `/dev/loop<minor>`
+    pub fn new_loop(fs: &Arc<DevFS>, device: Arc<dyn Device>, minor: usize) -> Self {
+        Self::new(fs, device, make_rdev(LOOP_MAJOR, minor))
+    }
+
+    /// Get the underlying device
+    pub fn device(&self) -> &Arc<dyn Device> {
+        &self.device
+    }
+}
+
+impl INode for BlockINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        Ok(self.device.read_at(offset, buf)?)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        Ok(self.device.write_at(offset, buf)?)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 1,
+            inode: self.inode_id,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::BlockDevice,
+            mode: 0o660,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: self.rdev,
+        })
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        Ok(self.device.sync()?)
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(self.device.sync()?)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.upgrade().unwrap()
+    }
+
+    impl_inode!(@common);
+}
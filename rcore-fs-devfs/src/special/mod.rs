@@ -4,15 +4,22 @@ use super::*;
 
 macro_rules! impl_inode {
     () => {
-        fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
-            Ok(())
-        }
+        impl_inode!(@common);
         fn sync_all(&self) -> Result<()> {
             Ok(())
         }
         fn sync_data(&self) -> Result<()> {
             Ok(())
         }
+        fn fs(&self) -> Arc<dyn FileSystem> {
+            unimplemented!()
+        }
+    };
+    // all but syncing and the file system, for devices which have them
+    (@common) => {
+        fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
+            Ok(())
+        }
         fn resize(&self, _len: usize) -> Result<()> {
             Err(FsError::NotSupported)
         }
@@ -40,17 +47,16 @@ macro_rules! impl_inode {
         fn mmap(&self, _area: MMapArea) -> Result<()> {
             Err(FsError::NotSupported)
         }
-        fn fs(&self) -> Arc<dyn FileSystem> {
-            unimplemented!()
-        }
         fn as_any_ref(&self) -> &dyn Any {
             self
         }
     };
 }
 
+mod block;
 mod null;
 mod zero;
 
+pub use self::block::*;
 pub use self::null::*;
 pub use self::zero::*;
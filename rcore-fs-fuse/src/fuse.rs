@@ -47,7 +47,7 @@ impl VfsFuse {
             nlink: info.nlinks as u32,
             uid: 501, // info.uid as u32,
             gid: 20,  // info.gid as u32,
-            rdev: 0,
+            rdev: info.rdev as u32,
             flags: 0,
         }
     }
@@ -77,6 +77,9 @@ impl VfsFuse {
             vfs::FsError::DirRemoved => ENOENT,
             vfs::FsError::DirNotEmpty => ENOTEMPTY,
             vfs::FsError::WrongFs => EINVAL,
+            vfs::FsError::ReadOnly => EROFS,
+            vfs::FsError::BrokenPipe => EPIPE,
+            vfs::FsError::FileTooBig => EFBIG,
             _ => EINVAL,
         }
     }
@@ -170,12 +173,21 @@ impl Filesystem for VfsFuse {
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _rdev: u32,
+        rdev: u32,
         reply: ReplyEntry,
     ) {
         let name = name.to_str().unwrap();
+        let type_ = match mode & libc::S_IFMT {
+            libc::S_IFCHR => vfs::FileType::CharDevice,
+            libc::S_IFBLK => vfs::FileType::BlockDevice,
+            libc::S_IFIFO => vfs::FileType::NamedPipe,
+            libc::S_IFSOCK => vfs::FileType::Socket,
+            _ => vfs::FileType::File,
+        };
+        // the kernel encodes it as (major << 8) | minor, with high bits of minor above
+        let rdev = vfs::make_rdev((rdev >> 8) as usize & 0xfff, rdev as usize & 0xff);
         let inode = try_vfs!(reply, self.get_inode(parent));
-        let target = try_vfs!(reply, inode.create(name, vfs::FileType::File, mode));
+        let target = try_vfs!(reply, inode.create2(name, type_, mode, rdev));
         let info = try_vfs!(reply, target.metadata());
         self.inodes.insert(info.inode, target);
         let attr = Self::trans_attr(info);
@@ -49,6 +49,14 @@ enum Cmd {
     #[structopt(name = "mount")]
     Mount,
 
+    /// Check the sfs <image>, <dir> is ignored
+    #[structopt(name = "fsck")]
+    Fsck {
+        /// Repair the problems found
+        #[structopt(long)]
+        repair: bool,
+    },
+
     #[structopt(name = "git-version")]
     GitVersion,
 }
@@ -67,6 +75,23 @@ fn main() {
             println!("{}", git_version!());
             return;
         }
+        Cmd::Fsck { repair } => {
+            assert_eq!(opt.fs, "sfs", "fsck only supports sfs");
+            let file = OpenOptions::new()
+                .read(true)
+                .write(repair)
+                .open(&opt.image)
+                .expect("failed to open image");
+            let report =
+                sfs::fsck::check(Arc::new(Mutex::new(file)), repair).expect("failed to check sfs");
+            for problem in report.problems.iter() {
+                println!("{}", problem);
+            }
+            if repair && !report.problems.is_empty() {
+                println!("{} problems left", report.remaining.len());
+            }
+            std::process::exit(!report.remaining.is_empty() as i32);
+        }
     };
 
     let fs: Arc<dyn FileSystem> = match opt.fs.as_str() {
@@ -111,6 +136,6 @@ fn main() {
             std::fs::create_dir(&opt.dir).expect("failed to create dir");
             unzip_dir(&opt.dir, fs.root_inode()).expect("failed to unzip fs");
         }
-        Cmd::GitVersion => unreachable!(),
+        Cmd::Fsck { .. } | Cmd::GitVersion => unreachable!(),
     }
 }
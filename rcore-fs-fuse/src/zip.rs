@@ -3,6 +3,8 @@ use std::fs;
 use std::io::{Read, Write};
 #[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::str;
 use std::sync::Arc;
@@ -12,6 +14,7 @@ use rcore_fs::{
     vfs::{FileType, INode},
 };
 
+#[cfg(windows)]
 const DEFAULT_MODE: u32 = 0o664;
 const BUF_SIZE: usize = 0x1000;
 
@@ -22,10 +25,16 @@ pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>>
         let name_ = entry.file_name();
         let name = name_.to_str().unwrap();
         let type_ = entry.file_type()?;
+        // symlinks are not followed
+        let metadata = entry.metadata()?;
+        #[cfg(unix)]
+        let mode = metadata.permissions().mode() & 0o7777;
+        #[cfg(windows)]
+        let mode = DEFAULT_MODE;
         if type_.is_file() {
-            let inode = inode.create(name, FileType::File, DEFAULT_MODE)?;
+            let inode = inode.create(name, FileType::File, mode)?;
             let mut file = fs::File::open(entry.path())?;
-            inode.resize(file.metadata()?.len() as usize)?;
+            inode.resize(metadata.len() as usize)?;
             let mut buf: [u8; BUF_SIZE] = unsafe { uninit_memory() };
             let mut offset = 0usize;
             let mut len = BUF_SIZE;
@@ -35,11 +44,11 @@ pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>>
                 offset += len;
             }
         } else if type_.is_dir() {
-            let inode = inode.create(name, FileType::Dir, DEFAULT_MODE)?;
+            let inode = inode.create(name, FileType::Dir, mode)?;
             zip_dir(entry.path().as_path(), inode)?;
         } else if type_.is_symlink() {
             let target = fs::read_link(entry.path())?;
-            let inode = inode.create(name, FileType::SymLink, DEFAULT_MODE)?;
+            let inode = inode.create(name, FileType::SymLink, mode)?;
             #[cfg(unix)]
             let data = target.as_os_str().as_bytes();
             #[cfg(windows)]
@@ -11,6 +11,9 @@ use std::sync::{Mutex, MutexGuard};
 #[macro_use]
 extern crate log;
 
+#[cfg(test)]
+mod tests;
+
 /// File system at host
 pub struct HostFS {
     path: PathBuf,
@@ -50,8 +53,13 @@ impl FileSystem for HostFS {
             files: statvfs.files() as _,
             ffree: statvfs.files_free() as _,
             namemax: statvfs.name_max() as _,
+            inline_blocks: 0,
         }
     }
+
+    fn name(&self) -> &'static str {
+        "hostfs"
+    }
 }
 
 impl HostFS {
@@ -143,8 +151,9 @@ impl INode for HNode {
     }
 
     fn create(&self, name: &str, type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
+        self.check_dir()?;
         let new_path = self.path.join(name);
-        if new_path.exists() {
+        if name == "." || name == ".." || new_path.exists() {
             return Err(FsError::EntryExist);
         }
         match type_ {
@@ -154,7 +163,8 @@ impl INode for HNode {
             FileType::Dir => {
                 std::fs::create_dir(&new_path)?;
             }
-            _ => unimplemented!("only support creating file or dir in HostFS"),
+            // only support creating file or dir in HostFS
+            _ => return Err(FsError::NotSupported),
         }
         Ok(Arc::new(HNode {
             path: new_path,
@@ -164,12 +174,23 @@ impl INode for HNode {
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
-        let other = other.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        self.check_dir()?;
+        let other = self.same_fs(other)?;
+        if self.path.join(name).exists() {
+            return Err(FsError::EntryExist);
+        }
+        if other.path.is_dir() {
+            return Err(FsError::IsDir);
+        }
         std::fs::hard_link(&other.path, &self.path.join(name))?;
         Ok(())
     }
 
     fn unlink(&self, name: &str) -> Result<()> {
+        self.check_dir()?;
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
         let new_path = self.path.join(name);
         if new_path.is_file() {
             std::fs::remove_file(new_path)?;
@@ -182,7 +203,7 @@ impl INode for HNode {
     }
 
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
-        let target = target.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        let target = self.same_fs(target)?;
         let old_path = self.path.join(old_name);
         let new_path = target.path.join(new_name);
         std::fs::rename(old_path, new_path)?;
@@ -190,7 +211,14 @@ impl INode for HNode {
     }
 
     fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
-        let new_path = self.path.join(name);
+        self.check_dir()?;
+        let new_path = match name {
+            "." => self.path.clone(),
+            // the parent of root is itself
+            ".." if self.path == self.fs.path => self.path.clone(),
+            ".." => self.path.parent().unwrap().to_path_buf(),
+            _ => self.path.join(name),
+        };
         if new_path.exists() {
             Ok(Arc::new(HNode {
                 path: new_path,
@@ -204,9 +232,14 @@ impl INode for HNode {
 
     fn get_entry(&self, id: usize) -> Result<String> {
         if self.path.is_dir() {
+            match id {
+                0 => return Ok(String::from(".")),
+                1 => return Ok(String::from("..")),
+                _ => {}
+            }
             self.path
                 .read_dir()?
-                .nth(id)
+                .nth(id - 2)
                 .ok_or(FsError::EntryNotFound)??
                 .file_name()
                 .into_string()
@@ -234,6 +267,21 @@ impl INode for HNode {
 }
 
 impl HNode {
+    fn check_dir(&self) -> Result<()> {
+        if !self.path.is_dir() {
+            return Err(FsError::NotDir);
+        }
+        Ok(())
+    }
+
+    /// Downcast `other` to `HNode` of the same `HostFS`
+    fn same_fs<'a>(&self, other: &'a Arc<dyn INode>) -> Result<&'a Self> {
+        match other.downcast_ref::<Self>() {
+            Some(other) if Arc::ptr_eq(&self.fs, &other.fs) => Ok(other),
+            _ => Err(FsError::NotSameFs),
+        }
+    }
+
     /// Ensure to open the file and store a `File` into `self.file`,
     /// return the `MutexGuard`.
     /// If the type of `self.path` is not file, then return Err
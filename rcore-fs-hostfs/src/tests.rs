@@ -0,0 +1,15 @@
+use crate::*;
+use rcore_fs_testsuite::{vfs_tests, Features};
+
+fn _create_new_hostfs() -> (Arc<HostFS>, tempfile::TempDir) {
+    let dir = tempfile::tempdir().expect("failed to create dir");
+    (HostFS::new(dir.path()), dir)
+}
+
+vfs_tests!(
+    _create_new_hostfs(),
+    Features {
+        symlink: false,
+        ..Features::all()
+    }
+);
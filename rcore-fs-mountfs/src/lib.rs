@@ -9,8 +9,14 @@ use alloc::{
     collections::BTreeMap,
     string::String,
     sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
 };
-use core::{any::Any, future::Future, pin::Pin};
 use rcore_fs::vfs::*;
 use spin::RwLock;
 
@@ -21,16 +27,52 @@ mod tests;
 pub struct MountFS {
     /// The inner file system
     inner: Arc<dyn FileSystem>,
+    /// The root INode of this mount in the inner file system,
+    /// which is not the root of `inner` for bind mounts
+    inner_root: Arc<dyn INode>,
     /// All mounted children file systems
     mountpoints: RwLock<BTreeMap<INodeId, Arc<MountFS>>>,
-    /// The mount point of this file system
-    self_mountpoint: Option<Arc<MNode>>,
+    /// The mount point of this file system, `None` for the root of a namespace
+    self_mountpoint: RwLock<Option<Arc<MNode>>>,
+    /// Number of alive `MNode`s in this file system
+    open_inodes: AtomicUsize,
+    /// Options of this mount
+    options: RwLock<MountOptions>,
     /// Weak reference to self
     self_ref: Weak<MountFS>,
 }
 
 type INodeId = usize;
 
+/// Options of a mount, which apply to all inodes in it
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MountOptions {
+    /// Reject all modifications with `FsError::ReadOnly`
+    pub read_only: bool,
+    /// Advisory: the kernel should not execute programs in this mount
+    pub no_exec: bool,
+    /// Advisory: the kernel should ignore set-user-ID and set-group-ID bits in this mount
+    pub no_suid: bool,
+    /// Reject accessing character or block devices with `FsError::NoDevice`
+    pub no_dev: bool,
+    /// Do not update access time on reads, setting it explicitly still works
+    pub no_atime: bool,
+}
+
+/// An entry of the mount table
+pub struct MountInfo {
+    /// Absolute path of the mount point
+    pub path: String,
+    /// Type name of the mounted file system
+    pub fs_type: &'static str,
+    /// Options of the mount
+    pub options: MountOptions,
+    /// Information of the mounted file system
+    pub info: FsInfo,
+    /// The mount
+    pub fs: Arc<MountFS>,
+}
+
 /// INode for `MountFS`
 pub struct MNode {
     /// The inner INode
@@ -44,10 +86,18 @@ pub struct MNode {
 impl MountFS {
     /// Create a `MountFS` wrapper for file system `fs`
     pub fn new(fs: Arc<dyn FileSystem>) -> Arc<Self> {
+        Self::new_with_options(fs, MountOptions::default())
+    }
+
+    /// Create a `MountFS` wrapper for file system `fs` with `options`
+    pub fn new_with_options(fs: Arc<dyn FileSystem>, options: MountOptions) -> Arc<Self> {
         MountFS {
+            inner_root: fs.root_inode(),
             inner: fs,
             mountpoints: RwLock::new(BTreeMap::new()),
-            self_mountpoint: None,
+            self_mountpoint: RwLock::new(None),
+            open_inodes: AtomicUsize::new(0),
+            options: RwLock::new(options),
             self_ref: Weak::default(),
         }
         .wrap()
@@ -69,13 +119,200 @@ impl MountFS {
 
     /// Strong type version of `root_inode`
     pub fn mountpoint_root_inode(&self) -> Arc<MNode> {
+        // counted under the lock of the parent mount, which `umount` checks it under
+        let mountpoint = self.self_mountpoint.read().clone();
+        let _parent = mountpoint
+            .as_ref()
+            .map(|inode| inode.vfs.mountpoints.read());
         MNode {
-            inode: self.inner.root_inode(),
+            inode: self.inner_root.clone(),
             vfs: self.self_ref.upgrade().unwrap(),
             self_ref: Weak::default(),
         }
         .wrap()
     }
+
+    /// The root INode of the namespace this mount is in
+    fn namespace_root(&self) -> Arc<MNode> {
+        let mountpoint = self.self_mountpoint.read().clone();
+        match mountpoint {
+            Some(inode) => inode.vfs.namespace_root(),
+            None => self.mountpoint_root_inode(),
+        }
+    }
+
+    /// List all mounts in the namespace this mount is in, like `/proc/mounts`.
+    ///
+    /// Parents are listed before their children, so unmounting in reverse
+    /// order unmounts children first.
+    pub fn mounts(&self) -> Result<Vec<MountInfo>> {
+        let mut mounts = Vec::new();
+        self.namespace_root().vfs.collect_mounts(&mut mounts)?;
+        Ok(mounts)
+    }
+
+    fn collect_mounts(&self, mounts: &mut Vec<MountInfo>) -> Result<()> {
+        mounts.push(MountInfo {
+            path: self.mountpoint_root_inode().path(None)?,
+            fs_type: self.inner.name(),
+            options: self.options(),
+            info: self.inner.info(),
+            fs: self.self_ref.upgrade().unwrap(),
+        });
+        let children: Vec<_> = self.mountpoints.read().values().cloned().collect();
+        for child in children {
+            child.collect_mounts(mounts)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the mount tree from this mount to a new namespace, whose root is
+    /// the copy of this mount. Mounts in the new namespace are independent
+    /// from the original ones, but share the same file systems.
+    pub fn new_namespace(&self) -> Arc<MountFS> {
+        self.copy_tree(None)
+    }
+
+    fn copy_tree(&self, mountpoint: Option<Arc<MNode>>) -> Arc<MountFS> {
+        let fs = MountFS {
+            inner: self.inner.clone(),
+            inner_root: self.inner_root.clone(),
+            mountpoints: RwLock::new(BTreeMap::new()),
+            self_mountpoint: RwLock::new(mountpoint),
+            open_inodes: AtomicUsize::new(0),
+            options: RwLock::new(self.options()),
+            self_ref: Weak::default(),
+        }
+        .wrap();
+        for (&inode_id, child) in self.mountpoints.read().iter() {
+            let mountpoint = MNode {
+                inode: child.self_mountpoint.read().as_ref().unwrap().inode.clone(),
+                vfs: fs.clone(),
+                self_ref: Weak::default(),
+            }
+            .wrap();
+            let child = child.copy_tree(Some(mountpoint));
+            fs.mountpoints.write().insert(inode_id, child);
+        }
+        fs
+    }
+
+    /// Is this mount `ancestor` or mounted under it?
+    fn is_under(&self, ancestor: &MountFS) -> bool {
+        if core::ptr::eq(self, ancestor) {
+            return true;
+        }
+        match &*self.self_mountpoint.read() {
+            Some(mountpoint) => mountpoint.vfs.is_under(ancestor),
+            None => false,
+        }
+    }
+
+    /// Make the mount of `new_root` the root of this namespace, and mount
+    /// the old root, which must be `self`, at `put_old`, like `pivot_root(2)`.
+    /// Return the new root.
+    pub fn pivot_root(&self, new_root: &Arc<MNode>, put_old: &Arc<MNode>) -> Result<Arc<MountFS>> {
+        if self.self_mountpoint.read().is_some()
+            || !new_root.is_mountpoint_root()
+            || core::ptr::eq(&*new_root.vfs, self)
+            || !new_root.vfs.is_under(self)
+            || !put_old.vfs.is_under(&new_root.vfs)
+        {
+            return Err(FsError::InvalidParam);
+        }
+        let metadata = put_old.metadata()?;
+        if metadata.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if put_old.vfs.mountpoints.read().contains_key(&metadata.inode) {
+            return Err(FsError::Busy);
+        }
+        // detach the new root from its mount point
+        let new_fs = new_root.vfs.clone();
+        let mountpoint = new_fs.self_mountpoint.write().take().unwrap();
+        let inode_id = mountpoint.metadata()?.inode;
+        mountpoint.vfs.mountpoints.write().remove(&inode_id);
+        // mount the old root at `put_old`
+        *self.self_mountpoint.write() = Some(put_old.clone());
+        put_old
+            .vfs
+            .mountpoints
+            .write()
+            .insert(metadata.inode, self.self_ref.upgrade().unwrap());
+        Ok(new_fs)
+    }
+
+    /// Options of this mount
+    pub fn options(&self) -> MountOptions {
+        *self.options.read()
+    }
+
+    /// Change options of this mount
+    pub fn remount(&self, options: MountOptions) -> Result<()> {
+        if options.read_only && !self.options().read_only {
+            self.sync()?;
+        }
+        *self.options.write() = options;
+        Ok(())
+    }
+
+    /// Return `ReadOnly` if this mount is read-only
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read().read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Return `NoDevice` if `inode` is a device and devices are not allowed in this mount
+    fn check_dev(&self, inode: &dyn INode) -> Result<()> {
+        if self.options.read().no_dev {
+            let type_ = inode.metadata()?.type_;
+            if type_ == FileType::CharDevice || type_ == FileType::BlockDevice {
+                return Err(FsError::NoDevice);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmount this file system from its mount point.
+    ///
+    /// Return `Busy` if it has open inodes or mounted children, unless `lazy`
+    /// is set. A lazily detached file system is still usable through its open
+    /// inodes, but can not be found from the mount point any more.
+    pub fn umount(&self, lazy: bool) -> Result<()> {
+        self._umount(lazy, 0)
+    }
+
+    /// Unmount with `exempt` open inodes held by the caller
+    fn _umount(&self, lazy: bool, exempt: usize) -> Result<()> {
+        // the root file system can not be unmounted
+        let mountpoint = self.self_mountpoint.read().clone().ok_or(FsError::Busy)?;
+        let inode_id = mountpoint.metadata()?.inode;
+        // new inodes are opened from the mount point under this lock
+        let mut mountpoints = mountpoint.vfs.mountpoints.write();
+        match mountpoints.get(&inode_id) {
+            Some(fs) if Arc::ptr_eq(fs, &self.self_ref.upgrade().unwrap()) => {}
+            // already unmounted
+            _ => return Err(FsError::InvalidParam),
+        }
+        if lazy {
+            self.sync()?;
+        } else {
+            // and file systems are mounted here under this one
+            let children = self.mountpoints.write();
+            if !children.is_empty() || self.open_inodes.load(Ordering::SeqCst) > exempt {
+                return Err(FsError::Busy);
+            }
+            // nothing mounted here to sync
+            self.inner.sync()?;
+        }
+        mountpoints.remove(&inode_id);
+        drop(mountpoints);
+        // ".." at the root of a detached mount is itself
+        *self.self_mountpoint.write() = None;
+        Ok(())
+    }
 }
 
 impl MNode {
@@ -84,6 +321,7 @@ impl MNode {
     fn wrap(self) -> Arc<Self> {
         // Create an Arc, make a Weak from it, then put it into the struct.
         // It's a little tricky.
+        self.vfs.open_inodes.fetch_add(1, Ordering::SeqCst);
         let inode = Arc::new(self);
         let weak = Arc::downgrade(&inode);
         let ptr = Arc::into_raw(inode) as *mut Self;
@@ -94,15 +332,38 @@ impl MNode {
     }
 
     /// Mount file system `fs` at this INode
-    pub fn mount(&self, fs: Arc<dyn FileSystem>) -> Result<Arc<MountFS>> {
+    pub fn mount(&self, fs: Arc<dyn FileSystem>, options: MountOptions) -> Result<Arc<MountFS>> {
+        let root = fs.root_inode();
+        self.mount_inner(fs, root, options)
+    }
+
+    /// Bind the subtree at `source` to this INode, like `mount --bind`.
+    /// Mounts under `source` are not included.
+    pub fn bind_mount(&self, source: &Arc<MNode>, options: MountOptions) -> Result<Arc<MountFS>> {
+        if source.inode.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        self.mount_inner(source.vfs.inner.clone(), source.inode.clone(), options)
+    }
+
+    /// Mount `root` in file system `fs` at this INode
+    fn mount_inner(
+        &self,
+        fs: Arc<dyn FileSystem>,
+        root: Arc<dyn INode>,
+        options: MountOptions,
+    ) -> Result<Arc<MountFS>> {
         let metadata = self.inode.metadata()?;
         if metadata.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
         let new_fs = MountFS {
             inner: fs,
+            inner_root: root,
             mountpoints: RwLock::new(BTreeMap::new()),
-            self_mountpoint: Some(self.self_ref.upgrade().unwrap()),
+            self_mountpoint: RwLock::new(Some(self.self_ref.upgrade().unwrap())),
+            open_inodes: AtomicUsize::new(0),
+            options: RwLock::new(options),
             self_ref: Weak::default(),
         }
         .wrap();
@@ -113,25 +374,74 @@ impl MNode {
         Ok(new_fs)
     }
 
+    /// Options of the mount this INode belongs to
+    pub fn mount_options(&self) -> MountOptions {
+        self.vfs.options()
+    }
+
+    /// Change options of the mount this INode belongs to
+    pub fn remount(&self, options: MountOptions) -> Result<()> {
+        self.vfs.remount(options)
+    }
+
+    /// Unmount the file system mounted at here, or whose root is here.
+    /// See `MountFS::umount`.
+    pub fn umount(&self, lazy: bool) -> Result<()> {
+        if self.vfs.self_mountpoint.read().is_some() && self.is_mountpoint_root() {
+            // exempt `self`
+            return self.vfs._umount(lazy, 1);
+        }
+        let inode_id = self.metadata()?.inode;
+        let sub_vfs = self.vfs.mountpoints.read().get(&inode_id).cloned();
+        match sub_vfs {
+            Some(sub_vfs) => sub_vfs.umount(lazy),
+            None => Err(FsError::InvalidParam),
+        }
+    }
+
     /// Get the root INode of the mounted fs at here.
     /// Return self if no mounted fs.
     fn overlaid_inode(&self) -> Arc<MNode> {
         let inode_id = self.metadata().unwrap().inode;
-        if let Some(sub_vfs) = self.vfs.mountpoints.read().get(&inode_id) {
-            sub_vfs.mountpoint_root_inode()
-        } else {
-            self.self_ref.upgrade().unwrap()
+        let sub_vfs = self.vfs.mountpoints.read().get(&inode_id).cloned();
+        match sub_vfs {
+            // there may be another fs mounted on its root
+            Some(sub_vfs) => sub_vfs.mountpoint_root_inode().overlaid_inode(),
+            None => self.self_ref.upgrade().unwrap(),
+        }
+    }
+
+    /// Return `NotSameFs` if `other` is not in the same mount,
+    /// even if it is in the same file system through a bind mount
+    fn check_same_mount(&self, other: &Arc<dyn INode>) -> Result<()> {
+        if Arc::as_ptr(&other.fs()) as *const () != Arc::as_ptr(&self.vfs) as *const () {
+            return Err(FsError::NotSameFs);
         }
+        Ok(())
     }
 
-    /// Is the root INode of its FS?
+    /// Is the root INode of its mount?
     fn is_mountpoint_root(&self) -> bool {
-        self.inode.fs().root_inode().metadata().unwrap().inode
-            == self.inode.metadata().unwrap().inode
+        self.vfs.inner_root.metadata().unwrap().inode == self.inode.metadata().unwrap().inode
+    }
+
+    /// Are `self` and `other` the same INode in the same mount?
+    fn is_same(&self, other: &MNode) -> bool {
+        Arc::ptr_eq(&self.vfs, &other.vfs)
+            && self.inode.metadata().unwrap().inode == other.inode.metadata().unwrap().inode
+    }
+
+    /// Is `self` the `root` directory, or the root of the namespace if `root` is `None`?
+    fn is_root(&self, root: Option<&Arc<MNode>>) -> bool {
+        match root {
+            Some(root) => self.is_same(root) || self.is_same(&root.overlaid_inode()),
+            None => self.is_mountpoint_root() && self.vfs.self_mountpoint.read().is_none(),
+        }
     }
 
     /// Strong type version of `create()`
     pub fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<Self>> {
+        self.vfs.check_writable()?;
         Ok(MNode {
             inode: self.inode.create(name, type_, mode)?,
             vfs: self.vfs.clone(),
@@ -140,42 +450,41 @@ impl MNode {
         .wrap())
     }
 
-    /// Strong type version of `find()`
-    pub fn find(&self, root: bool, name: &str) -> Result<Arc<Self>> {
+    /// Strong type version of `find()`.
+    ///
+    /// `root` is the root directory of the caller, which ".." never goes
+    /// beyond, like after `chroot(2)`. `None` means the root of the namespace.
+    pub fn find(&self, root: Option<&Arc<MNode>>, name: &str) -> Result<Arc<Self>> {
         match name {
             "" | "." => Ok(self.self_ref.upgrade().unwrap()),
             ".." => {
-                // Going Up
-                // We need to check these things:
-                // 1. Is going forward allowed, considering the current root?
-                // 2. Is going forward trespassing the filesystem border,
-                //    thus requires falling back to parent of original_mountpoint?
-                // TODO: check going up.
-                if root {
-                    Ok(self.self_ref.upgrade().unwrap())
-                } else if self.is_mountpoint_root() {
-                    // Here is mountpoint.
-                    match &self.vfs.self_mountpoint {
-                        Some(inode) => inode.find(root, ".."),
-                        // root fs
+                if self.is_root(root) {
+                    return Ok(self.self_ref.upgrade().unwrap());
+                }
+                if self.is_mountpoint_root() {
+                    // Going up across the mount, the parent is the parent of the mount point.
+                    let mountpoint = self.vfs.self_mountpoint.read().clone();
+                    return match mountpoint {
+                        Some(mountpoint) => mountpoint.find(root, ".."),
+                        // root of a namespace or a detached mount
                         None => Ok(self.self_ref.upgrade().unwrap()),
-                    }
-                } else {
-                    // Not trespassing filesystem border. Parent and myself in the same filesystem.
-                    Ok(MNode {
-                        inode: self.inode.find(name)?, // Going up is handled by the filesystem. A better API?
-                        vfs: self.vfs.clone(),
-                        self_ref: Weak::default(),
-                    }
-                    .wrap())
+                    };
+                }
+                // Parent and myself in the same mount.
+                Ok(MNode {
+                    inode: self.inode.find(name)?,
+                    vfs: self.vfs.clone(),
+                    self_ref: Weak::default(),
                 }
+                .wrap())
             }
             _ => {
                 // Going down may trespass the filesystem border.
                 // An INode replacement is required here.
+                let dir = self.overlaid_inode();
                 Ok(MNode {
-                    inode: self.overlaid_inode().inode.find(name)?,
-                    vfs: self.vfs.clone(),
+                    inode: dir.inode.find(name)?,
+                    vfs: dir.vfs.clone(),
                     self_ref: Weak::default(),
                 }
                 .wrap()
@@ -184,19 +493,64 @@ impl MNode {
         }
     }
 
+    /// Lookup path from this INode like `lookup_follow()` of `INode`, with
+    /// `root` as the root directory. See `find()`.
+    pub fn lookup_follow(
+        &self,
+        root: Option<&Arc<MNode>>,
+        path: &str,
+        follow_times: usize,
+    ) -> Result<Arc<Self>> {
+        if self.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let (mut result, rest_path) = match path.strip_prefix('/') {
+            Some(rest) => match root {
+                Some(root) => (root.overlaid_inode(), rest),
+                None => (self.vfs.namespace_root(), rest),
+            },
+            None => (self.self_ref.upgrade().unwrap(), path),
+        };
+        let mut names = rest_path.split('/').filter(|name| !name.is_empty());
+        while let Some(name) = names.next() {
+            if result.metadata()?.type_ != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            let inode = result.find(root, name)?;
+            if inode.metadata()?.type_ == FileType::SymLink && follow_times > 0 {
+                let mut content = [0u8; 256];
+                let len = inode.read_at(0, &mut content)?;
+                let link_path =
+                    core::str::from_utf8(&content[..len]).map_err(|_| FsError::NotDir)?;
+                let mut new_path = String::from(link_path);
+                for name in names {
+                    new_path += "/";
+                    new_path += name;
+                }
+                return result.lookup_follow(root, &new_path, follow_times - 1);
+            }
+            result = inode;
+        }
+        Ok(result)
+    }
+
     /// If `child` is a child of `self`, return its name.
+    /// `child` can be either a mount point or the root mounted on it.
     pub fn find_name_by_child(&self, child: &Arc<MNode>) -> Result<String> {
+        let dir = self.overlaid_inode();
         for index in 0.. {
-            let name = self.inode.get_entry(index)?;
+            let name = dir.inode.get_entry(index)?;
             match name.as_ref() {
                 "." | ".." => {}
                 _ => {
-                    let queryback = self.find(false, &name)?.overlaid_inode();
-                    // TODO: mountpoint check!
                     debug!("checking name {}", name);
-                    if Arc::ptr_eq(&queryback.vfs, &child.vfs)
-                        && queryback.inode.metadata()?.inode == child.inode.metadata()?.inode
-                    {
+                    let entry = MNode {
+                        inode: dir.inode.find(&name)?,
+                        vfs: dir.vfs.clone(),
+                        self_ref: Weak::default(),
+                    }
+                    .wrap();
+                    if entry.is_same(child) || entry.overlaid_inode().is_same(child) {
                         return Ok(name);
                     }
                 }
@@ -204,6 +558,31 @@ impl MNode {
         }
         Err(FsError::EntryNotFound)
     }
+
+    /// Get the absolute path of this INode from `root`, like `getcwd(3)`.
+    /// Return `EntryNotFound` if it is not under `root`.
+    pub fn path(&self, root: Option<&Arc<MNode>>) -> Result<String> {
+        let mut names = Vec::new();
+        let mut current = self.self_ref.upgrade().unwrap();
+        while !current.is_root(root) {
+            let parent = current.find(root, "..")?;
+            if parent.is_same(&current) {
+                // reach the top without passing `root`
+                return Err(FsError::EntryNotFound);
+            }
+            names.push(parent.find_name_by_child(&current)?);
+            current = parent;
+        }
+        if names.is_empty() {
+            return Ok(String::from("/"));
+        }
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path += "/";
+            path += name;
+        }
+        Ok(path)
+    }
 }
 
 impl FileSystem for MountFS {
@@ -216,24 +595,46 @@ impl FileSystem for MountFS {
     }
 
     fn root_inode(&self) -> Arc<dyn INode> {
-        match &self.self_mountpoint {
-            Some(inode) => inode.vfs.root_inode(),
-            None => self.mountpoint_root_inode(),
-        }
+        self.namespace_root()
     }
 
     fn info(&self) -> FsInfo {
         self.inner.info()
     }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+}
+
+impl Drop for MNode {
+    fn drop(&mut self) {
+        self.vfs.open_inodes.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 // unwrap `MNode` and forward methods to inner except `find()`
 impl INode for MNode {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
-        self.inode.read_at(offset, buf)
+        self.vfs.check_dev(&*self.inode)?;
+        if !self.vfs.options().no_atime {
+            return self.inode.read_at(offset, buf);
+        }
+        // the inner file system updates it by itself, put it back
+        let atime = self.inode.metadata().map(|metadata| metadata.atime);
+        let len = self.inode.read_at(offset, buf)?;
+        if let Ok(atime) = atime {
+            let metadata = self.inode.metadata()?;
+            if metadata.atime != atime {
+                self.inode.set_metadata(&Metadata { atime, ..metadata })?;
+            }
+        }
+        Ok(len)
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.vfs.check_writable()?;
+        self.vfs.check_dev(&*self.inode)?;
         self.inode.write_at(offset, buf)
     }
 
@@ -253,6 +654,7 @@ impl INode for MNode {
     }
 
     fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        self.vfs.check_writable()?;
         self.inode.set_metadata(metadata)
     }
 
@@ -265,6 +667,7 @@ impl INode for MNode {
     }
 
     fn resize(&self, len: usize) -> Result<()> {
+        self.vfs.check_writable()?;
         self.inode.resize(len)
     }
 
@@ -273,10 +676,13 @@ impl INode for MNode {
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        self.vfs.check_writable()?;
+        self.check_same_mount(other)?;
         self.inode.link(name, other)
     }
 
     fn unlink(&self, name: &str) -> Result<()> {
+        self.vfs.check_writable()?;
         let inode_id = self.inode.find(name)?.metadata()?.inode;
         // target INode is being mounted
         if self.vfs.mountpoints.read().contains_key(&inode_id) {
@@ -286,11 +692,13 @@ impl INode for MNode {
     }
 
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        self.vfs.check_writable()?;
+        self.check_same_mount(target)?;
         self.inode.move_(old_name, target, new_name)
     }
 
     fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
-        Ok(self.find(false, name)?)
+        Ok(self.find(None, name)?)
     }
 
     fn get_entry(&self, id: usize) -> Result<String> {
@@ -302,10 +710,12 @@ impl INode for MNode {
     }
 
     fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.vfs.check_dev(&*self.inode)?;
         self.inode.io_control(cmd, data)
     }
 
     fn mmap(&self, area: MMapArea) -> Result<()> {
+        self.vfs.check_dev(&*self.inode)?;
         self.inode.mmap(area)
     }
 
@@ -11,7 +11,7 @@ fn mount() {
     let root1 = ramfs.root_inode();
     root1.create("file", FileType::File, 0o777).unwrap();
 
-    mnt.mount(ramfs).unwrap();
+    mnt.mount(ramfs, MountOptions::default()).unwrap();
     assert!((mnt as Arc<dyn INode>).find("file").is_ok());
     assert!((root as Arc<dyn INode>).lookup("mnt/file").is_ok());
 }
@@ -22,6 +22,504 @@ fn remove_busy() {
     let root = rootfs.mountpoint_root_inode();
     let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
     let ramfs = RamFS::new();
-    mnt.mount(ramfs).unwrap();
+    mnt.mount(ramfs, MountOptions::default()).unwrap();
     assert_eq!(root.unlink("mnt"), Err(FsError::Busy));
 }
+
+#[test]
+fn umount() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    mnt.create("under", FileType::File, 0o777).unwrap();
+    assert_eq!(mnt.umount(false), Err(FsError::InvalidParam));
+
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("file", FileType::File, 0o777)
+        .unwrap();
+    mnt.mount(ramfs.clone(), MountOptions::default()).unwrap();
+    let mounted = root.find(None, "mnt").unwrap();
+    let root = root as Arc<dyn INode>;
+    assert!(root.lookup("mnt/under").is_err());
+
+    // unmount through the root of the mounted fs
+    mounted.umount(false).unwrap();
+    assert!(root.lookup("mnt/under").is_ok());
+    assert!(root.lookup("mnt/file").is_err());
+    assert_eq!(mnt.umount(false), Err(FsError::InvalidParam));
+
+    // mount again and unmount through the mount point
+    mnt.mount(ramfs, MountOptions::default()).unwrap();
+    assert!(root.lookup("mnt/file").is_ok());
+    mnt.umount(false).unwrap();
+    assert!(root.lookup("mnt/under").is_ok());
+
+    assert_eq!(rootfs.umount(false), Err(FsError::Busy));
+}
+
+#[test]
+fn umount_busy() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let fs = mnt.mount(RamFS::new(), MountOptions::default()).unwrap();
+
+    // open inode
+    let file = (root.clone() as Arc<dyn INode>)
+        .lookup("mnt")
+        .unwrap()
+        .create("file", FileType::File, 0o777)
+        .unwrap();
+    assert_eq!(mnt.umount(false), Err(FsError::Busy));
+    drop(file);
+
+    // nested mount
+    let sub = fs
+        .mountpoint_root_inode()
+        .create("sub", FileType::Dir, 0o777)
+        .unwrap();
+    sub.mount(RamFS::new(), MountOptions::default()).unwrap();
+    assert_eq!(mnt.umount(false), Err(FsError::Busy));
+    sub.umount(false).unwrap();
+    drop(sub);
+    mnt.umount(false).unwrap();
+}
+
+#[test]
+fn umount_lazy() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    mnt.mount(RamFS::new(), MountOptions::default()).unwrap();
+
+    let file = (root.clone() as Arc<dyn INode>)
+        .lookup("mnt")
+        .unwrap()
+        .create("file", FileType::File, 0o777)
+        .unwrap();
+    mnt.umount(true).unwrap();
+    assert!((root as Arc<dyn INode>).lookup("mnt/file").is_err());
+
+    // still usable through open inodes
+    file.write_at(0, b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    assert_eq!(file.read_at(0, &mut buf), Ok(5));
+}
+
+#[test]
+fn mount_read_only() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let ramfs = RamFS::new();
+    let file = ramfs
+        .root_inode()
+        .create("file", FileType::File, 0o777)
+        .unwrap();
+    file.write_at(0, b"hello").unwrap();
+    let options = MountOptions {
+        read_only: true,
+        ..MountOptions::default()
+    };
+    mnt.mount(ramfs, options).unwrap();
+
+    let mounted = root.find(None, "mnt").unwrap();
+    assert_eq!(mounted.mount_options(), options);
+    assert_eq!(root.mount_options(), MountOptions::default());
+    let file = mounted.find(None, "file").unwrap();
+    let mut buf = [0u8; 5];
+    assert_eq!(file.read_at(0, &mut buf), Ok(5));
+    assert_eq!(file.write_at(0, b"world"), Err(FsError::ReadOnly));
+    assert_eq!(file.resize(0), Err(FsError::ReadOnly));
+    let metadata = file.metadata().unwrap();
+    assert_eq!(file.set_metadata(&metadata), Err(FsError::ReadOnly));
+    assert_eq!(
+        mounted.create("new", FileType::File, 0o777).err(),
+        Some(FsError::ReadOnly)
+    );
+    assert_eq!(mounted.unlink("file"), Err(FsError::ReadOnly));
+    let dyn_file = file.clone() as Arc<dyn INode>;
+    assert_eq!(mounted.link("link", &dyn_file), Err(FsError::ReadOnly));
+    let dyn_mounted = mounted.clone() as Arc<dyn INode>;
+    assert_eq!(
+        mounted.move_("file", &dyn_mounted, "new"),
+        Err(FsError::ReadOnly)
+    );
+
+    // remount as read-write
+    mounted.remount(MountOptions::default()).unwrap();
+    file.write_at(0, b"world").unwrap();
+    mounted.create("new", FileType::File, 0o777).unwrap();
+}
+
+#[test]
+fn mount_no_atime() {
+    use core::sync::atomic::AtomicI64;
+    use rcore_fs::dev::TimeProvider;
+    use rcore_fs_ramfs::RamFSConfig;
+
+    struct Clock(AtomicI64);
+    impl TimeProvider for Clock {
+        fn current_time(&self) -> Timespec {
+            Timespec {
+                sec: self.0.load(Ordering::SeqCst),
+                nsec: 0,
+            }
+        }
+    }
+    static CLOCK: Clock = Clock(AtomicI64::new(10));
+    let ramfs = RamFS::new_with_config(RamFSConfig {
+        time_provider: Some(&CLOCK),
+        ..RamFSConfig::default()
+    });
+    let rootfs = MountFS::new_with_options(
+        ramfs,
+        MountOptions {
+            no_atime: true,
+            ..MountOptions::default()
+        },
+    );
+    let root = rootfs.mountpoint_root_inode();
+    let file = root.create("file", FileType::File, 0o777).unwrap();
+    file.write_at(0, b"data").unwrap();
+
+    // reading does not update it
+    CLOCK.0.store(99, Ordering::SeqCst);
+    file.read_at(0, &mut [0; 4]).unwrap();
+    assert_eq!(file.metadata().unwrap().atime.sec, 10);
+
+    // setting it does
+    let mut metadata = file.metadata().unwrap();
+    metadata.atime.sec = 1;
+    metadata.mtime.sec = 2;
+    file.set_metadata(&metadata).unwrap();
+    let new = file.metadata().unwrap();
+    assert_eq!((new.atime.sec, new.mtime.sec), (1, 2));
+}
+
+#[test]
+fn mount_no_dev() {
+    use rcore_fs_devfs::{special::NullINode, DevFS};
+
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let dev = root.create("dev", FileType::Dir, 0o777).unwrap();
+    let devfs = DevFS::new();
+    devfs
+        .root()
+        .add("null", Arc::new(NullINode::new()))
+        .unwrap();
+    let options = MountOptions {
+        no_dev: true,
+        ..MountOptions::default()
+    };
+    dev.mount(devfs, options).unwrap();
+
+    let null = (root.clone() as Arc<dyn INode>).lookup("dev/null").unwrap();
+    let mut buf = [0u8; 1];
+    assert_eq!(null.read_at(0, &mut buf), Err(FsError::NoDevice));
+    assert_eq!(null.write_at(0, &buf), Err(FsError::NoDevice));
+    assert_eq!(null.io_control(0, 0), Err(FsError::NoDevice));
+    // still visible
+    assert_eq!(null.metadata().unwrap().type_, FileType::CharDevice);
+    // other files are not affected
+    let file = root.create("file", FileType::File, 0o777).unwrap();
+    file.remount(options).unwrap();
+    assert_eq!(file.write_at(0, &buf), Ok(1));
+
+    let mounted = root.find(None, "dev").unwrap();
+    mounted.remount(MountOptions::default()).unwrap();
+    assert_eq!(null.read_at(0, &mut buf), Ok(0));
+    assert_eq!(null.write_at(0, &buf), Ok(1));
+}
+
+#[test]
+fn bind_mount() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let data = root.create("data", FileType::Dir, 0o777).unwrap();
+    let sub = data.create("sub", FileType::Dir, 0o777).unwrap();
+    sub.create("file", FileType::File, 0o777).unwrap();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+
+    mnt.bind_mount(&sub, MountOptions::default()).unwrap();
+    let root = root as Arc<dyn INode>;
+    let file = root.lookup("mnt/file").unwrap();
+    assert_eq!(
+        file.metadata().unwrap().inode,
+        root.lookup("data/sub/file")
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .inode
+    );
+    // ".." of the bind root is the parent of the mount point
+    let bound = root.lookup("mnt").unwrap();
+    assert_eq!(
+        bound.find("..").unwrap().metadata().unwrap().inode,
+        root.metadata().unwrap().inode
+    );
+    // changes are visible in both places
+    bound.create("new", FileType::File, 0o777).unwrap();
+    assert!(root.lookup("data/sub/new").is_ok());
+    // no link or move across mounts
+    let data = data as Arc<dyn INode>;
+    assert_eq!(bound.link("link", &data), Err(FsError::NotSameFs));
+    assert_eq!(bound.move_("new", &data, "new"), Err(FsError::NotSameFs));
+
+    mnt.umount(false).unwrap_err();
+    drop((file, bound));
+    mnt.umount(false).unwrap();
+    assert!(root.lookup("mnt/file").is_err());
+}
+
+#[test]
+fn namespace() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let other = root.create("other", FileType::Dir, 0o777).unwrap();
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("file", FileType::File, 0o777)
+        .unwrap();
+    mnt.mount(ramfs, MountOptions::default()).unwrap();
+
+    let ns = rootfs.new_namespace();
+    let ns_root = ns.mountpoint_root_inode() as Arc<dyn INode>;
+    assert!(ns_root.lookup("mnt/file").is_ok());
+
+    // mounts in one namespace do not appear in the other
+    other.mount(RamFS::new(), MountOptions::default()).unwrap();
+    ns_root
+        .lookup("other")
+        .unwrap()
+        .create("x", FileType::File, 0o777)
+        .unwrap();
+    let root = root as Arc<dyn INode>;
+    assert!(root.lookup("other/x").is_err());
+    mnt.umount(false).unwrap();
+    assert!(root.lookup("mnt/file").is_err());
+    assert!(ns_root.lookup("mnt/file").is_ok());
+}
+
+#[test]
+fn pivot_root() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    root.create("old_file", FileType::File, 0o777).unwrap();
+    let new = root.create("new", FileType::Dir, 0o777).unwrap();
+    let newfs = RamFS::new();
+    newfs
+        .root_inode()
+        .create("old", FileType::Dir, 0o777)
+        .unwrap();
+    new.mount(newfs, MountOptions::default()).unwrap();
+
+    let new_root = root.find(None, "new").unwrap();
+    let put_old = new_root.find(None, "old").unwrap();
+    assert_eq!(
+        rootfs.pivot_root(&root, &put_old).err(),
+        Some(FsError::InvalidParam)
+    );
+    let new_rootfs = rootfs.pivot_root(&new_root, &put_old).unwrap();
+
+    let root = new_rootfs.mountpoint_root_inode() as Arc<dyn INode>;
+    assert!(root.lookup("old/old_file").is_ok());
+    // the new root is not mounted at the old place any more
+    assert!(root.lookup("old/new/old").is_err());
+    assert_eq!(
+        root.find("..").unwrap().metadata().unwrap().inode,
+        root.metadata().unwrap().inode
+    );
+    let old = root.lookup("old").unwrap();
+    assert_eq!(
+        old.find("..").unwrap().metadata().unwrap().inode,
+        root.metadata().unwrap().inode
+    );
+}
+
+#[test]
+fn path() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    assert_eq!(root.path(None).unwrap(), "/");
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let fs = mnt.mount(RamFS::new(), MountOptions::default()).unwrap();
+    let a = fs
+        .mountpoint_root_inode()
+        .create("a", FileType::Dir, 0o777)
+        .unwrap();
+    a.mount(RamFS::new(), MountOptions::default()).unwrap();
+    // stacked on the root of the nested mount
+    let a = root.lookup_follow(None, "mnt/a", 0).unwrap();
+    a.mount(RamFS::new(), MountOptions::default()).unwrap();
+    root.lookup_follow(None, "/mnt/a", 0)
+        .unwrap()
+        .create("b", FileType::Dir, 0o777)
+        .unwrap();
+
+    let b = root.lookup_follow(None, "mnt/a/b", 0).unwrap();
+    assert_eq!(b.path(None).unwrap(), "/mnt/a/b");
+    let a = b.find(None, "..").unwrap();
+    assert_eq!(a.path(None).unwrap(), "/mnt/a");
+    assert!(a.is_same(&root.lookup_follow(None, "mnt/a", 0).unwrap()));
+    assert_eq!(a.find(None, "..").unwrap().path(None).unwrap(), "/mnt");
+    assert!(a
+        .find(None, "..")
+        .unwrap()
+        .find(None, "..")
+        .unwrap()
+        .is_same(&root));
+    assert!(root.find(None, "..").unwrap().is_same(&root));
+
+    // both the mount point and the mounted root are found by name
+    assert_eq!(root.find_name_by_child(&mnt).unwrap(), "mnt");
+    let mounted = root.find(None, "mnt").unwrap();
+    assert_eq!(root.find_name_by_child(&mounted).unwrap(), "mnt");
+}
+
+#[test]
+fn chroot() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let jail = root.create("jail", FileType::Dir, 0o777).unwrap();
+    root.create("secret", FileType::File, 0o777).unwrap();
+    let mnt = jail.create("mnt", FileType::Dir, 0o777).unwrap();
+    mnt.mount(RamFS::new(), MountOptions::default()).unwrap();
+    let dir = root
+        .lookup_follow(None, "jail/mnt", 0)
+        .unwrap()
+        .create("dir", FileType::Dir, 0o777)
+        .unwrap();
+
+    let top = dir.lookup_follow(Some(&jail), "../../../..", 0).unwrap();
+    assert!(top.is_same(&jail));
+    let jail = Some(&jail);
+    assert_eq!(dir.path(jail).unwrap(), "/mnt/dir");
+    assert_eq!(top.path(jail).unwrap(), "/");
+    assert!(dir.lookup_follow(jail, "../../../secret", 0).is_err());
+    assert!(dir.lookup_follow(jail, "/secret", 0).is_err());
+    assert!(dir
+        .lookup_follow(jail, "/mnt/dir", 0)
+        .unwrap()
+        .is_same(&dir));
+    // outside of the root
+    assert_eq!(root.path(jail), Err(FsError::EntryNotFound));
+
+    // ".." from a bind mount goes to the parent of its mount point
+    let bind = root.create("bind", FileType::Dir, 0o777).unwrap();
+    bind.bind_mount(&dir, MountOptions::default()).unwrap();
+    let bound = root.find(None, "bind").unwrap();
+    assert!(bound.find(None, "..").unwrap().is_same(&root));
+    assert_eq!(bound.path(None).unwrap(), "/bind");
+}
+
+#[test]
+fn mounts() {
+    use rcore_fs_devfs::DevFS;
+
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let dev = root.create("dev", FileType::Dir, 0o777).unwrap();
+    dev.mount(DevFS::new(), MountOptions::default()).unwrap();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let options = MountOptions {
+        read_only: true,
+        ..MountOptions::default()
+    };
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("sub", FileType::Dir, 0o777)
+        .unwrap();
+    let fs = mnt.mount(ramfs, options).unwrap();
+    let sub = fs.mountpoint_root_inode().find(None, "sub").unwrap();
+    sub.mount(RamFS::new(), MountOptions::default()).unwrap();
+    drop(sub);
+
+    let mounts = fs.mounts().unwrap();
+    let table: Vec<_> = mounts
+        .iter()
+        .map(|m| (m.path.as_str(), m.fs_type, m.options))
+        .collect();
+    assert_eq!(
+        table,
+        [
+            ("/", "ramfs", MountOptions::default()),
+            ("/dev", "devfs", MountOptions::default()),
+            ("/mnt", "ramfs", options),
+            ("/mnt/sub", "ramfs", MountOptions::default()),
+        ]
+    );
+
+    // unmount all in reverse order, except the root
+    drop((dev, mnt, fs));
+    for mount in mounts[1..].iter().rev() {
+        mount.fs.umount(false).unwrap();
+    }
+    assert_eq!(rootfs.mounts().unwrap().len(), 1);
+}
+
+#[test]
+fn mount_loop_device() {
+    use rcore_fs::dev::loop_device::LoopDevice;
+    use rcore_fs_devfs::{special::BlockINode, DevFS};
+    use rcore_fs_sfs::SimpleFileSystem;
+
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let dev = root.create("dev", FileType::Dir, 0o777).unwrap();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+
+    // make an SFS image as a regular file in the root fs
+    const SPACE: usize = 0x100 * 0x1000;
+    let image = root.create("sfs.img", FileType::File, 0o666).unwrap();
+    image.resize(SPACE).unwrap();
+    {
+        let device = LoopDevice::new(image.clone()).unwrap();
+        let sfs = SimpleFileSystem::create(Arc::new(device), SPACE).unwrap();
+        let file = sfs
+            .root_inode()
+            .create("hello", FileType::File, 0o666)
+            .unwrap();
+        file.write_at(0, b"world").unwrap();
+        sfs.sync().unwrap();
+    }
+
+    // expose the image as /dev/loop0
+    let devfs = DevFS::new();
+    let device = LoopDevice::new(image).unwrap();
+    devfs
+        .root()
+        .add(
+            "loop0",
+            Arc::new(BlockINode::new_loop(&devfs, Arc::new(device), 0)),
+        )
+        .unwrap();
+    let node = devfs.root_inode().find("loop0").unwrap();
+    assert_eq!(node.fs().name(), "devfs");
+    dev.mount(devfs, MountOptions::default()).unwrap();
+
+    // mount the SFS in /dev/loop0 to /mnt
+    let loop0 = (root.clone() as Arc<dyn INode>)
+        .lookup("dev/loop0")
+        .unwrap();
+    assert_eq!(loop0.metadata().unwrap().type_, FileType::BlockDevice);
+    let sfs = SimpleFileSystem::open(Arc::new(LoopDevice::new(loop0).unwrap())).unwrap();
+    mnt.mount(sfs, MountOptions::default()).unwrap();
+
+    let file = (root as Arc<dyn INode>).lookup("mnt/hello").unwrap();
+    let mut buf = [0u8; 5];
+    assert_eq!(file.read_at(0, &mut buf), Ok(5));
+    assert_eq!(&buf, b"world");
+}
+
+mod vfs {
+    use super::*;
+    use rcore_fs_testsuite::Features;
+    rcore_fs_testsuite::vfs_tests!(MountFS::new(RamFS::new()), Features::all());
+}
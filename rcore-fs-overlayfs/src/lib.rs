@@ -0,0 +1,709 @@
+//! Overlay (union) file system
+//!
+//! Merge a writable upper directory tree with one or more read-only lower
+//! trees. Files are copied up to the upper tree before modification.
+//! Deleted lower entries are hidden by whiteouts, and directories replacing
+//! lower ones are marked opaque, both stored as special files in the upper
+//! tree like AUFS, so that any file system can be the upper.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use rcore_fs::vfs::*;
+use spin::{Mutex, RwLock};
+
+#[cfg(test)]
+mod tests;
+
+/// Name prefix of whiteouts, which hide entries of the same name in lower layers
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+/// Name of the file in a directory which hides all entries in lower layers
+pub const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Layer index of the upper tree, lower trees are numbered from 1
+const UPPER: usize = 0;
+
+pub struct OverlayFS {
+    /// File systems of all layers, the upper one first.
+    /// Keep them alive since INodes may only hold weak references to them.
+    fss: Vec<Arc<dyn FileSystem>>,
+    /// Root of the upper tree
+    upper: Arc<dyn INode>,
+    /// Roots of the lower trees, from top to bottom
+    lowers: Vec<Arc<dyn INode>>,
+    /// Map (layer, inode id in layer) to inode id in overlay
+    ids: Mutex<BTreeMap<(usize, usize), usize>>,
+    /// Map (parent inode id, name) of hard linked lower files to inode id in overlay.
+    /// Each name is an inode of its own, since copying up one breaks the link.
+    link_ids: Mutex<BTreeMap<(usize, String), usize>>,
+    /// Next inode id to allocate
+    next_id: AtomicUsize,
+    /// Opened inodes
+    inodes: RwLock<BTreeMap<usize, Weak<OverlayINode>>>,
+    /// Serialize copy-up
+    copy_up_lock: Mutex<()>,
+    /// Weak reference to self
+    self_ref: Weak<OverlayFS>,
+}
+
+/// INode for `OverlayFS`
+pub struct OverlayINode {
+    /// INode number in overlay
+    id: usize,
+    /// INode in the upper tree, `None` before copied up
+    upper: RwLock<Option<Arc<dyn INode>>>,
+    /// INodes in lower trees with their layers. For a directory, all merged
+    /// ones from top to bottom. Otherwise, the top one.
+    lowers: RwLock<Vec<(usize, Arc<dyn INode>)>>,
+    /// Parent directory and the name in it, `None` for root.
+    /// Set where it is first found: only upper hard linked files can be found
+    /// at more than one place, and those need no copying up.
+    parent: RwLock<Option<(Arc<OverlayINode>, String)>>,
+    /// Names in the merged directory, built on the first listing
+    /// and dropped when the directory is changed
+    entry_cache: RwLock<Option<Arc<Vec<String>>>>,
+    /// Whether it has been removed from the tree
+    removed: AtomicBool,
+    /// Reference to FS
+    fs: Arc<OverlayFS>,
+    /// Weak reference to self
+    self_ref: Weak<OverlayINode>,
+}
+
+impl OverlayFS {
+    /// Merge directory `upper` with `lowers`, which are from top to bottom
+    pub fn new(upper: Arc<dyn INode>, lowers: Vec<Arc<dyn INode>>) -> Result<Arc<Self>> {
+        for dir in core::iter::once(&upper).chain(lowers.iter()) {
+            if dir.metadata()?.type_ != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+        }
+        let fss = core::iter::once(&upper)
+            .chain(lowers.iter())
+            .map(|dir| dir.fs())
+            .collect();
+        Ok(OverlayFS {
+            fss,
+            upper,
+            lowers,
+            ids: Mutex::new(BTreeMap::new()),
+            link_ids: Mutex::new(BTreeMap::new()),
+            next_id: AtomicUsize::new(1),
+            inodes: RwLock::new(BTreeMap::new()),
+            copy_up_lock: Mutex::new(()),
+            self_ref: Weak::default(),
+        }
+        .wrap())
+    }
+
+    /// Wrap pure `OverlayFS` with `Arc<..>`.
+    /// Used in constructors.
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ref = weak;
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// Get the overlay inode id of `inode` in `layer` found in `parent`,
+    /// allocate if not exists
+    fn map_id(
+        &self,
+        layer: usize,
+        inode: &Arc<dyn INode>,
+        parent: &Option<(Arc<OverlayINode>, String)>,
+    ) -> Result<usize> {
+        let metadata = inode.metadata()?;
+        if let Some((dir, name)) = parent {
+            if layer != UPPER && metadata.type_ != FileType::Dir && metadata.nlinks > 1 {
+                let mut ids = self.link_ids.lock();
+                let id = ids
+                    .entry((dir.id, name.clone()))
+                    .or_insert_with(|| self.next_id.fetch_add(1, Ordering::SeqCst));
+                return Ok(*id);
+            }
+        }
+        let key = (layer, metadata.inode);
+        let mut ids = self.ids.lock();
+        let id = ids
+            .entry(key)
+            .or_insert_with(|| self.next_id.fetch_add(1, Ordering::SeqCst));
+        Ok(*id)
+    }
+
+    /// Get the opened inode for merged `upper` and `lowers`, or create one
+    fn get_inode(
+        &self,
+        upper: Option<Arc<dyn INode>>,
+        lowers: Vec<(usize, Arc<dyn INode>)>,
+        parent: Option<(Arc<OverlayINode>, String)>,
+    ) -> Result<Arc<OverlayINode>> {
+        let id = match (&upper, lowers.first()) {
+            (Some(upper), _) => self.map_id(UPPER, upper, &parent)?,
+            (None, Some((layer, lower))) => self.map_id(*layer, lower, &parent)?,
+            (None, None) => return Err(FsError::EntryNotFound),
+        };
+        let mut inodes = self.inodes.write();
+        if let Some(inode) = inodes.get(&id).and_then(Weak::upgrade) {
+            // a removed inode is found again through another hard link
+            if inode.removed.swap(false, Ordering::SeqCst) {
+                *inode.parent.write() = parent;
+            }
+            return Ok(inode);
+        }
+        let inode = OverlayINode {
+            id,
+            upper: RwLock::new(upper),
+            lowers: RwLock::new(lowers),
+            parent: RwLock::new(parent),
+            entry_cache: RwLock::new(None),
+            removed: AtomicBool::new(false),
+            fs: self.self_ref.upgrade().unwrap(),
+            self_ref: Weak::default(),
+        }
+        .wrap();
+        inodes.insert(id, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+}
+
+impl FileSystem for OverlayFS {
+    fn sync(&self) -> Result<()> {
+        self.fss[0].sync()
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        let mut lowers = Vec::new();
+        if !is_opaque(&self.upper) {
+            for (i, lower) in self.lowers.iter().enumerate() {
+                lowers.push((i + 1, lower.clone()));
+                if is_opaque(lower) {
+                    break;
+                }
+            }
+        }
+        self.get_inode(Some(self.upper.clone()), lowers, None)
+            .expect("failed to open root")
+    }
+
+    fn info(&self) -> FsInfo {
+        self.fss[0].info()
+    }
+
+    fn name(&self) -> &'static str {
+        "overlay"
+    }
+}
+
+fn whiteout_name(name: &str) -> String {
+    format!("{}{}", WHITEOUT_PREFIX, name)
+}
+
+/// Names for whiteouts can not be used
+fn is_reserved(name: &str) -> bool {
+    name.starts_with(WHITEOUT_PREFIX)
+}
+
+fn has_whiteout(dir: &Arc<dyn INode>, name: &str) -> bool {
+    dir.find(&whiteout_name(name)).is_ok()
+}
+
+fn is_opaque(dir: &Arc<dyn INode>) -> bool {
+    dir.find(OPAQUE_MARKER).is_ok()
+}
+
+fn is_dir(inode: &Arc<dyn INode>) -> Result<bool> {
+    Ok(inode.metadata()?.type_ == FileType::Dir)
+}
+
+/// Copy the data and metadata of `lower` to `upper`
+fn copy_content(lower: &Arc<dyn INode>, upper: &Arc<dyn INode>, metadata: &Metadata) -> Result<()> {
+    if metadata.type_ == FileType::File || metadata.type_ == FileType::SymLink {
+        let mut buf = vec![0u8; 0x1000];
+        let mut offset = 0;
+        loop {
+            let len = lower.read_at(offset, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            upper.write_at(offset, &buf[..len])?;
+            offset += len;
+        }
+    }
+    match upper.set_metadata(metadata) {
+        Ok(()) | Err(FsError::NotSupported) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+impl OverlayINode {
+    /// Wrap pure `OverlayINode` with `Arc<..>`.
+    /// Used in constructors.
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let inode = Arc::new(self);
+        let weak = Arc::downgrade(&inode);
+        let ptr = Arc::into_raw(inode) as *mut Self;
+        unsafe {
+            (*ptr).self_ref = weak;
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// The top most INode, which provides the content
+    fn top(&self) -> Arc<dyn INode> {
+        match &*self.upper.read() {
+            Some(upper) => upper.clone(),
+            None => self.lowers.read()[0].1.clone(),
+        }
+    }
+
+    fn is_dir(&self) -> Result<bool> {
+        is_dir(&self.top())
+    }
+
+    fn check_dir(&self) -> Result<()> {
+        if !self.is_dir()? {
+            return Err(FsError::NotDir);
+        }
+        Ok(())
+    }
+
+    /// Is the INode copied up?
+    pub fn is_upper(&self) -> bool {
+        self.upper.read().is_some()
+    }
+
+    /// Find `name` in the merged directory
+    fn find_child(&self, name: &str) -> Result<Arc<OverlayINode>> {
+        if is_reserved(name) {
+            return Err(FsError::EntryNotFound);
+        }
+        let mut upper = None;
+        let mut search_lower = true;
+        if let Some(dir) = self.upper.read().clone() {
+            match dir.find(name) {
+                Ok(inode) => {
+                    search_lower = is_dir(&inode)? && !is_opaque(&inode);
+                    upper = Some(inode);
+                }
+                Err(FsError::EntryNotFound) => search_lower = !has_whiteout(&dir, name),
+                Err(e) => return Err(e),
+            }
+        }
+        let mut lowers = Vec::new();
+        if search_lower {
+            for (layer, dir) in self.lowers.read().iter() {
+                match dir.find(name) {
+                    Ok(inode) => {
+                        if !is_dir(&inode)? {
+                            // a non-directory is visible only if it is the top most
+                            if upper.is_none() && lowers.is_empty() {
+                                lowers.push((*layer, inode));
+                            }
+                            break;
+                        }
+                        let opaque = is_opaque(&inode);
+                        lowers.push((*layer, inode));
+                        if opaque {
+                            break;
+                        }
+                    }
+                    Err(FsError::EntryNotFound) if has_whiteout(dir, name) => break,
+                    Err(FsError::EntryNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let parent = (self.self_ref.upgrade().unwrap(), String::from(name));
+        self.fs.get_inode(upper, lowers, Some(parent))
+    }
+
+    /// Is `name` visible in lower layers of this directory?
+    fn in_lowers(&self, name: &str) -> bool {
+        for (_, dir) in self.lowers.read().iter() {
+            if dir.find(name).is_ok() {
+                return true;
+            }
+            if has_whiteout(dir, name) {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Names in the merged directory, starting with "." and ".."
+    fn entries(&self) -> Result<Arc<Vec<String>>> {
+        if let Some(entries) = &*self.entry_cache.read() {
+            return Ok(entries.clone());
+        }
+        // no changes are made to the directory while building it
+        let mut cache = self.entry_cache.write();
+        if cache.is_none() {
+            *cache = Some(Arc::new(self.merge_entries()?));
+        }
+        Ok(cache.clone().unwrap())
+    }
+
+    /// Drop the cached names after the directory is changed
+    fn invalidate_entries(&self) {
+        *self.entry_cache.write() = None;
+    }
+
+    /// Merge names in all layers
+    fn merge_entries(&self) -> Result<Vec<String>> {
+        self.check_dir()?;
+        let mut layers: Vec<Arc<dyn INode>> = self.upper.read().iter().cloned().collect();
+        layers.extend(self.lowers.read().iter().map(|(_, dir)| dir.clone()));
+        let mut names = vec![String::from("."), String::from("..")];
+        // names in upper layers or hidden by whiteouts
+        let mut hidden = BTreeSet::new();
+        for dir in layers {
+            let mut whiteouts = Vec::new();
+            for name in dir.list()? {
+                if name == "." || name == ".." || name == OPAQUE_MARKER {
+                    continue;
+                }
+                if let Some(name) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    whiteouts.push(String::from(name));
+                    continue;
+                }
+                if hidden.insert(name.clone()) {
+                    names.push(name);
+                }
+            }
+            // whiteouts only hide lower layers
+            hidden.extend(whiteouts);
+        }
+        Ok(names)
+    }
+
+    /// Copy up to the upper tree, including parent directories.
+    /// Return the upper INode.
+    fn copy_up(&self) -> Result<Arc<dyn INode>> {
+        if let Some(upper) = self.upper.read().clone() {
+            return Ok(upper);
+        }
+        if self.removed.load(Ordering::SeqCst) {
+            return Err(FsError::EntryNotFound);
+        }
+        // root is always in the upper tree
+        let (parent, name) = self.parent.read().clone().unwrap();
+        let parent_upper = parent.copy_up()?;
+
+        let _lock = self.fs.copy_up_lock.lock();
+        if let Some(upper) = self.upper.read().clone() {
+            return Ok(upper);
+        }
+        let lower = self.lowers.read()[0].1.clone();
+        let metadata = lower.metadata()?;
+        // a hard linked file is split from its other names, like overlayfs does
+        let upper =
+            parent_upper.create2(&name, metadata.type_, metadata.mode as u32, metadata.rdev)?;
+        if let Err(e) = copy_content(&lower, &upper, &metadata) {
+            // a half copied entry would make copying up again fail
+            let _ = parent_upper.unlink(&name);
+            return Err(e);
+        }
+        let key = (UPPER, upper.metadata()?.inode);
+        self.fs.ids.lock().insert(key, self.id);
+        *self.upper.write() = Some(upper.clone());
+        Ok(upper)
+    }
+
+    /// Copy up the whole subtree, and make it opaque if it is a directory.
+    /// Return the upper INode.
+    fn copy_up_tree(&self) -> Result<Arc<dyn INode>> {
+        let upper = self.copy_up()?;
+        if !is_dir(&upper)? {
+            return Ok(upper);
+        }
+        for name in self.entries()?.iter().skip(2) {
+            self.find_child(name)?.copy_up_tree()?;
+        }
+        if !is_opaque(&upper) {
+            upper.create(OPAQUE_MARKER, FileType::File, 0o644)?;
+        }
+        self.lowers.write().clear();
+        Ok(upper)
+    }
+
+    /// Mark `inode` removed from `name` of this directory
+    fn forget(&self, name: &str, inode: &OverlayINode) {
+        let key = (self.id, String::from(name));
+        self.fs.link_ids.lock().remove(&key);
+        inode.removed.store(true, Ordering::SeqCst);
+    }
+
+    /// Remove whiteouts and the opaque marker in an upper directory
+    fn clean_upper_dir(dir: &Arc<dyn INode>) -> Result<()> {
+        for name in dir.list()? {
+            if is_reserved(&name) {
+                dir.unlink(&name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the `OverlayINode` in the same file system
+    fn downcast<'a>(&self, other: &'a Arc<dyn INode>) -> Result<&'a OverlayINode> {
+        let other = other
+            .downcast_ref::<OverlayINode>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &other.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        Ok(other)
+    }
+}
+
+impl INode for OverlayINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.top().read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        if self.is_dir()? {
+            return Err(FsError::NotFile);
+        }
+        self.copy_up()?.write_at(offset, buf)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        self.top().poll()
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            inode: self.id,
+            ..self.top().metadata()?
+        })
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        self.copy_up()?.set_metadata(metadata)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        match self.upper.read().clone() {
+            Some(upper) => upper.sync_all(),
+            None => Ok(()),
+        }
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        match self.upper.read().clone() {
+            Some(upper) => upper.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        if self.is_dir()? {
+            return Err(FsError::NotFile);
+        }
+        self.copy_up()?.resize(len)
+    }
+
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<dyn INode>> {
+        self.check_dir()?;
+        if name == "." || name == ".." {
+            return Err(FsError::EntryExist);
+        }
+        if is_reserved(name) {
+            return Err(FsError::InvalidParam);
+        }
+        match self.find_child(name) {
+            Ok(_) => return Err(FsError::EntryExist),
+            Err(FsError::EntryNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let dir = self.copy_up()?;
+        let whiteout = has_whiteout(&dir, name);
+        let inode = dir.create2(name, type_, mode, data)?;
+        if whiteout {
+            dir.unlink(&whiteout_name(name))?;
+            if type_ == FileType::Dir {
+                // hide the deleted directory in lower layers
+                inode.create(OPAQUE_MARKER, FileType::File, 0o644)?;
+            }
+        }
+        self.invalidate_entries();
+        let parent = (self.self_ref.upgrade().unwrap(), String::from(name));
+        Ok(self.fs.get_inode(Some(inode), Vec::new(), Some(parent))?)
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        self.check_dir()?;
+        let other = self.downcast(other)?;
+        if name == "." || name == ".." || self.find_child(name).is_ok() {
+            return Err(FsError::EntryExist);
+        }
+        if is_reserved(name) {
+            return Err(FsError::InvalidParam);
+        }
+        if other.is_dir()? {
+            return Err(FsError::IsDir);
+        }
+        let dir = self.copy_up()?;
+        let other = other.copy_up()?;
+        if has_whiteout(&dir, name) {
+            dir.unlink(&whiteout_name(name))?;
+        }
+        dir.link(name, &other)?;
+        self.invalidate_entries();
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.check_dir()?;
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let inode = self.find_child(name)?;
+        if inode.is_dir()? && inode.entries()?.len() > 2 {
+            return Err(FsError::DirNotEmpty);
+        }
+        let dir = self.copy_up()?;
+        if let Some(upper) = inode.upper.read().clone() {
+            if is_dir(&upper)? {
+                Self::clean_upper_dir(&upper)?;
+            }
+            dir.unlink(name)?;
+            // the inode id may be reused by the upper file system
+            let metadata = upper.metadata()?;
+            if metadata.type_ == FileType::Dir || metadata.nlinks == 0 {
+                self.fs.ids.lock().remove(&(UPPER, metadata.inode));
+            }
+        }
+        if self.in_lowers(name) {
+            dir.create(&whiteout_name(name), FileType::File, 0o644)?;
+        }
+        self.invalidate_entries();
+        self.forget(name, &inode);
+        Ok(())
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        self.check_dir()?;
+        let target = self.downcast(target)?;
+        target.check_dir()?;
+        for name in [old_name, new_name].iter() {
+            if *name == "." || *name == ".." {
+                return Err(FsError::IsDir);
+            }
+            if is_reserved(name) {
+                return Err(FsError::InvalidParam);
+            }
+        }
+        let inode = self.find_child(old_name)?;
+        let old = match target.find_child(new_name) {
+            Ok(old) if old.id == inode.id => return Ok(()),
+            Ok(old) => {
+                match (inode.is_dir()?, old.is_dir()?) {
+                    (true, false) => return Err(FsError::NotDir),
+                    (false, true) => return Err(FsError::IsDir),
+                    (true, true) if old.entries()?.len() > 2 => return Err(FsError::DirNotEmpty),
+                    _ => {}
+                }
+                Some(old)
+            }
+            Err(FsError::EntryNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        // Directories are copied up as a whole, and made opaque to hide
+        // directories of the same name at the new place.
+        inode.copy_up_tree()?;
+        let src = self.copy_up()?;
+        let dst = target.copy_up()?;
+        // the old entry is replaced by the upper move_, or hidden if only in lower layers
+        let old_upper = old.as_ref().and_then(|old| old.upper.read().clone());
+        if let Some(old_upper) = &old_upper {
+            if is_dir(old_upper)? {
+                Self::clean_upper_dir(old_upper)?;
+            }
+        }
+        src.move_(old_name, &dst, new_name)?;
+        if has_whiteout(&dst, new_name) {
+            dst.unlink(&whiteout_name(new_name))?;
+        }
+        if self.in_lowers(old_name) {
+            src.create(&whiteout_name(old_name), FileType::File, 0o644)?;
+        }
+        self.invalidate_entries();
+        target.invalidate_entries();
+        if let Some(old) = old {
+            if let Some(old_upper) = old_upper {
+                // the inode id may be reused by the upper file system
+                let metadata = old_upper.metadata()?;
+                if metadata.type_ == FileType::Dir || metadata.nlinks == 0 {
+                    self.fs.ids.lock().remove(&(UPPER, metadata.inode));
+                }
+            }
+            target.forget(new_name, &old);
+        }
+        let key = (self.id, String::from(old_name));
+        self.fs.link_ids.lock().remove(&key);
+        let target = target.self_ref.upgrade().unwrap();
+        *inode.parent.write() = Some((target, String::from(new_name)));
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        self.check_dir()?;
+        match name {
+            "" | "." => Ok(self.self_ref.upgrade().unwrap()),
+            ".." => match &*self.parent.read() {
+                Some((parent, _)) => Ok(parent.clone()),
+                None => Ok(self.self_ref.upgrade().unwrap()),
+            },
+            _ => Ok(self.find_child(name)?),
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.entries()?
+            .get(id)
+            .cloned()
+            .ok_or(FsError::EntryNotFound)
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.top().io_control(cmd, data)
+    }
+
+    fn mmap(&self, area: MMapArea) -> Result<()> {
+        self.top().mmap(area)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
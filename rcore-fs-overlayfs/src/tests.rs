@@ -0,0 +1,315 @@
+extern crate std;
+
+use crate::*;
+use rcore_fs_ramfs::{RamFS, RamFSConfig};
+use rcore_fs_sfs::SimpleFileSystem;
+use std::sync::Mutex as StdMutex;
+
+fn create_lower() -> Arc<SimpleFileSystem> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    SimpleFileSystem::create(Arc::new(StdMutex::new(file)), 1024 * 1024)
+        .expect("failed to create SFS")
+}
+
+/// Lower SFS with:
+/// /file: "lower"
+/// /dir/a: "a"
+/// /dir/b
+/// /dir/sub/c
+fn create_populated_lower() -> Arc<SimpleFileSystem> {
+    let sfs = create_lower();
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    file.write_at(0, b"lower").unwrap();
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    dir.create("a", FileType::File, 0o644)
+        .unwrap()
+        .write_at(0, b"a")
+        .unwrap();
+    dir.create("b", FileType::File, 0o644).unwrap();
+    let sub = dir.create("sub", FileType::Dir, 0o755).unwrap();
+    sub.create("c", FileType::File, 0o644).unwrap();
+    sfs.sync().unwrap();
+    sfs
+}
+
+fn create_overlay(lower: &Arc<SimpleFileSystem>) -> (Arc<OverlayFS>, Arc<RamFS>) {
+    let upper = RamFS::new();
+    let overlay = OverlayFS::new(upper.root_inode(), vec![lower.root_inode()])
+        .expect("failed to create overlay");
+    (overlay, upper)
+}
+
+fn read_all(inode: &Arc<dyn INode>) -> Vec<u8> {
+    let mut buf = vec![0u8; inode.metadata().unwrap().size];
+    let len = inode.read_at(0, &mut buf).unwrap();
+    buf.truncate(len);
+    buf
+}
+
+fn children(dir: &Arc<dyn INode>) -> Vec<String> {
+    let mut names: Vec<String> = dir.list().unwrap().into_iter().skip(2).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn merged_listing() -> Result<()> {
+    let lower = create_populated_lower();
+    let (overlay, upper) = create_overlay(&lower);
+    let root = overlay.root_inode();
+    upper.root_inode().create("upper", FileType::File, 0o644)?;
+    upper.root_inode().create("dir", FileType::Dir, 0o755)?;
+    upper
+        .root_inode()
+        .find("dir")?
+        .create("d", FileType::File, 0o644)?;
+
+    assert_eq!(children(&root), ["dir", "file", "upper"]);
+    assert_eq!(children(&root.find("dir")?), ["a", "b", "d", "sub"]);
+    assert_eq!(read_all(&root.lookup("dir/a")?), b"a");
+    assert_eq!(
+        root.lookup("dir/sub/..")?.metadata()?.inode,
+        root.find("dir")?.metadata()?.inode
+    );
+    assert_eq!(overlay.name(), "overlay");
+    Ok(())
+}
+
+#[test]
+fn copy_up() -> Result<()> {
+    let lower = create_populated_lower();
+    let (overlay, upper) = create_overlay(&lower);
+    let root = overlay.root_inode();
+    let a = root.lookup("dir/a")?;
+    let id = a.metadata()?.inode;
+    assert!(!a.downcast_ref::<OverlayINode>().unwrap().is_upper());
+
+    a.write_at(1, b"bc")?;
+    assert!(a.downcast_ref::<OverlayINode>().unwrap().is_upper());
+    assert_eq!(read_all(&a), b"abc");
+    assert_eq!(a.metadata()?.inode, id, "inode id is kept by copy-up");
+    assert_eq!(read_all(&upper.root_inode().lookup("dir/a")?), b"abc");
+    assert_eq!(read_all(&lower.root_inode().lookup("dir/a")?), b"a");
+    // only the parents are copied up
+    assert_eq!(children(&upper.root_inode()), ["dir"]);
+    assert_eq!(children(&upper.root_inode().find("dir")?), ["a"]);
+    assert_eq!(children(&root.find("dir")?), ["a", "b", "sub"]);
+
+    let file = root.find("file")?;
+    let lower_mode = lower.root_inode().find("file")?.metadata()?.mode;
+    let mut metadata = file.metadata()?;
+    metadata.mode = 0o600;
+    file.set_metadata(&metadata)?;
+    assert_eq!(upper.root_inode().find("file")?.metadata()?.mode, 0o600);
+    assert_eq!(
+        lower.root_inode().find("file")?.metadata()?.mode,
+        lower_mode
+    );
+    Ok(())
+}
+
+#[test]
+fn whiteout() -> Result<()> {
+    let lower = create_populated_lower();
+    let (overlay, upper) = create_overlay(&lower);
+    let root = overlay.root_inode();
+
+    root.unlink("file")?;
+    assert_eq!(root.find("file").err(), Some(FsError::EntryNotFound));
+    assert!(upper.root_inode().find(".wh.file").is_ok());
+    assert!(lower.root_inode().find("file").is_ok());
+    assert_eq!(children(&root), ["dir"]);
+
+    // whiteouts are not visible
+    assert_eq!(root.find(".wh.file").err(), Some(FsError::EntryNotFound));
+    assert_eq!(
+        root.create(".wh.file", FileType::File, 0o644).err(),
+        Some(FsError::InvalidParam)
+    );
+
+    // a new file replaces the whiteout
+    let file = root.create("file", FileType::File, 0o644)?;
+    assert_eq!(read_all(&file), b"");
+    assert_eq!(children(&upper.root_inode()), ["file"]);
+
+    let dir = root.find("dir")?;
+    assert_eq!(root.unlink("dir").err(), Some(FsError::DirNotEmpty));
+    let sub = dir.find("sub")?;
+    sub.unlink("c")?;
+    dir.unlink("sub")?;
+    dir.unlink("a")?;
+    dir.unlink("b")?;
+    assert_eq!(children(&dir), Vec::<String>::new());
+    root.unlink("dir")?;
+    assert_eq!(children(&root), ["file"]);
+    assert_eq!(children(&upper.root_inode()), [".wh.dir", "file"]);
+    Ok(())
+}
+
+#[test]
+fn opaque_dir() -> Result<()> {
+    let lower = create_populated_lower();
+    let (overlay, upper) = create_overlay(&lower);
+    let root = overlay.root_inode();
+    let dir = root.find("dir")?;
+    for name in ["a", "b"].iter() {
+        dir.unlink(name)?;
+    }
+    dir.find("sub")?.unlink("c")?;
+    dir.unlink("sub")?;
+    root.unlink("dir")?;
+
+    // the new directory hides the old one in the lower layer
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    assert_eq!(children(&dir), Vec::<String>::new());
+    assert_eq!(children(&upper.root_inode().find("dir")?), [".wh..wh..opq"]);
+    dir.create("x", FileType::File, 0o644)?;
+    assert_eq!(children(&dir), ["x"]);
+
+    // reopen the overlay with the same layers
+    let overlay = OverlayFS::new(upper.root_inode(), vec![lower.root_inode()])?;
+    let root = overlay.root_inode();
+    assert_eq!(children(&root), ["dir", "file"]);
+    assert_eq!(children(&root.find("dir")?), ["x"]);
+    Ok(())
+}
+
+#[test]
+fn move_dir() -> Result<()> {
+    let lower = create_populated_lower();
+    let (overlay, upper) = create_overlay(&lower);
+    let root = overlay.root_inode();
+    let dir = root.find("dir")?;
+    let id = dir.metadata()?.inode;
+    let new = root.create("new", FileType::Dir, 0o755)?;
+
+    root.move_("dir", &new, "moved")?;
+    assert_eq!(root.find("dir").err(), Some(FsError::EntryNotFound));
+    let moved = new.find("moved")?;
+    assert_eq!(moved.metadata()?.inode, id);
+    assert_eq!(children(&moved), ["a", "b", "sub"]);
+    assert_eq!(read_all(&moved.lookup("a")?), b"a");
+    assert_eq!(moved.find("..")?.metadata()?.inode, new.metadata()?.inode);
+    assert_eq!(children(&root), ["file", "new"]);
+    assert!(upper.root_inode().find(".wh.dir").is_ok());
+
+    // a file is copied up before moved
+    root.move_("file", &moved, "file")?;
+    assert_eq!(read_all(&moved.find("file")?), b"lower");
+    assert_eq!(children(&root), ["new"]);
+    assert!(lower.root_inode().find("file").is_ok());
+    Ok(())
+}
+
+#[test]
+fn hard_linked_lower() -> Result<()> {
+    let lower = create_populated_lower();
+    let lower_root = lower.root_inode();
+    let file = lower_root.find("file")?;
+    lower_root.find("dir")?.link("link", &file)?;
+    let (overlay, upper) = create_overlay(&lower);
+    let root = overlay.root_inode();
+
+    let file = root.find("file")?;
+    let link = root.lookup("dir/link")?;
+    assert_eq!(read_all(&link), b"lower");
+    // copying up a name splits it from the others
+    link.write_at(0, b"upper")?;
+    assert_eq!(read_all(&root.lookup("dir/link")?), b"upper");
+    assert_eq!(read_all(&file), b"lower");
+    assert!(upper.root_inode().find("file").is_err());
+    root.move_("file", &root, "moved")?;
+    assert_eq!(read_all(&root.find("moved")?), b"lower");
+    assert_eq!(read_all(&lower_root.lookup("dir/link")?), b"lower");
+
+    root.find("dir")?.unlink("link")?;
+    assert_eq!(children(&root.find("dir")?), ["a", "b", "sub"]);
+    Ok(())
+}
+
+#[test]
+fn failed_copy_up() -> Result<()> {
+    let lower = create_populated_lower();
+    let big = lower.root_inode().create("big", FileType::File, 0o644)?;
+    big.resize(0x4000)?;
+    let upper = RamFS::new_with_config(RamFSConfig {
+        max_bytes: Some(0x2000),
+        ..RamFSConfig::default()
+    });
+    let overlay = OverlayFS::new(upper.root_inode(), vec![lower.root_inode()])?;
+    let root = overlay.root_inode();
+    let dir = root.find("dir")?;
+    dir.find("a")?.write_at(1, b"b")?;
+
+    // the target is kept, and no half copied file is left
+    assert_eq!(
+        root.move_("big", &dir, "a").err(),
+        Some(FsError::NoDeviceSpace)
+    );
+    assert_eq!(read_all(&dir.find("a")?), b"ab");
+    assert!(upper.root_inode().find("big").is_err());
+    assert_eq!(children(&root), ["big", "dir", "file"]);
+    Ok(())
+}
+
+#[test]
+fn listing_changes() -> Result<()> {
+    let lower = create_populated_lower();
+    let (overlay, _upper) = create_overlay(&lower);
+    let root = overlay.root_inode();
+    let dir = root.find("dir")?;
+    assert_eq!(children(&dir), ["a", "b", "sub"]);
+    dir.create("new", FileType::File, 0o644)?;
+    assert_eq!(children(&dir), ["a", "b", "new", "sub"]);
+    dir.unlink("a")?;
+    assert_eq!(children(&dir), ["b", "new", "sub"]);
+    dir.move_("new", &root, "new")?;
+    assert_eq!(children(&dir), ["b", "sub"]);
+    assert_eq!(children(&root), ["dir", "file", "new"]);
+    root.link("link", &root.find("new")?)?;
+    assert_eq!(children(&root), ["dir", "file", "link", "new"]);
+    Ok(())
+}
+
+#[test]
+fn multiple_lowers() -> Result<()> {
+    let lower1 = create_populated_lower();
+    let lower2 = create_lower();
+    let root2 = lower2.root_inode();
+    root2
+        .create("file", FileType::File, 0o644)?
+        .write_at(0, b"bottom")?;
+    root2.create("only", FileType::File, 0o644)?;
+    root2
+        .create("dir", FileType::Dir, 0o755)?
+        .create("z", FileType::File, 0o644)?;
+    lower1
+        .root_inode()
+        .create(".wh.only", FileType::File, 0o644)?;
+
+    let overlay = OverlayFS::new(
+        RamFS::new().root_inode(),
+        vec![lower1.root_inode(), lower2.root_inode()],
+    )?;
+    let root = overlay.root_inode();
+    assert_eq!(children(&root), ["dir", "file"]);
+    assert_eq!(read_all(&root.find("file")?), b"lower");
+    assert_eq!(children(&root.find("dir")?), ["a", "b", "sub", "z"]);
+    Ok(())
+}
+
+mod vfs {
+    use super::*;
+    use rcore_fs_testsuite::Features;
+    rcore_fs_testsuite::vfs_tests!(
+        {
+            let lower = create_lower();
+            OverlayFS::new(RamFS::new().root_inode(), vec![lower.root_inode()]).unwrap()
+        },
+        Features {
+            dir_nlinks: false,
+            ..Features::all()
+        }
+    );
+}
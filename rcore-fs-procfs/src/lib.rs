@@ -0,0 +1,507 @@
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rcore_fs::vfs::*;
+use spin::{Mutex, RwLock};
+
+#[cfg(test)]
+mod tests;
+
+/// Generate the content of a file
+pub type ReadFn = dyn Fn() -> String + Send + Sync;
+/// Handle data written to a file
+pub type WriteFn = dyn Fn(&[u8]) -> Result<()> + Send + Sync;
+/// List names of a dynamic directory
+pub type ListFn = dyn Fn() -> Vec<String> + Send + Sync;
+/// Lookup a name in a dynamic directory
+pub type LookupFn = dyn Fn(&Arc<ProcDir>, &str) -> Result<ProcEntry> + Send + Sync;
+
+/// Synthetic file system like procfs and sysfs
+///
+/// The tree is built by registering directories and files backed by
+/// callbacks through `ProcDir`. It is readonly from the root INode.
+///
+/// The content of a file is generated at the first read of each opened
+/// INode (returned by `find`) and kept for later reads, so that it is
+/// consistent when read in multiple calls. Reading at offset 0 generates
+/// it again. Likewise, the entries of each opened directory are listed
+/// when the entry 0 is read, and kept for the following entries.
+pub struct ProcFS {
+    root: Arc<ProcDir>,
+}
+
+impl FileSystem for ProcFS {
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.root.open()
+    }
+
+    fn info(&self) -> FsInfo {
+        FsInfo {
+            bsize: 0,
+            frsize: 0,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: 0,
+            inline_blocks: 0,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+}
+
+impl ProcFS {
+    pub fn new() -> Arc<Self> {
+        let fs = Arc::new(Self {
+            root: ProcDir::new(Weak::default(), Weak::default()),
+        });
+        *fs.root.fs.write() = Arc::downgrade(&fs);
+        fs
+    }
+
+    pub fn root(&self) -> Arc<ProcDir> {
+        self.root.clone()
+    }
+
+    /// Generate a new inode id
+    pub fn new_inode_id() -> usize {
+        static ID: AtomicUsize = AtomicUsize::new(1);
+        ID.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// An entry in `ProcDir`
+#[derive(Clone)]
+pub enum ProcEntry {
+    Dir(Arc<ProcDir>),
+    File(Arc<ProcFile>),
+    /// Any other INode, e.g. a device file or a symlink
+    INode(Arc<dyn INode>),
+}
+
+/// A file backed by callbacks
+pub struct ProcFile {
+    inode_id: usize,
+    mode: u16,
+    read: Option<Box<ReadFn>>,
+    write: Option<Box<WriteFn>>,
+}
+
+impl ProcFile {
+    /// A readonly file with content generated by `read`
+    pub fn new(read: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        ProcFile {
+            inode_id: ProcFS::new_inode_id(),
+            mode: 0o444,
+            read: Some(Box::new(read)),
+            write: None,
+        }
+    }
+
+    /// A write only file, data written is passed to `write`
+    pub fn write_only(write: impl Fn(&[u8]) -> Result<()> + Send + Sync + 'static) -> Self {
+        ProcFile {
+            inode_id: ProcFS::new_inode_id(),
+            mode: 0o200,
+            read: None,
+            write: Some(Box::new(write)),
+        }
+    }
+
+    /// Make the file writable, data written is passed to `write`
+    pub fn with_write(
+        mut self,
+        write: impl Fn(&[u8]) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.mode |= 0o200;
+        self.write = Some(Box::new(write));
+        self
+    }
+
+    /// Set the permission
+    pub fn with_mode(mut self, mode: u16) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+/// A directory in `ProcFS`
+///
+/// Besides registered entries, a directory can have dynamic entries
+/// which are listed and looked up by callbacks at `get_entry` and `find`.
+pub struct ProcDir {
+    this: Weak<ProcDir>,
+    parent: Weak<ProcDir>,
+    fs: RwLock<Weak<ProcFS>>,
+    children: RwLock<BTreeMap<String, ProcEntry>>,
+    dynamic: RwLock<Option<(Box<ListFn>, Box<LookupFn>)>>,
+    /// Inode ids of dynamic entries by name, so that they are the same at each lookup
+    dynamic_ids: Mutex<BTreeMap<String, usize>>,
+    /// Set by the parent if it is a dynamic entry
+    inode_id: AtomicUsize,
+}
+
+impl ProcDir {
+    fn new(parent: Weak<ProcDir>, fs: Weak<ProcFS>) -> Arc<Self> {
+        Self {
+            this: Weak::default(),
+            parent,
+            fs: RwLock::new(fs),
+            children: RwLock::new(BTreeMap::new()),
+            dynamic: RwLock::new(None),
+            dynamic_ids: Mutex::new(BTreeMap::new()),
+            inode_id: AtomicUsize::new(ProcFS::new_inode_id()),
+        }
+        .wrap()
+    }
+
+    /// Wrap pure ProcDir with Arc
+    /// Used in constructors
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let this = Arc::new(self);
+        let weak = Arc::downgrade(&this);
+        let ptr = Arc::into_raw(this) as *mut Self;
+        unsafe {
+            (*ptr).this = weak;
+        }
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    /// Open the directory, with a listing of its own
+    fn open(&self) -> Arc<dyn INode> {
+        Arc::new(ProcDirINode {
+            dir: self.this.upgrade().unwrap(),
+            listing: Mutex::new(None),
+        })
+    }
+
+    /// Create a directory whose parent is `self`, without adding it.
+    /// Used to create dynamic entries.
+    pub fn new_dir(&self) -> Arc<ProcDir> {
+        Self::new(self.this.clone(), self.fs.read().clone())
+    }
+
+    pub fn add_dir(&self, name: &str) -> Result<Arc<ProcDir>> {
+        let dir = self.new_dir();
+        self.add_entry(name, ProcEntry::Dir(dir.clone()))?;
+        Ok(dir)
+    }
+
+    pub fn add_file(&self, name: &str, file: ProcFile) -> Result<()> {
+        self.add_entry(name, ProcEntry::File(Arc::new(file)))
+    }
+
+    pub fn add(&self, name: &str, inode: Arc<dyn INode>) -> Result<()> {
+        self.add_entry(name, ProcEntry::INode(inode))
+    }
+
+    pub fn add_entry(&self, name: &str, entry: ProcEntry) -> Result<()> {
+        let mut children = self.children.write();
+        if children.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        children.insert(String::from(name), entry);
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let mut children = self.children.write();
+        children.remove(name).ok_or(FsError::EntryNotFound)?;
+        Ok(())
+    }
+
+    /// Set callbacks for dynamic entries, e.g. a directory for each process.
+    ///
+    /// `list` returns names of all dynamic entries, and `lookup` creates the
+    /// entry of a name, or returns `EntryNotFound`.
+    /// Registered entries take precedence over dynamic ones.
+    pub fn set_dynamic(
+        &self,
+        list: impl Fn() -> Vec<String> + Send + Sync + 'static,
+        lookup: impl Fn(&Arc<ProcDir>, &str) -> Result<ProcEntry> + Send + Sync + 'static,
+    ) {
+        *self.dynamic.write() = Some((Box::new(list), Box::new(lookup)));
+    }
+
+    /// Find the entry of `name`, with the inode id to give it if it is dynamic
+    fn find_entry(&self, name: &str) -> Result<(ProcEntry, Option<usize>)> {
+        if let Some(entry) = self.children.read().get(name) {
+            return Ok((entry.clone(), None));
+        }
+        let entry = match &*self.dynamic.read() {
+            Some((_, lookup)) => lookup(&self.this.upgrade().unwrap(), name)?,
+            None => return Err(FsError::EntryNotFound),
+        };
+        let id = *self
+            .dynamic_ids
+            .lock()
+            .entry(String::from(name))
+            .or_insert_with(ProcFS::new_inode_id);
+        Ok((entry, Some(id)))
+    }
+
+    /// List all names, and forget the ids of dynamic entries no longer listed
+    fn entries(&self) -> Vec<String> {
+        let children = self.children.read();
+        let mut names: Vec<String> = children.keys().cloned().collect();
+        if let Some((list, _)) = &*self.dynamic.read() {
+            let dynamic: Vec<String> = list()
+                .into_iter()
+                .filter(|name| !children.contains_key(name))
+                .collect();
+            self.dynamic_ids
+                .lock()
+                .retain(|name, _| dynamic.contains(name));
+            names.extend(dynamic);
+        }
+        names
+    }
+}
+
+/// An opened `ProcDir` with the snapshot of its listing
+pub struct ProcDirINode {
+    dir: Arc<ProcDir>,
+    listing: Mutex<Option<Vec<String>>>,
+}
+
+impl INode for ProcDirINode {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::NotFile)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotFile)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::NotFile)
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: self.dir.inode_id.load(Ordering::SeqCst),
+            size: self.dir.children.read().len(),
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::Dir,
+            mode: 0o555,
+            nlinks: 2,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resize(&self, _len: usize) -> Result<()> {
+        Err(FsError::NotFile)
+    }
+
+    fn create(&self, _name: &str, _type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
+        Err(FsError::NotSupported)
+    }
+
+    fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn move_(&self, _old_name: &str, _target: &Arc<dyn INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match name {
+            "" | "." => Ok(self.dir.open()),
+            ".." => match self.dir.parent.upgrade() {
+                Some(parent) => Ok(parent.open()),
+                // root
+                None => Ok(self.dir.open()),
+            },
+            name => match self.dir.find_entry(name)? {
+                (ProcEntry::Dir(dir), id) => {
+                    if let Some(id) = id {
+                        dir.inode_id.store(id, Ordering::SeqCst);
+                    }
+                    Ok(dir.open())
+                }
+                (ProcEntry::File(file), id) => Ok(Arc::new(ProcFileINode {
+                    inode_id: id.unwrap_or(file.inode_id),
+                    file,
+                    fs: self.dir.fs.read().clone(),
+                    content: Mutex::new(None),
+                })),
+                (ProcEntry::INode(inode), _) => Ok(inode),
+            },
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        let mut listing = self.listing.lock();
+        if listing.is_none() || id == 0 {
+            *listing = Some(self.dir.entries());
+        }
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            i => {
+                if let Some(s) = listing.as_ref().unwrap().get(i - 2) {
+                    Ok(s.to_string())
+                } else {
+                    Err(FsError::EntryNotFound)
+                }
+            }
+        }
+    }
+
+    fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn mmap(&self, _area: MMapArea) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.dir.fs.read().upgrade().unwrap()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// An opened `ProcFile` with the snapshot of its content
+pub struct ProcFileINode {
+    inode_id: usize,
+    file: Arc<ProcFile>,
+    fs: Weak<ProcFS>,
+    content: Mutex<Option<Vec<u8>>>,
+}
+
+impl INode for ProcFileINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let read = self.file.read.as_ref().ok_or(FsError::NotSupported)?;
+        let mut content = self.content.lock();
+        if content.is_none() || offset == 0 {
+            *content = Some(read().into_bytes());
+        }
+        let content = content.as_ref().unwrap();
+        if offset >= content.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        let write = self.file.write.as_ref().ok_or(FsError::NotSupported)?;
+        write(buf)?;
+        // the content may be changed
+        *self.content.lock() = None;
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: self.file.read.is_some(),
+            write: self.file.write.is_some(),
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: self.inode_id,
+            // like procfs, the size is unknown before read
+            size: self.content.lock().as_ref().map_or(0, Vec::len),
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::File,
+            mode: self.file.mode,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
+        Err(FsError::NotSupported)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resize(&self, _len: usize) -> Result<()> {
+        // allow truncating at open before writing
+        match self.file.write {
+            Some(_) => Ok(()),
+            None => Err(FsError::NotSupported),
+        }
+    }
+
+    fn find(&self, _name: &str) -> Result<Arc<dyn INode>> {
+        Err(FsError::NotDir)
+    }
+
+    fn get_entry(&self, _id: usize) -> Result<String> {
+        Err(FsError::NotDir)
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.fs.upgrade().unwrap()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
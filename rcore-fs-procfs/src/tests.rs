@@ -0,0 +1,175 @@
+extern crate std;
+
+use crate::*;
+use alloc::format;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex as StdMutex;
+
+fn read_all(inode: &Arc<dyn INode>) -> String {
+    let mut content = Vec::new();
+    let mut buf = [0u8; 4];
+    loop {
+        let len = inode.read_at(content.len(), &mut buf).unwrap();
+        if len == 0 {
+            break;
+        }
+        content.extend_from_slice(&buf[..len]);
+    }
+    String::from_utf8(content).unwrap()
+}
+
+#[test]
+fn files() -> Result<()> {
+    let fs = ProcFS::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let c = counter.clone();
+    let sys = fs.root().add_dir("sys")?;
+    sys.add_file(
+        "counter",
+        ProcFile::new(move || format!("{}\n", c.load(Ordering::SeqCst))),
+    )?;
+    let c = counter.clone();
+    sys.add_file(
+        "reset",
+        ProcFile::write_only(move |data| match data {
+            b"1\n" => {
+                c.store(0, Ordering::SeqCst);
+                Ok(())
+            }
+            _ => Err(FsError::InvalidParam),
+        }),
+    )?;
+    assert_eq!(
+        sys.add_file("counter", ProcFile::new(String::new)).err(),
+        Some(FsError::EntryExist)
+    );
+
+    let root = fs.root_inode();
+    assert_eq!(root.list()?, [".", "..", "sys"]);
+    assert_eq!(root.lookup("sys")?.list()?, [".", "..", "counter", "reset"]);
+    let file = root.lookup("sys/counter")?;
+    assert_eq!(file.metadata()?.type_, FileType::File);
+    assert_eq!(file.metadata()?.mode, 0o444);
+    counter.store(12345, Ordering::SeqCst);
+    assert_eq!(read_all(&file), "12345\n");
+    assert_eq!(file.metadata()?.size, 6);
+    assert_eq!(file.write_at(0, b"1").err(), Some(FsError::NotSupported));
+
+    let reset = root.lookup("sys/reset")?;
+    assert_eq!(reset.write_at(0, b"2\n").err(), Some(FsError::InvalidParam));
+    assert_eq!(reset.write_at(0, b"1\n")?, 2);
+    assert_eq!(read_all(&file), "0\n");
+    assert_eq!(
+        reset.read_at(0, &mut [0; 4]).err(),
+        Some(FsError::NotSupported)
+    );
+
+    sys.remove("reset")?;
+    assert_eq!(root.lookup("sys/reset").err(), Some(FsError::EntryNotFound));
+    assert_eq!(
+        root.create("x", FileType::File, 0o644).err(),
+        Some(FsError::NotSupported)
+    );
+    Ok(())
+}
+
+#[test]
+fn snapshot() -> Result<()> {
+    let fs = ProcFS::new();
+    let counter = Arc::new(AtomicUsize::new(1));
+    let c = counter.clone();
+    fs.root().add_file(
+        "long",
+        ProcFile::new(move || "x".repeat(c.load(Ordering::SeqCst) * 4)),
+    )?;
+    let file1 = fs.root_inode().find("long")?;
+    let mut buf = [0u8; 4];
+    assert_eq!(file1.read_at(0, &mut buf)?, 4);
+
+    // later reads do not see the change
+    counter.store(2, Ordering::SeqCst);
+    assert_eq!(file1.read_at(4, &mut buf)?, 0);
+    // but a new open does
+    let file2 = fs.root_inode().find("long")?;
+    assert_eq!(read_all(&file2).len(), 8);
+    // and reading from the start again
+    assert_eq!(read_all(&file1).len(), 8);
+    Ok(())
+}
+
+#[test]
+fn dynamic_dir() -> Result<()> {
+    let fs = ProcFS::new();
+    let pids = Arc::new(StdMutex::new(vec![1usize, 2]));
+    fs.root().add_file(
+        "meminfo",
+        ProcFile::new(|| String::from("MemTotal: 0 kB\n")),
+    )?;
+    let list_pids = pids.clone();
+    let lookup_pids = pids.clone();
+    fs.root().set_dynamic(
+        move || {
+            let pids = list_pids.lock().unwrap();
+            pids.iter().map(|pid| format!("{}", pid)).collect()
+        },
+        move |dir, name| {
+            let pid: usize = name.parse().map_err(|_| FsError::EntryNotFound)?;
+            if !lookup_pids.lock().unwrap().contains(&pid) {
+                return Err(FsError::EntryNotFound);
+            }
+            let proc_dir = dir.new_dir();
+            proc_dir.add_file("status", ProcFile::new(move || format!("Pid: {}\n", pid)))?;
+            Ok(ProcEntry::Dir(proc_dir))
+        },
+    );
+
+    let root = fs.root_inode();
+    assert_eq!(root.list()?, [".", "..", "meminfo", "1", "2"]);
+    assert_eq!(read_all(&root.lookup("2/status")?), "Pid: 2\n");
+    assert_eq!(
+        root.lookup("2/..")?.metadata()?.inode,
+        root.metadata()?.inode
+    );
+    assert_eq!(root.find("3").err(), Some(FsError::EntryNotFound));
+
+    pids.lock().unwrap().push(3);
+    assert_eq!(root.list()?, [".", "..", "meminfo", "1", "2", "3"]);
+    assert_eq!(read_all(&root.lookup("3/status")?), "Pid: 3\n");
+    pids.lock().unwrap().retain(|&pid| pid != 1);
+    assert_eq!(root.find("1").err(), Some(FsError::EntryNotFound));
+
+    // the same inode id at each lookup
+    let id = root.find("2")?.metadata()?.inode;
+    assert_eq!(root.find("2")?.metadata()?.inode, id);
+    assert_ne!(root.find("3")?.metadata()?.inode, id);
+    Ok(())
+}
+
+#[test]
+fn listing_snapshot() -> Result<()> {
+    let fs = ProcFS::new();
+    let names = Arc::new(StdMutex::new(vec![String::from("a"), String::from("b")]));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let (list_names, list_calls) = (names.clone(), calls.clone());
+    fs.root().set_dynamic(
+        move || {
+            list_calls.fetch_add(1, Ordering::SeqCst);
+            list_names.lock().unwrap().clone()
+        },
+        |_, _| Err(FsError::EntryNotFound),
+    );
+    let root = fs.root_inode();
+    assert_eq!(root.list()?, [".", "..", "a", "b"]);
+    assert_eq!(calls.load(Ordering::SeqCst), 1, "listed once");
+
+    // changes during a listing are not seen until it starts again
+    assert_eq!(root.get_entry(0)?, ".");
+    names.lock().unwrap().remove(0);
+    assert_eq!(root.get_entry(2)?, "a");
+    // nor by listing another opened one
+    let other = fs.root_inode();
+    assert_eq!(other.list()?, [".", "..", "b"]);
+    assert_eq!(root.get_entry(3)?, "b");
+    assert_eq!(root.list()?, [".", "..", "b"]);
+    Ok(())
+}
@@ -4,17 +4,65 @@ extern crate alloc;
 extern crate log;
 
 use alloc::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     string::{String, ToString},
     sync::{Arc, Weak},
+    vec,
     vec::Vec,
 };
 use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rcore_fs::dev::TimeProvider;
 use rcore_fs::vfs::*;
-use spin::{RwLock, RwLockWriteGuard};
+use spin::{Mutex, RwLock, RwLockWriteGuard};
+
+#[cfg(test)]
+mod tests;
+
+/// Block size for accounting, contents are charged in whole blocks like pages in tmpfs
+pub const BLKSIZE: usize = 4096;
 
 pub struct RamFS {
     root: Arc<LockedINode>,
+    config: RamFSConfig,
+    /// Number of blocks charged for file contents
+    used_blocks: AtomicUsize,
+    /// Number of inodes in use, including unlinked ones still opened
+    used_inodes: AtomicUsize,
+    /// Allocator of inode numbers
+    inode_ids: Mutex<InodeIdAllocator>,
+    /// Held by moves across dirs, so that no dir changes its parent meanwhile
+    rename_lock: Mutex<()>,
+}
+
+/// Configuration of `RamFS`, like the mount options of tmpfs
+#[derive(Clone)]
+pub struct RamFSConfig {
+    /// Max bytes of all file contents, unlimited if `None`
+    pub max_bytes: Option<usize>,
+    /// Max number of inodes, unlimited if `None`
+    pub max_inodes: Option<usize>,
+    /// Permission of the root directory
+    pub mode: u16,
+    /// Owner of the root directory
+    pub uid: usize,
+    /// Group of the root directory
+    pub gid: usize,
+    /// Source of timestamps, all times are zero if `None`
+    pub time_provider: Option<&'static dyn TimeProvider>,
+}
+
+impl Default for RamFSConfig {
+    fn default() -> Self {
+        RamFSConfig {
+            max_bytes: None,
+            max_inodes: None,
+            mode: 0o777,
+            uid: 0,
+            gid: 0,
+            time_provider: None,
+        }
+    }
 }
 
 impl FileSystem for RamFS {
@@ -27,21 +75,39 @@ impl FileSystem for RamFS {
     }
 
     fn info(&self) -> FsInfo {
+        // unlimited resources are reported as the max number
+        let blocks = self.max_blocks().unwrap_or(usize::MAX);
+        let bfree = blocks - self.used_blocks.load(Ordering::SeqCst);
+        let files = self.config.max_inodes.unwrap_or(usize::MAX);
+        let ffree = files - self.used_inodes.load(Ordering::SeqCst);
         FsInfo {
-            bsize: 0,
-            frsize: 0,
-            blocks: 0,
-            bfree: 0,
-            bavail: 0,
-            files: 0,
-            ffree: 0,
-            namemax: 0,
+            bsize: BLKSIZE,
+            frsize: BLKSIZE,
+            blocks,
+            bfree,
+            bavail: bfree,
+            files,
+            ffree,
+            namemax: usize::MAX,
+            inline_blocks: 0,
         }
     }
+
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
 }
 
 impl RamFS {
     pub fn new() -> Arc<Self> {
+        Self::new_with_config(RamFSConfig::default())
+    }
+
+    pub fn new_with_config(config: RamFSConfig) -> Arc<Self> {
+        let mut inode_ids = InodeIdAllocator::default();
+        let time = config
+            .time_provider
+            .map_or(Timespec { sec: 0, nsec: 0 }, |t| t.current_time());
         let root = Arc::new(LockedINode(RwLock::new(RamFSINode {
             this: Weak::default(),
             parent: Weak::default(),
@@ -49,32 +115,86 @@ impl RamFS {
             content: Vec::new(),
             extra: Metadata {
                 dev: 0,
-                inode: new_inode_id(),
+                inode: inode_ids.alloc(),
                 size: 0,
                 blk_size: 0,
                 blocks: 0,
-                atime: Timespec { sec: 0, nsec: 0 },
-                mtime: Timespec { sec: 0, nsec: 0 },
-                ctime: Timespec { sec: 0, nsec: 0 },
+                atime: time,
+                mtime: time,
+                ctime: time,
                 type_: FileType::Dir,
-                mode: 0o777,
-                nlinks: 1,
-                uid: 0,
-                gid: 0,
+                mode: config.mode,
+                nlinks: 2,
+                uid: config.uid,
+                gid: config.gid,
                 rdev: 0,
             },
             fs: Weak::default(),
         })));
-        let fs = Arc::new(RamFS { root });
+        let fs = Arc::new(RamFS {
+            root,
+            config,
+            used_blocks: AtomicUsize::new(0),
+            used_inodes: AtomicUsize::new(1),
+            inode_ids: Mutex::new(inode_ids),
+            rename_lock: Mutex::new(()),
+        });
         let mut root = fs.root.0.write();
         root.parent = Arc::downgrade(&fs.root);
         root.this = Arc::downgrade(&fs.root);
         root.fs = Arc::downgrade(&fs);
-        root.extra.inode =
-            Arc::into_raw(root.this.upgrade().unwrap()) as *const RamFSINode as usize;
         drop(root);
         fs
     }
+
+    pub fn config(&self) -> &RamFSConfig {
+        &self.config
+    }
+
+    /// Current time, or `None` if timestamps are not maintained
+    fn now(&self) -> Option<Timespec> {
+        self.config.time_provider.map(|t| t.current_time())
+    }
+
+    fn max_blocks(&self) -> Option<usize> {
+        self.config.max_bytes.map(|bytes| bytes / BLKSIZE)
+    }
+
+    /// Charge `n` more items to `used`, fail if `max` is exceeded
+    fn charge(used: &AtomicUsize, n: usize, max: Option<usize>) -> Result<()> {
+        used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| match max {
+            Some(max) if used + n > max => None,
+            _ => Some(used + n),
+        })
+        .map(|_| ())
+        .map_err(|_| FsError::NoDeviceSpace)
+    }
+}
+
+/// Allocate inode numbers from 1, reusing the smallest freed one
+#[derive(Default)]
+struct InodeIdAllocator {
+    next: usize,
+    freed: BTreeSet<usize>,
+}
+
+impl InodeIdAllocator {
+    fn alloc(&mut self) -> usize {
+        if let Some(id) = self.freed.pop_first() {
+            return id;
+        }
+        self.next += 1;
+        self.next
+    }
+
+    fn free(&mut self, id: usize) {
+        self.freed.insert(id);
+    }
+}
+
+/// Number of blocks charged for `len` bytes
+fn blocks_of(len: usize) -> usize {
+    len.div_ceil(BLKSIZE)
 }
 
 struct RamFSINode {
@@ -92,32 +212,86 @@ struct RamFSINode {
     fs: Weak<RamFS>,
 }
 
+impl RamFSINode {
+    /// Current time, or `None` if timestamps are not maintained
+    fn now(&self) -> Option<Timespec> {
+        self.fs.upgrade().and_then(|fs| fs.now())
+    }
+
+    /// Update mtime and ctime after the content is changed
+    fn touch(&mut self) {
+        if let Some(now) = self.now() {
+            self.extra.mtime = now;
+            self.extra.ctime = now;
+        }
+    }
+
+    /// Update ctime after the metadata is changed
+    fn touch_ctime(&mut self) {
+        if let Some(now) = self.now() {
+            self.extra.ctime = now;
+        }
+    }
+
+    /// Resize the content, charging or releasing blocks
+    fn set_len(&mut self, len: usize) -> Result<()> {
+        let old_blocks = blocks_of(self.content.len());
+        let new_blocks = blocks_of(len);
+        if let Some(fs) = self.fs.upgrade() {
+            if new_blocks > old_blocks {
+                RamFS::charge(&fs.used_blocks, new_blocks - old_blocks, fs.max_blocks())?;
+            } else {
+                fs.used_blocks
+                    .fetch_sub(old_blocks - new_blocks, Ordering::SeqCst);
+            }
+        }
+        self.content.resize(len, 0);
+        Ok(())
+    }
+}
+
+impl Drop for RamFSINode {
+    fn drop(&mut self) {
+        // the inode is freed after unlinked and closed
+        if let Some(fs) = self.fs.upgrade() {
+            fs.used_blocks
+                .fetch_sub(blocks_of(self.content.len()), Ordering::SeqCst);
+            fs.used_inodes.fetch_sub(1, Ordering::SeqCst);
+            fs.inode_ids.lock().free(self.extra.inode);
+        }
+    }
+}
+
 struct LockedINode(RwLock<RamFSINode>);
 
 impl INode for LockedINode {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
         let file = self.0.read();
         if file.extra.type_ == FileType::Dir {
-            return Err(FsError::IsDir);
+            return Err(FsError::NotFile);
         }
         let start = file.content.len().min(offset);
         let end = file.content.len().min(offset + buf.len());
         let src = &file.content[start..end];
         buf[0..src.len()].copy_from_slice(src);
-        Ok(src.len())
+        if let Some(now) = file.now() {
+            drop(file);
+            self.0.write().extra.atime = now;
+        }
+        Ok(end - start)
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
         let mut file = self.0.write();
         if file.extra.type_ == FileType::Dir {
-            return Err(FsError::IsDir);
+            return Err(FsError::NotFile);
         }
-        let content = &mut file.content;
-        if offset + buf.len() > content.len() {
-            content.resize(offset + buf.len(), 0);
+        if offset + buf.len() > file.content.len() {
+            file.set_len(offset + buf.len())?;
         }
-        let target = &mut content[offset..offset + buf.len()];
+        let target = &mut file.content[offset..offset + buf.len()];
         target.copy_from_slice(buf);
+        file.touch();
         Ok(buf.len())
     }
 
@@ -136,7 +310,12 @@ impl INode for LockedINode {
     fn metadata(&self) -> Result<Metadata> {
         let file = self.0.read();
         let mut metadata = file.extra.clone();
-        metadata.size = file.content.len();
+        metadata.size = match file.extra.type_ {
+            FileType::Dir => file.children.len(),
+            _ => file.content.len(),
+        };
+        metadata.blk_size = BLKSIZE;
+        metadata.blocks = blocks_of(file.content.len());
         Ok(metadata)
     }
 
@@ -148,6 +327,7 @@ impl INode for LockedINode {
         file.extra.mode = metadata.mode;
         file.extra.uid = metadata.uid;
         file.extra.gid = metadata.gid;
+        file.touch_ctime();
         Ok(())
     }
 
@@ -162,7 +342,8 @@ impl INode for LockedINode {
     fn resize(&self, len: usize) -> Result<()> {
         let mut file = self.0.write();
         if file.extra.type_ == FileType::File {
-            file.content.resize(len, 0);
+            file.set_len(len)?;
+            file.touch();
             Ok(())
         } else {
             Err(FsError::NotFile)
@@ -184,6 +365,9 @@ impl INode for LockedINode {
             if file.children.contains_key(name) {
                 return Err(FsError::EntryExist);
             }
+            let fs = file.fs.upgrade().unwrap();
+            RamFS::charge(&fs.used_inodes, 1, fs.config.max_inodes)?;
+            let time = fs.now().unwrap_or(Timespec { sec: 0, nsec: 0 });
             let temp_file = Arc::new(LockedINode(RwLock::new(RamFSINode {
                 parent: Weak::clone(&file.this),
                 this: Weak::default(),
@@ -191,16 +375,16 @@ impl INode for LockedINode {
                 content: Vec::new(),
                 extra: Metadata {
                     dev: 0,
-                    inode: new_inode_id(),
+                    inode: fs.inode_ids.lock().alloc(),
                     size: 0,
                     blk_size: 0,
                     blocks: 0,
-                    atime: Timespec { sec: 0, nsec: 0 },
-                    mtime: Timespec { sec: 0, nsec: 0 },
-                    ctime: Timespec { sec: 0, nsec: 0 },
+                    atime: time,
+                    mtime: time,
+                    ctime: time,
                     type_,
                     mode: mode as u16,
-                    nlinks: 1,
+                    nlinks: if type_ == FileType::Dir { 2 } else { 1 },
                     uid: 0,
                     gid: 0,
                     rdev: data,
@@ -210,6 +394,11 @@ impl INode for LockedINode {
             temp_file.0.write().this = Arc::downgrade(&temp_file);
             file.children
                 .insert(String::from(name), Arc::clone(&temp_file));
+            if type_ == FileType::Dir {
+                // ".." of the new directory
+                file.extra.nlinks += 1;
+            }
+            file.touch();
             Ok(temp_file)
         } else {
             Err(FsError::NotDir)
@@ -217,28 +406,39 @@ impl INode for LockedINode {
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
-        let other = other
-            .downcast_ref::<LockedINode>()
-            .ok_or(FsError::NotSameFs)?;
-        // to make sure locking order.
-        let mut locks = lock_multiple(&[&self.0, &other.0]).into_iter();
-
-        let mut file = locks.next().unwrap();
-        let mut other_l = locks.next().unwrap();
+        let other = self.same_fs(other)?;
+        // `other` may be this directory itself, check it before locking both
+        {
+            let file = self.0.read();
+            if file.extra.type_ != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            if file.children.contains_key(name) {
+                return Err(FsError::EntryExist);
+            }
+        }
+        if other.0.read().extra.type_ == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        // a dir before the files in it, like unlink
+        let mut file = self.0.write();
+        let mut other_l = other.0.write();
 
         if file.extra.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if other_l.extra.type_ == FileType::Dir {
-            return Err(FsError::IsDir);
-        }
         if file.children.contains_key(name) {
             return Err(FsError::EntryExist);
         }
+        if other_l.extra.type_ == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
 
         file.children
             .insert(String::from(name), other_l.this.upgrade().unwrap());
         other_l.extra.nlinks += 1;
+        other_l.touch_ctime();
+        file.touch();
         Ok(())
     }
 
@@ -248,25 +448,123 @@ impl INode for LockedINode {
             return Err(FsError::NotDir);
         }
         if name == "." || name == ".." {
-            return Err(FsError::DirNotEmpty);
+            return Err(FsError::IsDir);
         }
         let other = file.children.get(name).ok_or(FsError::EntryNotFound)?;
-        if !other.0.read().children.is_empty() {
-            return Err(FsError::DirNotEmpty);
-        }
-        other.0.write().extra.nlinks -= 1;
+        let is_dir = {
+            let mut other = other.0.write();
+            if !other.children.is_empty() {
+                return Err(FsError::DirNotEmpty);
+            }
+            if other.extra.type_ == FileType::Dir {
+                other.extra.nlinks = 0;
+                true
+            } else {
+                other.extra.nlinks -= 1;
+                other.touch_ctime();
+                false
+            }
+        };
+        // the inode is kept alive by opened references
         file.children.remove(name);
+        if is_dir {
+            file.extra.nlinks -= 1;
+        }
+        file.touch();
         Ok(())
     }
 
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
-        let elem = self.find(old_name)?;
-        target.link(new_name, &elem)?;
-        if let Err(err) = self.unlink(old_name) {
-            // recover
-            target.unlink(new_name)?;
-            return Err(err);
+        let dest = self.same_fs(target)?;
+        if old_name == "." || old_name == ".." || new_name == "." || new_name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let same_dir = core::ptr::eq(self, dest);
+        let fs = self.0.read().fs.upgrade().unwrap();
+        // across dirs, the ancestors of both must stay as they are until it is done
+        let _rename = match same_dir {
+            true => None,
+            false => Some(fs.rename_lock.lock()),
+        };
+        let (src_path, dest_path) = match same_dir {
+            true => (Vec::new(), Vec::new()),
+            false => (self.ancestors(), dest.ancestors()),
+        };
+        // the entries are looked up, checked and changed under the same locks
+        let mut dirs = match same_dir {
+            true => vec![self.0.write()],
+            false => lock_dirs(&[(src_path.len(), &self.0), (dest_path.len(), &dest.0)]),
+        };
+        let (src, dst) = (0, dirs.len() - 1);
+        if dirs.iter().any(|dir| dir.extra.type_ != FileType::Dir) {
+            return Err(FsError::NotDir);
+        }
+        let elem = dirs[src]
+            .children
+            .get(old_name)
+            .cloned()
+            .ok_or(FsError::EntryNotFound)?;
+        if dest_path.iter().any(|dir| Arc::ptr_eq(dir, &elem)) {
+            // into its own subtree, or into itself which is locked already
+            return Err(FsError::InvalidParam);
+        }
+        let is_dir = elem.0.read().extra.type_ == FileType::Dir;
+        let old = dirs[dst].children.get(new_name).cloned();
+        if let Some(old) = &old {
+            if Arc::ptr_eq(old, &elem) {
+                return Ok(());
+            }
+            if src_path.iter().any(|dir| Arc::ptr_eq(dir, old)) {
+                // an ancestor of self is not empty, and is locked already
+                return Err(match is_dir {
+                    true => FsError::DirNotEmpty,
+                    false => FsError::IsDir,
+                });
+            }
+        }
+        let mut locks = match &old {
+            Some(old) => lock_multiple(&[&elem.0, &old.0]),
+            None => vec![elem.0.write()],
+        }
+        .into_iter();
+        let mut elem_l = locks.next().unwrap();
+        let old_l = locks.next();
+
+        // replace the existing entry
+        if let Some(old_l) = &old_l {
+            match (is_dir, old_l.extra.type_ == FileType::Dir) {
+                (false, true) => return Err(FsError::IsDir),
+                (true, false) => return Err(FsError::NotDir),
+                (true, true) if !old_l.children.is_empty() => return Err(FsError::DirNotEmpty),
+                _ => {}
+            }
+        }
+
+        dirs[src].children.remove(old_name);
+        if is_dir {
+            dirs[src].extra.nlinks -= 1;
         }
+        dirs[src].touch();
+        dirs[dst]
+            .children
+            .insert(String::from(new_name), elem.clone());
+        if is_dir {
+            dirs[dst].extra.nlinks += 1;
+        }
+        dirs[dst].touch();
+        if let Some(mut old_l) = old_l {
+            if old_l.extra.type_ == FileType::Dir {
+                old_l.extra.nlinks = 0;
+                dirs[dst].extra.nlinks -= 1;
+            } else {
+                old_l.extra.nlinks -= 1;
+                old_l.touch_ctime();
+            }
+        }
+        if is_dir {
+            elem_l.parent = dirs[dst].this.clone();
+        }
+        elem_l.touch_ctime();
         Ok(())
     }
 
@@ -322,6 +620,49 @@ impl INode for LockedINode {
     }
 }
 
+impl LockedINode {
+    /// This dir and its ancestors up to the root
+    fn ancestors(&self) -> Vec<Arc<LockedINode>> {
+        let mut path = Vec::new();
+        let mut dir = self.0.read().this.upgrade().unwrap();
+        loop {
+            let parent = dir.0.read().parent.upgrade();
+            path.push(dir);
+            match parent {
+                Some(parent) if !Arc::ptr_eq(&parent, path.last().unwrap()) => dir = parent,
+                _ => return path,
+            }
+        }
+    }
+
+    /// Downcast `other` to `LockedINode` of the same `RamFS`
+    fn same_fs<'a>(&self, other: &'a Arc<dyn INode>) -> Result<&'a Self> {
+        let other = other.downcast_ref::<Self>().ok_or(FsError::NotSameFs)?;
+        // one lock at a time, as others may hold one and wait for the other
+        let fs = self.0.read().fs.clone();
+        match Weak::ptr_eq(&fs, &other.0.read().fs) {
+            true => Ok(other),
+            false => Err(FsError::NotSameFs),
+        }
+    }
+}
+
+/// Lock the (depth, dir) for writing, those closer to the root first, then by inode id.
+/// A dir is always locked before its children, as `unlink` does.
+fn lock_dirs<'a>(
+    dirs: &[(usize, &'a RwLock<RamFSINode>)],
+) -> Vec<RwLockWriteGuard<'a, RamFSINode>> {
+    let mut order: Vec<usize> = (0..dirs.len()).collect();
+    let mut guards = BTreeMap::new();
+    order.sort_by_key(|&i| (dirs[i].0, dirs[i].1.read().extra.inode));
+    for i in order {
+        guards.insert(i, dirs[i].1.write());
+    }
+    (0..dirs.len())
+        .map(|i| guards.remove(&i).unwrap())
+        .collect()
+}
+
 /// Lock INodes order by their inode id
 fn lock_multiple<'a>(locks: &[&'a RwLock<RamFSINode>]) -> Vec<RwLockWriteGuard<'a, RamFSINode>> {
     let mut order: Vec<usize> = (0..locks.len()).collect();
@@ -336,10 +677,3 @@ fn lock_multiple<'a>(locks: &[&'a RwLock<RamFSINode>]) -> Vec<RwLockWriteGuard<'
     }
     ret
 }
-
-/// Generate a new inode id
-fn new_inode_id() -> usize {
-    use core::sync::atomic::*;
-    static ID: AtomicUsize = AtomicUsize::new(1);
-    ID.fetch_add(1, Ordering::SeqCst)
-}
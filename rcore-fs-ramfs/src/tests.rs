@@ -0,0 +1,223 @@
+use crate::*;
+use core::sync::atomic::AtomicI64;
+use rcore_fs::dev::TimeProvider;
+use rcore_fs_testsuite::{model, vfs_tests, Features};
+
+const FEATURES: Features = Features::all();
+
+vfs_tests!(RamFS::new(), FEATURES);
+
+#[test]
+fn model() {
+    model::check(RamFS::new, FEATURES);
+}
+
+#[test]
+fn fifo() -> Result<()> {
+    use rcore_fs::pipe::{FifoTable, PipeEnd};
+    let fs = RamFS::new();
+    let fifo = fs.root_inode().create("fifo", FileType::NamedPipe, 0o644)?;
+    let table = FifoTable::new();
+    let read = table.open(&fifo, PipeEnd::Read)?;
+    let write = table.open(&fifo, PipeEnd::Write)?;
+    assert!(Arc::ptr_eq(read.pipe(), write.pipe()));
+    assert_eq!(write.write_at(0, b"data")?, 4);
+    assert_eq!(read.metadata()?.type_, FileType::NamedPipe);
+    assert_eq!(fifo.metadata()?.size, 0, "data is not stored in the node");
+
+    let mut buf = [0u8; 8];
+    assert_eq!(read.read_at(0, &mut buf)?, 4);
+    assert_eq!(&buf[..4], b"data");
+    drop(write);
+    assert_eq!(read.read_at(0, &mut buf)?, 0);
+    drop(read);
+
+    // a new pipe is attached after all ends are closed
+    let read = table.open(&fifo, PipeEnd::Read)?;
+    assert_eq!(read.pipe().writers(), 0);
+    let file = fs.root_inode().create("file", FileType::File, 0o644)?;
+    assert_eq!(
+        table.open(&file, PipeEnd::Read).err(),
+        Some(FsError::InvalidParam)
+    );
+
+    // FIFOs with the same inode id in different file systems
+    let other = RamFS::new();
+    let other_fifo = other
+        .root_inode()
+        .create("fifo", FileType::NamedPipe, 0o644)?;
+    assert_eq!(other_fifo.metadata()?.inode, fifo.metadata()?.inode);
+    let other_read = table.open(&other_fifo, PipeEnd::Read)?;
+    assert!(!Arc::ptr_eq(read.pipe(), other_read.pipe()));
+    assert!(other_read.fs().root_inode().find("fifo").is_ok());
+    Ok(())
+}
+
+#[test]
+fn limits() -> Result<()> {
+    let fs = RamFS::new_with_config(RamFSConfig {
+        max_bytes: Some(4 * BLKSIZE),
+        max_inodes: Some(3),
+        mode: 0o1777,
+        uid: 1000,
+        gid: 100,
+        ..RamFSConfig::default()
+    });
+    let root = fs.root_inode();
+    let metadata = root.metadata()?;
+    assert_eq!(
+        (metadata.mode, metadata.uid, metadata.gid),
+        (0o1777, 1000, 100)
+    );
+    let info = fs.info();
+    assert_eq!(
+        (info.blocks, info.bfree, info.files, info.ffree),
+        (4, 4, 3, 2)
+    );
+
+    let file = root.create("file", FileType::File, 0o644)?;
+    assert_eq!(file.write_at(0, &[1; BLKSIZE + 1])?, BLKSIZE + 1);
+    let metadata = file.metadata()?;
+    assert_eq!((metadata.size, metadata.blocks), (BLKSIZE + 1, 2));
+    assert_eq!((fs.info().bfree, fs.info().ffree), (2, 1));
+    assert_eq!(
+        file.write_at(4 * BLKSIZE, &[1]).err(),
+        Some(FsError::NoDeviceSpace)
+    );
+    assert_eq!(
+        file.metadata()?.size,
+        BLKSIZE + 1,
+        "failed write changes nothing"
+    );
+    assert_eq!(file.resize(5 * BLKSIZE).err(), Some(FsError::NoDeviceSpace));
+    file.resize(4 * BLKSIZE)?;
+    assert_eq!(fs.info().bfree, 0);
+    file.resize(BLKSIZE)?;
+    assert_eq!(fs.info().bfree, 3);
+
+    root.create("dir", FileType::Dir, 0o755)?;
+    assert_eq!(
+        root.create("full", FileType::File, 0o644).err(),
+        Some(FsError::NoDeviceSpace)
+    );
+    assert_eq!(root.metadata()?.size, 2);
+
+    // space is released after unlinked and closed
+    root.unlink("file")?;
+    assert_eq!((fs.info().bfree, fs.info().ffree), (3, 0));
+    drop(file);
+    assert_eq!((fs.info().bfree, fs.info().ffree), (4, 1));
+    root.create("file", FileType::File, 0o644)?;
+    Ok(())
+}
+
+#[test]
+fn inode_ids() -> Result<()> {
+    let fs = RamFS::new();
+    let root = fs.root_inode();
+    assert_eq!(root.metadata()?.inode, 1);
+    let a = root.create("a", FileType::File, 0o644)?;
+    let b = root.create("b", FileType::File, 0o644)?;
+    assert_eq!((a.metadata()?.inode, b.metadata()?.inode), (2, 3));
+
+    // the id is kept until the unlinked inode is closed
+    root.unlink("a")?;
+    assert_eq!(a.metadata()?.nlinks, 0);
+    assert_eq!(
+        root.create("c", FileType::File, 0o644)?.metadata()?.inode,
+        4
+    );
+    root.unlink("c")?;
+    drop(a);
+    assert_eq!(
+        root.create("d", FileType::File, 0o644)?.metadata()?.inode,
+        2
+    );
+    assert_eq!(
+        root.create("e", FileType::File, 0o644)?.metadata()?.inode,
+        4
+    );
+
+    // ids are per file system
+    assert_eq!(RamFS::new().root_inode().metadata()?.inode, 1);
+    Ok(())
+}
+
+#[test]
+fn unlink_and_move_into_parent() -> Result<()> {
+    extern crate std;
+    use std::thread;
+
+    let fs = RamFS::new();
+    let root = fs.root_inode();
+    // a subdir with a lower id than its parent
+    let c = root.create("c", FileType::Dir, 0o755)?;
+    c.create("file", FileType::File, 0o644)?;
+    let d = root.create("d", FileType::Dir, 0o755)?;
+    root.move_("c", &d, "c")?;
+    assert!(c.metadata()?.inode < d.metadata()?.inode);
+
+    let unlink = {
+        let d = d.clone();
+        thread::spawn(move || {
+            for _ in 0..100000 {
+                assert_eq!(d.unlink("c"), Err(FsError::DirNotEmpty));
+            }
+        })
+    };
+    for _ in 0..100000 {
+        assert_eq!(c.move_("nope", &d, "y"), Err(FsError::EntryNotFound));
+    }
+    unlink.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn timestamps() -> Result<()> {
+    struct Clock(AtomicI64);
+    impl TimeProvider for Clock {
+        fn current_time(&self) -> Timespec {
+            Timespec {
+                sec: self.0.load(Ordering::SeqCst),
+                nsec: 0,
+            }
+        }
+    }
+    static CLOCK: Clock = Clock(AtomicI64::new(10));
+    let tick = |sec| CLOCK.0.store(sec, Ordering::SeqCst);
+    let time = |sec| Timespec { sec, nsec: 0 };
+    let times = |inode: &Arc<dyn INode>| {
+        let m = inode.metadata().unwrap();
+        (m.atime.sec, m.mtime.sec, m.ctime.sec)
+    };
+
+    let fs = RamFS::new_with_config(RamFSConfig {
+        time_provider: Some(&CLOCK),
+        ..RamFSConfig::default()
+    });
+    let root = fs.root_inode();
+    assert_eq!(times(&root), (10, 10, 10));
+    tick(20);
+    let file = root.create("file", FileType::File, 0o644)?;
+    assert_eq!(times(&file), (20, 20, 20));
+    assert_eq!(times(&root), (10, 20, 20));
+
+    tick(30);
+    file.write_at(0, b"data")?;
+    assert_eq!(times(&file), (20, 30, 30));
+    tick(40);
+    file.read_at(0, &mut [0; 4])?;
+    assert_eq!(times(&file), (40, 30, 30));
+    tick(50);
+    root.link("link", &file)?;
+    assert_eq!(times(&file), (40, 30, 50));
+    assert_eq!(times(&root), (10, 50, 50));
+
+    tick(60);
+    let mut metadata = file.metadata()?;
+    metadata.atime = time(1);
+    metadata.mtime = time(2);
+    file.set_metadata(&metadata)?;
+    assert_eq!(times(&file), (1, 2, 60));
+    Ok(())
+}
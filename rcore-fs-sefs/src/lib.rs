@@ -26,6 +26,8 @@ use structs::*;
 
 pub mod dev;
 mod structs;
+#[cfg(test)]
+mod tests;
 
 /// Helper methods for `File`
 impl dyn File {
@@ -374,13 +376,51 @@ impl vfs::INode for INodeImpl {
         if dest_info.nlinks == 0 {
             return Err(FsError::DirRemoved);
         }
-        if dest.get_file_inode_id(new_name).is_some() {
-            return Err(FsError::EntryExist);
+        if new_name == "." || new_name == ".." {
+            return Err(FsError::IsDir);
         }
 
-        let (inode_id, entry_id) = self
+        let (inode_id, _) = self
             .get_file_inode_and_entry_id(old_name)
             .ok_or(FsError::EntryNotFound)?;
+        let inode = self.fs.get_inode(inode_id);
+        let is_dir = inode.disk_inode.read().type_ == FileType::Dir;
+        if is_dir {
+            // the dir can not be moved into its own subtree
+            let mut id = dest.id;
+            while id != BLKN_ROOT {
+                if id == inode_id {
+                    return Err(FsError::InvalidParam);
+                }
+                id = self.fs.get_inode(id).file.read_direntry(1)?.id as INodeId;
+            }
+        }
+
+        // replace the existing entry
+        if let Some((old_inode_id, id)) = dest.get_file_inode_and_entry_id(new_name) {
+            if old_inode_id == inode_id {
+                return Ok(());
+            }
+            let old_inode = self.fs.get_inode(old_inode_id);
+            let old_is_dir = old_inode.disk_inode.read().type_ == FileType::Dir;
+            match (is_dir, old_is_dir) {
+                (false, true) => return Err(FsError::IsDir),
+                (true, false) => return Err(FsError::NotDir),
+                (true, true) if old_inode.disk_inode.read().blocks > 2 => {
+                    return Err(FsError::DirNotEmpty)
+                }
+                _ => {}
+            }
+            old_inode.nlinks_dec();
+            if old_is_dir {
+                old_inode.nlinks_dec(); //for .
+                dest.nlinks_dec(); //for ..
+            }
+            dest.dirent_remove(id)?;
+        }
+
+        // entry id may be changed by dirent_remove
+        let (_, entry_id) = self.get_file_inode_and_entry_id(old_name).unwrap();
         if info.inode == dest_info.inode {
             // rename: in place modify name
             let entry = DiskEntry {
@@ -390,8 +430,6 @@ impl vfs::INode for INodeImpl {
             self.file.write_direntry(entry_id, &entry)?;
         } else {
             // move
-            let inode = self.fs.get_inode(inode_id);
-
             let entry = DiskEntry {
                 id: inode_id as u32,
                 name: Str256::from(new_name),
@@ -399,7 +437,14 @@ impl vfs::INode for INodeImpl {
             dest.dirent_append(&entry)?;
             self.dirent_remove(entry_id)?;
 
-            if inode.metadata()?.type_ == vfs::FileType::Dir {
+            if is_dir {
+                inode.file.write_direntry(
+                    1,
+                    &DiskEntry {
+                        id: dest.id as u32,
+                        name: Str256::from(".."),
+                    },
+                )?;
                 self.nlinks_dec();
                 dest.nlinks_inc();
             }
@@ -627,6 +672,16 @@ impl SEFS {
             }
         }
         // Load if not in set, or is weak ref.
+        // Check the type first, since loading an invalid enum value is UB.
+        let mut type_ = [0u8; 2];
+        self.meta_file
+            .read_exact_at(&mut type_, id * BLKSIZE + 4)
+            .unwrap();
+        assert!(
+            u16::from_ne_bytes(type_) <= FileType::SymLink as u16,
+            "invalid type of inode {}",
+            id
+        );
         let disk_inode = Dirty::new(self.meta_file.load_struct::<DiskINode>(id).unwrap());
         self._new_inode(id, disk_inode, false)
     }
@@ -710,8 +765,13 @@ impl vfs::FileSystem for SEFS {
             files: sb.blocks as usize,        // inaccurate
             ffree: sb.unused_blocks as usize, // inaccurate
             namemax: MAX_FNAME_LEN,
+            inline_blocks: 0,
         }
     }
+
+    fn name(&self) -> &'static str {
+        "sefs"
+    }
 }
 
 impl Drop for SEFS {
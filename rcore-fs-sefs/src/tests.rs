@@ -0,0 +1,161 @@
+use crate::dev::{DevResult, DeviceError};
+use crate::*;
+use rcore_fs::dev::std_impl::StdTimeProvider;
+use rcore_fs::vfs::FileSystem;
+use rcore_fs_testsuite::{crash, model, vfs_tests, Features};
+use std::collections::BTreeSet;
+
+fn _create_new_sefs() -> (Arc<SEFS>, tempfile::TempDir) {
+    let dir = tempfile::tempdir().expect("failed to create dir");
+    let sefs = SEFS::create(
+        Box::new(dev::std_impl::StdStorage::new(dir.path())),
+        &StdTimeProvider,
+    )
+    .expect("failed to create SEFS");
+    (sefs, dir)
+}
+
+vfs_tests!(_create_new_sefs());
+
+/// SEFS on a temporary directory, which can be reopened
+struct SefsDir {
+    sefs: Arc<SEFS>,
+    dir: tempfile::TempDir,
+}
+
+impl model::Target for SefsDir {
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.sefs.clone()
+    }
+
+    fn reopen(&mut self) {
+        self.sefs.sync().unwrap();
+        self.sefs = SEFS::open(
+            Box::new(dev::std_impl::StdStorage::new(self.dir.path())),
+            &StdTimeProvider,
+        )
+        .expect("failed to open SEFS");
+    }
+}
+
+#[test]
+fn model() {
+    model::check(
+        || {
+            let (sefs, dir) = _create_new_sefs();
+            SefsDir { sefs, dir }
+        },
+        Features::all(),
+    );
+}
+
+/// Storage in memory which records all writes
+struct RecordingStorage(Arc<crash::Recorder>);
+
+/// A file of `RecordingStorage`
+struct RecordingFile(Arc<crash::Recorder>, usize);
+
+impl dev::Storage for RecordingStorage {
+    fn open(&self, file_id: usize) -> DevResult<Box<dyn dev::File>> {
+        if !self.0.exists(file_id) {
+            return Err(DeviceError);
+        }
+        Ok(Box::new(RecordingFile(self.0.clone(), file_id)))
+    }
+
+    fn create(&self, file_id: usize) -> DevResult<Box<dyn dev::File>> {
+        self.0.create(file_id);
+        Ok(Box::new(RecordingFile(self.0.clone(), file_id)))
+    }
+
+    fn remove(&self, file_id: usize) -> DevResult<()> {
+        self.0.remove(file_id).ok_or(DeviceError)
+    }
+}
+
+impl dev::File for RecordingFile {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> DevResult<usize> {
+        self.0.read_at(self.1, offset, buf).ok_or(DeviceError)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: usize) -> DevResult<usize> {
+        self.0.write_at(self.1, offset, buf).ok_or(DeviceError)
+    }
+
+    fn set_len(&self, len: usize) -> DevResult<()> {
+        self.0.set_len(self.1, len).ok_or(DeviceError)
+    }
+
+    fn flush(&self) -> DevResult<()> {
+        self.0.flush(self.1);
+        Ok(())
+    }
+}
+
+/// Check the superblock matches the free map, and every reachable inode is
+/// allocated and has its file
+fn check_files(sefs: &Arc<SEFS>) -> core::result::Result<(), String> {
+    fn collect(dir: &Arc<dyn INode>, ids: &mut BTreeSet<usize>) -> vfs::Result<()> {
+        for name in dir.list()? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let child = dir.find(&name)?;
+            let info = child.metadata()?;
+            if ids.insert(info.inode) && info.type_ == vfs::FileType::Dir {
+                collect(&child, ids)?;
+            }
+        }
+        Ok(())
+    }
+    let mut ids = BTreeSet::new();
+    ids.insert(BLKN_ROOT);
+    collect(&sefs.root_inode(), &mut ids).map_err(|e| format!("walk: {:?}", e))?;
+
+    let free_map = sefs.free_map.read();
+    let unused = free_map.count_ones();
+    let super_block = sefs.super_block.read();
+    if super_block.unused_blocks as usize != unused {
+        return Err(format!(
+            "superblock has {} unused blocks, free map has {}",
+            super_block.unused_blocks, unused
+        ));
+    }
+    for id in ids {
+        if free_map[id] {
+            return Err(format!("inode {} is free", id));
+        }
+        if sefs.device.open(id).is_err() {
+            return Err(format!("file of inode {} is missing", id));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_consistency() {
+    let recorder = crash::Recorder::new(crash::Image::new());
+    let sefs = SEFS::create(
+        Box::new(RecordingStorage(recorder.clone())),
+        &StdTimeProvider,
+    )
+    .expect("failed to create SEFS");
+    sefs.sync().unwrap();
+    let base = recorder.image();
+    recorder.take_events();
+
+    crash::workload(&*sefs, Features::all());
+    drop(sefs);
+    let events = recorder.take_events();
+
+    let report = crash::check(&base, &events, &crash::Config::default(), |image| {
+        let storage = RecordingStorage(crash::Recorder::new(image));
+        let sefs = SEFS::open(Box::new(storage), &StdTimeProvider)
+            .map_err(|e| format!("open: {:?}", e))?;
+        crash::check_tree(&*sefs, Features::all())?;
+        check_files(&sefs)
+    });
+    println!("{}", report);
+    let failures: Vec<_> = report.durable_failures().collect();
+    assert!(failures.is_empty(), "corrupted after sync: {:#?}", failures);
+}
@@ -0,0 +1,96 @@
+//! Benchmarks of the block allocator of SFS
+//!
+//! Run with `cargo bench -p rcore-fs-sfs`.
+
+use rcore_fs::vfs::{FileSystem, FileType, INode};
+use rcore_fs_sfs::{SimpleFileSystem, BLKSIZE};
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Size of the test image
+const SPACE: usize = 256 * 1024 * 1024;
+
+type Workload = fn(&SimpleFileSystem);
+
+fn new_sfs(extents: bool) -> Arc<SimpleFileSystem> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let sfs =
+        SimpleFileSystem::create(Arc::new(Mutex::new(file)), SPACE).expect("failed to create SFS");
+    sfs.set_extents(extents);
+    sfs
+}
+
+/// Append to one large file a block at a time
+fn sequential(sfs: &SimpleFileSystem) {
+    let file = sfs
+        .root_inode()
+        .create("big", FileType::File, 0o644)
+        .unwrap();
+    let buf = [0xaau8; BLKSIZE];
+    for i in 0..SPACE / BLKSIZE / 2 {
+        file.write_at(i * BLKSIZE, &buf).unwrap();
+    }
+}
+
+/// Append to many files in turn
+fn interleaved(sfs: &SimpleFileSystem) {
+    let root = sfs.root_inode();
+    let files: Vec<Arc<dyn INode>> = (0..16)
+        .map(|i| {
+            root.create(&format!("file{}", i), FileType::File, 0o644)
+                .unwrap()
+        })
+        .collect();
+    let buf = [0x55u8; BLKSIZE];
+    for i in 0..SPACE / BLKSIZE / 2 / files.len() {
+        for file in files.iter() {
+            file.write_at(i * BLKSIZE, &buf).unwrap();
+        }
+    }
+}
+
+/// Create and remove small files, then write a large one into the holes
+fn aged(sfs: &SimpleFileSystem) {
+    let root = sfs.root_inode();
+    for i in 0..1000 {
+        let file = root
+            .create(&format!("small{}", i), FileType::File, 0o644)
+            .unwrap();
+        file.resize((i % 7 + 1) * BLKSIZE).unwrap();
+    }
+    for i in (0..1000).step_by(2) {
+        root.unlink(&format!("small{}", i)).unwrap();
+    }
+    let file = root.create("big", FileType::File, 0o644).unwrap();
+    file.resize(SPACE / 4).unwrap();
+}
+
+fn main() {
+    let workloads: [(&str, Workload); 3] = [
+        ("sequential", sequential),
+        ("interleaved", interleaved),
+        ("aged", aged),
+    ];
+    for &(name, workload) in workloads.iter() {
+        for &extents in [false, true].iter() {
+            let sfs = new_sfs(extents);
+            let start = Instant::now();
+            workload(&sfs);
+            sfs.sync().unwrap();
+            let elapsed = start.elapsed();
+            let report = sfs.fragmentation().unwrap();
+            println!(
+                "{:<12} extents: {:<5} {:>8.2?}  files: {:>4}  fragmented: {:>4}  file extents: {:>6}  free extents: {:>4}",
+                name,
+                extents,
+                elapsed,
+                report.files,
+                report.fragmented_files,
+                report.file_extents,
+                report.free_extents,
+            );
+        }
+    }
+}
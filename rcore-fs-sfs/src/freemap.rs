@@ -0,0 +1,193 @@
+//! Free map of SFS, split into groups of one freemap block each
+//!
+//! Every group has its own lock, so that files allocating in different groups do not
+//! contend. The number of free blocks is counted by the FS, where blocks are reserved
+//! before they are taken from the groups.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use bitvec::prelude::*;
+use rcore_fs::{dirty::Dirty, vfs};
+use spin::Mutex;
+
+use crate::structs::{BlockId, BLKBITS, BLKSIZE};
+
+/// Bitmap of blocks, those in use are marked 0
+pub struct FreeMap {
+    groups: Vec<Mutex<Dirty<BitVec<Lsb0, u8>>>>,
+}
+
+impl FreeMap {
+    /// Load from the content of the freemap blocks
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        let groups = data
+            .chunks(BLKSIZE)
+            .map(|block| Mutex::new(Dirty::new(BitVec::from_vec(block.to_vec()))))
+            .collect();
+        FreeMap { groups }
+    }
+    /// A freemap of `blocks` blocks, a multiple of `BLKBITS`, all in use, to be written out
+    pub fn new(blocks: usize) -> Self {
+        let mut free_map = FreeMap { groups: Vec::new() };
+        free_map.resize(blocks);
+        free_map
+    }
+    /// Number of blocks it can map
+    pub fn len(&self) -> usize {
+        self.groups.len() * BLKBITS
+    }
+    /// Number of free blocks among the first `blocks`
+    pub fn count_free(&self, blocks: usize) -> usize {
+        let groups = self.groups.iter().enumerate();
+        groups
+            .map(|(index, group)| {
+                let end = blocks.saturating_sub(index * BLKBITS).min(BLKBITS);
+                group.lock()[..end].count_ones()
+            })
+            .sum()
+    }
+    pub fn is_free(&self, block_id: BlockId) -> bool {
+        self.groups[block_id / BLKBITS].lock()[block_id % BLKBITS]
+    }
+    pub fn set(&self, block_id: BlockId, free: bool) {
+        self.groups[block_id / BLKBITS]
+            .lock()
+            .set(block_id % BLKBITS, free);
+    }
+    /// Allocate a run of at most `len` blocks.
+    /// Take the first run of `len` free blocks in the group of `goal` from `goal` on,
+    /// or in the groups after it, wrapping around at the end.
+    /// If there is no such one, take the longest run in the first group with a free block.
+    pub fn alloc_run(&self, goal: BlockId, len: usize) -> Option<Range<BlockId>> {
+        let goal = if goal < self.len() { goal } else { 0 };
+        let first = goal / BLKBITS;
+        for &whole in [true, false].iter() {
+            for i in 0..self.groups.len() {
+                let index = (first + i) % self.groups.len();
+                let mut group = self.groups[index].lock();
+                let local_goal = if i == 0 { goal % BLKBITS } else { 0 };
+                let run = match group.find_run(local_goal, len) {
+                    Some(run) if !whole || run.len() == len.min(BLKBITS) => run,
+                    _ => continue,
+                };
+                for block_id in run.clone() {
+                    group.set(block_id, false);
+                }
+                let base = index * BLKBITS;
+                return Some(base + run.start..base + run.end);
+            }
+        }
+        None
+    }
+    /// Find the first free block in `begin..end`
+    pub fn next_free(&self, begin: BlockId, end: BlockId) -> Option<BlockId> {
+        let mut i = begin;
+        while i < end {
+            let base = i / BLKBITS * BLKBITS;
+            let group_end = end.min(base + BLKBITS);
+            let group = self.groups[i / BLKBITS].lock();
+            if let Some(block_id) = group.next_free(i - base, group_end - base) {
+                return Some(base + block_id);
+            }
+            i = group_end;
+        }
+        None
+    }
+    /// Find the end of the free run starting at `begin`, no further than `end`
+    pub fn run_end(&self, begin: BlockId, end: BlockId) -> BlockId {
+        let mut i = begin;
+        while i < end {
+            let base = i / BLKBITS * BLKBITS;
+            let group_end = end.min(base + BLKBITS);
+            let run_end = base
+                + self.groups[i / BLKBITS]
+                    .lock()
+                    .run_end(i - base, group_end - base);
+            if run_end < group_end {
+                return run_end;
+            }
+            i = group_end;
+        }
+        end
+    }
+    /// Grow or truncate to `blocks` blocks, a multiple of `BLKBITS`.
+    /// Blocks added are in use.
+    pub fn resize(&mut self, blocks: usize) {
+        debug_assert_eq!(blocks / BLKBITS * BLKBITS, blocks);
+        let groups = blocks / BLKBITS;
+        for group in self.groups.drain(groups.min(self.groups.len())..) {
+            // beyond the image, nothing to write back
+            group.lock().sync();
+        }
+        while self.groups.len() < groups {
+            let group = BitVec::repeat(false, BLKBITS);
+            self.groups.push(Mutex::new(Dirty::new_dirty(group)));
+        }
+    }
+    /// Write back the groups modified, by `write` with their index and content
+    pub fn flush(&self, mut write: impl FnMut(usize, &[u8]) -> vfs::Result<()>) -> vfs::Result<()> {
+        for (index, group) in self.groups.iter().enumerate() {
+            let mut group = group.lock();
+            if group.dirty() {
+                write(index, group.as_raw_slice())?;
+                group.sync();
+            }
+        }
+        Ok(())
+    }
+}
+
+trait BitsetAlloc {
+    /// Find a run of at most `len` free blocks.
+    /// Take the first run of `len` free blocks from `goal` on, wrapping around at the end,
+    /// or the longest run if there is no such one.
+    fn find_run(&self, goal: usize, len: usize) -> Option<Range<usize>>;
+    /// Find the first free block in `begin..end`
+    fn next_free(&self, begin: usize, end: usize) -> Option<usize>;
+    /// Find the end of the free run starting at `begin`, no further than `end`
+    fn run_end(&self, begin: usize, end: usize) -> usize;
+}
+
+impl BitsetAlloc for BitVec<Lsb0, u8> {
+    fn find_run(&self, goal: usize, len: usize) -> Option<Range<usize>> {
+        let goal = if goal < self.len() { goal } else { 0 };
+        let mut best: Option<Range<usize>> = None;
+        for &(begin, end) in [(goal, self.len()), (0, goal)].iter() {
+            let mut i = begin;
+            while let Some(start) = self.next_free(i, end) {
+                let run = start..self.run_end(start, end.min(start + len));
+                if run.len() > best.as_ref().map_or(0, |best| best.len()) {
+                    best = Some(run.clone());
+                }
+                if run.len() == len {
+                    break;
+                }
+                i = run.end;
+            }
+            if best.as_ref().map(|best| best.len()) == Some(len) {
+                break;
+            }
+        }
+        best
+    }
+    fn next_free(&self, begin: usize, end: usize) -> Option<usize> {
+        let bytes = self.as_raw_slice();
+        let mut i = begin;
+        while i < end {
+            // skip bytes with no free blocks
+            if bytes[i / 8] == 0 {
+                i = (i / 8 + 1) * 8;
+                continue;
+            }
+            if self[i] {
+                return Some(i);
+            }
+            i += 1;
+        }
+        None
+    }
+    fn run_end(&self, begin: usize, end: usize) -> usize {
+        (begin..end).find(|&i| !self[i]).unwrap_or(end)
+    }
+}
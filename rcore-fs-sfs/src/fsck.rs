@@ -0,0 +1,1112 @@
+//! Checker of SFS images, like `fsck`
+//!
+//! The image is read directly instead of through `SimpleFileSystem`, which trusts it.
+//! Repairing is done in passes until nothing is left to repair: fixes of inodes,
+//! dir structures and the freemap are written directly, then fixes of dir entries
+//! are made through `SimpleFileSystem`, which can open the image by then.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{fmt, ops::Range, str};
+
+use bitvec::prelude::*;
+use rcore_fs::{
+    dev::Device,
+    vfs::{self, FileSystem},
+};
+
+use crate::{dir_hash, journal::Journal, structs::*, DeviceExt, SimpleFileSystem};
+
+/// name of the dir in root which orphans are moved to
+pub const LOST_AND_FOUND: &str = "lost+found";
+/// max number of passes when repairing
+const MAX_PASSES: usize = 4;
+
+/// A problem found in an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The superblock is invalid, nothing else is checked
+    BadSuperBlock(&'static str),
+    /// The header of the journal is invalid
+    BadJournal,
+    /// The journal has a committed transaction which is not in place yet
+    JournalNotReplayed,
+    /// The root inode is not a dir, nothing else is checked
+    BadRoot,
+    /// The block tree of the inode is invalid from file block `block` on
+    BadBlock { inode: INodeId, block: usize },
+    /// The block is used by two inodes, or twice by one
+    SharedBlock {
+        block: BlockId,
+        inodes: (INodeId, INodeId),
+    },
+    /// The size of the inode does not match its blocks
+    BadSize {
+        inode: INodeId,
+        size: usize,
+        blocks: usize,
+    },
+    /// The structure of the dir is invalid
+    BadDir { dir: INodeId, reason: &'static str },
+    /// The entry of the dir is invalid
+    BadEntry {
+        dir: INodeId,
+        name: String,
+        reason: &'static str,
+    },
+    /// '..' of the dir is not its parent
+    BadParent {
+        dir: INodeId,
+        parent: INodeId,
+        expected: INodeId,
+    },
+    /// The link count of the inode is wrong
+    WrongNlinks {
+        inode: INodeId,
+        nlinks: usize,
+        expected: usize,
+    },
+    /// The inode is in use but not reachable from the root
+    Orphan(INodeId),
+    /// The blocks are in use but marked free
+    MarkedFree(Range<BlockId>),
+    /// The blocks are marked in use but not used
+    Leaked(Range<BlockId>),
+    /// The number of unused blocks in the superblock is wrong
+    WrongUnusedBlocks { unused: usize, expected: usize },
+    /// The number of files with inline data in the superblock is wrong
+    WrongInlineFiles { files: usize, expected: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::BadSuperBlock(reason) => write!(f, "bad superblock: {}", reason),
+            Problem::BadJournal => write!(f, "bad journal header"),
+            Problem::JournalNotReplayed => write!(f, "journal is not replayed"),
+            Problem::BadRoot => write!(f, "root inode is not a dir"),
+            Problem::BadBlock { inode, block } => {
+                write!(f, "inode {}: bad block tree at file block {}", inode, block)
+            }
+            Problem::SharedBlock { block, inodes } => write!(
+                f,
+                "block {} is shared by inode {} and {}",
+                block, inodes.0, inodes.1
+            ),
+            Problem::BadSize {
+                inode,
+                size,
+                blocks,
+            } => write!(f, "inode {}: size {} with {} blocks", inode, size, blocks),
+            Problem::BadDir { dir, reason } => write!(f, "dir {}: {}", dir, reason),
+            Problem::BadEntry { dir, name, reason } => {
+                write!(f, "dir {}: entry {:?}: {}", dir, name, reason)
+            }
+            Problem::BadParent {
+                dir,
+                parent,
+                expected,
+            } => write!(f, "dir {}: '..' is {}, expect {}", dir, parent, expected),
+            Problem::WrongNlinks {
+                inode,
+                nlinks,
+                expected,
+            } => write!(f, "inode {}: nlinks {}, expect {}", inode, nlinks, expected),
+            Problem::Orphan(inode) => write!(f, "inode {} is not reachable", inode),
+            Problem::MarkedFree(blocks) => write!(f, "blocks {:?} are used but free", blocks),
+            Problem::Leaked(blocks) => write!(f, "blocks {:?} are not used", blocks),
+            Problem::WrongUnusedBlocks { unused, expected } => {
+                write!(f, "{} unused blocks, expect {}", unused, expected)
+            }
+            Problem::WrongInlineFiles { files, expected } => {
+                write!(f, "{} files with inline data, expect {}", files, expected)
+            }
+        }
+    }
+}
+
+/// Result of `check`
+#[derive(Debug, Default)]
+pub struct Report {
+    /// problems found
+    pub problems: Vec<Problem>,
+    /// problems left after repairing, the same as `problems` without repairing
+    pub remaining: Vec<Problem>,
+}
+
+/// Check the image on `device`. With `repair`, fix the problems found as far as possible:
+/// the freemap is rebuilt, inodes are truncated at invalid blocks, invalid entries are
+/// removed, link counts are corrected, and orphans are moved to `lost+found` as `#<inode>`.
+/// Shared blocks are not repaired.
+pub fn check(device: Arc<dyn Device>, repair: bool) -> vfs::Result<Report> {
+    let mut report = Report::default();
+    for pass in 0..MAX_PASSES {
+        let checker = Checker::run(device.clone(), repair)?;
+        if pass == 0 {
+            report.problems = checker.problems.clone();
+        }
+        report.remaining = checker.problems.clone();
+        if !repair || checker.fixes.is_empty() {
+            break;
+        }
+        checker.repair()?;
+    }
+    Ok(report)
+}
+
+/// A fix of a problem
+#[derive(Debug)]
+enum Fix {
+    /// Keep only the first `blocks` blocks of the inode
+    Truncate {
+        inode: INodeId,
+        blocks: usize,
+    },
+    SetSize {
+        inode: INodeId,
+        size: usize,
+    },
+    SetNlinks {
+        inode: INodeId,
+        nlinks: usize,
+    },
+    /// Clear the records of an indexed dir from `offset` in the bucket block on
+    TruncateBucket {
+        block: BlockId,
+        offset: usize,
+    },
+    /// Rewrite the header of an indexed dir in `block`
+    WriteDirHeader {
+        block: BlockId,
+        header: DiskDirHeader,
+    },
+    RemoveEntry {
+        dir: INodeId,
+        entry: usize,
+    },
+    /// Move the entry of an indexed dir to the bucket its name hashes to
+    MoveEntry {
+        dir: INodeId,
+        entry: usize,
+        inode: INodeId,
+        name: String,
+    },
+    SetSelf {
+        dir: INodeId,
+    },
+    SetParent {
+        dir: INodeId,
+        parent: INodeId,
+    },
+    /// Link the orphan in lost+found
+    Adopt {
+        inode: INodeId,
+    },
+    RebuildFreeMap,
+    SetInlineFiles {
+        files: usize,
+    },
+    FormatJournal,
+}
+
+/// An entry read from a dir: (entry id, inode id, name)
+type RawEntry = (usize, INodeId, Vec<u8>);
+
+struct Checker {
+    device: Arc<dyn Device>,
+    super_block: SuperBlock,
+    /// number of blocks on the device, image files may end before the last block
+    device_blocks: usize,
+    free_map: BitVec<Lsb0, u8>,
+    /// inode using each block
+    owner: BTreeMap<BlockId, INodeId>,
+    /// (nlinks, expected nlinks) of the inodes found
+    links: BTreeMap<INodeId, (usize, usize)>,
+    /// data blocks of the dirs found
+    dirs: BTreeMap<INodeId, Vec<BlockId>>,
+    /// number of inodes found with inline data
+    inline_files: usize,
+    problems: Vec<Problem>,
+    fixes: Vec<Fix>,
+}
+
+/// Why the block tree of an inode can not be walked
+enum TreeError {
+    /// The tree is invalid from this file block on
+    Invalid(usize),
+    Device(vfs::FsError),
+}
+
+impl From<vfs::FsError> for TreeError {
+    fn from(e: vfs::FsError) -> Self {
+        TreeError::Device(e)
+    }
+}
+
+impl Checker {
+    fn new(device: Arc<dyn Device>) -> vfs::Result<Self> {
+        let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        super_block.sanitize();
+        Ok(Checker {
+            device,
+            device_blocks: 0,
+            super_block,
+            free_map: BitVec::new(),
+            owner: BTreeMap::new(),
+            links: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+            inline_files: 0,
+            problems: Vec::new(),
+            fixes: Vec::new(),
+        })
+    }
+    /// Check the image, replay the journal first if `repair`
+    fn run(device: Arc<dyn Device>, repair: bool) -> vfs::Result<Self> {
+        let mut checker = Checker::new(device.clone())?;
+        let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        super_block.sanitize();
+        if let Err(reason) = checker.check_super_block(&super_block) {
+            checker.problems.push(Problem::BadSuperBlock(reason));
+            return Ok(checker);
+        }
+        if super_block.has_journal() {
+            let start = super_block.journal_start as usize;
+            let header = device.load_struct::<JournalHeader>(start)?;
+            if header.magic != JOURNAL_MAGIC {
+                checker.problems.push(Problem::BadJournal);
+                checker.fixes.push(Fix::FormatJournal);
+            } else if header.blocks > 0 && repair {
+                Journal::open(&device, start, super_block.journal_blocks as usize)?;
+                super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+                super_block.sanitize();
+            } else if header.blocks > 0 {
+                checker.problems.push(Problem::JournalNotReplayed);
+            }
+        }
+        checker.super_block = super_block;
+        let freemap_blocks = checker.super_block.freemap_blocks as usize;
+        let mut free_map = vec![0u8; BLKSIZE * freemap_blocks];
+        for i in 0..freemap_blocks {
+            device.read_block(
+                BLKN_FREEMAP + i,
+                0,
+                &mut free_map[i * BLKSIZE..(i + 1) * BLKSIZE],
+            )?;
+        }
+        checker.free_map = BitVec::from_vec(free_map);
+
+        match checker.load_inode(BLKN_ROOT) {
+            Some(root) if root.type_ == FileType::Dir => {
+                checker.check_inode(BLKN_ROOT, &root)?;
+                checker.links.insert(BLKN_ROOT, (root.nlinks as usize, 2));
+                checker.walk(BLKN_ROOT, Some(BLKN_ROOT))?;
+            }
+            _ => {
+                checker.problems.push(Problem::BadRoot);
+                return Ok(checker);
+            }
+        }
+        checker.find_orphans()?;
+        for (&inode, &(nlinks, expected)) in checker.links.iter() {
+            if nlinks != expected {
+                checker.problems.push(Problem::WrongNlinks {
+                    inode,
+                    nlinks,
+                    expected,
+                });
+                checker.fixes.push(Fix::SetNlinks {
+                    inode,
+                    nlinks: expected,
+                });
+            }
+        }
+        checker.check_free_map();
+        let (files, expected) = (
+            checker.super_block.inline_files as usize,
+            checker.inline_files,
+        );
+        if files != expected {
+            checker
+                .problems
+                .push(Problem::WrongInlineFiles { files, expected });
+            checker.fixes.push(Fix::SetInlineFiles { files: expected });
+        }
+        Ok(checker)
+    }
+    fn check_super_block(&mut self, super_block: &SuperBlock) -> Result<(), &'static str> {
+        if super_block.magic != MAGIC && super_block.magic != MAGIC_VERSIONED {
+            return Err("wrong magic");
+        }
+        if !super_block.check() {
+            return Err("unsupported version");
+        }
+        let blocks = super_block.blocks as usize;
+        let freemap_blocks = super_block.freemap_blocks as usize;
+        if blocks < BLKN_FREEMAP + freemap_blocks + 1 || freemap_blocks * BLKBITS < blocks {
+            return Err("wrong number of freemap blocks");
+        }
+        self.device_blocks = device_blocks(&self.device, blocks);
+        if self.device_blocks < BLKN_FREEMAP + freemap_blocks {
+            return Err("freemap beyond the end of the device");
+        }
+        if super_block.has_journal() {
+            let start = super_block.journal_start as usize;
+            let end = start + super_block.journal_blocks as usize;
+            if start < BLKN_FREEMAP + freemap_blocks
+                || end > blocks
+                || end - start < MIN_JOURNAL_BLOCKS
+            {
+                return Err("journal out of range");
+            }
+            if start >= self.device_blocks {
+                return Err("journal beyond the end of the device");
+            }
+        }
+        Ok(())
+    }
+    /// Whether the block is always in use: the superblock, root, freemap or journal
+    fn is_reserved(&self, block: BlockId) -> bool {
+        let journal_start = self.super_block.journal_start as usize;
+        let journal = journal_start..journal_start + self.super_block.journal_blocks as usize;
+        block < BLKN_FREEMAP + self.super_block.freemap_blocks as usize || journal.contains(&block)
+    }
+    /// Whether the block may be used by inodes
+    fn is_data_block(&self, block: BlockId) -> bool {
+        !self.is_reserved(block) && block < self.device_blocks
+    }
+    /// Load the inode if the block may be one
+    fn load_inode(&self, id: INodeId) -> Option<DiskINode> {
+        if id != BLKN_ROOT && !self.is_data_block(id) {
+            return None;
+        }
+        // check the type first, since loading an invalid enum value is UB
+        let mut type_ = [0u8; 2];
+        self.device.read_block(id, 4, &mut type_).ok()?;
+        let type_ = u16::from_ne_bytes(type_);
+        if type_ == FileType::Invalid as u16 || type_ > self.super_block.max_file_type() as u16 {
+            return None;
+        }
+        let mut disk_inode = self.device.load_struct::<DiskINode>(id).ok()?;
+        disk_inode.sanitize(&self.super_block);
+        Some(disk_inode)
+    }
+    fn read_index(&self, block: u32) -> vfs::Result<IndirectBlock> {
+        let mut index = IndirectBlock {
+            entries: [0; BLK_NENTRY],
+        };
+        // entries are written one by one, an image file may end in the middle
+        self.device
+            .read_at(block as usize * BLKSIZE, index.as_buf_mut())?;
+        Ok(index)
+    }
+    /// The data blocks and index blocks of the first `blocks` blocks of the inode,
+    /// or the file block at which the tree is invalid
+    fn block_tree(
+        &self,
+        disk_inode: &DiskINode,
+        blocks: usize,
+    ) -> Result<(Vec<BlockId>, Vec<BlockId>), TreeError> {
+        // the number on disk may be anything, bound it before allocating
+        let max = (self.super_block.max_file_size() as usize / BLKSIZE).min(self.device_blocks);
+        if blocks > max {
+            return Err(TreeError::Invalid(max));
+        }
+        let mut data = Vec::with_capacity(blocks);
+        let mut index = Vec::new();
+        let valid = |block: u32| self.is_data_block(block as usize);
+        if disk_inode.has_extents() {
+            for i in 0..NEXTENT {
+                let (start, len) = disk_inode.extent(i);
+                for block in start as usize..start as usize + len as usize {
+                    if data.len() == blocks {
+                        break;
+                    }
+                    if !self.is_data_block(block) {
+                        return Err(TreeError::Invalid(data.len()));
+                    }
+                    data.push(block);
+                }
+            }
+            return match data.len() {
+                len if len < blocks => Err(TreeError::Invalid(len)),
+                _ => Ok((data, index)),
+            };
+        }
+        let push = |data: &mut Vec<BlockId>, block: u32| match valid(block) {
+            true => {
+                data.push(block as BlockId);
+                Ok(())
+            }
+            false => Err(TreeError::Invalid(data.len())),
+        };
+        for &block in disk_inode.direct[..blocks.min(NDIRECT)].iter() {
+            push(&mut data, block)?;
+        }
+        if blocks >= MAX_NBLOCK_DIRECT {
+            if !valid(disk_inode.indirect) {
+                return Err(TreeError::Invalid(MAX_NBLOCK_DIRECT));
+            }
+            index.push(disk_inode.indirect as BlockId);
+            let entries = self.read_index(disk_inode.indirect)?.entries;
+            for &block in entries[..blocks.min(MAX_NBLOCK_INDIRECT) - NDIRECT].iter() {
+                push(&mut data, block)?;
+            }
+        }
+        if blocks >= MAX_NBLOCK_INDIRECT {
+            if !valid(disk_inode.db_indirect) {
+                return Err(TreeError::Invalid(MAX_NBLOCK_INDIRECT));
+            }
+            index.push(disk_inode.db_indirect as BlockId);
+            let db_entries = self.read_index(disk_inode.db_indirect)?.entries;
+            let indirects = ((blocks - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1).min(BLK_NENTRY);
+            for (i, &indirect) in db_entries[..indirects].iter().enumerate() {
+                let begin = MAX_NBLOCK_INDIRECT + i * BLK_NENTRY;
+                if !valid(indirect) {
+                    return Err(TreeError::Invalid(begin));
+                }
+                index.push(indirect as BlockId);
+                let entries = self.read_index(indirect)?.entries;
+                let end = blocks.min(begin + BLK_NENTRY).max(begin);
+                for &block in entries[..end - begin].iter() {
+                    push(&mut data, block)?;
+                }
+            }
+        }
+        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT {
+            if !valid(disk_inode.tr_indirect) {
+                return Err(TreeError::Invalid(MAX_NBLOCK_DOUBLE_INDIRECT));
+            }
+            index.push(disk_inode.tr_indirect as BlockId);
+            let tr_entries = self.read_index(disk_inode.tr_indirect)?.entries;
+            let rest = blocks - MAX_NBLOCK_DOUBLE_INDIRECT;
+            let db_indirects = rest.div_ceil(BLK_NENTRY * BLK_NENTRY);
+            for (i, &db_indirect) in tr_entries[..db_indirects].iter().enumerate() {
+                let db_begin = MAX_NBLOCK_DOUBLE_INDIRECT + i * BLK_NENTRY * BLK_NENTRY;
+                if !valid(db_indirect) {
+                    return Err(TreeError::Invalid(db_begin));
+                }
+                index.push(db_indirect as BlockId);
+                let db_entries = self.read_index(db_indirect)?.entries;
+                let indirects = (blocks - db_begin).div_ceil(BLK_NENTRY).min(BLK_NENTRY);
+                for (j, &indirect) in db_entries[..indirects].iter().enumerate() {
+                    let begin = db_begin + j * BLK_NENTRY;
+                    if !valid(indirect) {
+                        return Err(TreeError::Invalid(begin));
+                    }
+                    index.push(indirect as BlockId);
+                    let entries = self.read_index(indirect)?.entries;
+                    for &block in entries[..blocks.min(begin + BLK_NENTRY) - begin].iter() {
+                        push(&mut data, block)?;
+                    }
+                }
+            }
+        }
+        Ok((data, index))
+    }
+    /// Check the blocks and size of the inode and claim its blocks
+    fn check_inode(&mut self, id: INodeId, disk_inode: &DiskINode) -> vfs::Result<()> {
+        if disk_inode.has_inline_data() {
+            self.check_inline(id, disk_inode);
+            return Ok(());
+        }
+        let mut blocks = disk_inode.blocks as usize;
+        let (data, index) = loop {
+            match self.block_tree(disk_inode, blocks) {
+                Ok(tree) => break tree,
+                Err(TreeError::Invalid(block)) => {
+                    if blocks == disk_inode.blocks as usize {
+                        self.problems.push(Problem::BadBlock { inode: id, block });
+                    }
+                    blocks = block.min(blocks - 1);
+                }
+                Err(TreeError::Device(e)) => return Err(e),
+            }
+        };
+        if blocks != disk_inode.blocks as usize {
+            self.fixes.push(Fix::Truncate { inode: id, blocks });
+        }
+        self.claim(id, id);
+        for &block in data.iter().chain(index.iter()) {
+            self.claim(block, id);
+        }
+        let size = disk_inode.size();
+        let linear_dir = disk_inode.type_ == FileType::Dir && !disk_inode.has_dir_index();
+        if size.div_ceil(BLKSIZE) != blocks
+            || linear_dir && size / DIRENT_SIZE * DIRENT_SIZE != size
+        {
+            self.problems.push(Problem::BadSize {
+                inode: id,
+                size,
+                blocks,
+            });
+            let size = match linear_dir {
+                true => size.min(blocks * BLKSIZE) / DIRENT_SIZE * DIRENT_SIZE,
+                false => blocks * BLKSIZE,
+            };
+            let new_blocks = size.div_ceil(BLKSIZE);
+            if new_blocks < blocks {
+                self.fixes.push(Fix::Truncate {
+                    inode: id,
+                    blocks: new_blocks,
+                });
+            }
+            self.fixes.push(Fix::SetSize { inode: id, size });
+        }
+        if disk_inode.type_ == FileType::Dir {
+            self.dirs.insert(id, data);
+        }
+        Ok(())
+    }
+    /// Check the size of the inode with inline data, which has no blocks
+    fn check_inline(&mut self, id: INodeId, disk_inode: &DiskINode) {
+        self.claim(id, id);
+        let (size, blocks) = (disk_inode.size(), disk_inode.blocks as usize);
+        if blocks > 0 || size > MAX_INLINE_DATA {
+            self.problems.push(Problem::BadSize {
+                inode: id,
+                size,
+                blocks,
+            });
+            if blocks > 0 {
+                self.fixes.push(Fix::Truncate {
+                    inode: id,
+                    blocks: 0,
+                });
+            }
+            self.fixes.push(Fix::SetSize {
+                inode: id,
+                size: size.min(MAX_INLINE_DATA),
+            });
+        }
+        if size > 0 {
+            self.inline_files += 1;
+        }
+    }
+    fn claim(&mut self, block: BlockId, inode: INodeId) {
+        if let Some(other) = self.owner.insert(block, inode) {
+            self.problems.push(Problem::SharedBlock {
+                block,
+                inodes: (other, inode),
+            });
+        }
+    }
+    /// Read the entries of the dir other than '.' and '..', check its structure.
+    /// Check '..' unless `parent` is None.
+    fn dir_entries(&mut self, dir: INodeId, parent: Option<INodeId>) -> vfs::Result<Vec<RawEntry>> {
+        let disk_inode = self.load_inode(dir).unwrap();
+        let data = self.dirs[&dir].clone();
+        let mut content = vec![0u8; data.len() * BLKSIZE];
+        for (i, &block) in data.iter().enumerate() {
+            // the last block of the device may be partial
+            let buf = &mut content[i * BLKSIZE..(i + 1) * BLKSIZE];
+            self.device.read_at(block * BLKSIZE, buf)?;
+        }
+        if disk_inode.has_dir_index() {
+            return Ok(self.indexed_entries(dir, parent, &data, &content));
+        }
+        let count = disk_inode.size().min(content.len()) / DIRENT_SIZE;
+        let entry = |i: usize| {
+            let raw = &content[i * DIRENT_SIZE..(i + 1) * DIRENT_SIZE];
+            let id = u32::from_ne_bytes([raw[0], raw[1], raw[2], raw[3]]) as INodeId;
+            let name = &raw[ENTRY_SIZE..];
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            (i, id, name[..len].to_vec())
+        };
+        if count < 2 {
+            self.problems.push(Problem::BadDir {
+                dir,
+                reason: "no '.' and '..'",
+            });
+            return Ok(Vec::new());
+        }
+        let (_, id, name) = entry(0);
+        if name != b"." || id != dir {
+            self.problems.push(Problem::BadEntry {
+                dir,
+                name: String::from_utf8_lossy(&name).into(),
+                reason: "not '.'",
+            });
+            self.fixes.push(Fix::SetSelf { dir });
+        }
+        let (_, id, name) = entry(1);
+        if name != b".." {
+            self.problems.push(Problem::BadEntry {
+                dir,
+                name: String::from_utf8_lossy(&name).into(),
+                reason: "not '..'",
+            });
+        }
+        self.check_parent(dir, id, parent);
+        Ok((2..count).map(entry).collect())
+    }
+    fn indexed_entries(
+        &mut self,
+        dir: INodeId,
+        parent: Option<INodeId>,
+        data: &[BlockId],
+        content: &[u8],
+    ) -> Vec<RawEntry> {
+        let header = DiskDirHeader {
+            count: u32::from_ne_bytes([content[0], content[1], content[2], content[3]]),
+            buckets: u32::from_ne_bytes([content[4], content[5], content[6], content[7]]),
+            parent: u32::from_ne_bytes([content[8], content[9], content[10], content[11]]),
+        };
+        let buckets = header.buckets as usize;
+        if !(buckets == 0 || buckets.is_power_of_two() && buckets <= MAX_DIR_BUCKETS)
+            || buckets + 1 != data.len()
+        {
+            self.problems.push(Problem::BadDir {
+                dir,
+                reason: "invalid header",
+            });
+            // make it empty, the entries become orphans
+            self.fixes.push(Fix::Truncate {
+                inode: dir,
+                blocks: 1,
+            });
+            self.fixes.push(Fix::SetSize {
+                inode: dir,
+                size: BLKSIZE,
+            });
+            self.fixes.push(Fix::WriteDirHeader {
+                block: data[0],
+                header: DiskDirHeader {
+                    count: 2,
+                    buckets: 0,
+                    parent: parent.unwrap_or(header.parent as INodeId) as u32,
+                },
+            });
+            return Vec::new();
+        }
+        self.check_parent(dir, header.parent as INodeId, parent);
+        let mut entries = Vec::new();
+        for bucket in 0..buckets {
+            let block = &content[(bucket + 1) * BLKSIZE..(bucket + 2) * BLKSIZE];
+            let mut offset = 0;
+            while offset + DiskDirRecord::size_for(0) <= BLKSIZE {
+                let id = u32::from_ne_bytes([
+                    block[offset],
+                    block[offset + 1],
+                    block[offset + 2],
+                    block[offset + 3],
+                ]);
+                if id == 0 {
+                    break;
+                }
+                let rec_len = u16::from_ne_bytes([block[offset + 4], block[offset + 5]]) as usize;
+                let name_len = block[offset + 6] as usize;
+                if rec_len != DiskDirRecord::size_for(name_len) || offset + rec_len > BLKSIZE {
+                    self.problems.push(Problem::BadDir {
+                        dir,
+                        reason: "corrupted bucket",
+                    });
+                    self.fixes.push(Fix::TruncateBucket {
+                        block: data[bucket + 1],
+                        offset,
+                    });
+                    break;
+                }
+                let name_begin = offset + DiskDirRecord::size_for(0);
+                let name = block[name_begin..name_begin + name_len].to_vec();
+                let entry = (bucket + 1) * BLKSIZE + offset;
+                let hashed = str::from_utf8(&name)
+                    .map(|name| dir_hash(name) as usize & (buckets - 1) == bucket)
+                    .unwrap_or(true);
+                if !hashed {
+                    self.problems.push(Problem::BadEntry {
+                        dir,
+                        name: String::from_utf8_lossy(&name).into(),
+                        reason: "in a wrong bucket",
+                    });
+                    self.fixes.push(Fix::MoveEntry {
+                        dir,
+                        entry,
+                        inode: id as INodeId,
+                        name: String::from_utf8_lossy(&name).into(),
+                    });
+                }
+                entries.push((entry, id as INodeId, name));
+                offset += rec_len;
+            }
+        }
+        if header.count as usize != entries.len() + 2 {
+            self.problems.push(Problem::BadDir {
+                dir,
+                reason: "wrong number of entries",
+            });
+            self.fixes.push(Fix::WriteDirHeader {
+                block: data[0],
+                header: DiskDirHeader {
+                    count: entries.len() as u32 + 2,
+                    ..header
+                },
+            });
+        }
+        entries
+    }
+    fn check_parent(&mut self, dir: INodeId, parent: INodeId, expected: Option<INodeId>) {
+        match expected {
+            Some(expected) if parent != expected => {
+                self.problems.push(Problem::BadParent {
+                    dir,
+                    parent,
+                    expected,
+                });
+                self.fixes.push(Fix::SetParent {
+                    dir,
+                    parent: expected,
+                });
+            }
+            _ => {}
+        }
+    }
+    /// Walk the tree from the dir, check entries and inodes found
+    fn walk(&mut self, dir: INodeId, parent: Option<INodeId>) -> vfs::Result<()> {
+        let mut dirs = vec![(dir, parent)];
+        while let Some((dir, parent)) = dirs.pop() {
+            let mut names = BTreeSet::new();
+            for (entry, id, name) in self.dir_entries(dir, parent)? {
+                let reason = match str::from_utf8(&name) {
+                    Ok("") | Ok(".") | Ok("..") | Err(_) => Some("invalid name"),
+                    Ok(name) if name.len() > MAX_FNAME_LEN || name.contains('/') => {
+                        Some("invalid name")
+                    }
+                    Ok(_) if !names.insert(name.clone()) => Some("duplicated name"),
+                    Ok(_) => self.check_link(dir, id, &mut dirs)?,
+                };
+                if let Some(reason) = reason {
+                    self.problems.push(Problem::BadEntry {
+                        dir,
+                        name: String::from_utf8_lossy(&name).into(),
+                        reason,
+                    });
+                    self.fixes.push(Fix::RemoveEntry { dir, entry });
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Count a link from the dir to the inode, check the inode if it is new
+    fn check_link(
+        &mut self,
+        dir: INodeId,
+        id: INodeId,
+        dirs: &mut Vec<(INodeId, Option<INodeId>)>,
+    ) -> vfs::Result<Option<&'static str>> {
+        if let Some(links) = self.links.get_mut(&id) {
+            if self.dirs.contains_key(&id) {
+                return Ok(Some("dir linked twice"));
+            }
+            links.1 += 1;
+            return Ok(None);
+        }
+        if self.owner.contains_key(&id) {
+            return Ok(Some("invalid inode"));
+        }
+        let disk_inode = match self.load_inode(id) {
+            Some(disk_inode) => disk_inode,
+            None => return Ok(Some("invalid inode")),
+        };
+        self.check_inode(id, &disk_inode)?;
+        let nlinks = disk_inode.nlinks as usize;
+        if disk_inode.type_ == FileType::Dir {
+            self.links.insert(id, (nlinks, 2));
+            self.links.get_mut(&dir).unwrap().1 += 1;
+            dirs.push((id, Some(dir)));
+        } else {
+            self.links.insert(id, (nlinks, 1));
+        }
+        Ok(None)
+    }
+    /// Find inodes which are in use but not reachable, walk the trees from them
+    fn find_orphans(&mut self) -> vfs::Result<()> {
+        // inodes in blocks marked used, which are not used by others
+        let mut candidates: Vec<INodeId> = Vec::new();
+        for block in 0..self.super_block.blocks as usize {
+            if !self.is_data_block(block) || self.free_map[block] || self.owner.contains_key(&block)
+            {
+                continue;
+            }
+            let disk_inode = match self.load_inode(block) {
+                Some(disk_inode) => disk_inode,
+                None => continue,
+            };
+            match self.block_tree(&disk_inode, disk_inode.blocks as usize) {
+                Ok((data, index)) => {
+                    if data
+                        .iter()
+                        .chain(index.iter())
+                        .all(|block| !self.owner.contains_key(block))
+                    {
+                        candidates.push(block);
+                    }
+                }
+                Err(TreeError::Invalid(_)) => {}
+                Err(TreeError::Device(e)) => return Err(e),
+            }
+        }
+        // those in dirs of others are not the roots of trees
+        let mut children = BTreeSet::new();
+        for &id in candidates.iter() {
+            let disk_inode = self.load_inode(id).unwrap();
+            if disk_inode.type_ != FileType::Dir {
+                continue;
+            }
+            // a scratch checker, not to claim the blocks
+            let mut checker = Checker::new(self.device.clone())?;
+            checker.device_blocks = self.device_blocks;
+            checker.check_inode(id, &disk_inode)?;
+            for (_, child, _) in checker.dir_entries(id, None)? {
+                if child != id {
+                    children.insert(child);
+                }
+            }
+        }
+        let roots = candidates.iter().filter(|id| !children.contains(id));
+        // then those in loops of dirs
+        for &id in roots.chain(candidates.iter()) {
+            if self.owner.contains_key(&id) {
+                continue;
+            }
+            let disk_inode = self.load_inode(id).unwrap();
+            self.problems.push(Problem::Orphan(id));
+            self.fixes.push(Fix::Adopt { inode: id });
+            self.check_inode(id, &disk_inode)?;
+            let nlinks = disk_inode.nlinks as usize;
+            if disk_inode.type_ == FileType::Dir {
+                self.links.insert(id, (nlinks, 2));
+                // '..' is set when adopted
+                self.walk(id, None)?;
+            } else {
+                self.links.insert(id, (nlinks, 1));
+            }
+        }
+        Ok(())
+    }
+    /// Compare the freemap with the blocks in use
+    fn check_free_map(&mut self) {
+        let blocks = self.super_block.blocks as usize;
+        let used = |block: BlockId| self.is_reserved(block) || self.owner.contains_key(&block);
+        let mut problems = Vec::new();
+        let mut begin = 0;
+        while begin < blocks {
+            let wrong = |block: BlockId| used(block) == self.free_map[block];
+            if !wrong(begin) {
+                begin += 1;
+                continue;
+            }
+            let free = self.free_map[begin];
+            let end = (begin..blocks)
+                .find(|&block| !wrong(block) || self.free_map[block] != free)
+                .unwrap_or(blocks);
+            problems.push(match free {
+                true => Problem::MarkedFree(begin..end),
+                false => Problem::Leaked(begin..end),
+            });
+            begin = end;
+        }
+        let expected = (0..blocks).filter(|&block| !used(block)).count();
+        let unused = self.super_block.unused_blocks as usize;
+        if unused != expected {
+            problems.push(Problem::WrongUnusedBlocks { unused, expected });
+        }
+        if !problems.is_empty() {
+            self.problems.extend(problems);
+            self.fixes.push(Fix::RebuildFreeMap);
+        }
+    }
+    /// Apply the fixes
+    fn repair(mut self) -> vfs::Result<()> {
+        let fixes = core::mem::take(&mut self.fixes);
+        for fix in fixes.iter() {
+            match *fix {
+                Fix::Truncate { inode, blocks } => {
+                    self.update_inode(inode, |disk_inode| truncate(disk_inode, blocks))?
+                }
+                Fix::SetSize { inode, size } => {
+                    self.update_inode(inode, |disk_inode| disk_inode.set_size(size))?
+                }
+                Fix::SetNlinks { inode, nlinks } => {
+                    self.update_inode(inode, |disk_inode| disk_inode.nlinks = nlinks as u16)?
+                }
+                Fix::TruncateBucket { block, offset } => {
+                    self.device
+                        .write_block(block, offset, &[0u8; BLKSIZE][offset..])?
+                }
+                Fix::WriteDirHeader { block, ref header } => {
+                    self.device.write_block(block, 0, header.as_buf())?
+                }
+                Fix::RebuildFreeMap => self.rebuild_free_map()?,
+                Fix::SetInlineFiles { files } => {
+                    self.super_block.inline_files = files as u32;
+                    self.device
+                        .write_at(BLKSIZE * BLKN_SUPER, self.super_block.as_buf())?;
+                }
+                Fix::FormatJournal => {
+                    Journal::format(
+                        &self.device,
+                        self.super_block.journal_start as usize,
+                        self.super_block.journal_blocks as usize,
+                    )?;
+                }
+                _ => {}
+            }
+        }
+        self.device.sync()?;
+
+        let entry_fixes: Vec<&Fix> = fixes
+            .iter()
+            .filter(|fix| {
+                matches!(
+                    fix,
+                    Fix::RemoveEntry { .. }
+                        | Fix::MoveEntry { .. }
+                        | Fix::SetSelf { .. }
+                        | Fix::SetParent { .. }
+                        | Fix::Adopt { .. }
+                )
+            })
+            .collect();
+        if entry_fixes.is_empty() {
+            return Ok(());
+        }
+        let sfs = SimpleFileSystem::open(self.device.clone())?;
+        // removing entries moves the ones after them, remove from the last one
+        let mut removed: Vec<(INodeId, usize)> = entry_fixes
+            .iter()
+            .filter_map(|fix| match **fix {
+                Fix::RemoveEntry { dir, entry } | Fix::MoveEntry { dir, entry, .. } => {
+                    Some((dir, entry))
+                }
+                _ => None,
+            })
+            .collect();
+        removed.sort_unstable();
+        for &(dir, entry) in removed.iter().rev() {
+            sfs.get_inode(dir).remove_direntry(entry)?;
+        }
+        let mut lost_and_found = None;
+        for fix in entry_fixes {
+            match *fix {
+                Fix::MoveEntry {
+                    dir,
+                    inode,
+                    ref name,
+                    ..
+                } => sfs.get_inode(dir).append_direntry(&DiskEntry {
+                    id: inode as u32,
+                    name: Str256::from(name.as_str()),
+                })?,
+                Fix::SetSelf { dir } => sfs.get_inode(dir).write_direntry(
+                    0,
+                    &DiskEntry {
+                        id: dir as u32,
+                        name: Str256::from("."),
+                    },
+                )?,
+                Fix::SetParent { dir, parent } => sfs.get_inode(dir).set_parent(parent)?,
+                Fix::Adopt { inode } => {
+                    if lost_and_found.is_none() {
+                        let root = sfs.root_inode();
+                        let dir = match root.find(LOST_AND_FOUND) {
+                            Ok(dir) => dir,
+                            Err(_) => root.create(LOST_AND_FOUND, vfs::FileType::Dir, 0o700)?,
+                        };
+                        lost_and_found = Some(sfs.get_inode(dir.metadata()?.inode));
+                    }
+                    let dir = lost_and_found.as_ref().unwrap();
+                    dir.append_direntry(&DiskEntry {
+                        id: inode as u32,
+                        name: Str256::from(alloc::format!("#{}", inode).as_str()),
+                    })?;
+                    let orphan = sfs.get_inode(inode);
+                    if orphan.disk_inode.read().type_ == FileType::Dir {
+                        orphan.set_parent(dir.id)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        drop(lost_and_found);
+        sfs.sync()
+    }
+    fn update_inode(&self, id: INodeId, f: impl FnOnce(&mut DiskINode)) -> vfs::Result<()> {
+        let mut disk_inode = self.load_inode(id).unwrap();
+        f(&mut disk_inode);
+        self.device.write_block(id, 0, disk_inode.as_buf())
+    }
+    fn rebuild_free_map(&mut self) -> vfs::Result<()> {
+        let blocks = self.super_block.blocks as usize;
+        let mut unused = 0;
+        for block in 0..self.free_map.len() {
+            let free =
+                block < blocks && !self.is_reserved(block) && !self.owner.contains_key(&block);
+            self.free_map.set(block, free);
+            unused += free as usize;
+        }
+        let data = self.free_map.as_raw_slice();
+        for i in 0..self.super_block.freemap_blocks as usize {
+            self.device
+                .write_block(BLKN_FREEMAP + i, 0, &data[i * BLKSIZE..(i + 1) * BLKSIZE])?;
+        }
+        self.super_block.unused_blocks = unused as u32;
+        self.device
+            .write_at(BLKSIZE * BLKN_SUPER, self.super_block.as_buf())?;
+        Ok(())
+    }
+}
+
+/// Keep only the first `blocks` blocks of the inode
+fn truncate(disk_inode: &mut DiskINode, blocks: usize) {
+    disk_inode.blocks = blocks as u32;
+    disk_inode.set_size(disk_inode.size().min(blocks * BLKSIZE));
+    if disk_inode.has_extents() {
+        let mut begin = 0;
+        for i in 0..NEXTENT {
+            let (start, len) = disk_inode.extent(i);
+            let keep = blocks.saturating_sub(begin).min(len as usize);
+            match keep {
+                0 => disk_inode.set_extent(i, 0, 0),
+                _ => disk_inode.set_extent(i, start, keep as u32),
+            }
+            begin += len as usize;
+        }
+        return;
+    }
+    for block in disk_inode.direct[blocks.min(NDIRECT)..].iter_mut() {
+        *block = 0;
+    }
+    if blocks < MAX_NBLOCK_DIRECT {
+        disk_inode.indirect = 0;
+    }
+    if blocks < MAX_NBLOCK_INDIRECT {
+        disk_inode.db_indirect = 0;
+    }
+    if blocks <= MAX_NBLOCK_DOUBLE_INDIRECT {
+        disk_inode.tr_indirect = 0;
+    }
+}
+
+/// Number of blocks on the device, up to `max`
+fn device_blocks(device: &Arc<dyn Device>, max: usize) -> usize {
+    // the last block may be partial, as files are not padded
+    let readable = |blocks: usize| {
+        let mut byte = [0u8];
+        matches!(device.read_at((blocks - 1) * BLKSIZE, &mut byte), Ok(1))
+    };
+    // binary search the last readable block
+    let (mut low, mut high) = (0, max);
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        match readable(mid) {
+            true => low = mid,
+            false => high = mid - 1,
+        }
+    }
+    low
+}
@@ -0,0 +1,238 @@
+//! Write-ahead journal of metadata
+//!
+//! Metadata blocks (superblock, freemap, inodes, indirect blocks and dir contents)
+//! modified since the last commit are kept in memory as the running transaction,
+//! while file data is written to the device directly. A commit:
+//!
+//! 1. writes the descriptor blocks and the logged blocks after the journal header, flush.
+//!    File data written before is flushed with them (ordered mode).
+//! 2. writes the header with the number of logged blocks and their checksum, flush.
+//!    The transaction is committed once the header is on disk.
+//! 3. writes the logged blocks to their homes, flush.
+//! 4. writes the header with no logged blocks.
+//!
+//! On open, a header with logged blocks means a crash after 2, and the blocks are
+//! written to their homes again. A header lost in 4 replays the transaction again,
+//! which is harmless since its blocks are not reused before the next commit, whose
+//! partially overwritten log fails the checksum.
+
+use alloc::{
+    boxed::Box,
+    collections::{btree_map::Entry, BTreeMap},
+    sync::Arc,
+    vec::Vec,
+};
+
+use rcore_fs::{
+    dev::Device,
+    vfs::{self, FsError},
+};
+
+use crate::{structs::*, DeviceExt};
+
+pub struct Journal {
+    /// first block of the journal
+    start: BlockId,
+    /// number of blocks of the journal
+    blocks: usize,
+    /// sequence number of the last committed transaction
+    sequence: u32,
+    /// metadata blocks modified by the running transaction
+    pending: BTreeMap<BlockId, Box<[u8; BLKSIZE]>>,
+    /// blocks freed by the running transaction, not reused until it is committed
+    freed: Vec<BlockId>,
+}
+
+impl Journal {
+    /// Init an empty journal on the device
+    pub fn format(device: &Arc<dyn Device>, start: BlockId, blocks: usize) -> vfs::Result<Self> {
+        assert!(blocks >= MIN_JOURNAL_BLOCKS, "journal too small");
+        let journal = Journal {
+            start,
+            blocks,
+            sequence: 0,
+            pending: BTreeMap::new(),
+            freed: Vec::new(),
+        };
+        journal.write_header(device, 0, 0)?;
+        Ok(journal)
+    }
+    /// Load the journal from the device, replay the transaction committed before a crash
+    pub fn open(device: &Arc<dyn Device>, start: BlockId, blocks: usize) -> vfs::Result<Self> {
+        let header = device.load_struct::<JournalHeader>(start)?;
+        if header.magic != JOURNAL_MAGIC || blocks < MIN_JOURNAL_BLOCKS {
+            return Err(FsError::WrongFs);
+        }
+        let mut journal = Journal {
+            start,
+            blocks,
+            sequence: header.sequence,
+            pending: BTreeMap::new(),
+            freed: Vec::new(),
+        };
+        if header.blocks > 0 {
+            journal.replay(device, &header)?;
+        }
+        Ok(journal)
+    }
+    /// Max number of blocks in a transaction
+    pub fn capacity(&self) -> usize {
+        let space = self.blocks - 1;
+        space - space.div_ceil(BLK_NENTRY + 1)
+    }
+    /// Whether the running transaction should be committed before it outgrows the journal
+    pub fn is_full(&self) -> bool {
+        self.pending.len() * 2 >= self.capacity()
+    }
+    /// Read a metadata block, as modified by the running transaction
+    pub fn read(
+        &self,
+        device: &Arc<dyn Device>,
+        id: BlockId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> vfs::Result<()> {
+        match self.pending.get(&id) {
+            Some(block) => {
+                buf.copy_from_slice(&block[offset..offset + buf.len()]);
+                Ok(())
+            }
+            None => device.read_block(id, offset, buf),
+        }
+    }
+    /// Modify a metadata block in the running transaction
+    pub fn write(
+        &mut self,
+        device: &Arc<dyn Device>,
+        id: BlockId,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfs::Result<()> {
+        let block = match self.pending.entry(id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let mut block = Box::new([0u8; BLKSIZE]);
+                if buf.len() < BLKSIZE {
+                    // a new block may be beyond the end of an image file, the rest is zeros
+                    device.read_at(id * BLKSIZE, &mut block[..])?;
+                }
+                entry.insert(block)
+            }
+        };
+        block[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+    /// Free a block in the running transaction
+    pub fn free(&mut self, id: BlockId) {
+        // its content no longer matters
+        self.pending.remove(&id);
+        self.freed.push(id);
+    }
+    /// Take the blocks freed by the running transaction, to be marked free in it
+    pub fn take_freed(&mut self) -> Vec<BlockId> {
+        core::mem::take(&mut self.freed)
+    }
+    /// Commit the running transaction and write it in place
+    pub fn commit(&mut self, device: &Arc<dyn Device>) -> vfs::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(device.sync()?);
+        }
+        let ids: Vec<u32> = self.pending.keys().map(|&id| id as u32).collect();
+        if ids.len() > self.capacity() {
+            warn!(
+                "transaction of {} blocks does not fit in the journal, write it in place",
+                ids.len()
+            );
+            return self.checkpoint(device);
+        }
+        let descriptors = ids.len().div_ceil(BLK_NENTRY);
+        for (i, chunk) in ids.chunks(BLK_NENTRY).enumerate() {
+            let mut descriptor = IndirectBlock {
+                entries: [0; BLK_NENTRY],
+            };
+            descriptor.entries[..chunk.len()].copy_from_slice(chunk);
+            device.write_block(self.start + 1 + i, 0, descriptor.as_buf())?;
+        }
+        for (i, block) in self.pending.values().enumerate() {
+            device.write_block(self.start + 1 + descriptors + i, 0, &block[..])?;
+        }
+        device.sync()?;
+        self.sequence = self.sequence.wrapping_add(1);
+        let checksum = checksum(&ids, self.pending.values());
+        self.write_header(device, ids.len(), checksum)?;
+        device.sync()?;
+        self.checkpoint(device)?;
+        self.write_header(device, 0, 0)
+    }
+    /// Write the blocks of the running transaction in place
+    fn checkpoint(&mut self, device: &Arc<dyn Device>) -> vfs::Result<()> {
+        for (&id, block) in self.pending.iter() {
+            device.write_block(id, 0, &block[..])?;
+        }
+        device.sync()?;
+        self.pending.clear();
+        Ok(())
+    }
+    /// Write in place the transaction logged in the journal, if it is intact
+    fn replay(&mut self, device: &Arc<dyn Device>, header: &JournalHeader) -> vfs::Result<()> {
+        let count = header.blocks as usize;
+        if count > self.capacity() {
+            warn!("invalid journal of {} blocks, ignored", count);
+            return Ok(());
+        }
+        let descriptors = count.div_ceil(BLK_NENTRY);
+        let mut ids = Vec::with_capacity(count);
+        for i in 0..descriptors {
+            let descriptor = device.load_struct::<IndirectBlock>(self.start + 1 + i)?;
+            let len = (count - i * BLK_NENTRY).min(BLK_NENTRY);
+            ids.extend_from_slice(&descriptor.entries[..len]);
+        }
+        let mut blocks = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut block = Box::new([0u8; BLKSIZE]);
+            device.read_block(self.start + 1 + descriptors + i, 0, &mut block[..])?;
+            blocks.push(block);
+        }
+        if checksum(&ids, blocks.iter()) != header.checksum {
+            warn!(
+                "journal checksum mismatch, transaction {} ignored",
+                header.sequence
+            );
+            return Ok(());
+        }
+        info!("replay transaction {} of {} blocks", header.sequence, count);
+        self.pending = ids
+            .into_iter()
+            .map(|id| id as BlockId)
+            .zip(blocks)
+            .collect();
+        self.checkpoint(device)?;
+        self.write_header(device, 0, 0)?;
+        Ok(device.sync()?)
+    }
+    fn write_header(
+        &self,
+        device: &Arc<dyn Device>,
+        blocks: usize,
+        checksum: u32,
+    ) -> vfs::Result<()> {
+        let header = JournalHeader {
+            magic: JOURNAL_MAGIC,
+            sequence: self.sequence,
+            blocks: blocks as u32,
+            checksum,
+        };
+        device.write_block(self.start, 0, header.as_buf())
+    }
+}
+
+/// Checksum of the logged blocks and where they belong (FNV-1a)
+fn checksum<'a>(ids: &[u32], blocks: impl Iterator<Item = &'a Box<[u8; BLKSIZE]>>) -> u32 {
+    let bytes = ids
+        .iter()
+        .flat_map(|id| id.to_ne_bytes())
+        .chain(blocks.flat_map(|block| block.iter().copied()));
+    bytes.fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
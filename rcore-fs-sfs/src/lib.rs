@@ -5,7 +5,8 @@ extern crate alloc;
 extern crate log;
 
 use alloc::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
+    str,
     string::String,
     sync::{Arc, Weak},
     vec,
@@ -14,20 +15,28 @@ use alloc::{
 use core::{
     any::Any,
     fmt::{Debug, Error, Formatter},
+    mem::size_of,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
 };
 
 use bitvec::prelude::*;
-use spin::RwLock;
+use spin::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use rcore_fs::{
-    dev::Device,
+    dev::{Device, DeviceRegistry},
     dirty::Dirty,
     util::*,
     vfs::{self, FileSystem, FsError, INode, MMapArea, Metadata},
 };
 
+use self::{freemap::FreeMap, journal::Journal};
 pub use structs::*;
 
+mod freemap;
+pub mod fsck;
+mod journal;
+pub mod resize;
 mod structs;
 #[cfg(test)]
 mod tests;
@@ -65,9 +74,12 @@ pub struct INodeImpl {
     disk_inode: RwLock<Dirty<DiskINode>>,
     /// Reference to SFS, used by almost all operations
     fs: Arc<SimpleFileSystem>,
-    /// Char/block device id (major, minor)
-    /// e.g. crw-rw-rw- 1 root wheel 3, 2 May 13 16:40 /dev/null
-    device_inode_id: usize,
+    /// (index, offset) of the last entry found in the indexed dir, to list it in linear time
+    dir_cursor: Mutex<(usize, usize)>,
+    /// held exclusively while the content or the entries are changed, shared while they are read
+    content_lock: RwLock<()>,
+    /// blocks reserved by the `_resize` in progress, not allocated yet
+    reserved: AtomicUsize,
 }
 
 impl Debug for INodeImpl {
@@ -81,15 +93,42 @@ impl Debug for INodeImpl {
 }
 
 impl INodeImpl {
+    /// Whether it is a char or block device node
+    fn is_device(&self) -> bool {
+        let type_ = self.disk_inode.read().type_;
+        type_ == FileType::CharDevice || type_ == FileType::BlockDevice
+    }
+    /// Resolve the device of the device node through the registry of the FS
+    fn device(&self) -> vfs::Result<Arc<dyn INode>> {
+        let rdev = self.disk_inode.read().rdev;
+        let registry = self.fs.device_registry.read();
+        registry
+            .as_ref()
+            .and_then(|registry| registry.get(rdev))
+            .ok_or(FsError::NoDevice)
+    }
     /// Map file block id to disk block id
     fn get_disk_block_id(&self, file_block_id: BlockId) -> vfs::Result<BlockId> {
-        let disk_inode = self.disk_inode.read();
+        self.map_block(&self.disk_inode.read(), file_block_id)
+    }
+    /// Map file block id to disk block id, with the disk inode already locked
+    fn map_block(&self, disk_inode: &DiskINode, file_block_id: BlockId) -> vfs::Result<BlockId> {
+        if disk_inode.has_extents() && file_block_id < disk_inode.blocks as BlockId {
+            let mut begin = 0;
+            for i in 0..NEXTENT {
+                let (start, len) = disk_inode.extent(i);
+                if file_block_id < begin + len as BlockId {
+                    return Ok(start as BlockId + file_block_id - begin);
+                }
+                begin += len as BlockId;
+            }
+        }
         match file_block_id {
             id if id >= disk_inode.blocks as BlockId => Err(FsError::InvalidParam),
             id if id < MAX_NBLOCK_DIRECT => Ok(disk_inode.direct[id] as BlockId),
             id if id < MAX_NBLOCK_INDIRECT => {
                 let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
+                self.fs.read_meta(
                     disk_inode.indirect as usize,
                     ENTRY_SIZE * (id - NDIRECT),
                     disk_block_id.as_buf_mut(),
@@ -100,14 +139,14 @@ impl INodeImpl {
                 // double indirect
                 let indirect_id = id - MAX_NBLOCK_INDIRECT;
                 let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
+                self.fs.read_meta(
                     disk_inode.db_indirect as usize,
                     ENTRY_SIZE * (indirect_id / BLK_NENTRY),
                     indirect_block_id.as_buf_mut(),
                 )?;
                 assert!(indirect_block_id > 0);
                 let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
+                self.fs.read_meta(
                     indirect_block_id as usize,
                     ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
                     disk_block_id.as_buf_mut(),
@@ -115,7 +154,15 @@ impl INodeImpl {
                 assert!(disk_block_id > 0);
                 Ok(disk_block_id as BlockId)
             }
-            _ => unimplemented!("triple indirect blocks is not supported"),
+            id if id < MAX_NBLOCK_TRIPLE_INDIRECT => {
+                // triple indirect
+                let (indirect_block_id, entry_id) =
+                    self.locate_triple_indirect(disk_inode.tr_indirect, id)?;
+                let disk_block_id = self.read_entry(indirect_block_id, entry_id)?;
+                assert!(disk_block_id > 0);
+                Ok(disk_block_id as BlockId)
+            }
+            _ => Err(FsError::InvalidParam),
         }
     }
     fn set_disk_block_id(&self, file_block_id: BlockId, disk_block_id: BlockId) -> vfs::Result<()> {
@@ -127,7 +174,7 @@ impl INodeImpl {
             }
             id if id < MAX_NBLOCK_INDIRECT => {
                 let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
+                self.fs.write_meta(
                     self.disk_inode.read().indirect as usize,
                     ENTRY_SIZE * (id - NDIRECT),
                     disk_block_id.as_buf(),
@@ -138,26 +185,111 @@ impl INodeImpl {
                 // double indirect
                 let indirect_id = id - MAX_NBLOCK_INDIRECT;
                 let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
+                self.fs.read_meta(
                     self.disk_inode.read().db_indirect as usize,
                     ENTRY_SIZE * (indirect_id / BLK_NENTRY),
                     indirect_block_id.as_buf_mut(),
                 )?;
                 assert!(indirect_block_id > 0);
                 let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
+                self.fs.write_meta(
                     indirect_block_id as usize,
                     ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
                     disk_block_id.as_buf(),
                 )?;
                 Ok(())
             }
-            _ => unimplemented!("triple indirect blocks is not supported"),
+            id if id < MAX_NBLOCK_TRIPLE_INDIRECT => {
+                // triple indirect
+                let tr_indirect = self.disk_inode.read().tr_indirect;
+                let (indirect_block_id, entry_id) = self.locate_triple_indirect(tr_indirect, id)?;
+                self.write_entry(indirect_block_id, entry_id, disk_block_id as u32)
+            }
+            _ => Err(FsError::InvalidParam),
+        }
+    }
+    /// Read an entry of an indirect block
+    fn read_entry(&self, block_id: u32, entry_id: usize) -> vfs::Result<u32> {
+        let mut entry: u32 = 0;
+        self.fs
+            .read_meta(block_id as usize, ENTRY_SIZE * entry_id, entry.as_buf_mut())?;
+        Ok(entry)
+    }
+    /// Write an entry of an indirect block
+    fn write_entry(&self, block_id: u32, entry_id: usize, entry: u32) -> vfs::Result<()> {
+        self.fs
+            .write_meta(block_id as usize, ENTRY_SIZE * entry_id, entry.as_buf())
+    }
+    /// Find the indirect block and the entry in it which maps a triple indirect file block
+    fn locate_triple_indirect(
+        &self,
+        tr_indirect: u32,
+        file_block_id: BlockId,
+    ) -> vfs::Result<(u32, usize)> {
+        let (db_entry, entry, leaf_entry) = Self::triple_indirect_entries(file_block_id);
+        assert!(tr_indirect > 0);
+        let db_indirect = self.read_entry(tr_indirect, db_entry)?;
+        assert!(db_indirect > 0);
+        let indirect = self.read_entry(db_indirect, entry)?;
+        assert!(indirect > 0);
+        Ok((indirect, leaf_entry))
+    }
+    /// Split a triple indirect file block id into entry ids of the three levels
+    fn triple_indirect_entries(file_block_id: BlockId) -> (usize, usize, usize) {
+        let id = file_block_id - MAX_NBLOCK_DOUBLE_INDIRECT;
+        (
+            id / (BLK_NENTRY * BLK_NENTRY),
+            id / BLK_NENTRY % BLK_NENTRY,
+            id % BLK_NENTRY,
+        )
+    }
+    /// Allocate the indirect blocks needed to map a triple indirect file block.
+    /// Blocks must be added in increasing order.
+    fn alloc_triple_indirect(&self, file_block_id: BlockId) -> vfs::Result<()> {
+        let (db_entry, entry, leaf_entry) = Self::triple_indirect_entries(file_block_id);
+        if leaf_entry != 0 {
+            return Ok(());
+        }
+        if (db_entry, entry) == (0, 0) {
+            self.disk_inode.write().tr_indirect = self.alloc_indirect()?;
+        }
+        let tr_indirect = self.disk_inode.read().tr_indirect;
+        if entry == 0 {
+            let db_indirect = self.alloc_indirect()?;
+            self.write_entry(tr_indirect, db_entry, db_indirect)?;
         }
+        let db_indirect = self.read_entry(tr_indirect, db_entry)?;
+        let indirect = self.alloc_indirect()?;
+        self.write_entry(db_indirect, entry, indirect)
+    }
+    /// Free the indirect blocks no longer needed after removing a triple indirect file block.
+    /// Blocks must be removed in decreasing order.
+    fn free_triple_indirect(&self, file_block_id: BlockId) -> vfs::Result<()> {
+        let (db_entry, entry, leaf_entry) = Self::triple_indirect_entries(file_block_id);
+        if leaf_entry != 0 {
+            return Ok(());
+        }
+        let tr_indirect = self.disk_inode.read().tr_indirect;
+        let db_indirect = self.read_entry(tr_indirect, db_entry)?;
+        let indirect = self.read_entry(db_indirect, entry)?;
+        self.fs.free_block(indirect as usize);
+        if entry == 0 {
+            self.fs.free_block(db_indirect as usize);
+        }
+        if (db_entry, entry) == (0, 0) {
+            self.fs.free_block(tr_indirect as usize);
+            self.disk_inode.write().tr_indirect = 0;
+        }
+        Ok(())
     }
     /// Only for Dir
+    ///
+    /// The entry id is the index of the entry, or the offset of its record in indexed dirs.
     fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
-        (0..self.disk_inode.read().size as usize / DIRENT_SIZE)
+        if self.disk_inode.read().has_dir_index() {
+            return self.lookup_indexed(name).unwrap();
+        }
+        (0..self.disk_inode.read().size() / DIRENT_SIZE)
             .map(|i| (self.read_direntry(i as usize).unwrap(), i))
             .find(|(entry, _)| entry.name.as_ref() == name)
             .map(|(entry, id)| (entry.id as INodeId, id as usize))
@@ -166,6 +298,13 @@ impl INodeImpl {
         self.get_file_inode_and_entry_id(name)
             .map(|(inode_id, _)| inode_id)
     }
+    /// Number of entries in the dir, including '.' and '..'
+    fn dir_count(&self) -> vfs::Result<usize> {
+        if self.disk_inode.read().has_dir_index() {
+            return Ok(self.read_dir_header()?.count as usize);
+        }
+        Ok(self.disk_inode.read().size() / DIRENT_SIZE)
+    }
     /// Init dir content. Insert 2 init entries.
     /// This do not init nlinks, please modify the nlinks in the invoker.
     fn init_direntry(&self, parent: INodeId) -> vfs::Result<()> {
@@ -187,6 +326,26 @@ impl INodeImpl {
         )?;
         Ok(())
     }
+    /// Get the `id`th entry of the dir, no matter how it is stored
+    fn get_direntry(&self, id: usize) -> vfs::Result<DiskEntry> {
+        if id >= self.dir_count()? {
+            return Err(FsError::EntryNotFound);
+        }
+        if !self.disk_inode.read().has_dir_index() {
+            return self.read_direntry(id);
+        }
+        match id {
+            0 => Ok(DiskEntry {
+                id: self.id as u32,
+                name: Str256::from("."),
+            }),
+            1 => Ok(DiskEntry {
+                id: self.read_dir_header()?.parent,
+                name: Str256::from(".."),
+            }),
+            _ => self.read_indexed(id),
+        }
+    }
     fn read_direntry(&self, id: usize) -> vfs::Result<DiskEntry> {
         let mut direntry: DiskEntry = unsafe { uninit_memory() };
         self._read_at(DIRENT_SIZE * id, direntry.as_buf_mut())?;
@@ -197,8 +356,18 @@ impl INodeImpl {
         Ok(())
     }
     fn append_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
-        let size = self.disk_inode.read().size as usize;
+        if self.disk_inode.read().has_dir_index() {
+            return self.insert_indexed(direntry.id, direntry.name.as_ref());
+        }
+        let size = self.disk_inode.read().size();
         let dirent_count = size / DIRENT_SIZE;
+        if dirent_count >= DIR_INDEX_THRESHOLD
+            && self.fs.dir_index.load(AtomicOrdering::Relaxed)
+            && self.fs.super_block.read().has_dir_index()
+        {
+            self.index_dir()?;
+            return self.insert_indexed(direntry.id, direntry.name.as_ref());
+        }
         self._resize(size + DIRENT_SIZE)?;
         self.write_direntry(dirent_count, direntry)?;
         Ok(())
@@ -206,7 +375,10 @@ impl INodeImpl {
     /// remove a direntry in middle of file and insert the last one here, useful for direntry remove
     /// should be only used in unlink
     fn remove_direntry(&self, id: usize) -> vfs::Result<()> {
-        let size = self.disk_inode.read().size as usize;
+        if self.disk_inode.read().has_dir_index() {
+            return self.remove_indexed(id);
+        }
+        let size = self.disk_inode.read().size();
         let dirent_count = size / DIRENT_SIZE;
         debug_assert!(id < dirent_count);
         let last_dirent = self.read_direntry(dirent_count - 1)?;
@@ -214,117 +386,479 @@ impl INodeImpl {
         self._resize(size - DIRENT_SIZE)?;
         Ok(())
     }
+    /// Change the name of the direntry `id`
+    fn rename_direntry(&self, id: usize, inode_id: INodeId, name: &str) -> vfs::Result<()> {
+        if self.disk_inode.read().has_dir_index() {
+            self.remove_indexed(id)?;
+            return self.insert_indexed(inode_id as u32, name);
+        }
+        self.write_direntry(
+            id,
+            &DiskEntry {
+                id: inode_id as u32,
+                name: Str256::from(name),
+            },
+        )
+    }
+    /// Change the '..' entry
+    fn set_parent(&self, parent: INodeId) -> vfs::Result<()> {
+        if self.disk_inode.read().has_dir_index() {
+            let mut header = self.read_dir_header()?;
+            header.parent = parent as u32;
+            return self.write_dir_header(&header);
+        }
+        self.write_direntry(
+            1,
+            &DiskEntry {
+                id: parent as u32,
+                name: Str256::from(".."),
+            },
+        )
+    }
+    /// Convert the dir from an array of entries to an indexed one
+    fn index_dir(&self) -> vfs::Result<()> {
+        let entries = (0..self.dir_count()?)
+            .map(|i| self.read_direntry(i))
+            .collect::<vfs::Result<Vec<_>>>()?;
+        self._resize(0)?;
+        self.disk_inode.write().flags |= INODE_FLAG_INDEXED;
+        self._resize(BLKSIZE)?;
+        self.write_dir_header(&DiskDirHeader {
+            count: 2,
+            buckets: 0,
+            parent: entries[1].id,
+        })?;
+        for entry in entries[2..].iter() {
+            self.insert_indexed(entry.id, entry.name.as_ref())?;
+        }
+        Ok(())
+    }
+    fn read_dir_header(&self) -> vfs::Result<DiskDirHeader> {
+        let mut header: DiskDirHeader = unsafe { uninit_memory() };
+        self._read_at(0, header.as_buf_mut())?;
+        Ok(header)
+    }
+    fn write_dir_header(&self, header: &DiskDirHeader) -> vfs::Result<()> {
+        self._write_at(0, header.as_buf())?;
+        // positions of entries may have changed
+        *self.dir_cursor.lock() = (0, 0);
+        Ok(())
+    }
+    fn read_bucket(&self, bucket: usize) -> vfs::Result<[u8; BLKSIZE]> {
+        let mut block = [0u8; BLKSIZE];
+        self._read_at((bucket + 1) * BLKSIZE, &mut block)?;
+        Ok(block)
+    }
+    /// Find `name` in the bucket it hashes to
+    fn lookup_indexed(&self, name: &str) -> vfs::Result<Option<(INodeId, usize)>> {
+        let header = self.read_dir_header()?;
+        match name {
+            "." => return Ok(Some((self.id, 0))),
+            ".." => return Ok(Some((header.parent as INodeId, 1))),
+            _ if header.buckets == 0 => return Ok(None),
+            _ => {}
+        }
+        let bucket = dir_hash(name) as usize & (header.buckets as usize - 1);
+        let block = self.read_bucket(bucket)?;
+        Ok(DirRecords::new(&block, 0)
+            .find(|&(_, _, record_name)| record_name == name.as_bytes())
+            .map(|(offset, id, _)| (id as INodeId, (bucket + 1) * BLKSIZE + offset)))
+    }
+    /// Get the `id`th entry by walking the buckets, starting from the last one found
+    fn read_indexed(&self, id: usize) -> vfs::Result<DiskEntry> {
+        let buckets = self.read_dir_header()?.buckets as usize;
+        let mut cursor = self.dir_cursor.lock();
+        let (mut index, mut offset) = *cursor;
+        if index < 2 || index > id {
+            index = 2;
+            offset = BLKSIZE;
+        }
+        loop {
+            let bucket = offset / BLKSIZE - 1;
+            if bucket >= buckets {
+                return Err(FsError::EntryNotFound);
+            }
+            let block = self.read_bucket(bucket)?;
+            for (record_offset, inode_id, name) in DirRecords::new(&block, offset % BLKSIZE) {
+                if index == id {
+                    *cursor = (index, (bucket + 1) * BLKSIZE + record_offset);
+                    return Ok(DiskEntry {
+                        id: inode_id,
+                        name: Str256::from(str::from_utf8(name).unwrap()),
+                    });
+                }
+                index += 1;
+            }
+            offset = (bucket + 2) * BLKSIZE;
+        }
+    }
+    fn insert_indexed(&self, inode_id: u32, name: &str) -> vfs::Result<()> {
+        let mut header = self.read_dir_header()?;
+        let rec_len = DiskDirRecord::size_for(name.len());
+        loop {
+            if header.buckets > 0 {
+                let bucket = dir_hash(name) as usize & (header.buckets as usize - 1);
+                let mut block = self.read_bucket(bucket)?;
+                let end = DirRecords::end(&block);
+                if end + rec_len <= BLKSIZE {
+                    write_dir_record(&mut block[end..], inode_id, name);
+                    self._write_at((bucket + 1) * BLKSIZE + end, &block[end..end + rec_len])?;
+                    break;
+                }
+            }
+            self.split_buckets(&mut header)?;
+        }
+        header.count += 1;
+        self.write_dir_header(&header)
+    }
+    /// Remove the record at `offset`, move the records after it forward
+    fn remove_indexed(&self, offset: usize) -> vfs::Result<()> {
+        let mut header = self.read_dir_header()?;
+        let bucket = offset / BLKSIZE - 1;
+        let mut block = self.read_bucket(bucket)?;
+        let begin = offset % BLKSIZE;
+        let end = DirRecords::end(&block);
+        let (_, _, name) = DirRecords::new(&block, begin).next().unwrap();
+        let rec_len = DiskDirRecord::size_for(name.len());
+        block.copy_within(begin + rec_len..end, begin);
+        for byte in block[end - rec_len..end].iter_mut() {
+            *byte = 0;
+        }
+        self._write_at(offset, &block[begin..end])?;
+        header.count -= 1;
+        self.write_dir_header(&header)
+    }
+    /// Double the number of buckets until every entry fits, and rehash entries into them
+    fn split_buckets(&self, header: &mut DiskDirHeader) -> vfs::Result<()> {
+        let mut entries = Vec::new();
+        for bucket in 0..header.buckets as usize {
+            let block = self.read_bucket(bucket)?;
+            for (_, inode_id, name) in DirRecords::new(&block, 0) {
+                entries.push((inode_id, String::from(str::from_utf8(name).unwrap())));
+            }
+        }
+        let mut buckets = (header.buckets as usize * 2).max(1);
+        let blocks = loop {
+            if buckets > MAX_DIR_BUCKETS {
+                return Err(FsError::NoDeviceSpace);
+            }
+            let mut blocks = vec![[0u8; BLKSIZE]; buckets];
+            let mut ends = vec![0usize; buckets];
+            let fits = entries.iter().all(|(inode_id, name)| {
+                let bucket = dir_hash(name) as usize & (buckets - 1);
+                let end = ends[bucket];
+                if end + DiskDirRecord::size_for(name.len()) > BLKSIZE {
+                    return false;
+                }
+                ends[bucket] += write_dir_record(&mut blocks[bucket][end..], *inode_id, name);
+                true
+            });
+            if fits {
+                break blocks;
+            }
+            buckets *= 2;
+        };
+        self._resize((buckets + 1) * BLKSIZE)?;
+        for (bucket, block) in blocks.iter().enumerate() {
+            self._write_at((bucket + 1) * BLKSIZE, block)?;
+        }
+        header.buckets = buckets as u32;
+        self.write_dir_header(header)
+    }
     /// Resize content size, no matter what type it is.
     fn _resize(&self, len: usize) -> vfs::Result<()> {
-        if len > MAX_FILE_SIZE {
-            return Err(FsError::InvalidParam);
+        if len as u64 > self.fs.super_block.read().max_file_size() {
+            return Err(FsError::FileTooBig);
         }
-        let blocks = ((len + BLKSIZE - 1) / BLKSIZE) as u32;
-        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT as u32 {
-            return Err(FsError::InvalidParam);
+        if self.disk_inode.read().has_inline_data() {
+            if len <= MAX_INLINE_DATA {
+                return self.resize_inline(len);
+            }
+            self.inline_to_blocks(len)?;
         }
+        let blocks = ((len + BLKSIZE - 1) / BLKSIZE) as u32;
         use core::cmp::Ordering;
         let old_blocks = self.disk_inode.read().blocks;
         match blocks.cmp(&old_blocks) {
             Ordering::Equal => {
-                self.disk_inode.write().size = len as u32;
-            }
-            Ordering::Greater => {
                 let mut disk_inode = self.disk_inode.write();
-                disk_inode.blocks = blocks;
-                // allocate indirect block if needed
-                if old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32 {
-                    disk_inode.indirect = self.fs.alloc_block().expect("no space") as u32;
-                }
-                // allocate double indirect block if needed
-                if blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    if disk_inode.db_indirect == 0 {
-                        disk_inode.db_indirect = self.fs.alloc_block().expect("no space") as u32;
-                    }
-                    let indirect_begin = {
-                        if (old_blocks as usize) < MAX_NBLOCK_INDIRECT {
-                            0
-                        } else {
-                            (old_blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
-                        }
-                    };
-                    let indirect_end = (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
-                    for i in indirect_begin..indirect_end {
-                        let indirect = self.fs.alloc_block().expect("no space") as u32;
-                        self.fs.device.write_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf(),
-                        )?;
-                    }
-                }
+                let old_size = disk_inode.size();
+                disk_inode.set_size(len);
                 drop(disk_inode);
-                // allocate extra blocks
-                for i in old_blocks..blocks {
-                    let disk_block_id = self.fs.alloc_block().expect("no space");
-                    self.set_disk_block_id(i as usize, disk_block_id)?;
+                // clean up the tail left by a previous shrink
+                if len > old_size {
+                    self._clean_at(old_size, len)?;
+                }
+            }
+            Ordering::Greater => {
+                let extents = self.disk_inode.read().has_extents();
+                // extents may be converted to block lists, count the worst case
+                let mut needed = (blocks - old_blocks) as usize + indirect_blocks(blocks as usize);
+                if !extents {
+                    needed -= indirect_blocks(old_blocks as usize);
                 }
+                // so that concurrent allocations can not take them halfway
+                self.fs.reserve_blocks(needed)?;
+                self.reserved.store(needed, AtomicOrdering::Relaxed);
+                let result = self.add_blocks(old_blocks as usize, blocks as usize, extents);
+                self.fs
+                    .release_blocks(self.reserved.swap(0, AtomicOrdering::Relaxed));
+                result?;
                 // clean up
                 let mut disk_inode = self.disk_inode.write();
-                let old_size = disk_inode.size as usize;
-                disk_inode.size = len as u32;
+                let old_size = disk_inode.size();
+                disk_inode.set_size(len);
                 drop(disk_inode);
                 self._clean_at(old_size, len)?;
             }
             Ordering::Less => {
-                // free extra blocks
-                for i in blocks..old_blocks {
-                    let disk_block_id = self.get_disk_block_id(i as usize)?;
-                    self.fs.free_block(disk_block_id);
+                if self.disk_inode.read().has_extents() {
+                    self.unmap_extents(blocks as usize);
+                } else {
+                    self.unmap_blocks(blocks as usize)?;
                 }
                 let mut disk_inode = self.disk_inode.write();
-                // free indirect block if needed
-                if blocks < MAX_NBLOCK_DIRECT as u32
-                    && disk_inode.blocks >= MAX_NBLOCK_DIRECT as u32
-                {
-                    self.fs.free_block(disk_inode.indirect as usize);
-                    disk_inode.indirect = 0;
+                disk_inode.blocks = blocks;
+                disk_inode.set_size(len);
+            }
+        }
+        Ok(())
+    }
+    /// Add data blocks until there are `blocks` blocks, after the current last block if possible
+    fn add_blocks(&self, old_blocks: usize, blocks: usize, extents: bool) -> vfs::Result<()> {
+        let goal = match old_blocks {
+            0 => self.id + 1,
+            _ => self.get_disk_block_id(old_blocks - 1)? + 1,
+        };
+        let runs = self.alloc_blocks(goal, blocks - old_blocks)?;
+        if extents {
+            self.map_extents(runs)
+        } else {
+            self.map_blocks(blocks, runs.into_iter().flatten())
+        }
+    }
+    /// Allocate `count` blocks from those reserved by `_resize`, or reserve them now
+    fn alloc_blocks(&self, goal: BlockId, count: usize) -> vfs::Result<Vec<Range<BlockId>>> {
+        let reserved = self.reserved.load(AtomicOrdering::Relaxed);
+        match reserved.checked_sub(count) {
+            Some(rest) => self.reserved.store(rest, AtomicOrdering::Relaxed),
+            None => self.fs.reserve_blocks(count)?,
+        }
+        self.fs.alloc_reserved(goal, count)
+    }
+    /// Allocate an indirect block
+    fn alloc_indirect(&self) -> vfs::Result<u32> {
+        let goal = self.fs.alloc_hint.load(AtomicOrdering::Relaxed);
+        Ok(self.alloc_blocks(goal, 1)?[0].start as u32)
+    }
+    /// Resize inline data, count the files with inline data
+    fn resize_inline(&self, len: usize) -> vfs::Result<()> {
+        let old_size = self.disk_inode.read().size();
+        self.disk_inode.write().set_size(len);
+        if old_size == 0 && len > 0 {
+            self.fs.inline_files.fetch_add(1, AtomicOrdering::Relaxed);
+        } else if old_size > 0 && len == 0 {
+            self.fs.inline_files.fetch_sub(1, AtomicOrdering::Relaxed);
+        }
+        // clean up the tail left by a previous shrink
+        if len > old_size {
+            self._clean_at(old_size, len)?;
+        }
+        Ok(())
+    }
+    /// Move inline data to data blocks, since it grows to `len` bytes
+    fn inline_to_blocks(&self, len: usize) -> vfs::Result<()> {
+        let mut data = vec![0u8; self.disk_inode.read().size()];
+        self._read_at(0, &mut data)?;
+        self.resize_inline(0)?;
+        self.disk_inode.write().flags &= !INODE_FLAG_INLINE;
+        if let Err(err) = self._resize(len) {
+            // no space for the blocks, keep it inline
+            self.disk_inode.write().flags |= INODE_FLAG_INLINE;
+            self.resize_inline(data.len())?;
+            self._write_at(0, &data)?;
+            return Err(err);
+        }
+        self._write_at(0, &data)?;
+        Ok(())
+    }
+    /// Append data blocks to block lists until there are `blocks` blocks,
+    /// allocate indirect blocks if needed.
+    fn map_blocks(&self, blocks: usize, data: impl Iterator<Item = BlockId>) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        let old_blocks = disk_inode.blocks as usize;
+        disk_inode.blocks = blocks as u32;
+        // allocate indirect block if needed
+        if old_blocks < MAX_NBLOCK_DIRECT && blocks >= MAX_NBLOCK_DIRECT {
+            disk_inode.indirect = self.alloc_indirect()?;
+        }
+        // allocate double indirect block if needed
+        if blocks >= MAX_NBLOCK_INDIRECT {
+            if disk_inode.db_indirect == 0 {
+                disk_inode.db_indirect = self.alloc_indirect()?;
+            }
+            let indirect_begin = {
+                if old_blocks < MAX_NBLOCK_INDIRECT {
+                    0
+                } else {
+                    (old_blocks - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
                 }
-                // free double indirect block if needed
-                if disk_inode.blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    let indirect_begin = {
-                        if (blocks as usize) < MAX_NBLOCK_INDIRECT {
-                            0
-                        } else {
-                            (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
-                        }
-                    };
-                    let indirect_end =
-                        (disk_inode.blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
-                    for i in indirect_begin..indirect_end {
-                        let mut indirect: u32 = 0;
-                        self.fs.device.read_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf_mut(),
-                        )?;
-                        assert!(indirect > 0);
-                        self.fs.free_block(indirect as usize);
-                    }
-                    if blocks < MAX_NBLOCK_INDIRECT as u32 {
-                        assert!(disk_inode.db_indirect > 0);
-                        self.fs.free_block(disk_inode.db_indirect as usize);
-                        disk_inode.db_indirect = 0;
-                    }
+            };
+            let indirect_end = ((blocks - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1).min(BLK_NENTRY);
+            for i in indirect_begin..indirect_end {
+                let indirect = self.alloc_indirect()?;
+                self.fs.write_meta(
+                    disk_inode.db_indirect as usize,
+                    ENTRY_SIZE * i,
+                    indirect.as_buf(),
+                )?;
+            }
+        }
+        drop(disk_inode);
+        for (i, disk_block_id) in (old_blocks..blocks).zip(data) {
+            if i >= MAX_NBLOCK_DOUBLE_INDIRECT {
+                self.alloc_triple_indirect(i)?;
+            }
+            self.set_disk_block_id(i, disk_block_id)?;
+        }
+        Ok(())
+    }
+    /// Free data blocks in block lists until there are `blocks` blocks,
+    /// free indirect blocks if no longer needed.
+    fn unmap_blocks(&self, blocks: usize) -> vfs::Result<()> {
+        let old_blocks = self.disk_inode.read().blocks as usize;
+        // free extra blocks, from the end so that indirect blocks are freed once empty
+        for i in (blocks..old_blocks).rev() {
+            let disk_block_id = self.get_disk_block_id(i)?;
+            self.fs.free_block(disk_block_id);
+            if i >= MAX_NBLOCK_DOUBLE_INDIRECT {
+                self.free_triple_indirect(i)?;
+            }
+        }
+        let mut disk_inode = self.disk_inode.write();
+        // free indirect block if needed
+        if blocks < MAX_NBLOCK_DIRECT && old_blocks >= MAX_NBLOCK_DIRECT {
+            self.fs.free_block(disk_inode.indirect as usize);
+            disk_inode.indirect = 0;
+        }
+        // free double indirect block if needed
+        if old_blocks >= MAX_NBLOCK_INDIRECT {
+            let indirect_begin = {
+                if blocks < MAX_NBLOCK_INDIRECT {
+                    0
+                } else {
+                    (blocks - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
                 }
-                disk_inode.blocks = blocks;
-                disk_inode.size = len as u32;
+            };
+            let indirect_end =
+                ((old_blocks - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1).min(BLK_NENTRY);
+            for i in indirect_begin..indirect_end {
+                let mut indirect: u32 = 0;
+                self.fs.read_meta(
+                    disk_inode.db_indirect as usize,
+                    ENTRY_SIZE * i,
+                    indirect.as_buf_mut(),
+                )?;
+                assert!(indirect > 0);
+                self.fs.free_block(indirect as usize);
+            }
+            if blocks < MAX_NBLOCK_INDIRECT {
+                assert!(disk_inode.db_indirect > 0);
+                self.fs.free_block(disk_inode.db_indirect as usize);
+                disk_inode.db_indirect = 0;
             }
         }
         Ok(())
     }
+    /// Append runs of data blocks to extents.
+    /// Convert to block lists if they do not fit.
+    fn map_extents(&self, runs: Vec<Range<BlockId>>) -> vfs::Result<()> {
+        let mut runs = runs.into_iter();
+        while let Some(run) = runs.next() {
+            let mut disk_inode = self.disk_inode.write();
+            let used = (0..NEXTENT)
+                .take_while(|&i| disk_inode.extent(i).1 > 0)
+                .count();
+            let last = used.checked_sub(1).map(|i| disk_inode.extent(i));
+            match last {
+                // merge with the last extent
+                Some((start, len)) if (start + len) as BlockId == run.start => {
+                    let len = len + run.len() as u32;
+                    disk_inode.set_extent(used - 1, start, len);
+                }
+                _ if used < NEXTENT => {
+                    disk_inode.set_extent(used, run.start as u32, run.len() as u32);
+                }
+                _ => {
+                    let blocks = disk_inode.blocks as usize;
+                    drop(disk_inode);
+                    self.extents_to_blocks()?;
+                    let new_blocks = blocks + run.len() + runs.clone().flatten().count();
+                    return self.map_blocks(new_blocks, run.chain(runs.flatten()));
+                }
+            }
+            disk_inode.blocks += run.len() as u32;
+        }
+        Ok(())
+    }
+    /// Free data blocks in extents until there are `blocks` blocks
+    fn unmap_extents(&self, blocks: usize) {
+        let mut disk_inode = self.disk_inode.write();
+        let mut begin = 0;
+        for i in 0..NEXTENT {
+            let (start, len) = disk_inode.extent(i);
+            let keep = blocks.saturating_sub(begin).min(len as usize);
+            for block_id in start as usize + keep..(start + len) as usize {
+                self.fs.free_block(block_id);
+            }
+            match keep {
+                0 => disk_inode.set_extent(i, 0, 0),
+                _ => disk_inode.set_extent(i, start, keep as u32),
+            }
+            begin += len as usize;
+        }
+    }
+    /// Move the blocks of the file from extents to block lists
+    fn extents_to_blocks(&self) -> vfs::Result<()> {
+        let blocks = self.disk_inode.read().blocks as usize;
+        let data = (0..blocks)
+            .map(|i| self.get_disk_block_id(i))
+            .collect::<vfs::Result<Vec<_>>>()?;
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.flags &= !INODE_FLAG_EXTENTS;
+        disk_inode.direct = [0; NDIRECT];
+        disk_inode.blocks = 0;
+        drop(disk_inode);
+        self.map_blocks(blocks, data.into_iter())
+    }
     // Note: the _\w*_at method always return begin>size?0:begin<end?0:(min(size,end)-begin) when success
-    /// Read/Write content, no matter what type it is
+    /// Read/Write content, no matter what type it is.
+    /// Content of dirs is metadata, which goes through the journal.
     fn _io_at<F>(&self, begin: usize, end: usize, mut f: F) -> vfs::Result<usize>
     where
-        F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<()>,
+        F: FnMut(&BlockIo, &BlockRange, usize) -> vfs::Result<()>,
     {
-        let size = self.disk_inode.read().size as usize;
+        // blocks are mapped under one lock, the content lock keeps them in place
+        let disk_inode = self.disk_inode.read();
+        let (size, inline) = (disk_inode.size(), disk_inode.has_inline_data());
+        let io = BlockIo {
+            fs: &self.fs,
+            // inline data is in the inode block
+            meta: disk_inode.type_ == FileType::Dir || inline,
+        };
+        if inline {
+            let (begin, end) = (size.min(begin), size.min(end));
+            if begin < end {
+                let range = BlockRange {
+                    block: self.id,
+                    begin: INLINE_DATA_OFFSET + begin,
+                    end: INLINE_DATA_OFFSET + end,
+                    block_size_log2: BLKSIZE_LOG2,
+                };
+                f(&io, &range, 0)?;
+            }
+            return Ok(end.saturating_sub(begin));
+        }
         let iter = BlockIter {
             begin: size.min(begin),
             end: size.min(end),
@@ -334,16 +868,16 @@ impl INodeImpl {
         // For each block
         let mut buf_offset = 0usize;
         for mut range in iter {
-            range.block = self.get_disk_block_id(range.block)?;
-            f(&self.fs.device, &range, buf_offset)?;
+            range.block = self.map_block(&disk_inode, range.block)?;
+            f(&io, &range, buf_offset)?;
             buf_offset += range.len();
         }
         Ok(buf_offset)
     }
     /// Read content, no matter what type it is
     fn _read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.read_block(
+        self._io_at(offset, offset + buf.len(), |io, range, offset| {
+            io.read_block(
                 range.block,
                 range.begin,
                 &mut buf[offset..offset + range.len()],
@@ -352,17 +886,26 @@ impl INodeImpl {
     }
     /// Write content, no matter what type it is
     fn _write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.write_block(range.block, range.begin, &buf[offset..offset + range.len()])
+        self._io_at(offset, offset + buf.len(), |io, range, offset| {
+            io.write_block(range.block, range.begin, &buf[offset..offset + range.len()])
         })
     }
     /// Clean content, no matter what type it is
     fn _clean_at(&self, begin: usize, end: usize) -> vfs::Result<usize> {
         static ZEROS: [u8; BLKSIZE] = [0; BLKSIZE];
-        self._io_at(begin, end, |device, range, _| {
-            device.write_block(range.block, range.begin, &ZEROS[..range.len()])
+        self._io_at(begin, end, |io, range, _| {
+            io.write_block(range.block, range.begin, &ZEROS[..range.len()])
         })
     }
+    /// Write back the inode if dirty
+    fn _sync_all(&self) -> vfs::Result<()> {
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.dirty() {
+            self.fs.write_meta(self.id, 0, disk_inode.as_buf())?;
+            disk_inode.sync();
+        }
+        Ok(())
+    }
     fn nlinks_inc(&self) {
         self.disk_inode.write().nlinks += 1;
     }
@@ -373,6 +916,8 @@ impl INodeImpl {
     }
 
     pub fn link_inodeimpl(&self, name: &str, other: &Arc<INodeImpl>) -> vfs::Result<()> {
+        let _op = self.fs.begin_op()?;
+        let _lock = self.content_lock.write();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -394,10 +939,7 @@ impl INodeImpl {
             id: child.id as u32,
             name: Str256::from(name),
         };
-        let disk_inode = self.disk_inode.write();
-        let old_size = disk_inode.size as usize;
-        self._resize(old_size + BLKSIZE)?;
-        self._write_at(old_size, entry.as_buf()).unwrap();
+        self.append_direntry(&entry)?;
         child.nlinks_inc();
         Ok(())
     }
@@ -405,42 +947,42 @@ impl INodeImpl {
 
 impl vfs::INode for INodeImpl {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        match self.disk_inode.read().type_ {
-            FileType::File => self._read_at(offset, buf),
-            FileType::SymLink => self._read_at(offset, buf),
-            FileType::CharDevice => {
-                let device_inodes = self.fs.device_inodes.read();
-                let device_inode = device_inodes.get(&self.device_inode_id);
-                match device_inode {
-                    Some(device) => device.read_at(offset, buf),
-                    None => Err(FsError::DeviceError),
-                }
+        let type_ = self.disk_inode.read().type_;
+        match type_ {
+            FileType::File | FileType::SymLink => {
+                let _lock = self.content_lock.read();
+                self._read_at(offset, buf)
             }
+            FileType::CharDevice | FileType::BlockDevice => self.device()?.read_at(offset, buf),
             _ => Err(FsError::NotFile),
         }
     }
     fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        let DiskINode { type_, size, .. } = **self.disk_inode.read();
+        // the device may block, do not hold up commits
+        if self.is_device() {
+            return self.device()?.write_at(offset, buf);
+        }
+        let _op = self.fs.begin_op()?;
+        let _lock = self.content_lock.write();
+        let (type_, size) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.type_, disk_inode.size())
+        };
         match type_ {
             FileType::File | FileType::SymLink => {
-                let end_offset = offset + buf.len();
-                if (size as usize) < end_offset {
+                let end_offset = offset.checked_add(buf.len()).ok_or(FsError::FileTooBig)?;
+                if size < end_offset {
                     self._resize(end_offset)?;
                 }
                 self._write_at(offset, buf)
             }
-            FileType::CharDevice => {
-                let device_inodes = self.fs.device_inodes.write();
-                let device_inode = device_inodes.get(&self.device_inode_id);
-                match device_inode {
-                    Some(device) => device.write_at(offset, buf),
-                    None => Err(FsError::DeviceError),
-                }
-            }
             _ => Err(FsError::NotFile),
         }
     }
     fn poll(&self) -> vfs::Result<vfs::PollStatus> {
+        if self.is_device() {
+            return self.device()?.poll();
+        }
         Ok(vfs::PollStatus {
             read: true,
             write: true,
@@ -449,51 +991,76 @@ impl vfs::INode for INodeImpl {
     }
     /// the size returned here is logical size(entry num for directory), not the disk space used.
     fn metadata(&self) -> vfs::Result<vfs::Metadata> {
+        let has_owner = self.fs.super_block.read().has_owner();
         let disk_inode = self.disk_inode.read();
         Ok(vfs::Metadata {
             dev: 0,
             inode: self.id,
             size: match disk_inode.type_ {
-                FileType::File | FileType::SymLink => disk_inode.size as usize,
-                FileType::Dir => disk_inode.size as usize,
-                FileType::CharDevice => 0,
-                FileType::BlockDevice => 0,
-                _ => panic!("Unknown file type"),
+                FileType::File | FileType::SymLink => disk_inode.size(),
+                FileType::Dir => disk_inode.size(),
+                FileType::CharDevice | FileType::BlockDevice => 0,
+                FileType::NamedPipe | FileType::Socket => 0,
+                FileType::Invalid => panic!("Unknown file type"),
+            },
+            mode: if has_owner {
+                disk_inode.mode
+            } else {
+                DEFAULT_MODE
             },
-            mode: 0o777,
             type_: vfs::FileType::from(disk_inode.type_),
             blocks: disk_inode.blocks as usize,
             atime: disk_inode.atime,
             mtime: disk_inode.mtime,
             ctime: disk_inode.ctime,
             nlinks: disk_inode.nlinks as usize,
-            uid: 0,
-            gid: 0,
+            uid: if has_owner {
+                disk_inode.uid as usize
+            } else {
+                0
+            },
+            gid: if has_owner {
+                disk_inode.gid as usize
+            } else {
+                0
+            },
             blk_size: BLKSIZE,
-            rdev: self.device_inode_id,
+            rdev: match disk_inode.type_ {
+                FileType::CharDevice | FileType::BlockDevice => disk_inode.rdev,
+                _ => 0,
+            },
         })
     }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        let has_owner = self.fs.super_block.read().has_owner();
+        // old images have no space for them
+        if !has_owner && (metadata.mode, metadata.uid, metadata.gid) != (DEFAULT_MODE, 0, 0) {
+            return Err(FsError::NotSupported);
+        }
         let mut disk_inode = self.disk_inode.write();
         disk_inode.atime = metadata.atime;
         disk_inode.mtime = metadata.mtime;
         disk_inode.ctime = metadata.ctime;
+        if has_owner {
+            disk_inode.mode = metadata.mode;
+            disk_inode.uid = metadata.uid as u32;
+            disk_inode.gid = metadata.gid as u32;
+        }
         Ok(())
     }
     fn sync_all(&self) -> vfs::Result<()> {
-        let mut disk_inode = self.disk_inode.write();
-        if disk_inode.dirty() {
-            self.fs
-                .device
-                .write_block(self.id, 0, disk_inode.as_buf())?;
-            disk_inode.sync();
+        if self.fs.journal.is_some() {
+            // only a commit makes it durable
+            return self.fs.sync();
         }
-        Ok(())
+        self._sync_all()
     }
     fn sync_data(&self) -> vfs::Result<()> {
         self.sync_all()
     }
     fn resize(&self, len: usize) -> vfs::Result<()> {
+        let _op = self.fs.begin_op()?;
+        let _lock = self.content_lock.write();
         if self.disk_inode.read().type_ != FileType::File
             && self.disk_inode.read().type_ != FileType::SymLink
         {
@@ -505,9 +1072,11 @@ impl vfs::INode for INodeImpl {
         &self,
         name: &str,
         type_: vfs::FileType,
-        _mode: u32,
+        mode: u32,
         data: usize,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        let _op = self.fs.begin_op()?;
+        let _lock = self.content_lock.write();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -526,9 +1095,21 @@ impl vfs::INode for INodeImpl {
             vfs::FileType::File => self.fs.new_inode_file()?,
             vfs::FileType::SymLink => self.fs.new_inode_symlink()?,
             vfs::FileType::Dir => self.fs.new_inode_dir(self.id)?,
-            vfs::FileType::CharDevice => self.fs.new_inode_chardevice(data)?,
-            _ => return Err(vfs::FsError::InvalidParam),
+            vfs::FileType::CharDevice => self.fs.new_inode_special(FileType::CharDevice, data)?,
+            vfs::FileType::BlockDevice => self.fs.new_inode_special(FileType::BlockDevice, data)?,
+            vfs::FileType::NamedPipe | vfs::FileType::Socket => {
+                // older drivers can not load them
+                if !self.fs.super_block.read().has_special_files() {
+                    return Err(FsError::NotSupported);
+                }
+                let type_ = match type_ {
+                    vfs::FileType::NamedPipe => FileType::NamedPipe,
+                    _ => FileType::Socket,
+                };
+                self.fs.new_inode_special(type_, NODEVICE)?
+            }
         };
+        inode.disk_inode.write().mode = mode as u16 & 0o7777;
 
         // Write new entry
         self.append_direntry(&DiskEntry {
@@ -545,6 +1126,8 @@ impl vfs::INode for INodeImpl {
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        let _op = self.fs.begin_op()?;
+        let _lock = self.content_lock.write();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -572,6 +1155,8 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn unlink(&self, name: &str) -> vfs::Result<()> {
+        let _op = self.fs.begin_op()?;
+        let _lock = self.content_lock.write();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
@@ -592,9 +1177,14 @@ impl vfs::INode for INodeImpl {
         let inode = self.fs.get_inode(inode_id);
 
         let type_ = inode.disk_inode.read().type_;
+        // nothing is created in the dir once it is checked empty
+        let _child_lock = match type_ {
+            FileType::Dir => Some(inode.content_lock.write()),
+            _ => None,
+        };
         if type_ == FileType::Dir {
             // only . and ..
-            if inode.disk_inode.read().size as usize / DIRENT_SIZE > 2 {
+            if inode.dir_count()? > 2 {
                 return Err(FsError::DirNotEmpty);
             }
         }
@@ -608,49 +1198,90 @@ impl vfs::INode for INodeImpl {
         Ok(())
     }
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks == 0 {
-            return Err(FsError::DirRemoved);
-        }
-        if old_name == "." {
-            return Err(FsError::IsDir);
-        }
-        if old_name == ".." {
-            return Err(FsError::IsDir);
-        }
-
         let dest = target
             .downcast_ref::<INodeImpl>()
             .ok_or(FsError::NotSameFs)?;
-        let dest_info = dest.metadata()?;
         if !Arc::ptr_eq(&self.fs, &dest.fs) {
             return Err(FsError::NotSameFs);
         }
-        if dest_info.type_ != vfs::FileType::Dir {
+        if self.disk_inode.read().type_ != FileType::Dir
+            || dest.disk_inode.read().type_ != FileType::Dir
+        {
             return Err(FsError::NotDir);
         }
-        if dest_info.nlinks == 0 {
-            return Err(FsError::DirRemoved);
+        if old_name == "." || old_name == ".." || new_name == "." || new_name == ".." {
+            return Err(FsError::IsDir);
         }
-        if let Some((_, id)) = dest.get_file_inode_and_entry_id(new_name) {
-            dest.remove_direntry(id)?;
+
+        // across dirs, the ancestors of both must stay as they are until it is done
+        let _rename = match self.id == dest.id {
+            true => None,
+            false => Some(self.fs.rename_lock.lock()),
+        };
+        let _op = self.fs.begin_op()?;
+        let (src_path, dest_path) = match _rename {
+            Some(_) => (self.fs.ancestors(self), self.fs.ancestors(dest)),
+            None => (Vec::new(), Vec::new()),
+        };
+        let _dirs = lock_dirs(vec![(src_path.len(), self), (dest_path.len(), dest)]);
+        if self.disk_inode.read().nlinks == 0 || dest.disk_inode.read().nlinks == 0 {
+            return Err(FsError::DirRemoved);
         }
 
-        let (inode_id, entry_id) = self
+        let (inode_id, _) = self
             .get_file_inode_and_entry_id(old_name)
             .ok_or(FsError::EntryNotFound)?;
-        if info.inode == dest_info.inode {
+        let inode = self.fs.get_inode(inode_id);
+        let is_dir = inode.disk_inode.read().type_ == FileType::Dir;
+        if is_dir && dest_path.contains(&inode_id) {
+            // into its own subtree
+            return Err(FsError::InvalidParam);
+        }
+        let old_inode = match dest.get_file_inode_and_entry_id(new_name) {
+            Some((old_inode_id, _)) if old_inode_id == inode_id => return Ok(()),
+            Some((old_inode_id, id)) => Some((self.fs.get_inode(old_inode_id), id)),
+            None => None,
+        };
+
+        // the dirs moved and replaced are locked after their parents
+        let mut children = Vec::new();
+        if is_dir {
+            children.push((src_path.len() + 1, &*inode));
+        }
+        if let Some((old_inode, _)) = &old_inode {
+            let old_is_dir = old_inode.disk_inode.read().type_ == FileType::Dir;
+            match (is_dir, old_is_dir) {
+                (false, true) => return Err(FsError::IsDir),
+                (true, false) => return Err(FsError::NotDir),
+                // an ancestor of self is not empty, and must not be locked after it
+                (true, true) if src_path.contains(&old_inode.id) => {
+                    return Err(FsError::DirNotEmpty)
+                }
+                (true, true) => children.push((dest_path.len() + 1, &**old_inode)),
+                _ => {}
+            }
+        }
+        let _children = lock_dirs(children);
+
+        // replace the existing entry
+        if let Some((old_inode, id)) = &old_inode {
+            let old_is_dir = old_inode.disk_inode.read().type_ == FileType::Dir;
+            if old_is_dir && old_inode.dir_count()? > 2 {
+                return Err(FsError::DirNotEmpty);
+            }
+            old_inode.nlinks_dec();
+            if old_is_dir {
+                old_inode.nlinks_dec(); //for .
+                dest.nlinks_dec(); //for ..
+            }
+            dest.remove_direntry(*id)?;
+        }
+
+        // entry id may be changed by remove_direntry
+        let (_, entry_id) = self.get_file_inode_and_entry_id(old_name).unwrap();
+        if self.id == dest.id {
             // rename: in place modify name
-            self.write_direntry(
-                entry_id,
-                &DiskEntry {
-                    id: inode_id as u32,
-                    name: Str256::from(new_name),
-                },
-            )?;
+            self.rename_direntry(entry_id, inode_id, new_name)?;
         } else {
             // move
             dest.append_direntry(&DiskEntry {
@@ -659,8 +1290,8 @@ impl vfs::INode for INodeImpl {
             })?;
             self.remove_direntry(entry_id)?;
 
-            let inode = self.fs.get_inode(inode_id);
-            if inode.metadata()?.type_ == vfs::FileType::Dir {
+            if is_dir {
+                inode.set_parent(dest.id)?;
                 self.nlinks_dec();
                 dest.nlinks_inc();
             }
@@ -672,6 +1303,7 @@ impl vfs::INode for INodeImpl {
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
+        let _lock = self.content_lock.read();
         let inode_id = self.get_file_inode_id(name).ok_or(FsError::EntryNotFound)?;
         Ok(self.fs.get_inode(inode_id))
     }
@@ -679,10 +1311,8 @@ impl vfs::INode for INodeImpl {
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
-            return Err(FsError::EntryNotFound);
-        };
-        let entry = self.read_direntry(id)?;
+        let _lock = self.content_lock.read();
+        let entry = self.get_direntry(id)?;
         Ok(String::from(entry.name.as_ref()))
     }
 
@@ -690,10 +1320,8 @@ impl vfs::INode for INodeImpl {
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
-            return Err(FsError::EntryNotFound);
-        };
-        let entry = self.read_direntry(id)?;
+        let _lock = self.content_lock.read();
+        let entry = self.get_direntry(id)?;
         Ok((
             self.fs.get_inode(entry.id as usize).metadata()?,
             String::from(entry.name.as_ref()),
@@ -701,14 +1329,12 @@ impl vfs::INode for INodeImpl {
     }
 
     fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
-        if self.metadata().unwrap().type_ != vfs::FileType::CharDevice {
+        if !self.is_device() {
             return Err(FsError::IOCTLError);
         }
-        let device_inodes = self.fs.device_inodes.read();
-        let device_inode = device_inodes.get(&self.device_inode_id);
-        match device_inode {
-            Some(x) => x.io_control(_cmd, _data),
-            None => {
+        match self.device() {
+            Ok(device) => device.io_control(_cmd, _data),
+            Err(_) => {
                 warn!("cannot find corresponding device inode in call_inoctl");
                 Err(FsError::IOCTLError)
             }
@@ -728,44 +1354,113 @@ impl vfs::INode for INodeImpl {
 impl Drop for INodeImpl {
     /// Auto sync when drop
     fn drop(&mut self) {
-        self.sync_all()
+        // may be dropped in the middle of an operation, so never commit here
+        let _op = self.fs.op_lock.read();
+        self._sync_all()
             .expect("Failed to sync when dropping the SimpleFileSystem Inode");
         if self.disk_inode.read().nlinks == 0 {
             self._resize(0).unwrap();
             self.disk_inode.write().sync();
             self.fs.free_block(self.id);
         }
+        // unless it has been loaded again, in its new block or in a new inode reusing this one
+        let mut inodes = self.fs.inodes.write();
+        if matches!(inodes.get(&self.id), Some(inode) if inode.strong_count() == 0) {
+            inodes.remove(&self.id);
+        }
     }
 }
 
+/// Fragmentation statistics of SFS
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Fragmentation {
+    /// number of regular files
+    pub files: usize,
+    /// number of regular files not stored in one contiguous run
+    pub fragmented_files: usize,
+    /// number of contiguous runs of data blocks in all regular files
+    pub file_extents: usize,
+    /// number of free blocks
+    pub free_blocks: usize,
+    /// number of contiguous runs of free blocks
+    pub free_extents: usize,
+    /// length of the longest run of free blocks
+    pub largest_free_extent: usize,
+}
+
 /// filesystem for sfs
 ///
 /// ## 内部可变性
 /// 为了方便协调外部及INode对SFS的访问，并为日后并行化做准备，
 /// 将SFS设置为内部可变，即对外接口全部是&self，struct的全部field用RwLock包起来
 /// 这样其内部各field均可独立访问
+///
+/// ## Lock order
+/// Operations on different files and dirs only share the locks at the end of the list,
+/// which are held briefly. Locks are taken in this order:
+///
+/// 1. `rename_lock`, by moves across dirs
+/// 2. `op_lock`, by operations modifying metadata
+/// 3. the content locks of dirs, those closer to the root first, then by id,
+///    then the content lock of a file in them
+/// 4. disk inodes
+/// 5. `inodes`, groups of the freemap, the journal and the superblock
 pub struct SimpleFileSystem {
-    /// on-disk superblock
+    /// on-disk superblock, its counters are kept up to date in `unused_blocks`
+    /// and `inline_files` and written back on sync
     super_block: RwLock<Dirty<SuperBlock>>,
-    /// blocks in use are mared 0
-    free_map: RwLock<Dirty<BitVec<Lsb0, u8>>>,
+    /// locked exclusively only to grow or shrink it
+    free_map: RwLock<FreeMap>,
+    /// number of free blocks not reserved
+    unused_blocks: AtomicUsize,
+    /// number of files with inline data
+    inline_files: AtomicUsize,
     /// inode list
     inodes: RwLock<BTreeMap<INodeId, Weak<INodeImpl>>>,
     /// device
     device: Arc<dyn Device>,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SimpleFileSystem>,
-    /// device inode
-    device_inodes: RwLock<BTreeMap<usize, Arc<DeviceINode>>>,
+    /// resolves device nodes to devices
+    device_registry: RwLock<Option<Arc<dyn DeviceRegistry>>>,
+    /// where to start looking for free blocks
+    alloc_hint: AtomicUsize,
+    /// map blocks of new files by extents
+    extents: AtomicBool,
+    /// index large dirs
+    dir_index: AtomicBool,
+    /// store small files and symlinks inline
+    inline_data: AtomicBool,
+    /// journal of metadata, if the image has one
+    journal: Option<Mutex<Journal>>,
+    /// held by operations modifying metadata, and exclusively by commits,
+    /// so that no transaction contains half an operation
+    op_lock: RwLock<()>,
+    /// held by moves across dirs, so that the tree only changes by one at a time
+    rename_lock: Mutex<()>,
 }
 
 impl SimpleFileSystem {
     /// Load SFS from device
     pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
-        let super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
         if !super_block.check() {
             return Err(FsError::WrongFs);
         }
+        super_block.sanitize();
+        let journal = match super_block.has_journal() {
+            true => {
+                let journal = Journal::open(
+                    &device,
+                    super_block.journal_start as usize,
+                    super_block.journal_blocks as usize,
+                )?;
+                // the superblock may be replayed
+                super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+                Some(Mutex::new(journal))
+            }
+            false => None,
+        };
         let mut freemap_disk = vec![0u8; BLKSIZE * super_block.freemap_blocks as usize];
         for i in 0..super_block.freemap_blocks as usize {
             device.read_block(
@@ -774,46 +1469,93 @@ impl SimpleFileSystem {
                 &mut freemap_disk[i * BLKSIZE..(i + 1) * BLKSIZE],
             )?;
         }
+        let free_map = FreeMap::from_vec(freemap_disk);
+        // the counter on disk may be wrong, and more blocks could be reserved than are free
+        let unused_blocks = free_map.count_free(super_block.blocks as usize);
 
         Ok(SimpleFileSystem {
+            unused_blocks: AtomicUsize::new(unused_blocks),
+            inline_files: AtomicUsize::new(super_block.inline_files as usize),
             super_block: RwLock::new(Dirty::new(super_block)),
-            free_map: RwLock::new(Dirty::new(BitVec::from_vec(freemap_disk))),
+            free_map: RwLock::new(free_map),
             inodes: RwLock::new(BTreeMap::new()),
             device,
             self_ptr: Weak::default(),
-            device_inodes: RwLock::new(BTreeMap::new()),
+            device_registry: RwLock::new(None),
+            alloc_hint: AtomicUsize::new(0),
+            extents: AtomicBool::new(false),
+            dir_index: AtomicBool::new(true),
+            inline_data: AtomicBool::new(true),
+            journal,
+            op_lock: RwLock::new(()),
+            rename_lock: Mutex::new(()),
         }
         .wrap())
     }
     /// Create a new SFS on blank disk
     pub fn create(device: Arc<dyn Device>, space: usize) -> vfs::Result<Arc<Self>> {
+        Self::create_with_journal(device, space, 0)
+    }
+    /// Create a new SFS on blank disk, with a journal of `journal_blocks` blocks
+    /// after the freemap, or none if it is 0.
+    ///
+    /// A transaction larger than half of the journal is committed before the next operation.
+    pub fn create_with_journal(
+        device: Arc<dyn Device>,
+        space: usize,
+        journal_blocks: usize,
+    ) -> vfs::Result<Arc<Self>> {
         let blocks = (space + BLKSIZE - 1) / BLKSIZE;
         let freemap_blocks = (space + BLKBITS * BLKSIZE - 1) / BLKBITS / BLKSIZE;
         assert!(blocks >= 16, "space too small");
+        let journal_start = BLKN_FREEMAP + freemap_blocks;
+        let data_start = journal_start + journal_blocks;
+        assert!(data_start < blocks, "journal too large");
 
         let super_block = SuperBlock {
-            magic: MAGIC,
+            magic: MAGIC_VERSIONED,
             blocks: blocks as u32,
-            unused_blocks: (blocks - BLKN_FREEMAP - freemap_blocks) as u32,
+            unused_blocks: (blocks - data_start) as u32,
             info: Str32::from(DEFAULT_INFO),
             freemap_blocks: freemap_blocks as u32,
+            version: VERSION,
+            features: match journal_blocks {
+                0 => 0,
+                _ => FEATURE_JOURNAL,
+            },
+            journal_start: journal_start as u32,
+            journal_blocks: journal_blocks as u32,
+            inline_files: 0,
         };
-        let free_map = {
-            let mut bitset = BitVec::with_capacity(freemap_blocks * BLKBITS);
-            bitset.extend(core::iter::repeat(false).take(freemap_blocks * BLKBITS));
-            for i in (BLKN_FREEMAP + freemap_blocks)..blocks {
-                bitset.set(i, true);
-            }
-            bitset
+        let free_map = FreeMap::new(freemap_blocks * BLKBITS);
+        for i in data_start..blocks {
+            free_map.set(i, true);
+        }
+        let journal = match journal_blocks {
+            0 => None,
+            _ => Some(Mutex::new(Journal::format(
+                &device,
+                journal_start,
+                journal_blocks,
+            )?)),
         };
 
         let sfs = SimpleFileSystem {
             super_block: RwLock::new(Dirty::new_dirty(super_block)),
-            free_map: RwLock::new(Dirty::new_dirty(free_map)),
+            free_map: RwLock::new(free_map),
+            unused_blocks: AtomicUsize::new(blocks - data_start),
+            inline_files: AtomicUsize::new(0),
             inodes: RwLock::new(BTreeMap::new()),
             device,
             self_ptr: Weak::default(),
-            device_inodes: RwLock::new(BTreeMap::new()),
+            device_registry: RwLock::new(None),
+            alloc_hint: AtomicUsize::new(0),
+            extents: AtomicBool::new(false),
+            dir_index: AtomicBool::new(true),
+            inline_data: AtomicBool::new(true),
+            journal,
+            op_lock: RwLock::new(()),
+            rename_lock: Mutex::new(()),
         }
         .wrap();
 
@@ -822,7 +1564,7 @@ impl SimpleFileSystem {
         root.init_direntry(BLKN_ROOT)?;
         root.nlinks_inc(); //for .
         root.nlinks_inc(); //for ..(root's parent is itself)
-        root.sync_all()?;
+        root._sync_all()?;
 
         Ok(sfs)
     }
@@ -840,79 +1582,225 @@ impl SimpleFileSystem {
         unsafe { Arc::from_raw(ptr) }
     }
 
+    /// Map blocks of regular files created from now on by extents.
+    /// Ignored on images before version 3.
+    pub fn set_extents(&self, enable: bool) {
+        self.extents.store(enable, AtomicOrdering::Relaxed);
+    }
+    /// Hash entries of dirs into buckets once they outgrow one block, which is the default.
+    /// Ignored on images before version 4.
+    pub fn set_dir_index(&self, enable: bool) {
+        self.dir_index.store(enable, AtomicOrdering::Relaxed);
+    }
+    /// Store files and symlinks created from now on in their inode block until they
+    /// outgrow it, which is the default. Ignored on images before version 7.
+    pub fn set_inline_data(&self, enable: bool) {
+        self.inline_data.store(enable, AtomicOrdering::Relaxed);
+    }
+    /// Resolve char and block device nodes through `registry`.
+    /// Without one, accessing them fails with `NoDevice`.
+    pub fn set_device_registry(&self, registry: Arc<dyn DeviceRegistry>) {
+        *self.device_registry.write() = Some(registry);
+    }
     /// Allocate a block, return block id
     fn alloc_block(&self) -> Option<usize> {
-        let mut free_map = self.free_map.write();
-        let id = free_map.alloc();
-        if let Some(block_id) = id {
-            let mut super_block = self.super_block.write();
-            if super_block.unused_blocks == 0 {
-                free_map.set(block_id, true);
-                return None;
+        let goal = self.alloc_hint.load(AtomicOrdering::Relaxed);
+        let runs = self.alloc_blocks(goal, 1).ok()?;
+        Some(runs[0].start)
+    }
+    /// Allocate `count` blocks starting from `goal` if possible,
+    /// return as few runs of contiguous blocks as it can
+    fn alloc_blocks(&self, goal: BlockId, count: usize) -> vfs::Result<Vec<Range<BlockId>>> {
+        self.reserve_blocks(count)?;
+        self.alloc_reserved(goal, count)
+    }
+    /// Reserve `count` free blocks, so that allocating them later can not fail
+    fn reserve_blocks(&self, count: usize) -> vfs::Result<()> {
+        self.unused_blocks
+            .fetch_update(AtomicOrdering::Relaxed, AtomicOrdering::Relaxed, |unused| {
+                unused.checked_sub(count)
+            })
+            .map(|_| ())
+            .map_err(|_| FsError::NoDeviceSpace)
+    }
+    /// Give back reserved blocks which are not allocated
+    fn release_blocks(&self, count: usize) {
+        self.unused_blocks.fetch_add(count, AtomicOrdering::Relaxed);
+    }
+    /// Allocate `count` reserved blocks like `alloc_blocks`.
+    /// If a whole pass over the freemap finds no free block, give back the
+    /// reservation and fail with `NoDeviceSpace`.
+    fn alloc_reserved(&self, goal: BlockId, count: usize) -> vfs::Result<Vec<Range<BlockId>>> {
+        let free_map = self.free_map.read();
+        let mut runs: Vec<Range<BlockId>> = Vec::new();
+        let mut goal = goal;
+        let mut remain = count;
+        while remain > 0 {
+            let run = match free_map.alloc_run(goal, remain) {
+                Some(run) => run,
+                None => {
+                    for block_id in runs.into_iter().flatten() {
+                        free_map.set(block_id, true);
+                    }
+                    self.release_blocks(count);
+                    return Err(FsError::NoDeviceSpace);
+                }
+            };
+            trace!("alloc blocks {:#x?}", run);
+            goal = run.end;
+            remain -= run.len();
+            // runs are split at the end of groups
+            match runs.last_mut() {
+                Some(last) if last.end == run.start => last.end = run.end,
+                _ => runs.push(run),
             }
-            super_block.unused_blocks -= 1; // will not underflow
-            trace!("alloc block {:#x}", block_id);
-        } else {
-            let super_block = self.super_block.read();
-            panic!("{:?}", super_block)
         }
-        id
+        self.alloc_hint.store(goal, AtomicOrdering::Relaxed);
+        Ok(runs)
     }
-    /// Free a block
+    /// Free a block.
+    /// With a journal, it is not reused until the running transaction is committed,
+    /// so that a crash does not leave the old owner with the content of the new one.
     fn free_block(&self, block_id: usize) {
-        let mut free_map = self.free_map.write();
-        assert!(!free_map[block_id]);
+        match &self.journal {
+            Some(journal) => {
+                assert!(!self.free_map.read().is_free(block_id));
+                journal.lock().free(block_id);
+            }
+            None => self._free_block(block_id),
+        }
+    }
+    fn _free_block(&self, block_id: usize) {
+        let free_map = self.free_map.read();
+        assert!(!free_map.is_free(block_id));
         free_map.set(block_id, true);
-        self.super_block.write().unused_blocks += 1;
+        self.release_blocks(1);
         trace!("free block {:#x}", block_id);
     }
-
-    pub fn new_device_inode(&self, device_inode_id: usize, device_inode: Arc<DeviceINode>) {
-        self.device_inodes
-            .write()
-            .insert(device_inode_id, device_inode);
+    /// Read a metadata block, as modified by the running transaction
+    fn read_meta(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.lock().read(&self.device, id, offset, buf),
+            None => self.device.read_block(id, offset, buf),
+        }
+    }
+    /// Write a metadata block, in the running transaction if there is a journal
+    fn write_meta(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.lock().write(&self.device, id, offset, buf),
+            None => self.device.write_block(id, offset, buf),
+        }
+    }
+    /// Start an operation modifying metadata, no commit is made until the guard is dropped.
+    /// Commit first if the journal is filling up.
+    fn begin_op(&self) -> vfs::Result<RwLockReadGuard<'_, ()>> {
+        if let Some(journal) = &self.journal {
+            if journal.lock().is_full() {
+                self.sync()?;
+            }
+        }
+        Ok(self.op_lock.read())
     }
 
     /// Create a new INode struct, then insert it to self.inodes
     /// Private used for load or create INode
     fn _new_inode(&self, id: INodeId, disk_inode: Dirty<DiskINode>) -> Arc<INodeImpl> {
-        let device_inode_id = disk_inode.device_inode_id;
-        let inode = Arc::new(INodeImpl {
+        let inode = self.make_inode(id, disk_inode);
+        self.inodes.write().insert(id, Arc::downgrade(&inode));
+        inode
+    }
+    fn make_inode(&self, id: INodeId, disk_inode: Dirty<DiskINode>) -> Arc<INodeImpl> {
+        Arc::new(INodeImpl {
             id,
             disk_inode: RwLock::new(disk_inode),
             fs: self.self_ptr.upgrade().unwrap(),
-            device_inode_id,
-        });
-        self.inodes.write().insert(id, Arc::downgrade(&inode));
-        inode
+            dir_cursor: Mutex::new((0, 0)),
+            content_lock: RwLock::new(()),
+            reserved: AtomicUsize::new(0),
+        })
     }
 
     /// Get inode by id. Load if not in memory.
     /// ** Must ensure it's a valid INode **
     fn get_inode(&self, id: INodeId) -> Arc<INodeImpl> {
-        assert!(!self.free_map.read()[id]);
+        assert!(!self.free_map.read().is_free(id));
 
-        // In the BTreeSet and not weak.
-        if let Some(inode) = self.inodes.read().get(&id) {
-            if let Some(inode) = inode.upgrade() {
-                return inode;
+        loop {
+            // In the BTreeSet and not weak.
+            if let Some(inode) = self.inodes.read().get(&id) {
+                if let Some(inode) = inode.upgrade() {
+                    return inode;
+                }
+            }
+            // Load under the lock, so that it is loaded once
+            let mut inodes = self.inodes.write();
+            match inodes.get(&id).map(Weak::upgrade) {
+                Some(Some(inode)) => return inode,
+                // being dropped, wait until it is written back and removed
+                Some(None) => {
+                    drop(inodes);
+                    core::hint::spin_loop();
+                    continue;
+                }
+                None => {}
             }
+            // Check the type first, since loading an invalid enum value is UB.
+            let mut type_ = [0u8; 2];
+            self.read_meta(id, 4, &mut type_).unwrap();
+            assert!(
+                u16::from_ne_bytes(type_) <= self.super_block.read().max_file_type() as u16,
+                "invalid type of inode {}",
+                id
+            );
+            let mut disk_inode: DiskINode = unsafe { uninit_memory() };
+            self.read_meta(id, 0, disk_inode.as_buf_mut()).unwrap();
+            disk_inode.sanitize(&self.super_block.read());
+            let inode = self.make_inode(id, Dirty::new(disk_inode));
+            inodes.insert(id, Arc::downgrade(&inode));
+            return inode;
         }
-        // Load if not in set, or is weak ref.
-        let disk_inode = Dirty::new(self.device.load_struct::<DiskINode>(id).unwrap());
-        self._new_inode(id, disk_inode)
+    }
+    /// Ids of the dirs from `dir` up to the root, following '..'.
+    /// Only stable while `rename_lock` is held.
+    fn ancestors(&self, dir: &INodeImpl) -> Vec<INodeId> {
+        let mut path = vec![dir.id];
+        let mut parent = {
+            let _lock = dir.content_lock.read();
+            dir.get_file_inode_id("..")
+        };
+        while let Some(id) = parent.filter(|id| !path.contains(id)) {
+            path.push(id);
+            let dir = self.get_inode(id);
+            let _lock = dir.content_lock.read();
+            parent = dir.get_file_inode_id("..");
+        }
+        path
     }
     /// Create a new INode file
     fn new_inode_file(&self) -> vfs::Result<Arc<INodeImpl>> {
         let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_file());
-        Ok(self._new_inode(id, disk_inode))
+        let mut disk_inode = DiskINode::new_file();
+        if self.extents.load(AtomicOrdering::Relaxed) && self.super_block.read().has_flags() {
+            disk_inode.flags |= INODE_FLAG_EXTENTS;
+        }
+        disk_inode.flags |= self.inline_flag();
+        Ok(self._new_inode(id, Dirty::new_dirty(disk_inode)))
     }
     /// Create a new INode symlink
     fn new_inode_symlink(&self) -> vfs::Result<Arc<INodeImpl>> {
         let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_symlink());
-        Ok(self._new_inode(id, disk_inode))
+        let mut disk_inode = DiskINode::new_symlink();
+        disk_inode.flags |= self.inline_flag();
+        Ok(self._new_inode(id, Dirty::new_dirty(disk_inode)))
+    }
+    /// Flags of new files and symlinks for inline data
+    fn inline_flag(&self) -> u32 {
+        match self.inline_data.load(AtomicOrdering::Relaxed)
+            && self.super_block.read().has_inline_data()
+        {
+            true => INODE_FLAG_INLINE,
+            false => 0,
+        }
     }
     /// Create a new INode dir
     fn new_inode_dir(&self, parent: INodeId) -> vfs::Result<Arc<INodeImpl>> {
@@ -922,55 +1810,112 @@ impl SimpleFileSystem {
         inode.init_direntry(parent)?;
         Ok(inode)
     }
-    /// Create a new INode chardevice
-    pub fn new_inode_chardevice(&self, device_inode_id: usize) -> vfs::Result<Arc<INodeImpl>> {
+    /// Create a new INode of device node, FIFO or socket
+    fn new_inode_special(&self, type_: FileType, rdev: usize) -> vfs::Result<Arc<INodeImpl>> {
         let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_chardevice(device_inode_id));
+        let disk_inode = Dirty::new_dirty(DiskINode::new_special(type_, rdev));
         let new_inode = self._new_inode(id, disk_inode);
         Ok(new_inode)
     }
-    fn flush_weak_inodes(&self) {
-        let mut inodes = self.inodes.write();
-        let remove_ids: Vec<_> = inodes
-            .iter()
-            .filter(|(_, inode)| inode.upgrade().is_none())
-            .map(|(&id, _)| id)
-            .collect();
-        for id in remove_ids.iter() {
-            inodes.remove(id);
+    /// Report how fragmented the files and the free space are
+    pub fn fragmentation(&self) -> vfs::Result<Fragmentation> {
+        let mut report = Fragmentation::default();
+        {
+            let free_map = self.free_map.read();
+            let end = self.super_block.read().blocks as usize;
+            let mut i = 0;
+            while let Some(start) = free_map.next_free(i, end) {
+                i = free_map.run_end(start, end);
+                report.free_blocks += i - start;
+                report.free_extents += 1;
+                report.largest_free_extent = report.largest_free_extent.max(i - start);
+            }
+        }
+        let mut visited = BTreeSet::new();
+        let mut dirs = vec![BLKN_ROOT];
+        while let Some(dir_id) = dirs.pop() {
+            let dir = self.get_inode(dir_id);
+            let _lock = dir.content_lock.read();
+            for i in 0..dir.dir_count()? {
+                let entry = dir.get_direntry(i)?;
+                if [".", ".."].contains(&entry.name.as_ref()) || !visited.insert(entry.id) {
+                    continue;
+                }
+                let inode = self.get_inode(entry.id as INodeId);
+                let _lock = inode.content_lock.read();
+                let (type_, blocks) = {
+                    let disk_inode = inode.disk_inode.read();
+                    (disk_inode.type_, disk_inode.blocks as usize)
+                };
+                match type_ {
+                    FileType::Dir => dirs.push(entry.id as INodeId),
+                    FileType::File => {
+                        let mut extents = 0;
+                        let mut last = None;
+                        for i in 0..blocks {
+                            let block_id = inode.get_disk_block_id(i)?;
+                            if last.map(|last| last + 1) != Some(block_id) {
+                                extents += 1;
+                            }
+                            last = Some(block_id);
+                        }
+                        report.files += 1;
+                        report.file_extents += extents;
+                        if extents > 1 {
+                            report.fragmented_files += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
         }
+        Ok(report)
     }
 }
 
 impl vfs::FileSystem for SimpleFileSystem {
     /// Write back super block if dirty
     fn sync(&self) -> vfs::Result<()> {
-        // order is important, see issue #18
-        let mut free_map = self.free_map.write();
-        let mut super_block = self.super_block.write();
-        if super_block.dirty() {
-            self.device
-                .write_at(BLKSIZE * BLKN_SUPER, super_block.as_buf())?;
-            super_block.sync();
-        }
-        if free_map.dirty() {
-            let data = free_map.as_buf();
-            for i in 0..super_block.freemap_blocks as usize {
-                self.device.write_at(
-                    BLKSIZE * (BLKN_FREEMAP + i),
-                    &data[i * BLKSIZE..(i + 1) * BLKSIZE],
-                )?;
+        let inodes: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        // no operation is half done in the transaction
+        let _op = self.op_lock.write();
+        {
+            // order is important, see issue #18
+            let free_map = self.free_map.read();
+            let mut super_block = self.super_block.write();
+            if let Some(journal) = &self.journal {
+                // reusable once this transaction is committed
+                for block_id in journal.lock().take_freed() {
+                    free_map.set(block_id, true);
+                    self.release_blocks(1);
+                }
             }
-            free_map.sync();
-        }
-        self.flush_weak_inodes();
-        for inode in self.inodes.read().values() {
-            if let Some(inode) = inode.upgrade() {
-                inode.sync_all()?;
+            let unused_blocks = self.unused_blocks.load(AtomicOrdering::Relaxed) as u32;
+            if super_block.unused_blocks != unused_blocks {
+                super_block.unused_blocks = unused_blocks;
+            }
+            let inline_files = self.inline_files.load(AtomicOrdering::Relaxed) as u32;
+            if super_block.inline_files != inline_files {
+                super_block.inline_files = inline_files;
             }
+            if super_block.dirty() {
+                self.write_meta(BLKN_SUPER, 0, super_block.as_buf())?;
+                super_block.sync();
+            }
+            free_map.flush(|i, data| self.write_meta(BLKN_FREEMAP + i, 0, data))?;
+        }
+        for inode in inodes.iter() {
+            inode._sync_all()?;
+        }
+        match &self.journal {
+            Some(journal) => journal.lock().commit(&self.device),
+            None => Ok(self.device.sync()?),
         }
-        self.device.sync()?;
-        Ok(())
     }
 
     fn root_inode(&self) -> Arc<dyn vfs::INode> {
@@ -982,17 +1927,23 @@ impl vfs::FileSystem for SimpleFileSystem {
 
     fn info(&self) -> vfs::FsInfo {
         let sb = self.super_block.read();
+        let unused_blocks = self.unused_blocks.load(AtomicOrdering::Relaxed);
         vfs::FsInfo {
             bsize: BLKSIZE,
             frsize: BLKSIZE,
             blocks: sb.blocks as usize,
-            bfree: sb.unused_blocks as usize,
-            bavail: sb.unused_blocks as usize,
-            files: sb.blocks as usize,        // inaccurate
-            ffree: sb.unused_blocks as usize, // inaccurate
+            bfree: unused_blocks,
+            bavail: unused_blocks,
+            files: sb.blocks as usize, // inaccurate
+            ffree: unused_blocks,      // inaccurate
             namemax: MAX_FNAME_LEN,
+            inline_blocks: self.inline_files.load(AtomicOrdering::Relaxed),
         }
     }
+
+    fn name(&self) -> &'static str {
+        "sfs"
+    }
 }
 
 impl Drop for SimpleFileSystem {
@@ -1003,19 +1954,113 @@ impl Drop for SimpleFileSystem {
     }
 }
 
-trait BitsetAlloc {
-    fn alloc(&mut self) -> Option<usize>;
+/// Access to the blocks of the content of an inode
+struct BlockIo<'a> {
+    fs: &'a SimpleFileSystem,
+    /// whether they are metadata
+    meta: bool,
 }
 
-impl BitsetAlloc for BitVec<Lsb0, u8> {
-    fn alloc(&mut self) -> Option<usize> {
-        // TODO: more efficient
-        let id = (0..self.len()).find(|&i| self[i]);
-        if let Some(id) = id {
-            self.set(id, false);
+impl BlockIo<'_> {
+    fn read_block(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        match self.meta {
+            true => self.fs.read_meta(id, offset, buf),
+            false => self.fs.device.read_block(id, offset, buf),
         }
-        id
     }
+    fn write_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+        match self.meta {
+            true => self.fs.write_meta(id, offset, buf),
+            false => self.fs.device.write_block(id, offset, buf),
+        }
+    }
+}
+
+/// Lock the (depth, dir) for writing, those closer to the root first, then by id.
+/// The same dir may be in twice.
+fn lock_dirs<'a>(mut dirs: Vec<(usize, &'a INodeImpl)>) -> Vec<RwLockWriteGuard<'a, ()>> {
+    dirs.sort_by_key(|&(depth, dir)| (depth, dir.id));
+    dirs.dedup_by_key(|&mut (_, dir)| dir.id);
+    dirs.into_iter()
+        .map(|(_, dir)| dir.content_lock.write())
+        .collect()
+}
+
+/// Hash of names in indexed dirs (FNV-1a)
+fn dir_hash(name: &str) -> u32 {
+    name.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Write a record of indexed dirs to the beginning of `buf`, return its length
+fn write_dir_record(buf: &mut [u8], id: u32, name: &str) -> usize {
+    let rec_len = DiskDirRecord::size_for(name.len());
+    let record = DiskDirRecord {
+        id,
+        rec_len: rec_len as u16,
+        name_len: name.len() as u8,
+        _reserved: 0,
+    };
+    let header_len = record.as_buf().len();
+    buf[..header_len].copy_from_slice(record.as_buf());
+    buf[header_len..header_len + name.len()].copy_from_slice(name.as_bytes());
+    rec_len
+}
+
+/// Iterator over (offset, id, name) of records in a bucket of indexed dirs
+struct DirRecords<'a> {
+    block: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> DirRecords<'a> {
+    fn new(block: &'a [u8], offset: usize) -> Self {
+        DirRecords { block, offset }
+    }
+    /// Offset of the end of records in the bucket
+    fn end(block: &[u8]) -> usize {
+        let mut records = DirRecords::new(block, 0);
+        while records.next().is_some() {}
+        records.offset
+    }
+}
+
+impl<'a> Iterator for DirRecords<'a> {
+    type Item = (usize, u32, &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        if offset + size_of::<DiskDirRecord>() > self.block.len() {
+            return None;
+        }
+        let mut record: DiskDirRecord = unsafe { uninit_memory() };
+        let header_len = record.as_buf().len();
+        record
+            .as_buf_mut()
+            .copy_from_slice(&self.block[offset..offset + header_len]);
+        if record.id == 0 {
+            return None;
+        }
+        self.offset += record.rec_len as usize;
+        let name = &self.block[offset + header_len..offset + header_len + record.name_len as usize];
+        Some((offset, record.id, name))
+    }
+}
+
+/// Number of indirect blocks used by a file of `blocks` blocks
+fn indirect_blocks(blocks: usize) -> usize {
+    let mut count = 0;
+    if blocks >= MAX_NBLOCK_DIRECT {
+        count += 1;
+    }
+    if blocks >= MAX_NBLOCK_INDIRECT {
+        count += 1 + ((blocks - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1).min(BLK_NENTRY);
+    }
+    if blocks > MAX_NBLOCK_DOUBLE_INDIRECT {
+        let blocks = blocks - MAX_NBLOCK_DOUBLE_INDIRECT;
+        count += 1 + blocks.div_ceil(BLK_NENTRY * BLK_NENTRY) + blocks.div_ceil(BLK_NENTRY);
+    }
+    count
 }
 
 impl AsBuf for BitVec<Lsb0, u8> {
@@ -1037,6 +2082,8 @@ impl From<FileType> for vfs::FileType {
             FileType::Dir => vfs::FileType::Dir,
             FileType::CharDevice => vfs::FileType::CharDevice,
             FileType::BlockDevice => vfs::FileType::BlockDevice,
+            FileType::NamedPipe => vfs::FileType::NamedPipe,
+            FileType::Socket => vfs::FileType::Socket,
             _ => panic!("unknown file type"),
         }
     }
@@ -0,0 +1,483 @@
+//! Growing and shrinking SFS
+//!
+//! The freemap is right after the root inode, so growing it takes the blocks after it,
+//! and shrinking takes the blocks at the end. Blocks in the way are vacated first:
+//! they are reserved so that nothing is allocated there, then the journal, data blocks,
+//! indirect blocks and inodes in them are moved elsewhere. Moving an inode changes its id,
+//! so the entries linking to it, its '.' and the '..' of its subdirs are rewritten.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{ops::Range, sync::atomic::Ordering};
+
+use rcore_fs::{
+    dev::Device,
+    dirty::Dirty,
+    util::uninit_memory,
+    vfs::{self, FileSystem, FsError},
+};
+
+use crate::{
+    indirect_blocks, journal::Journal, structs::*, DeviceExt, INodeImpl, SimpleFileSystem,
+};
+
+/// Grow the image on `device` to `space` bytes
+pub fn grow(device: Arc<dyn Device>, space: usize) -> vfs::Result<()> {
+    SimpleFileSystem::open(device)?.grow(space)
+}
+
+/// Shrink the image on `device` to `space` bytes, moving the data out of the end.
+/// Only unmounted images can be shrunk.
+pub fn shrink(device: Arc<dyn Device>, space: usize) -> vfs::Result<()> {
+    SimpleFileSystem::open(device)?.shrink(space)
+}
+
+/// Where the inodes to move are linked
+#[derive(Default)]
+struct Links {
+    /// dirs with entries linking to each inode
+    entries: BTreeMap<INodeId, BTreeSet<INodeId>>,
+    /// subdirs of each dir
+    subdirs: BTreeMap<INodeId, Vec<INodeId>>,
+}
+
+impl SimpleFileSystem {
+    /// Grow to `space` bytes, moving the blocks in the way of the freemap.
+    ///
+    /// Inodes in the way are moved unless they are open, which fails with `FsError::Busy`.
+    pub fn grow(&self, space: usize) -> vfs::Result<()> {
+        let blocks = space.div_ceil(BLKSIZE);
+        let (old_blocks, old_freemap_blocks) = {
+            let super_block = self.super_block.read();
+            (
+                super_block.blocks as usize,
+                super_block.freemap_blocks as usize,
+            )
+        };
+        if blocks < old_blocks {
+            return Err(FsError::InvalidParam);
+        }
+        let freemap_blocks = blocks.div_ceil(BLKBITS);
+        let added = BLKN_FREEMAP + old_freemap_blocks..BLKN_FREEMAP + freemap_blocks;
+        if added.end > blocks {
+            return Err(FsError::InvalidParam);
+        }
+        self.vacate(added.start.min(old_blocks)..added.end.min(old_blocks))?;
+
+        {
+            let mut free_map = self.free_map.write();
+            let mut super_block = self.super_block.write();
+            free_map.resize(freemap_blocks * BLKBITS);
+            for block_id in old_blocks.max(added.end)..blocks {
+                free_map.set(block_id, true);
+            }
+            self.release_blocks(blocks - old_blocks.max(added.end));
+            super_block.blocks = blocks as u32;
+            super_block.freemap_blocks = freemap_blocks as u32;
+            // without a journal, the new freemap must be on disk before the superblock
+            if self.journal.is_none() && !added.is_empty() {
+                free_map.flush(|i, data| self.device.write_block(BLKN_FREEMAP + i, 0, data))?;
+                self.device.sync()?;
+            }
+        }
+        self.sync()
+    }
+    /// Shrink to `space` bytes, moving the blocks at the end elsewhere
+    fn shrink(&self, space: usize) -> vfs::Result<()> {
+        let blocks = space.div_ceil(BLKSIZE);
+        let (old_blocks, old_freemap_blocks) = {
+            let super_block = self.super_block.read();
+            (
+                super_block.blocks as usize,
+                super_block.freemap_blocks as usize,
+            )
+        };
+        if blocks > old_blocks || blocks < 16 || blocks <= BLKN_FREEMAP + old_freemap_blocks {
+            return Err(FsError::InvalidParam);
+        }
+        // fail early if the blocks in use do not fit
+        {
+            let free_map = self.free_map.read();
+            let used = (blocks..old_blocks)
+                .filter(|&i| !free_map.is_free(i))
+                .count();
+            let free = (0..blocks).filter(|&i| free_map.is_free(i)).count();
+            if used > free {
+                return Err(FsError::NoDeviceSpace);
+            }
+        }
+        self.vacate(blocks..old_blocks)?;
+
+        let freemap_blocks = blocks.div_ceil(BLKBITS);
+        let mut free_map = self.free_map.write();
+        let mut super_block = self.super_block.write();
+        for block_id in BLKN_FREEMAP + freemap_blocks..BLKN_FREEMAP + old_freemap_blocks {
+            free_map.set(block_id, true);
+            self.release_blocks(1);
+        }
+        free_map.resize(freemap_blocks * BLKBITS);
+        super_block.blocks = blocks as u32;
+        super_block.freemap_blocks = freemap_blocks as u32;
+        drop(super_block);
+        drop(free_map);
+        self.sync()
+    }
+    /// Move everything out of `range`, leaving the blocks marked in use
+    fn vacate(&self, range: Range<BlockId>) -> vfs::Result<()> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        // frees are applied, inodes are written back
+        self.sync()?;
+        let mut reserved = Vec::new();
+        {
+            // no operation is allocating, so no free block is reserved
+            let _op = self.op_lock.write();
+            let free_map = self.free_map.read();
+            for block_id in range.clone() {
+                if free_map.is_free(block_id) {
+                    free_map.set(block_id, false);
+                    self.unused_blocks.fetch_sub(1, Ordering::Relaxed);
+                    reserved.push(block_id);
+                }
+            }
+        }
+        let mut moved = Vec::new();
+        match self.move_out(&range, &mut moved) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                for &block_id in reserved.iter() {
+                    self._free_block(block_id);
+                }
+                for &block_id in moved.iter() {
+                    self.free_block(block_id);
+                }
+                self.sync()?;
+                Err(err)
+            }
+        }
+    }
+    /// Move the journal and the blocks of inodes out of `range`,
+    /// record the blocks moved from in `moved`
+    fn move_out(&self, range: &Range<BlockId>, moved: &mut Vec<BlockId>) -> vfs::Result<()> {
+        self.move_journal(range, moved)?;
+        // the links found stay in their dirs
+        let _rename = self.rename_lock.lock();
+        let (inodes, links) = self.find_links(range)?;
+        // ids of the inodes moved
+        let mut renamed = BTreeMap::new();
+        for id in inodes {
+            let _op = self.begin_op()?;
+            let inode = self.get_inode(id);
+            if range.contains(&id) && Arc::strong_count(&inode) > 1 {
+                return Err(FsError::Busy);
+            }
+            {
+                let _lock = inode.content_lock.write();
+                inode.relocate(range, moved)?;
+            }
+            if range.contains(&id) {
+                let new_id = self.move_inode(inode, &links, &renamed)?;
+                renamed.insert(id, new_id);
+                moved.push(id);
+            }
+        }
+        self.sync()
+    }
+    /// Move the journal out of `range` if it is there
+    fn move_journal(&self, range: &Range<BlockId>, moved: &mut Vec<BlockId>) -> vfs::Result<()> {
+        let (start, blocks) = {
+            let super_block = self.super_block.read();
+            (
+                super_block.journal_start as usize,
+                super_block.journal_blocks as usize,
+            )
+        };
+        let journal = match &self.journal {
+            Some(journal) if start < range.end && range.start < start + blocks => journal,
+            _ => return Ok(()),
+        };
+        let new_start = {
+            self.reserve_blocks(blocks)?;
+            let free_map = self.free_map.read();
+            let goal = self.alloc_hint.load(Ordering::Relaxed);
+            match free_map.alloc_run(goal, blocks) {
+                Some(run) if run.len() == blocks => run.start,
+                run => {
+                    for block_id in run.into_iter().flatten() {
+                        free_map.set(block_id, true);
+                    }
+                    self.release_blocks(blocks);
+                    return Err(FsError::NoDeviceSpace);
+                }
+            }
+        };
+        // the old journal is in use until the superblock pointing to the new one is committed
+        *journal.lock() = Journal::format(&self.device, new_start, blocks)?;
+        self.super_block.write().journal_start = new_start as u32;
+        for block_id in start..start + blocks {
+            match range.contains(&block_id) {
+                true => moved.push(block_id),
+                false => self.free_block(block_id),
+            }
+        }
+        self.sync()
+    }
+    /// Find the inodes in use, and where those in `range` are linked
+    fn find_links(&self, range: &Range<BlockId>) -> vfs::Result<(Vec<INodeId>, Links)> {
+        let mut links = Links::default();
+        let mut visited = BTreeSet::new();
+        visited.insert(BLKN_ROOT);
+        let mut dirs = vec![BLKN_ROOT];
+        while let Some(dir_id) = dirs.pop() {
+            let dir = self.get_inode(dir_id);
+            let _lock = dir.content_lock.read();
+            for i in 2..dir.dir_count()? {
+                let entry = dir.get_direntry(i)?;
+                let id = entry.id as INodeId;
+                if range.contains(&id) {
+                    links.entries.entry(id).or_default().insert(dir_id);
+                }
+                if !visited.insert(id) {
+                    continue;
+                }
+                if self.get_inode(id).disk_inode.read().type_ == FileType::Dir {
+                    dirs.push(id);
+                    if range.contains(&dir_id) {
+                        links.subdirs.entry(dir_id).or_default().push(id);
+                    }
+                }
+            }
+        }
+        // and those unlinked but still open
+        let open: Vec<INodeId> = self
+            .inodes
+            .read()
+            .iter()
+            .filter(|(_, inode)| inode.upgrade().is_some())
+            .map(|(&id, _)| id)
+            .collect();
+        visited.extend(open);
+        Ok((visited.into_iter().collect(), links))
+    }
+    /// Copy the inode to a new block, and link the new one in place of it. Return its id.
+    ///
+    /// The dirs linking to it are locked throughout, since a lookup meanwhile would load
+    /// the old block, which is freed afterwards. It fails with `FsError::Busy` if the
+    /// inode is open.
+    fn move_inode(
+        &self,
+        inode: Arc<INodeImpl>,
+        links: &Links,
+        renamed: &BTreeMap<INodeId, INodeId>,
+    ) -> vfs::Result<INodeId> {
+        let renamed = |id: INodeId| *renamed.get(&id).unwrap_or(&id);
+        let id = inode.id;
+        // the dirs with entries to it, and the subdirs with '..' to it
+        let mut dirs = BTreeMap::new();
+        let entries = links.entries.get(&id).into_iter().flatten();
+        let subdirs = links.subdirs.get(&id).into_iter().flatten();
+        for &dir_id in entries.chain(subdirs) {
+            let dir_id = renamed(dir_id);
+            dirs.entry(dir_id).or_insert_with(|| self.get_inode(dir_id));
+        }
+        // all or none, since others may hold one of them and wait for another
+        let _locks = loop {
+            let locks: Vec<_> = dirs
+                .values()
+                .map(|dir| dir.content_lock.try_write())
+                .collect();
+            if locks.iter().all(Option::is_some) {
+                break locks;
+            }
+            drop(locks);
+            core::hint::spin_loop();
+        };
+        if Arc::strong_count(&inode) > 1 {
+            return Err(FsError::Busy);
+        }
+        let new_id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+        let mut disk_inode: DiskINode = unsafe { uninit_memory() };
+        {
+            let mut old = inode.disk_inode.write();
+            disk_inode.as_buf_mut().copy_from_slice(old.as_buf());
+            // the old block is left as it is
+            old.sync();
+        }
+        drop(inode);
+        self.inodes.write().remove(&id);
+        let inode = self._new_inode(new_id, Dirty::new_dirty(disk_inode));
+        let size = inode.disk_inode.read().size();
+        if inode.disk_inode.read().has_inline_data() && size > 0 {
+            let mut data = vec![0u8; size];
+            self.read_meta(id, INLINE_DATA_OFFSET, &mut data)?;
+            self.write_meta(new_id, INLINE_DATA_OFFSET, &data)?;
+        }
+        if inode.disk_inode.read().type_ == FileType::Dir {
+            if !inode.disk_inode.read().has_dir_index() {
+                inode.write_direntry(
+                    0,
+                    &DiskEntry {
+                        id: new_id as u32,
+                        name: Str256::from("."),
+                    },
+                )?;
+            }
+            for &subdir in links.subdirs.get(&id).into_iter().flatten() {
+                dirs[&renamed(subdir)].set_parent(new_id)?;
+            }
+        }
+        for &dir_id in links.entries.get(&id).into_iter().flatten() {
+            let dir = &dirs[&renamed(dir_id)];
+            // the entries may have been renamed in the dir since they were found
+            let mut names = Vec::new();
+            for i in 2..dir.dir_count()? {
+                let entry = dir.get_direntry(i)?;
+                if entry.id as INodeId == id {
+                    names.push(String::from(entry.name.as_ref()));
+                }
+            }
+            for name in names {
+                let (_, entry_id) = dir.get_file_inode_and_entry_id(&name).unwrap();
+                dir.rename_direntry(entry_id, new_id, &name)?;
+            }
+        }
+        Ok(new_id)
+    }
+    /// Copy the block to a new one if it is in `range`, return where it is now
+    fn relocate_block(
+        &self,
+        block_id: BlockId,
+        range: &Range<BlockId>,
+        meta: bool,
+        moved: &mut Vec<BlockId>,
+    ) -> vfs::Result<BlockId> {
+        if !range.contains(&block_id) {
+            return Ok(block_id);
+        }
+        let new_id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
+        let mut buf = [0u8; BLKSIZE];
+        if meta {
+            self.read_meta(block_id, 0, &mut buf)?;
+            self.write_meta(new_id, 0, &buf)?;
+        } else {
+            // the last block of an image file may be partial
+            self.device.read_at(block_id * BLKSIZE, &mut buf)?;
+            self.device.write_block(new_id, 0, &buf)?;
+        }
+        moved.push(block_id);
+        Ok(new_id)
+    }
+}
+
+impl INodeImpl {
+    /// Move the data blocks and indirect blocks in `range` elsewhere,
+    /// record the blocks moved from in `moved`
+    fn relocate(&self, range: &Range<BlockId>, moved: &mut Vec<BlockId>) -> vfs::Result<()> {
+        if self.disk_inode.read().has_extents() && !self.relocate_extents(range, moved)? {
+            return Ok(());
+        }
+        let fs = &self.fs;
+        let blocks = self.disk_inode.read().blocks as usize;
+        if blocks >= MAX_NBLOCK_DIRECT {
+            let indirect = self.disk_inode.read().indirect as BlockId;
+            let indirect = fs.relocate_block(indirect, range, true, moved)?;
+            self.disk_inode.write().indirect = indirect as u32;
+        }
+        if blocks >= MAX_NBLOCK_INDIRECT {
+            let db_indirect = self.disk_inode.read().db_indirect as BlockId;
+            let db_indirect = fs.relocate_block(db_indirect, range, true, moved)?;
+            self.disk_inode.write().db_indirect = db_indirect as u32;
+            let indirects = ((blocks - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1).min(BLK_NENTRY);
+            self.relocate_entries(db_indirect as u32, indirects, range, moved)?;
+        }
+        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT {
+            let rest = blocks - MAX_NBLOCK_DOUBLE_INDIRECT;
+            let tr_indirect = self.disk_inode.read().tr_indirect as BlockId;
+            let tr_indirect = fs.relocate_block(tr_indirect, range, true, moved)? as u32;
+            self.disk_inode.write().tr_indirect = tr_indirect;
+            let db_indirects = rest.div_ceil(BLK_NENTRY * BLK_NENTRY);
+            self.relocate_entries(tr_indirect, db_indirects, range, moved)?;
+            for i in 0..db_indirects {
+                let db_indirect = self.read_entry(tr_indirect, i)?;
+                let indirects = (rest - i * BLK_NENTRY * BLK_NENTRY)
+                    .div_ceil(BLK_NENTRY)
+                    .min(BLK_NENTRY);
+                self.relocate_entries(db_indirect, indirects, range, moved)?;
+            }
+        }
+        let meta = self.disk_inode.read().type_ == FileType::Dir;
+        for i in 0..blocks {
+            let block_id = self.get_disk_block_id(i)?;
+            if range.contains(&block_id) {
+                let block_id = fs.relocate_block(block_id, range, meta, moved)?;
+                self.set_disk_block_id(i, block_id)?;
+            }
+        }
+        Ok(())
+    }
+    /// Move the blocks in `range` of the first `count` entries of the indirect block
+    fn relocate_entries(
+        &self,
+        block_id: u32,
+        count: usize,
+        range: &Range<BlockId>,
+        moved: &mut Vec<BlockId>,
+    ) -> vfs::Result<()> {
+        for i in 0..count {
+            let entry = self.read_entry(block_id, i)?;
+            let new_entry = self
+                .fs
+                .relocate_block(entry as BlockId, range, true, moved)?;
+            if new_entry != entry as BlockId {
+                self.write_entry(block_id, i, new_entry as u32)?;
+            }
+        }
+        Ok(())
+    }
+    /// Move the extents overlapping `range` to new runs.
+    /// Return true if it is converted to block lists, since no run is long enough.
+    fn relocate_extents(
+        &self,
+        range: &Range<BlockId>,
+        moved: &mut Vec<BlockId>,
+    ) -> vfs::Result<bool> {
+        for i in 0..NEXTENT {
+            let (start, len) = self.disk_inode.read().extent(i);
+            let extent = start as BlockId..(start + len) as BlockId;
+            if extent.is_empty() || extent.end <= range.start || range.end <= extent.start {
+                continue;
+            }
+            let goal = self.fs.alloc_hint.load(Ordering::Relaxed);
+            let runs = self.fs.alloc_blocks(goal, extent.len())?;
+            if runs.len() > 1 {
+                for block_id in runs.into_iter().flatten() {
+                    self.fs._free_block(block_id);
+                }
+                let blocks = self.disk_inode.read().blocks as usize;
+                if indirect_blocks(blocks) > self.fs.unused_blocks.load(Ordering::Relaxed) {
+                    return Err(FsError::NoDeviceSpace);
+                }
+                self.extents_to_blocks()?;
+                return Ok(true);
+            }
+            let run = runs[0].clone();
+            let mut buf = [0u8; BLKSIZE];
+            for (old, new) in extent.zip(run.clone()) {
+                self.fs.device.read_at(old * BLKSIZE, &mut buf)?;
+                self.fs.device.write_block(new, 0, &buf)?;
+                match range.contains(&old) {
+                    true => moved.push(old),
+                    false => self.fs.free_block(old),
+                }
+            }
+            self.disk_inode.write().set_extent(i, run.start as u32, len);
+        }
+        Ok(false)
+    }
+}
@@ -1,6 +1,5 @@
 //! On-disk structures in SFS
 
-use crate::vfs;
 use alloc::str;
 
 use core::fmt::{Debug, Error, Formatter};
@@ -13,7 +12,7 @@ use static_assertions::const_assert;
 #[repr(C)]
 #[derive(Debug)]
 pub struct SuperBlock {
-    /// magic number, should be SFS_MAGIC
+    /// magic number, MAGIC_VERSIONED, or MAGIC for images before version 1
     pub magic: u32,
     /// number of blocks in fs
     pub blocks: u32,
@@ -23,13 +22,23 @@ pub struct SuperBlock {
     pub info: Str32,
     /// number of freemap blocks
     pub freemap_blocks: u32,
+    /// version of the on-disk format, only valid with `MAGIC_VERSIONED`
+    pub version: u32,
+    /// optional features (FEATURE_*), only valid since version 5
+    pub features: u32,
+    /// first block of the journal, only valid with `FEATURE_JOURNAL`
+    pub journal_start: u32,
+    /// number of blocks of the journal, only valid with `FEATURE_JOURNAL`
+    pub journal_blocks: u32,
+    /// number of files and symlinks with data stored inline, only valid since version 7
+    pub inline_files: u32,
 }
 
 /// inode (on disk)
 #[repr(C)]
 #[derive(Debug)]
 pub struct DiskINode {
-    /// size of the file (in bytes)
+    /// size of the file (in bytes), low 32 bits
     /// undefined in dir (256 * #entries ?)
     pub size: u32,
     /// one of SYS_TYPE_* above
@@ -39,36 +48,56 @@ pub struct DiskINode {
     pub nlinks: u16,
     /// number of blocks
     pub blocks: u32,
-    /// direct blocks
+    /// direct blocks, or (start, length) pairs of extents with `INODE_FLAG_EXTENTS`
     pub direct: [u32; NDIRECT],
     /// indirect blocks
     pub indirect: u32,
     /// double indirect blocks
     pub db_indirect: u32,
-    /// device inode id for char/block device (major, minor)
-    pub device_inode_id: usize,
+    /// device number of char/block devices, made by `vfs::make_rdev`, or NODEVICE
+    pub rdev: usize,
     /// Time of last access
     pub atime: Timespec,
     /// Time of last modification
     pub mtime: Timespec,
     /// Time of last change
     pub ctime: Timespec,
+    /// permission, only valid since version 1
+    pub mode: u16,
+    /// owner, only valid since version 1
+    pub uid: u32,
+    /// group, only valid since version 1
+    pub gid: u32,
+    /// triple indirect blocks, only valid since version 2
+    pub tr_indirect: u32,
+    /// high 32 bits of the size, only valid since version 2
+    pub size_hi: u32,
+    /// INODE_FLAG_*, only valid since version 3
+    pub flags: u32,
 }
 
-/*
-pub trait DeviceINode : Any + Sync + Send{
-    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> vfs::Result<usize>;
-    fn write_at(&self, _offset: usize, buf: &[u8]) -> vfs::Result<usize>;
-}
-*/
-
-pub type DeviceINode = dyn vfs::INode;
-
 #[repr(C)]
 pub struct IndirectBlock {
     pub entries: [u32; BLK_NENTRY],
 }
 
+/// header of the journal (on disk), in its first block
+///
+/// It is followed by the descriptor blocks, listing where the logged blocks belong,
+/// then the logged blocks.
+#[repr(C)]
+#[derive(Debug)]
+pub struct JournalHeader {
+    /// magic number, should be JOURNAL_MAGIC
+    pub magic: u32,
+    /// sequence number of the last committed transaction
+    pub sequence: u32,
+    /// number of blocks logged by the committed transaction, 0 if it is in place
+    pub blocks: u32,
+    /// checksum of the descriptor entries and the logged blocks
+    pub checksum: u32,
+}
+
 /// file entry (on disk)
 #[repr(C)]
 #[derive(Debug)]
@@ -79,6 +108,35 @@ pub struct DiskEntry {
     pub name: Str256,
 }
 
+/// header of indexed dirs (on disk), at the beginning of the first block
+///
+/// Entries other than '.' and '..' are hashed into `buckets` blocks after it.
+#[repr(C)]
+#[derive(Debug)]
+pub struct DiskDirHeader {
+    /// number of entries, including '.' and '..'
+    pub count: u32,
+    /// number of bucket blocks, 0 or a power of 2
+    pub buckets: u32,
+    /// inode number of '..'
+    pub parent: u32,
+}
+
+/// entry in a bucket of indexed dirs (on disk), followed by the name
+///
+/// Entries are packed from the beginning of the bucket, the first one with id 0 ends it.
+#[repr(C)]
+#[derive(Debug)]
+pub struct DiskDirRecord {
+    /// inode number
+    pub id: u32,
+    /// length of this record, including the name and padding
+    pub rec_len: u16,
+    /// length of the name
+    pub name_len: u8,
+    pub _reserved: u8,
+}
+
 #[repr(C)]
 pub struct Str256(pub [u8; 256]);
 
@@ -129,11 +187,118 @@ impl<'a> From<&'a str> for Str32 {
 
 impl SuperBlock {
     pub fn check(&self) -> bool {
-        self.magic == MAGIC
+        match self.magic {
+            MAGIC => true,
+            MAGIC_VERSIONED => (1..=VERSION).contains(&self.version),
+            _ => false,
+        }
+    }
+    /// Whether mode, uid and gid are stored in inodes
+    pub fn has_owner(&self) -> bool {
+        self.version >= 1
+    }
+    /// Whether triple indirect blocks and 64-bit size are supported
+    pub fn has_large_file(&self) -> bool {
+        self.version >= 2
+    }
+    /// Whether inodes have flags, which make extents possible
+    pub fn has_flags(&self) -> bool {
+        self.version >= 3
+    }
+    /// Whether dirs may be indexed
+    pub fn has_dir_index(&self) -> bool {
+        self.version >= 4
+    }
+    /// Whether the superblock has the `features` field
+    pub fn has_features(&self) -> bool {
+        self.version >= 5
+    }
+    /// Whether FIFOs and sockets may be stored
+    pub fn has_special_files(&self) -> bool {
+        self.version >= 6
+    }
+    /// Whether small files and symlinks may be stored in their inode block
+    pub fn has_inline_data(&self) -> bool {
+        self.version >= 7
+    }
+    /// Whether metadata is written through a journal
+    pub fn has_journal(&self) -> bool {
+        self.has_features() && self.features & FEATURE_JOURNAL != 0
+    }
+    /// Clear the fields which the image has no space for, the content may be garbage
+    pub fn sanitize(&mut self) {
+        if self.magic == MAGIC {
+            self.version = 0;
+        }
+        if !self.has_features() {
+            self.features = 0;
+            self.journal_start = 0;
+            self.journal_blocks = 0;
+        }
+        if !self.has_inline_data() {
+            self.inline_files = 0;
+        }
+    }
+    /// Max file size of this version
+    pub fn max_file_size(&self) -> u64 {
+        if self.has_large_file() {
+            MAX_FILE_SIZE
+        } else {
+            MAX_FILE_SIZE_V1
+        }
+    }
+    /// Max file type of this version, inodes of greater types are invalid
+    pub fn max_file_type(&self) -> FileType {
+        if self.has_special_files() {
+            FileType::Socket
+        } else {
+            FileType::BlockDevice
+        }
     }
 }
 
 impl DiskINode {
+    /// Size of the file (in bytes)
+    pub fn size(&self) -> usize {
+        (self.size as u64 | (self.size_hi as u64) << 32) as usize
+    }
+    pub fn set_size(&mut self, size: usize) {
+        self.size = size as u32;
+        self.size_hi = ((size as u64) >> 32) as u32;
+    }
+    /// Clear the fields which the image has no space for, the content may be garbage
+    pub fn sanitize(&mut self, super_block: &SuperBlock) {
+        if !super_block.has_large_file() {
+            self.tr_indirect = 0;
+            self.size_hi = 0;
+        }
+        if !super_block.has_flags() {
+            self.flags = 0;
+        }
+        if !super_block.has_inline_data() {
+            self.flags &= !INODE_FLAG_INLINE;
+        }
+    }
+    /// Whether blocks are mapped by extents instead of block lists
+    pub fn has_extents(&self) -> bool {
+        self.flags & INODE_FLAG_EXTENTS != 0
+    }
+    /// Whether entries of the dir are hashed into buckets
+    pub fn has_dir_index(&self) -> bool {
+        self.flags & INODE_FLAG_INDEXED != 0
+    }
+    /// Whether the content is stored in the inode block, from `INLINE_DATA_OFFSET` on
+    pub fn has_inline_data(&self) -> bool {
+        self.flags & INODE_FLAG_INLINE != 0
+    }
+    /// Get the (start, length) of the extent in slot `i`, length is 0 if unused
+    pub fn extent(&self, i: usize) -> (u32, u32) {
+        (self.direct[i * 2], self.direct[i * 2 + 1])
+    }
+    pub fn set_extent(&mut self, i: usize, start: u32, len: u32) {
+        self.direct[i * 2] = start;
+        self.direct[i * 2 + 1] = len;
+    }
     pub const fn new_file() -> Self {
         DiskINode {
             size: 0,
@@ -143,10 +308,16 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id: NODEVICE,
+            rdev: NODEVICE,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: DEFAULT_MODE,
+            uid: 0,
+            gid: 0,
+            tr_indirect: 0,
+            size_hi: 0,
+            flags: 0,
         }
     }
     pub const fn new_symlink() -> Self {
@@ -158,10 +329,16 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id: NODEVICE,
+            rdev: NODEVICE,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: DEFAULT_MODE,
+            uid: 0,
+            gid: 0,
+            tr_indirect: 0,
+            size_hi: 0,
+            flags: 0,
         }
     }
     pub const fn new_dir() -> Self {
@@ -173,25 +350,38 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id: NODEVICE,
+            rdev: NODEVICE,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: DEFAULT_MODE,
+            uid: 0,
+            gid: 0,
+            tr_indirect: 0,
+            size_hi: 0,
+            flags: 0,
         }
     }
-    pub const fn new_chardevice(device_inode_id: usize) -> Self {
+    /// Create a device node, FIFO or socket, which has no blocks
+    pub const fn new_special(type_: FileType, rdev: usize) -> Self {
         DiskINode {
             size: 0,
-            type_: FileType::CharDevice,
+            type_,
             nlinks: 0,
             blocks: 0,
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id,
+            rdev,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            mode: DEFAULT_MODE,
+            uid: 0,
+            gid: 0,
+            tr_indirect: 0,
+            size_hi: 0,
+            flags: 0,
         }
     }
 }
@@ -212,6 +402,21 @@ impl AsBuf for DiskINode {}
 
 impl AsBuf for DiskEntry {}
 
+impl AsBuf for IndirectBlock {}
+
+impl AsBuf for JournalHeader {}
+
+impl AsBuf for DiskDirHeader {}
+
+impl AsBuf for DiskDirRecord {}
+
+impl DiskDirRecord {
+    /// Length of the record for a name of `name_len` bytes, aligned to 4 bytes
+    pub const fn size_for(name_len: usize) -> usize {
+        (size_of::<DiskDirRecord>() + name_len + 3) & !3
+    }
+}
+
 impl AsBuf for u32 {}
 
 /*
@@ -223,23 +428,59 @@ pub type INodeId = BlockId;
 
 pub const NODEVICE: usize = 100;
 
-/// magic number for sfs
+/// magic number for sfs images before version 1, which have no version field
 pub const MAGIC: u32 = 0x2f8dbe2b;
+/// magic number for sfs images since version 1
+pub const MAGIC_VERSIONED: u32 = 0x2f8dbe2c;
+/// current version of the on-disk format
+/// 1: add mode, uid and gid to inode
+/// 2: add triple indirect blocks and 64-bit size to inode
+/// 3: add flags to inode
+/// 4: add indexed dirs
+/// 5: add features and journal to superblock
+/// 6: add FIFOs and sockets
+/// 7: add inline data
+pub const VERSION: u32 = 7;
+/// metadata is written through a journal
+pub const FEATURE_JOURNAL: u32 = 1;
+/// magic number of the journal
+pub const JOURNAL_MAGIC: u32 = 0x4a4e4c53;
+/// min number of blocks of the journal: header, a descriptor and a logged block
+pub const MIN_JOURNAL_BLOCKS: usize = 3;
+/// permission of inodes in images before version 1
+pub const DEFAULT_MODE: u16 = 0o777;
 /// size of block
 pub const BLKSIZE: usize = 1usize << BLKSIZE_LOG2;
 /// log2( size of block )
 pub const BLKSIZE_LOG2: u8 = 12;
 /// number of direct blocks in inode
 pub const NDIRECT: usize = 12;
+/// number of extents in inode
+pub const NEXTENT: usize = NDIRECT / 2;
+/// blocks of the inode are mapped by extents
+pub const INODE_FLAG_EXTENTS: u32 = 1;
+/// entries of the dir are hashed into buckets
+pub const INODE_FLAG_INDEXED: u32 = 2;
+/// content of the file or symlink is stored in the inode block
+pub const INODE_FLAG_INLINE: u32 = 4;
+/// offset of inline data in the inode block, leaving room for the inode to grow
+pub const INLINE_DATA_OFFSET: usize = 256;
+/// max size of inline data
+pub const MAX_INLINE_DATA: usize = BLKSIZE - INLINE_DATA_OFFSET;
+/// number of entries at which dirs get indexed
+pub const DIR_INDEX_THRESHOLD: usize = BLKSIZE / DIRENT_SIZE;
+/// max number of buckets in indexed dirs
+pub const MAX_DIR_BUCKETS: usize = 1 << 16;
 /// default sfs infomation string
 pub const DEFAULT_INFO: &str = "simple file system";
 /// max length of infomation
 pub const MAX_INFO_LEN: usize = 31;
 /// max length of filename
 pub const MAX_FNAME_LEN: usize = 255;
-/// max file size in theory (48KB + 4MB + 4GB)
-/// however, the file size is stored in u32
-pub const MAX_FILE_SIZE: usize = 0xffffffff;
+/// max file size in theory (48KB + 4MB + 4GB + 4TB)
+pub const MAX_FILE_SIZE: u64 = MAX_NBLOCK_TRIPLE_INDIRECT as u64 * BLKSIZE as u64;
+/// max file size before version 2, the file size was stored in u32
+pub const MAX_FILE_SIZE_V1: u64 = 0xffffffff;
 /// block the superblock lives in
 pub const BLKN_SUPER: BlockId = 0;
 /// location of the root dir inode
@@ -260,6 +501,9 @@ pub const MAX_NBLOCK_DIRECT: usize = NDIRECT;
 pub const MAX_NBLOCK_INDIRECT: usize = NDIRECT + BLK_NENTRY;
 /// max number of blocks with double indirect blocks
 pub const MAX_NBLOCK_DOUBLE_INDIRECT: usize = NDIRECT + BLK_NENTRY + BLK_NENTRY * BLK_NENTRY;
+/// max number of blocks with triple indirect blocks
+pub const MAX_NBLOCK_TRIPLE_INDIRECT: usize =
+    MAX_NBLOCK_DOUBLE_INDIRECT + BLK_NENTRY * BLK_NENTRY * BLK_NENTRY;
 
 /// file types
 #[repr(u16)]
@@ -271,10 +515,15 @@ pub enum FileType {
     SymLink = 3,
     CharDevice = 4,
     BlockDevice = 5,
+    NamedPipe = 6,
+    Socket = 7,
 }
 
 const_assert!(size_of::<SuperBlock>() <= BLKSIZE);
-const_assert!(size_of::<DiskINode>() <= BLKSIZE);
+const_assert!(size_of::<DiskINode>() <= INLINE_DATA_OFFSET);
 const_assert!(size_of::<DiskEntry>() <= BLKSIZE);
+const_assert!(size_of::<JournalHeader>() <= BLKSIZE);
+const_assert!(size_of::<DiskDirHeader>() <= BLKSIZE);
+const_assert!(size_of::<DiskDirRecord>() == 8);
 const_assert!(size_of::<IndirectBlock>() == BLKSIZE);
 const_assert!(DEFAULT_INFO.len() <= MAX_INFO_LEN);
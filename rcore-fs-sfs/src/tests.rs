@@ -3,11 +3,15 @@ extern crate std;
 use crate::*;
 use rcore_fs::{
     util::uninit_memory,
-    vfs::{FileSystem, FileType, Metadata, Result, Timespec},
+    vfs::{make_rdev, FileSystem, FileType, Metadata, Result, Timespec},
 };
+use rcore_fs_testsuite::{crash, model, Features};
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fs::{self, OpenOptions},
-    sync::{Arc, Mutex},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
 };
 
 fn _open_sample_file() -> Arc<SimpleFileSystem> {
@@ -60,7 +64,7 @@ fn create_file() -> Result<()> {
             gid: 0,
             blk_size: 4096,
             dev: 0,
-            rdev: 100, // dummo why 100 here, maybe legacy data?
+            rdev: 0,
         }
     );
 
@@ -329,6 +333,253 @@ fn test_double_indirect_blocks() -> Result<()> {
     Ok(())
 }
 
+#[test]
+#[ignore] // needs more than 4 GiB of disk space
+fn test_triple_indirect_blocks() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let space = (MAX_NBLOCK_DOUBLE_INDIRECT + 4 * BLK_NENTRY) * BLKSIZE;
+    let sfs = SimpleFileSystem::create(device.clone(), space)?;
+    let unused_blocks = sfs.info().bfree;
+    let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+
+    // force usage of triple indirect block
+    let size = MAX_NBLOCK_DOUBLE_INDIRECT * BLKSIZE;
+    file1.resize(size)?;
+    file1.write_at(size, b"hello")?;
+    assert_eq!(file1.metadata()?.size, size + 5);
+    // and of a second indirect block under it
+    file1.write_at(size + (BLK_NENTRY + 1) * BLKSIZE, b"world")?;
+    check_blocks(&sfs).unwrap();
+    drop(file1);
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    let file1 = sfs.root_inode().find("file1")?;
+    let mut buf = [0u8; 5];
+    file1.read_at(size, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    file1.read_at(size + (BLK_NENTRY + 1) * BLKSIZE, &mut buf)?;
+    assert_eq!(&buf, b"world");
+    assert_eq!(
+        file1.metadata()?.size,
+        size + (BLK_NENTRY + 1) * BLKSIZE + 5
+    );
+
+    // resize up and down
+    file1.resize(size + BLKSIZE)?;
+    check_blocks(&sfs).unwrap();
+    file1.resize(size)?;
+    check_blocks(&sfs).unwrap();
+    file1.resize(0)?;
+    assert_eq!(sfs.info().bfree, unused_blocks - 1);
+    Ok(())
+}
+
+#[test]
+fn file_too_big() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 4096 * 4096)?;
+    let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+    let unused_blocks = sfs.info().bfree;
+    assert_eq!(
+        file1.resize(MAX_FILE_SIZE as usize + 1),
+        Err(FsError::FileTooBig)
+    );
+    assert_eq!(file1.write_at(usize::MAX, b"x"), Err(FsError::FileTooBig));
+    // fits in the format, but not on the device
+    assert_eq!(file1.resize(1 << 32), Err(FsError::NoDeviceSpace));
+    assert_eq!(sfs.info().bfree, unused_blocks);
+    assert_eq!(file1.metadata()?.size, 0);
+    drop(file1);
+    sfs.sync()?;
+    drop(sfs);
+
+    // images before version 2 store the size in u32
+    const VERSION_OFFSET: usize = 48;
+    device.write_at(VERSION_OFFSET, &1u32.to_ne_bytes())?;
+    let sfs = SimpleFileSystem::open(device)?;
+    let file1 = sfs.root_inode().find("file1")?;
+    assert_eq!(file1.resize(1 << 32), Err(FsError::FileTooBig));
+    file1.resize(4096)?;
+    assert_eq!(file1.metadata()?.size, 4096);
+    Ok(())
+}
+
+#[test]
+fn contiguous_allocation() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    file1.resize(100 * BLKSIZE)?;
+    file2.resize(BLKSIZE)?;
+    // grows in place while the blocks after it are free
+    file2.resize(200 * BLKSIZE)?;
+    let report = sfs.fragmentation()?;
+    assert_eq!(report.files, 2);
+    assert_eq!(report.file_extents, 2);
+    assert_eq!(report.fragmented_files, 0);
+    assert_eq!(report.free_extents, 1);
+
+    // freeing file1 leaves a hole for its inode and one for its data
+    drop(file1);
+    root.unlink("file1")?;
+    let report = sfs.fragmentation()?;
+    assert_eq!(report.files, 1);
+    assert_eq!(report.free_extents, 3);
+    assert_eq!(report.free_blocks, sfs.info().bfree);
+    check_blocks(&sfs).unwrap();
+    Ok(())
+}
+
+#[test]
+fn extents() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let unused_blocks = sfs.info().bfree;
+    sfs.set_extents(true);
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o777)?;
+    let file2 = root.create("file2", FileType::File, 0o777)?;
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    assert!(sfs
+        .get_inode(file1.metadata()?.inode)
+        .disk_inode
+        .read()
+        .has_extents());
+    assert!(!sfs
+        .get_inode(dir.metadata()?.inode)
+        .disk_inode
+        .read()
+        .has_extents());
+
+    // more blocks than block lists could hold directly in one extent
+    file1.resize(100 * BLKSIZE)?;
+    file1.write_at(99 * BLKSIZE, b"extent")?;
+    assert_eq!(sfs.fragmentation()?.file_extents, 1);
+    check_blocks(&sfs).unwrap();
+
+    // interleaved appends fill up all extents, then fall back to block lists
+    for i in 0..2 * NEXTENT {
+        file1.resize((101 + i) * BLKSIZE)?;
+        file2.resize((i + 1) * BLKSIZE)?;
+        file1.write_at((100 + i) * BLKSIZE, &[i as u8])?;
+    }
+    let file1_inode = sfs.get_inode(file1.metadata()?.inode);
+    assert!(!file1_inode.disk_inode.read().has_extents());
+    check_blocks(&sfs).unwrap();
+    let mut buf = [0u8; 6];
+    file1.read_at(99 * BLKSIZE, &mut buf)?;
+    assert_eq!(&buf, b"extent");
+    for i in 0..2 * NEXTENT {
+        file1.read_at((100 + i) * BLKSIZE, &mut buf[..1])?;
+        assert_eq!(buf[0], i as u8);
+    }
+
+    // shrink across extents
+    file2.resize(2 * BLKSIZE + 1)?;
+    assert_eq!(file2.metadata()?.blocks, 3);
+    check_blocks(&sfs).unwrap();
+    drop(file1_inode);
+    drop(file1);
+    drop(file2);
+    drop(dir);
+    root.unlink("file1")?;
+    root.unlink("file2")?;
+    root.unlink("dir")?;
+    sfs.sync()?;
+    assert_eq!(sfs.info().bfree, unused_blocks);
+    Ok(())
+}
+
+#[test]
+fn indexed_dirs() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let is_indexed = |sfs: &Arc<SimpleFileSystem>, dir: &Arc<dyn INode>| {
+        let id = dir.metadata().unwrap().inode;
+        sfs.get_inode(id).disk_inode.read().has_dir_index()
+    };
+    let name = |i: usize| format!("{}{}", "x".repeat(i % 200), i);
+
+    // small dirs are not indexed
+    for i in 0..DIR_INDEX_THRESHOLD - 2 {
+        dir1.create(&name(i), FileType::File, 0o777)?;
+    }
+    assert!(!is_indexed(&sfs, &dir1));
+    for i in DIR_INDEX_THRESHOLD - 2..1000 {
+        dir1.create(&name(i), FileType::File, 0o777)?;
+    }
+    assert!(is_indexed(&sfs, &dir1));
+    assert_eq!(dir1.lookup("..")?.metadata()?.inode, root.metadata()?.inode);
+    assert_eq!(
+        dir1.create(&name(10), FileType::File, 0o777).err(),
+        Some(FsError::EntryExist)
+    );
+    let names = dir1.list()?;
+    assert_eq!(names.len(), 1002);
+    assert_eq!(names[..2], [".", ".."]);
+    let expected: BTreeSet<_> = (0..1000).map(name).collect();
+    assert_eq!(
+        names[2..].iter().cloned().collect::<BTreeSet<_>>(),
+        expected
+    );
+
+    // remove, rename and move entries
+    for i in (0..1000).step_by(2) {
+        dir1.unlink(&name(i))?;
+    }
+    dir1.move_(&name(1), &dir1, "renamed")?;
+    let dir2 = root.create("dir2", FileType::Dir, 0o777)?;
+    dir1.move_(&name(3), &dir2, "moved")?;
+    root.move_("dir2", &dir1, "dir2")?;
+    assert_eq!(dir2.lookup("..")?.metadata()?.inode, dir1.metadata()?.inode);
+    assert_eq!(dir1.list()?.len(), 2 + 500);
+    assert_eq!(dir1.find(&name(0)).err(), Some(FsError::EntryNotFound));
+    assert_eq!(dir1.find(&name(1)).err(), Some(FsError::EntryNotFound));
+    dir1.find("renamed")?;
+    dir1.lookup("dir2/moved")?;
+    for i in (5..1000).step_by(2) {
+        dir1.find(&name(i))?;
+    }
+    assert_eq!(dir1.metadata()?.nlinks, 3);
+    check_blocks(&sfs).unwrap();
+
+    // without index, or on older images, dirs stay arrays of entries
+    sfs.set_dir_index(false);
+    let dir3 = root.create("dir3", FileType::Dir, 0o777)?;
+    for i in 0..100 {
+        dir3.create(&name(i), FileType::File, 0o777)?;
+    }
+    assert!(!is_indexed(&sfs, &dir3));
+    drop((dir1, dir2, dir3, root));
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let root = sfs.root_inode();
+    assert_eq!(root.lookup("dir1")?.list()?.len(), 502);
+    root.lookup("dir1/renamed")?;
+    assert_eq!(root.lookup("dir3")?.list()?.len(), 102);
+    drop(root);
+    drop(sfs);
+
+    const VERSION_OFFSET: usize = 48;
+    device.write_at(VERSION_OFFSET, &3u32.to_ne_bytes())?;
+    let sfs = SimpleFileSystem::open(device)?;
+    let dir4 = sfs.root_inode().create("dir4", FileType::Dir, 0o777)?;
+    for i in 0..100 {
+        dir4.create(&name(i), FileType::File, 0o777)?;
+    }
+    assert!(!is_indexed(&sfs, &dir4));
+    Ok(())
+}
+
 #[test]
 fn arc_layout() {
     // [usize, usize, T]
@@ -563,3 +814,983 @@ fn create_then_get_entry() -> Result<()> {
     sfs.sync()?;
     Ok(())
 }
+
+mod vfs {
+    use super::*;
+    rcore_fs_testsuite::vfs_tests!(_create_new_sfs());
+}
+
+/// SFS on a temporary file, which can be reopened
+struct SfsImage {
+    device: Arc<Mutex<fs::File>>,
+    sfs: Arc<SimpleFileSystem>,
+}
+
+impl model::Target for SfsImage {
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.sfs.clone()
+    }
+
+    fn reopen(&mut self) {
+        self.sfs.sync().unwrap();
+        self.sfs = SimpleFileSystem::open(self.device.clone()).expect("failed to open SFS");
+    }
+}
+
+#[test]
+fn permissions() -> Result<()> {
+    let file = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    let sfs = SimpleFileSystem::create(file.clone(), 32 * 4096)?;
+    let root = sfs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o640)?;
+    let dir1 = root.create("dir1", FileType::Dir, 0o1755)?;
+    assert_eq!(file1.metadata()?.mode, 0o640);
+    assert_eq!(dir1.metadata()?.mode, 0o1755);
+
+    let mut metadata = file1.metadata()?;
+    metadata.mode = 0o600;
+    metadata.uid = 1000;
+    metadata.gid = 100;
+    file1.set_metadata(&metadata)?;
+    drop(file1);
+    drop(dir1);
+    drop(root);
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(file.clone())?;
+    let metadata = sfs.root_inode().find("file1")?.metadata()?;
+    assert_eq!(
+        (metadata.mode, metadata.uid, metadata.gid),
+        (0o600, 1000, 100)
+    );
+    drop(sfs);
+
+    // images before version 1 have no permissions, nor a version field
+    const VERSION_OFFSET: usize = 48;
+    file.write_at(0, &MAGIC.to_ne_bytes())?;
+    let sfs = SimpleFileSystem::open(file.clone())?;
+    let file1 = sfs.root_inode().find("file1")?;
+    let metadata = file1.metadata()?;
+    assert_eq!((metadata.mode, metadata.uid, metadata.gid), (0o777, 0, 0));
+    let chmod = Metadata {
+        mode: 0o600,
+        ..metadata.clone()
+    };
+    assert_eq!(file1.set_metadata(&chmod), Err(FsError::NotSupported));
+    let chown = Metadata {
+        uid: 1000,
+        ..metadata.clone()
+    };
+    assert_eq!(file1.set_metadata(&chown), Err(FsError::NotSupported));
+    let touch = Metadata {
+        mtime: Timespec { sec: 1, nsec: 0 },
+        ..metadata
+    };
+    file1.set_metadata(&touch)?;
+    assert_eq!(file1.metadata()?.mode, 0o777);
+    assert_eq!(file1.metadata()?.mtime.sec, 1);
+    drop(file1);
+    drop(sfs);
+
+    // newer images can not be opened
+    file.write_at(0, &MAGIC_VERSIONED.to_ne_bytes())?;
+    file.write_at(VERSION_OFFSET, &(VERSION + 1).to_ne_bytes())?;
+    assert!(SimpleFileSystem::open(file.clone()).is_err());
+    file.write_at(VERSION_OFFSET, &0u32.to_ne_bytes())?;
+    assert!(SimpleFileSystem::open(file).is_err());
+    Ok(())
+}
+
+#[test]
+fn model() {
+    model::check(
+        || {
+            let file = tempfile::tempfile().expect("failed to create file");
+            let device = Arc::new(Mutex::new(file));
+            let sfs = SimpleFileSystem::create(device.clone(), 4096 * 4096)
+                .expect("failed to create SFS");
+            SfsImage { device, sfs }
+        },
+        Features::all(),
+    );
+}
+
+/// Check the superblock matches the free map, and blocks of every reachable
+/// inode are allocated and not shared with others
+fn check_blocks(sfs: &Arc<SimpleFileSystem>) -> core::result::Result<(), String> {
+    fn collect(dir: &Arc<dyn INode>, ids: &mut BTreeSet<usize>) -> Result<()> {
+        for name in dir.list()? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let child = dir.find(&name)?;
+            let info = child.metadata()?;
+            if ids.insert(info.inode) && info.type_ == FileType::Dir {
+                collect(&child, ids)?;
+            }
+        }
+        Ok(())
+    }
+    let mut ids = BTreeSet::new();
+    ids.insert(BLKN_ROOT);
+    collect(&sfs.root_inode(), &mut ids).map_err(|e| format!("walk: {:?}", e))?;
+
+    let super_block = sfs.super_block.read();
+    let free_map = sfs.free_map.read();
+    let unused = (0..free_map.len()).filter(|&b| free_map.is_free(b)).count();
+    let unused_blocks = sfs.unused_blocks.load(AtomicOrdering::Relaxed);
+    if unused_blocks != unused {
+        return Err(format!(
+            "{} unused blocks counted, free map has {}",
+            unused_blocks, unused
+        ));
+    }
+    let mut owner = BTreeMap::new();
+    let mut claim = |block: usize, inode: usize| {
+        if block >= super_block.blocks as usize {
+            return Err(format!(
+                "block {} of inode {} is out of range",
+                block, inode
+            ));
+        }
+        if free_map.is_free(block) {
+            return Err(format!("block {} of inode {} is free", block, inode));
+        }
+        match owner.insert(block, inode) {
+            Some(other) => Err(format!(
+                "block {} is shared by inode {} and {}",
+                block, other, inode
+            )),
+            None => Ok(()),
+        }
+    };
+    let journal_start = super_block.journal_start as usize;
+    for block in journal_start..journal_start + super_block.journal_blocks as usize {
+        claim(block, 0)?;
+    }
+    for &id in ids.iter() {
+        claim(id, id)?;
+        let inode = sfs.get_inode(id);
+        let (blocks, indirect, db_indirect, tr_indirect, extents) = {
+            let disk_inode = inode.disk_inode.read();
+            let blocks = disk_inode.blocks as usize;
+            (
+                blocks,
+                disk_inode.indirect,
+                disk_inode.db_indirect,
+                disk_inode.tr_indirect,
+                disk_inode.has_extents(),
+            )
+        };
+        for i in 0..blocks {
+            let block = inode
+                .get_disk_block_id(i)
+                .map_err(|e| format!("inode {} block {}: {:?}", id, i, e))?;
+            claim(block, id)?;
+        }
+        if extents {
+            continue;
+        }
+        if blocks > MAX_NBLOCK_DIRECT {
+            claim(indirect as usize, id)?;
+        }
+        if blocks > MAX_NBLOCK_INDIRECT {
+            claim(db_indirect as usize, id)?;
+            let indirects = (blocks - MAX_NBLOCK_INDIRECT).div_ceil(BLK_NENTRY);
+            for i in 0..indirects.min(BLK_NENTRY) {
+                let block = inode.read_entry(db_indirect, i).unwrap();
+                claim(block as usize, id)?;
+            }
+        }
+        if blocks > MAX_NBLOCK_DOUBLE_INDIRECT {
+            claim(tr_indirect as usize, id)?;
+            let indirects = (blocks - MAX_NBLOCK_DOUBLE_INDIRECT).div_ceil(BLK_NENTRY);
+            for i in 0..indirects.div_ceil(BLK_NENTRY) {
+                let db_indirect = inode.read_entry(tr_indirect, i).unwrap();
+                claim(db_indirect as usize, id)?;
+                for j in 0..(indirects - i * BLK_NENTRY).min(BLK_NENTRY) {
+                    let block = inode.read_entry(db_indirect, j).unwrap();
+                    claim(block as usize, id)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_consistency() {
+    let recorder = crash::Recorder::new(crash::Image::new());
+    let sfs =
+        SimpleFileSystem::create(recorder.clone(), 1024 * 4096).expect("failed to create SFS");
+    sfs.sync().unwrap();
+    let base = recorder.image();
+    recorder.take_events();
+
+    crash::workload(&*sfs, Features::all());
+    drop(sfs);
+    let events = recorder.take_events();
+
+    let report = crash::check(&base, &events, &crash::Config::default(), |image| {
+        let sfs = SimpleFileSystem::open(crash::Recorder::new(image))
+            .map_err(|e| format!("open: {:?}", e))?;
+        crash::check_tree(&*sfs, Features::all())?;
+        check_blocks(&sfs)
+    });
+    println!("{}", report);
+    let failures: Vec<_> = report.durable_failures().collect();
+    assert!(failures.is_empty(), "corrupted after sync: {:#?}", failures);
+}
+
+#[test]
+fn journal_crash_consistency() {
+    let recorder = crash::Recorder::new(crash::Image::new());
+    let sfs = SimpleFileSystem::create_with_journal(recorder.clone(), 1024 * 4096, 64)
+        .expect("failed to create SFS");
+    sfs.sync().unwrap();
+    let base = recorder.image();
+    recorder.take_events();
+
+    crash::workload(&*sfs, Features::all());
+    drop(sfs);
+    let events = recorder.take_events();
+
+    let report = crash::check(&base, &events, &crash::Config::default(), |image| {
+        let sfs = SimpleFileSystem::open(crash::Recorder::new(image))
+            .map_err(|e| format!("open: {:?}", e))?;
+        crash::check_tree(&*sfs, Features::all())?;
+        check_blocks(&sfs)
+    });
+    println!("{}", report);
+    assert!(
+        report.failures.is_empty(),
+        "corrupted: {:#?}",
+        report.failures
+    );
+}
+
+#[test]
+fn journal() -> Result<()> {
+    let recorder = crash::Recorder::new(crash::Image::new());
+    let sfs = SimpleFileSystem::create_with_journal(recorder.clone(), 1024 * 4096, 16)?;
+    assert!(sfs.super_block.read().has_journal());
+    sfs.sync()?;
+    let reopen = || SimpleFileSystem::open(crash::Recorder::new(recorder.image())).unwrap();
+
+    // nothing is in place until committed
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let file = dir.create("file", FileType::File, 0o777)?;
+    file.write_at(0, &[1u8; 3 * BLKSIZE])?;
+    assert_eq!(
+        reopen().root_inode().find("dir").err(),
+        Some(FsError::EntryNotFound)
+    );
+    file.sync_all()?;
+    let copy = reopen();
+    let mut buf = [0u8; 3 * BLKSIZE];
+    copy.root_inode().lookup("dir/file")?.read_at(0, &mut buf)?;
+    assert_eq!(buf, [1u8; 3 * BLKSIZE]);
+    check_blocks(&copy).unwrap();
+    drop(copy);
+
+    // freed blocks are reused after the commit
+    let unused = sfs.info().bfree;
+    drop(file);
+    dir.unlink("file")?;
+    assert_eq!(sfs.info().bfree, unused);
+    sfs.sync()?;
+    assert_eq!(sfs.info().bfree, unused + 4);
+
+    // a transaction larger than the journal is written in place
+    drop((dir, root));
+    let recorder = crash::Recorder::new(crash::Image::new());
+    let sfs = SimpleFileSystem::create_with_journal(recorder.clone(), 1024 * 4096, 3)?;
+    let root = sfs.root_inode();
+    for i in 0..10 {
+        let dir = root.create(&format!("dir{}", i), FileType::Dir, 0o777)?;
+        dir.create("file", FileType::File, 0o777)?
+            .resize(i * BLKSIZE)?;
+    }
+    drop(root);
+    sfs.sync()?;
+    check_blocks(&sfs).unwrap();
+    let copy = SimpleFileSystem::open(crash::Recorder::new(recorder.image()))?;
+    assert_eq!(copy.root_inode().list()?.len(), 12);
+    check_blocks(&copy).unwrap();
+
+    // images without journal still open
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+    assert!(!sfs.super_block.read().has_journal());
+    sfs.root_inode().create("file", FileType::File, 0o777)?;
+    drop(sfs);
+    const VERSION_OFFSET: usize = 48;
+    device.write_at(VERSION_OFFSET, &4u32.to_ne_bytes())?;
+    let sfs = SimpleFileSystem::open(device)?;
+    assert!(!sfs.super_block.read().has_journal());
+    sfs.root_inode().find("file")?;
+    Ok(())
+}
+
+#[test]
+fn fsck() -> Result<()> {
+    let recorder = crash::Recorder::new(crash::Image::new());
+    let sfs = SimpleFileSystem::create_with_journal(recorder.clone(), 1024 * 4096, 16)?;
+    let root = sfs.root_inode();
+    let id = |inode: &Arc<dyn INode>| inode.metadata().unwrap().inode;
+    let x = id(&root.create("x", FileType::File, 0o777)?);
+    let y = id(&root.create("y", FileType::File, 0o777)?);
+    let y2 = id(&root.create("y2", FileType::File, 0o777)?);
+    let big = root.create("big", FileType::File, 0o777)?;
+    big.write_at(0, &[2u8; 3 * BLKSIZE])?;
+    let idx = root.create("idx", FileType::Dir, 0o777)?;
+    for i in 0..300 {
+        idx.create(&format!("file{}", i), FileType::File, 0o777)?;
+    }
+    let a = root.create("a", FileType::Dir, 0o777)?;
+    a.create("b", FileType::Dir, 0o777)?
+        .create("g", FileType::File, 0o777)?;
+    let o = id(&root.create("o", FileType::File, 0o777)?);
+    let root_block = sfs.get_inode(BLKN_ROOT).disk_inode.read().direct[0] as usize;
+    let blocks = sfs.super_block.read().blocks as usize;
+    let (big, a) = (id(&big), id(&a));
+    drop((root, idx));
+    sfs.sync()?;
+    drop(sfs);
+    let device: Arc<dyn Device> = recorder.clone();
+
+    let report = fsck::check(device.clone(), false)?;
+    assert_eq!(report.problems, []);
+
+    // mark a free block used and a used block free
+    let flip = |block: usize| {
+        let offset = BLKN_FREEMAP * BLKSIZE + block / 8;
+        let mut byte = [0u8];
+        device.read_at(offset, &mut byte).unwrap();
+        byte[0] ^= 1 << (block % 8);
+        device.write_at(offset, &byte).unwrap();
+    };
+    flip(blocks - 1);
+    flip(y);
+    // wrong nlinks, an invalid block pointer
+    device.write_at(y * BLKSIZE + 6, &5u16.to_ne_bytes())?;
+    device.write_at(big * BLKSIZE + 16, &u32::MAX.to_ne_bytes())?;
+    // entries of an invalid inode and of a duplicated name
+    device.write_at(
+        root_block * BLKSIZE + 2 * DIRENT_SIZE,
+        &u32::MAX.to_ne_bytes(),
+    )?;
+    device.write_at(root_block * BLKSIZE + 4 * DIRENT_SIZE + ENTRY_SIZE, b"y\0")?;
+    // drop the last entries of root, 'a' and 'o'
+    device.write_at(BLKSIZE * BLKN_ROOT, &(7 * DIRENT_SIZE as u32).to_ne_bytes())?;
+
+    let report = fsck::check(device.clone(), false)?;
+    for problem in [
+        fsck::Problem::Leaked(blocks - 1..blocks),
+        fsck::Problem::MarkedFree(y..y + 1),
+        fsck::Problem::WrongNlinks {
+            inode: y,
+            nlinks: 5,
+            expected: 1,
+        },
+        fsck::Problem::BadBlock {
+            inode: big,
+            block: 1,
+        },
+        fsck::Problem::BadEntry {
+            dir: BLKN_ROOT,
+            name: "x".into(),
+            reason: "invalid inode",
+        },
+        fsck::Problem::BadEntry {
+            dir: BLKN_ROOT,
+            name: "y".into(),
+            reason: "duplicated name",
+        },
+        fsck::Problem::Orphan(x),
+        fsck::Problem::Orphan(y2),
+        fsck::Problem::Orphan(a),
+        fsck::Problem::Orphan(o),
+    ] {
+        assert!(report.problems.contains(&problem), "{} not found", problem);
+    }
+    assert_eq!(report.remaining, report.problems);
+
+    let report = fsck::check(device.clone(), true)?;
+    assert_eq!(report.remaining, []);
+    assert_eq!(fsck::check(device.clone(), false)?.problems, []);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    check_blocks(&sfs).unwrap();
+    let root = sfs.root_inode();
+    let lost_and_found = root.find(fsck::LOST_AND_FOUND)?;
+    for orphan in [x, y2, a, o] {
+        let inode = lost_and_found.find(&format!("#{}", orphan))?;
+        assert_eq!(inode.metadata()?.nlinks, if orphan == a { 3 } else { 1 });
+    }
+    assert_eq!(lost_and_found.metadata()?.nlinks, 3);
+    let b = root.lookup(&format!("{}/#{}/b", fsck::LOST_AND_FOUND, a))?;
+    assert_eq!(id(&b.lookup("../..")?), id(&lost_and_found));
+    b.find("g")?;
+    assert_eq!(root.find("y")?.metadata()?.nlinks, 1);
+    assert_eq!(root.lookup("idx")?.list()?.len(), 302);
+    let big = root.find("big")?;
+    assert_eq!(big.metadata()?.size, BLKSIZE);
+    let mut buf = [0u8; BLKSIZE];
+    big.read_at(0, &mut buf)?;
+    assert_eq!(buf, [2u8; BLKSIZE]);
+    Ok(())
+}
+
+#[test]
+fn fsck_huge_blocks() -> Result<()> {
+    let device = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    let sfs = SimpleFileSystem::create(device.clone(), 32 * 4096)?;
+    let file = sfs.root_inode().create("file", FileType::File, 0o777)?;
+    file.write_at(0, &[1u8; BLKSIZE])?;
+    let inode = file.metadata()?.inode;
+    drop(file);
+    sfs.sync()?;
+    drop(sfs);
+
+    // a number of blocks far beyond the device
+    const BLOCKS_OFFSET: usize = 8;
+    device.write_at(inode * BLKSIZE + BLOCKS_OFFSET, &u32::MAX.to_ne_bytes())?;
+    let device: Arc<dyn Device> = device;
+    let report = fsck::check(device.clone(), true)?;
+    assert!(report
+        .problems
+        .iter()
+        .any(|problem| matches!(problem, fsck::Problem::BadBlock { inode: i, .. } if *i == inode)));
+    assert_eq!(report.remaining, []);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    let file = sfs.root_inode().find("file")?;
+    assert_eq!(file.metadata()?.blocks, 1);
+    let mut buf = [0u8; BLKSIZE];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(buf, [1u8; BLKSIZE]);
+    Ok(())
+}
+
+#[test]
+fn wrong_unused_blocks() -> Result<()> {
+    let file = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    let sfs = SimpleFileSystem::create(file.clone(), 32 * 4096)?;
+    let free = sfs.info().bfree;
+    sfs.sync()?;
+    drop(sfs);
+
+    // more unused blocks counted on disk than there are, recounted when opened
+    const UNUSED_BLOCKS_OFFSET: usize = 8;
+    file.write_at(UNUSED_BLOCKS_OFFSET, &1000u32.to_ne_bytes())?;
+    let sfs = SimpleFileSystem::open(file)?;
+    assert_eq!(sfs.info().bfree, free);
+    check_blocks(&sfs).unwrap();
+
+    // allocating fails instead of looking for blocks forever
+    let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+    sfs.unused_blocks.fetch_add(10, AtomicOrdering::Relaxed);
+    assert_eq!(
+        file1.resize((free + 5) * BLKSIZE),
+        Err(FsError::NoDeviceSpace)
+    );
+    assert_eq!(sfs.info().bfree, free - 1 + 10);
+    sfs.unused_blocks.fetch_sub(10, AtomicOrdering::Relaxed);
+    check_blocks(&sfs).unwrap();
+    Ok(())
+}
+
+#[test]
+fn grow() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file));
+    let space = BLKBITS * BLKSIZE;
+    let sfs = SimpleFileSystem::create(device.clone(), space)?;
+    let root = sfs.root_inode();
+    let id = |inode: &Arc<dyn INode>| inode.metadata().unwrap().inode;
+    // the first inodes are right after the freemap, in the way of it
+    let file = root.create("file", FileType::File, 0o777)?;
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    assert!(id(&file) < BLKN_FREEMAP + 4);
+    dir.link("link", &file)?;
+    dir.create("subdir", FileType::Dir, 0o777)?
+        .create("x", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..20 * BLKSIZE).map(|i| i as u8).collect();
+    file.write_at(0, &data)?;
+
+    // an open inode can not be moved
+    assert_eq!(sfs.grow(4 * space).err(), Some(FsError::Busy));
+    assert_eq!(sfs.super_block.read().blocks as usize, BLKBITS);
+    check_blocks(&sfs).unwrap();
+    let unused = sfs.info().bfree;
+    drop((file, dir, root));
+    drop(sfs);
+    assert_eq!(fsck::check(device.clone(), false)?.problems, []);
+
+    resize::grow(device.clone(), 4 * space)?;
+    assert_eq!(
+        resize::grow(device.clone(), space).err(),
+        Some(FsError::InvalidParam)
+    );
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    {
+        let super_block = sfs.super_block.read();
+        assert_eq!(super_block.blocks as usize, 4 * BLKBITS);
+        assert_eq!(super_block.freemap_blocks, 4);
+        assert_eq!(super_block.unused_blocks as usize, unused + 3 * BLKBITS - 3);
+    }
+    let root = sfs.root_inode();
+    let file = root.find("file")?;
+    assert!(id(&file) >= BLKN_FREEMAP + 4);
+    let mut buf = vec![0u8; data.len()];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(buf, data);
+    let dir = root.find("dir")?;
+    assert_eq!(id(&dir.find("link")?), id(&file));
+    assert_eq!(id(&dir.find(".")?), id(&dir));
+    assert_eq!(id(&dir.lookup("subdir/..")?), id(&dir));
+    dir.lookup("subdir/x")?;
+    check_blocks(&sfs).unwrap();
+
+    // the new space is usable
+    root.create("big", FileType::File, 0o777)?
+        .resize(space + BLKSIZE)?;
+    drop((file, dir, root));
+    drop(sfs);
+    assert_eq!(fsck::check(device, false)?.problems, []);
+    Ok(())
+}
+
+#[test]
+fn grow_online() -> Result<()> {
+    let recorder = crash::Recorder::new(crash::Image::new());
+    let space = BLKBITS * BLKSIZE;
+    let sfs = SimpleFileSystem::create_with_journal(recorder.clone(), space, 16)?;
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o777)?;
+    file.write_at(0, b"hello")?;
+
+    // the journal is in the way
+    sfs.grow(2 * space)?;
+    assert_ne!(
+        sfs.super_block.read().journal_start as usize,
+        BLKN_FREEMAP + 1
+    );
+    file.write_at(5, b" world")?;
+    root.create("dir", FileType::Dir, 0o777)?;
+    sfs.sync()?;
+    check_blocks(&sfs).unwrap();
+
+    let copy = SimpleFileSystem::open(crash::Recorder::new(recorder.image()))?;
+    assert_eq!(copy.super_block.read().blocks as usize, 2 * BLKBITS);
+    assert!(copy.super_block.read().has_journal());
+    let mut buf = [0u8; 11];
+    copy.root_inode().find("file")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"hello world");
+    copy.root_inode().find("dir")?;
+    check_blocks(&copy).unwrap();
+    drop(copy);
+    assert_eq!(
+        fsck::check(crash::Recorder::new(recorder.image()), false)?.problems,
+        []
+    );
+    Ok(())
+}
+
+#[test]
+fn grow_online_lookups() -> Result<()> {
+    let space = BLKBITS * BLKSIZE;
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(tempfile::tempfile().unwrap())), space)?;
+    let root = sfs.root_inode();
+    // inodes in the way, which are looked up meanwhile
+    for i in 0..20 {
+        root.create(&format!("file{}", i), FileType::File, 0o777)?
+            .write_at(0, &[i as u8; 10])?;
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let lookups = {
+        let (root, stop) = (root.clone(), stop.clone());
+        thread::spawn(move || {
+            while !stop.load(AtomicOrdering::Relaxed) {
+                for i in 0..20 {
+                    let mut buf = [0u8; 10];
+                    let file = root.find(&format!("file{}", i)).unwrap();
+                    file.read_at(0, &mut buf).unwrap();
+                    assert_eq!(buf, [i as u8; 10]);
+                }
+            }
+        })
+    };
+    let mut result = sfs.grow(8 * space);
+    while result == Err(FsError::Busy) {
+        thread::yield_now();
+        result = sfs.grow(8 * space);
+    }
+    stop.store(true, AtomicOrdering::Relaxed);
+    lookups.join().unwrap();
+    result?;
+    assert_eq!(sfs.super_block.read().blocks as usize, 8 * BLKBITS);
+    check_blocks(&sfs).unwrap();
+    for i in 0..20 {
+        let mut buf = [0u8; 10];
+        root.find(&format!("file{}", i))?.read_at(0, &mut buf)?;
+        assert_eq!(buf, [i as u8; 10]);
+    }
+    Ok(())
+}
+
+#[test]
+fn shrink() -> Result<()> {
+    let recorder = crash::Recorder::new(crash::Image::new());
+    let device: Arc<dyn Device> = recorder.clone();
+    let sfs = SimpleFileSystem::create_with_journal(device.clone(), 2 * BLKBITS * BLKSIZE, 16)?;
+    let root = sfs.root_inode();
+    let id = |inode: &Arc<dyn INode>| inode.metadata().unwrap().inode;
+    // allocate at the end
+    sfs.alloc_hint
+        .store(2 * BLKBITS - 1000, AtomicOrdering::Relaxed);
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    for i in 0..300 {
+        dir.create(&format!("file{}", i), FileType::File, 0o777)?;
+    }
+    dir.create("subdir", FileType::Dir, 0o777)?
+        .create("x", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..20 * BLKSIZE).map(|i| i as u8).collect();
+    dir.create("data", FileType::File, 0o777)?
+        .write_at(0, &data)?;
+    sfs.set_extents(true);
+    let extents = root.create("extents", FileType::File, 0o777)?;
+    extents.write_at(0, &data)?;
+    dir.link("link", &extents)?;
+    assert!(id(&dir) > BLKBITS);
+    drop((dir, extents, root));
+    drop(sfs);
+
+    assert_eq!(
+        resize::shrink(device.clone(), 16 * BLKSIZE).err(),
+        Some(FsError::NoDeviceSpace)
+    );
+    assert_eq!(fsck::check(device.clone(), false)?.problems, []);
+    resize::shrink(device.clone(), 1024 * BLKSIZE)?;
+    assert_eq!(
+        resize::shrink(device.clone(), 2048 * BLKSIZE).err(),
+        Some(FsError::InvalidParam)
+    );
+    assert_eq!(fsck::check(device.clone(), false)?.problems, []);
+
+    let sfs = SimpleFileSystem::open(device)?;
+    assert_eq!(sfs.super_block.read().blocks, 1024);
+    assert_eq!(sfs.super_block.read().freemap_blocks, 1);
+    let root = sfs.root_inode();
+    let dir = root.find("dir")?;
+    assert!(id(&dir) < 1024);
+    assert_eq!(dir.list()?.len(), 2 + 303);
+    assert_eq!(id(&dir.lookup("subdir/..")?), id(&dir));
+    let mut buf = vec![0u8; data.len()];
+    dir.find("data")?.read_at(0, &mut buf)?;
+    assert_eq!(buf, data);
+    let extents = root.find("extents")?;
+    assert_eq!(id(&dir.find("link")?), id(&extents));
+    extents.read_at(0, &mut buf)?;
+    assert_eq!(buf, data);
+    assert!(sfs.get_inode(id(&extents)).disk_inode.read().has_extents());
+    check_blocks(&sfs).unwrap();
+    Ok(())
+}
+
+#[test]
+fn device_nodes() -> Result<()> {
+    struct Registry(BTreeMap<usize, Arc<dyn INode>>);
+    impl DeviceRegistry for Registry {
+        fn get(&self, rdev: usize) -> Option<Arc<dyn INode>> {
+            self.0.get(&rdev).cloned()
+        }
+    }
+
+    let file = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    let sfs = SimpleFileSystem::create(file.clone(), 32 * 4096)?;
+    let root = sfs.root_inode();
+    let null = root.create2("null", FileType::CharDevice, 0o666, make_rdev(1, 3))?;
+    root.create2("sda", FileType::BlockDevice, 0o660, make_rdev(8, 0))?;
+    root.create2("fifo", FileType::NamedPipe, 0o644, 0)?;
+    root.create2("sock", FileType::Socket, 0o755, 0)?;
+    root.create("backing", FileType::File, 0o644)?;
+    assert_eq!(null.read_at(0, &mut [0; 4]), Err(FsError::NoDevice));
+    drop(null);
+    drop(root);
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(file.clone())?;
+    let root = sfs.root_inode();
+    let check = |name: &str, type_: FileType, mode: u16, rdev: usize| -> Result<()> {
+        let metadata = root.find(name)?.metadata()?;
+        assert_eq!((metadata.type_, metadata.mode), (type_, mode), "{}", name);
+        assert_eq!((metadata.size, metadata.rdev), (0, rdev), "{}", name);
+        Ok(())
+    };
+    check("null", FileType::CharDevice, 0o666, make_rdev(1, 3))?;
+    check("sda", FileType::BlockDevice, 0o660, make_rdev(8, 0))?;
+    check("fifo", FileType::NamedPipe, 0o644, 0)?;
+    check("sock", FileType::Socket, 0o755, 0)?;
+    check("backing", FileType::File, 0o644, 0)?;
+
+    let backing = root.find("backing")?;
+    let mut devices = BTreeMap::new();
+    devices.insert(make_rdev(1, 3), backing.clone());
+    sfs.set_device_registry(Arc::new(Registry(devices)));
+    let null = root.find("null")?;
+    assert_eq!(null.write_at(0, b"hello")?, 5);
+    let mut buf = [0; 5];
+    assert_eq!(backing.read_at(0, &mut buf)?, 5);
+    assert_eq!(&buf, b"hello");
+    assert_eq!(null.read_at(1, &mut buf)?, 4);
+    assert_eq!(
+        root.find("sda")?.read_at(0, &mut buf),
+        Err(FsError::NoDevice)
+    );
+    assert_eq!(
+        root.find("fifo")?.read_at(0, &mut buf),
+        Err(FsError::NotFile)
+    );
+    assert_eq!(root.find("sock")?.write_at(0, &buf), Err(FsError::NotFile));
+
+    // FIFOs are backed by pipes of the kernel
+    let fifos = rcore_fs::pipe::FifoTable::new();
+    let fifo = root.find("fifo")?;
+    let writer = fifos.open(&fifo, rcore_fs::pipe::PipeEnd::Write)?;
+    let reader = fifos.open(&fifo, rcore_fs::pipe::PipeEnd::Read)?;
+    assert_eq!(writer.write_at(0, b"hi")?, 2);
+    assert_eq!(reader.read_at(0, &mut buf)?, 2);
+    drop((writer, reader, fifo, null, backing, root));
+    sfs.sync()?;
+    drop(sfs);
+    assert!(fsck::check(file.clone(), false)?.problems.is_empty());
+
+    // images before version 6 can only store devices
+    const VERSION_OFFSET: usize = 48;
+    file.write_at(VERSION_OFFSET, &5u32.to_ne_bytes())?;
+    let sfs = SimpleFileSystem::open(file)?;
+    let root = sfs.root_inode();
+    let result = root.create2("fifo2", FileType::NamedPipe, 0o644, 0);
+    assert_eq!(result.err(), Some(FsError::NotSupported));
+    root.create2("tty", FileType::CharDevice, 0o620, make_rdev(4, 1))?;
+    sfs.sync()?;
+    Ok(())
+}
+
+#[test]
+fn inline_data() -> Result<()> {
+    let file = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    let sfs = SimpleFileSystem::create(file.clone(), 256 * BLKSIZE)?;
+    let root = sfs.root_inode();
+    let unused = sfs.info().bfree;
+
+    let small = root.create("small", FileType::File, 0o644)?;
+    small.write_at(0, b"hello")?;
+    let link = root.create("link", FileType::SymLink, 0o777)?;
+    link.write_at(0, b"small")?;
+    root.create("empty", FileType::File, 0o644)?;
+    assert_eq!(small.metadata()?.blocks, 0);
+    assert_eq!(link.metadata()?.blocks, 0);
+    // only the inodes are allocated
+    assert_eq!(sfs.info().bfree, unused - 3);
+    assert_eq!(sfs.info().inline_blocks, 2);
+
+    // grow up to the end of the inode block, shrink, then grow again
+    let data: Vec<u8> = (0..MAX_INLINE_DATA).map(|i| i as u8).collect();
+    small.write_at(0, &data)?;
+    small.resize(2)?;
+    small.resize(6)?;
+    let mut buf = [0xff; 6];
+    small.read_at(0, &mut buf)?;
+    assert_eq!(buf, [0, 1, 0, 0, 0, 0]);
+    small.write_at(0, &data)?;
+    assert_eq!(small.metadata()?.blocks, 0);
+    assert_eq!(sfs.info().bfree, unused - 3);
+
+    // one more byte moves it to a data block
+    small.write_at(MAX_INLINE_DATA, b"!")?;
+    assert_eq!(small.metadata()?.blocks, 1);
+    assert_eq!(sfs.info().bfree, unused - 4);
+    assert_eq!(sfs.info().inline_blocks, 1);
+    let mut buf = vec![0; MAX_INLINE_DATA + 1];
+    assert_eq!(small.read_at(0, &mut buf)?, MAX_INLINE_DATA + 1);
+    assert_eq!(&buf[..MAX_INLINE_DATA], &data[..]);
+    assert_eq!(buf[MAX_INLINE_DATA], b'!');
+
+    let tiny = root.create("tiny", FileType::File, 0o644)?;
+    tiny.write_at(0, b"tiny")?;
+    assert_eq!(sfs.info().inline_blocks, 2);
+    tiny.resize(0)?;
+    assert_eq!(sfs.info().inline_blocks, 1);
+    tiny.write_at(0, b"tiny")?;
+    drop(tiny);
+    root.unlink("tiny")?;
+    assert_eq!(sfs.info().inline_blocks, 1);
+    assert_eq!(sfs.info().bfree, unused - 4);
+
+    // files do not fit if disabled
+    sfs.set_inline_data(false);
+    let big = root.create("big", FileType::File, 0o644)?;
+    big.write_at(0, b"big")?;
+    assert_eq!(big.metadata()?.blocks, 1);
+    drop((small, link, big, root));
+    check_blocks(&sfs).unwrap();
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(file.clone())?;
+    let root = sfs.root_inode();
+    assert_eq!(sfs.info().inline_blocks, 1);
+    let mut buf = [0; 5];
+    assert_eq!(root.find("link")?.read_at(0, &mut buf)?, 5);
+    assert_eq!(&buf, b"small");
+    assert_eq!(root.find("small")?.metadata()?.size, MAX_INLINE_DATA + 1);
+    drop(root);
+    drop(sfs);
+    assert!(fsck::check(file.clone(), false)?.problems.is_empty());
+
+    // a wrong count is repaired by fsck
+    let device: Arc<dyn Device> = file.clone();
+    let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    super_block.inline_files = 5;
+    device.write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+    let report = fsck::check(device.clone(), true)?;
+    let expected = fsck::Problem::WrongInlineFiles {
+        files: 5,
+        expected: 1,
+    };
+    assert_eq!(report.problems, vec![expected]);
+    assert!(report.remaining.is_empty());
+
+    // images before version 7 have no inline data
+    const VERSION_OFFSET: usize = 48;
+    let file = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    SimpleFileSystem::create(file.clone(), 256 * BLKSIZE)?;
+    file.write_at(VERSION_OFFSET, &6u32.to_ne_bytes())?;
+    let sfs = SimpleFileSystem::open(file)?;
+    let small = sfs.root_inode().create("small", FileType::File, 0o644)?;
+    small.write_at(0, b"hello")?;
+    assert_eq!(small.metadata()?.blocks, 1);
+    assert_eq!(sfs.info().inline_blocks, 0);
+    Ok(())
+}
+
+#[test]
+fn concurrency() -> Result<()> {
+    const THREADS: usize = 8;
+    const ROUNDS: usize = 40;
+    let device = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    // more than one group of the freemap
+    let space = 2 * BLKBITS * BLKSIZE;
+    let sfs = SimpleFileSystem::create_with_journal(device.clone(), space, 64)?;
+    sfs.set_extents(true);
+    let root = sfs.root_inode();
+    let shared = root.create("shared", FileType::File, 0o644)?;
+    for t in 0..THREADS {
+        let dir = root.create(&format!("dir{}", t), FileType::Dir, 0o755)?;
+        let deep = dir.create("deep", FileType::Dir, 0o755)?;
+        deep.create("keep", FileType::File, 0o644)?;
+    }
+
+    let (done, finished) = mpsc::channel();
+    for t in 0..THREADS {
+        let (sfs, root, shared, done) = (sfs.clone(), root.clone(), shared.clone(), done.clone());
+        thread::spawn(move || {
+            let result = (|| -> Result<()> {
+                let dir = root.find(&format!("dir{}", t))?;
+                // pairs of threads move between their dirs in opposite directions
+                let other = root.find(&format!("dir{}", t ^ 1))?;
+                let deep = dir.find("deep")?;
+                let file = dir.create("file", FileType::File, 0o644)?;
+                let mut buf = [0u8; BLKSIZE];
+                for round in 0..ROUNDS {
+                    // disjoint blocks of one file, growing it
+                    let block = round * THREADS + t;
+                    shared.write_at(block * BLKSIZE, &[t as u8 + 1; BLKSIZE])?;
+                    shared.read_at(block * BLKSIZE, &mut buf)?;
+                    assert_eq!(buf, [t as u8 + 1; BLKSIZE]);
+                    file.write_at(round * 100, &[round as u8; 100])?;
+
+                    // entries of one dir, files dropped while others look them up
+                    let name = format!("f{}-{}", t, round);
+                    root.create(&name, FileType::File, 0o644)?
+                        .write_at(0, b"x")?;
+                    match root.find(&format!("f{}-{}", t ^ 1, round)) {
+                        Ok(file) => assert!(file.read_at(0, &mut buf)? <= 1),
+                        Err(err) => assert_eq!(err, FsError::EntryNotFound),
+                    }
+                    if round % 2 == 1 {
+                        root.unlink(&name)?;
+                    }
+
+                    // a subtree moved away and back
+                    let sub = dir.create("sub", FileType::Dir, 0o755)?;
+                    sub.create("x", FileType::File, 0o644)?;
+                    let moved = format!("sub{}", t);
+                    dir.move_("sub", &other, &moved)?;
+                    other.move_(&moved, &dir, "sub")?;
+                    sub.unlink("x")?;
+                    dir.unlink("sub")?;
+
+                    // up and down a level, while the partner locks the same parent and child
+                    deep.create("y", FileType::File, 0o644)?;
+                    deep.move_("y", &dir, "y")?;
+                    dir.move_("y", &deep, "y")?;
+                    deep.unlink("y")?;
+                    assert_eq!(other.unlink("deep"), Err(FsError::DirNotEmpty));
+                    if round % 8 == 0 {
+                        sfs.sync()?;
+                    }
+                }
+                Ok(())
+            })();
+            done.send(result).unwrap();
+        });
+    }
+    for _ in 0..THREADS {
+        let result = finished.recv_timeout(Duration::from_secs(120));
+        result.expect("deadlock")?;
+    }
+
+    // no write is lost
+    let mut buf = [0u8; BLKSIZE];
+    assert_eq!(shared.metadata()?.size, ROUNDS * THREADS * BLKSIZE);
+    for block in 0..ROUNDS * THREADS {
+        shared.read_at(block * BLKSIZE, &mut buf)?;
+        assert_eq!(buf, [(block % THREADS) as u8 + 1; BLKSIZE]);
+    }
+    for t in 0..THREADS {
+        let dir = root.find(&format!("dir{}", t))?;
+        assert_eq!(dir.list()?, [".", "..", "deep", "file"]);
+        assert_eq!(dir.find("deep")?.list()?, [".", "..", "keep"]);
+        let file = dir.find("file")?;
+        for round in 0..ROUNDS {
+            file.read_at(round * 100, &mut buf[..100])?;
+            assert_eq!(buf[..100], [round as u8; 100]);
+        }
+        for round in 0..ROUNDS {
+            let found = root.find(&format!("f{}-{}", t, round)).is_ok();
+            assert_eq!(found, round % 2 == 0);
+        }
+    }
+    assert_eq!(root.metadata()?.nlinks, THREADS + 2);
+    assert_eq!(root.find("dir0")?.metadata()?.nlinks, 3);
+    check_blocks(&sfs).unwrap();
+
+    // the lock order relies on dirs not moving into their subtrees
+    let dir = root.find("dir0")?;
+    let sub = dir.create("sub", FileType::Dir, 0o755)?;
+    let result = root.move_("dir0", &sub, "dir0");
+    assert_eq!(result.err(), Some(FsError::InvalidParam));
+    drop((dir, sub, shared, root));
+    sfs.sync()?;
+    drop(sfs);
+    assert_eq!(fsck::check(device, false)?.problems, []);
+    Ok(())
+}
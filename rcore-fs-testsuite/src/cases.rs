@@ -0,0 +1,557 @@
+//! Test cases of the `INode` contract
+//!
+//! Each case panics with a message describing the violated rule.
+
+use crate::Env;
+use rcore_fs::vfs::{FileType, FsError, INode, Result};
+use std::sync::Arc;
+
+/// Get the error of `result`, or `None` if it is ok
+fn err<T>(result: Result<T>) -> Option<FsError> {
+    result.err()
+}
+
+fn inode_id(inode: &Arc<dyn INode>) -> usize {
+    inode.metadata().unwrap().inode
+}
+
+fn nlinks(inode: &Arc<dyn INode>) -> usize {
+    inode.metadata().unwrap().nlinks
+}
+
+fn read_all(inode: &Arc<dyn INode>) -> Vec<u8> {
+    let mut buf = vec![0u8; inode.metadata().unwrap().size];
+    let len = inode.read_at(0, &mut buf).unwrap();
+    assert_eq!(len, buf.len(), "read_at should read the whole file");
+    buf
+}
+
+/// Entries of a directory except "." and ".."
+fn children(dir: &Arc<dyn INode>) -> Vec<String> {
+    let mut names: Vec<String> = dir
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|name| name != "." && name != "..")
+        .collect();
+    names.sort();
+    names
+}
+
+/// The root is a directory, whose "." and ".." are itself
+pub fn root_dir(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    assert_eq!(root.metadata().unwrap().type_, FileType::Dir);
+    let id = inode_id(&root);
+    assert_eq!(inode_id(&root.find(".").unwrap()), id, "root/. is root");
+    assert_eq!(inode_id(&root.find("..").unwrap()), id, "root/.. is root");
+    assert_eq!(root.get_entry(0).unwrap(), ".", "entry 0 is .");
+    assert_eq!(root.get_entry(1).unwrap(), "..", "entry 1 is ..");
+    assert!(
+        children(&root).is_empty(),
+        "new file system should be empty"
+    );
+    if env.features().dir_nlinks {
+        assert_eq!(nlinks(&root), 2, "nlinks of empty root");
+    }
+    fs.sync().unwrap();
+}
+
+pub fn create_file(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    let info = file.metadata().unwrap();
+    assert_eq!(info.type_, FileType::File);
+    assert_eq!(info.size, 0, "new file is empty");
+    assert_eq!(info.nlinks, 1, "nlinks of new file");
+    assert_eq!(inode_id(&root.find("file").unwrap()), info.inode);
+    assert_eq!(children(&root), ["file"]);
+
+    assert_eq!(
+        err(root.create("file", FileType::File, 0o644)),
+        Some(FsError::EntryExist),
+        "create an existing name"
+    );
+    assert_eq!(
+        err(root.create("file", FileType::Dir, 0o755)),
+        Some(FsError::EntryExist),
+        "create an existing name"
+    );
+    assert_eq!(
+        err(root.create(".", FileType::File, 0o644)),
+        Some(FsError::EntryExist),
+        "create ."
+    );
+    assert_eq!(
+        err(root.create("..", FileType::Dir, 0o755)),
+        Some(FsError::EntryExist),
+        "create .."
+    );
+    assert_eq!(
+        err(root.find("nothing")),
+        Some(FsError::EntryNotFound),
+        "find a non-existent name"
+    );
+    fs.sync().unwrap();
+}
+
+/// Directory operations on a file
+pub fn non_dir_operations(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    assert_eq!(
+        err(file.create("a", FileType::File, 0o644)),
+        Some(FsError::NotDir),
+        "create in a file"
+    );
+    assert_eq!(err(file.find("a")), Some(FsError::NotDir), "find in a file");
+    assert_eq!(
+        err(file.get_entry(0)),
+        Some(FsError::NotDir),
+        "get_entry of a file"
+    );
+    assert_eq!(
+        err(file.unlink("a")),
+        Some(FsError::NotDir),
+        "unlink in a file"
+    );
+    assert_eq!(err(file.list()), Some(FsError::NotDir), "list a file");
+    assert_eq!(
+        err(root.lookup("file/a")),
+        Some(FsError::NotDir),
+        "lookup through a file"
+    );
+    if env.features().hard_link {
+        let other = root.create("other", FileType::File, 0o644).unwrap();
+        assert_eq!(
+            err(file.link("a", &other)),
+            Some(FsError::NotDir),
+            "link in a file"
+        );
+    }
+}
+
+pub fn read_write(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    assert_eq!(file.write_at(0, b"hello"), Ok(5));
+    assert_eq!(file.write_at(10, b"world"), Ok(5));
+    assert_eq!(file.metadata().unwrap().size, 15, "write extends the file");
+    assert_eq!(read_all(&file), b"hello\0\0\0\0\0world", "hole reads zeros");
+
+    let mut buf = [0xffu8; 32];
+    assert_eq!(file.read_at(3, &mut buf), Ok(12), "read across the end");
+    assert_eq!(&buf[..12], b"lo\0\0\0\0\0world");
+    assert_eq!(file.read_at(15, &mut buf), Ok(0), "read at the end");
+    assert_eq!(file.read_at(100, &mut buf), Ok(0), "read after the end");
+
+    // overwrite in the middle
+    assert_eq!(file.write_at(4, b"o, w"), Ok(4));
+    assert_eq!(file.metadata().unwrap().size, 15);
+    assert_eq!(read_all(&file), b"hello, w\0\0world");
+
+    // data across blocks
+    let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+    assert_eq!(file.write_at(4000, &data), Ok(data.len()));
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(file.read_at(4000, &mut buf), Ok(data.len()));
+    assert_eq!(buf, data);
+    fs.sync().unwrap();
+}
+
+pub fn resize(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    file.write_at(0, b"hello").unwrap();
+    file.resize(8192).unwrap();
+    assert_eq!(file.metadata().unwrap().size, 8192);
+    let data = read_all(&file);
+    assert_eq!(&data[..5], b"hello");
+    assert!(data[5..].iter().all(|&b| b == 0), "expanded data is zero");
+
+    file.resize(2).unwrap();
+    assert_eq!(read_all(&file), b"he");
+    file.resize(6).unwrap();
+    assert_eq!(
+        read_all(&file),
+        b"he\0\0\0\0",
+        "shrink then grow reads zeros"
+    );
+    file.resize(0).unwrap();
+    assert_eq!(file.metadata().unwrap().size, 0);
+    fs.sync().unwrap();
+}
+
+/// File operations on a directory
+pub fn dir_io(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    let mut buf = [0u8; 4];
+    assert_eq!(
+        err(dir.read_at(0, &mut buf)),
+        Some(FsError::NotFile),
+        "read a directory"
+    );
+    assert_eq!(
+        err(dir.write_at(0, &buf)),
+        Some(FsError::NotFile),
+        "write a directory"
+    );
+    assert_eq!(
+        err(dir.resize(0)),
+        Some(FsError::NotFile),
+        "resize a directory"
+    );
+}
+
+pub fn create_dir(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    assert_eq!(dir.metadata().unwrap().type_, FileType::Dir);
+    assert_eq!(inode_id(&dir.find(".").unwrap()), inode_id(&dir), "dir/.");
+    assert_eq!(
+        inode_id(&dir.find("..").unwrap()),
+        inode_id(&root),
+        "dir/.."
+    );
+    assert_eq!(dir.list().unwrap(), [".", ".."], "new directory is empty");
+
+    let sub = dir.create("sub", FileType::Dir, 0o755).unwrap();
+    assert_eq!(inode_id(&sub.find("..").unwrap()), inode_id(&dir), "sub/..");
+    if env.features().dir_nlinks {
+        assert_eq!(nlinks(&sub), 2, "nlinks of empty dir");
+        assert_eq!(nlinks(&dir), 3, "nlinks of dir with a subdir");
+        assert_eq!(nlinks(&root), 3, "nlinks of root with a subdir");
+        dir.create("file", FileType::File, 0o644).unwrap();
+        assert_eq!(nlinks(&dir), 3, "a file does not link to its parent");
+    }
+    fs.sync().unwrap();
+}
+
+pub fn unlink_file(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    file.write_at(0, b"data").unwrap();
+    root.unlink("file").unwrap();
+    assert_eq!(err(root.find("file")), Some(FsError::EntryNotFound));
+    assert!(children(&root).is_empty());
+    assert_eq!(
+        err(root.unlink("file")),
+        Some(FsError::EntryNotFound),
+        "unlink a removed file"
+    );
+    drop(file);
+
+    // the name can be reused
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    assert_eq!(file.metadata().unwrap().size, 0);
+    fs.sync().unwrap();
+}
+
+pub fn unlink_dir(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    dir.create("file", FileType::File, 0o644).unwrap();
+    assert_eq!(
+        err(root.unlink("dir")),
+        Some(FsError::DirNotEmpty),
+        "unlink a non-empty directory"
+    );
+    assert_eq!(err(dir.unlink(".")), Some(FsError::IsDir), "unlink .");
+    assert_eq!(err(dir.unlink("..")), Some(FsError::IsDir), "unlink ..");
+    dir.unlink("file").unwrap();
+    root.unlink("dir").unwrap();
+    assert_eq!(err(root.find("dir")), Some(FsError::EntryNotFound));
+    if env.features().dir_nlinks {
+        assert_eq!(nlinks(&root), 2, "nlinks of root after rmdir");
+    }
+    fs.sync().unwrap();
+}
+
+pub fn hard_link(env: &Env) {
+    if !env.features().hard_link {
+        return;
+    }
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    file.write_at(0, b"data").unwrap();
+
+    dir.link("link", &file).unwrap();
+    assert_eq!(nlinks(&file), 2, "nlinks after link");
+    let link = dir.find("link").unwrap();
+    assert_eq!(inode_id(&link), inode_id(&file), "link is the same inode");
+    link.write_at(4, b"!").unwrap();
+    assert_eq!(read_all(&file), b"data!", "content is shared");
+
+    assert_eq!(
+        err(dir.link("link", &file)),
+        Some(FsError::EntryExist),
+        "link to an existing name"
+    );
+    assert_eq!(
+        err(root.link("dir2", &dir)),
+        Some(FsError::IsDir),
+        "link a directory"
+    );
+
+    root.unlink("file").unwrap();
+    assert_eq!(nlinks(&link), 1, "nlinks after unlink");
+    assert_eq!(read_all(&link), b"data!", "content is kept by other links");
+    fs.sync().unwrap();
+}
+
+/// Operations between different file systems
+pub fn cross_fs(env: &Env) {
+    let fs1 = env.new_fs();
+    let fs2 = env.new_fs();
+    let root1 = fs1.root_inode();
+    let root2 = fs2.root_inode();
+    let file = root1.create("file", FileType::File, 0o644).unwrap();
+    if env.features().hard_link {
+        assert_eq!(
+            err(root2.link("file", &file)),
+            Some(FsError::NotSameFs),
+            "link across file systems"
+        );
+    }
+    assert_eq!(
+        err(root1.move_("file", &root2, "file")),
+        Some(FsError::NotSameFs),
+        "move across file systems"
+    );
+    assert!(root1.find("file").is_ok());
+    assert_eq!(err(root2.find("file")), Some(FsError::EntryNotFound));
+}
+
+pub fn rename(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    file.write_at(0, b"data").unwrap();
+    let id = inode_id(&file);
+    root.move_("file", &root, "file2").unwrap();
+    assert_eq!(err(root.find("file")), Some(FsError::EntryNotFound));
+    let file2 = root.find("file2").unwrap();
+    assert_eq!(inode_id(&file2), id);
+    assert_eq!(read_all(&file2), b"data");
+    assert_eq!(nlinks(&file2), 1, "nlinks is unchanged by rename");
+    assert_eq!(children(&root), ["file2"]);
+
+    assert_eq!(
+        err(root.move_("file", &root, "file3")),
+        Some(FsError::EntryNotFound),
+        "move a non-existent name"
+    );
+
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    let id = inode_id(&dir);
+    root.move_("dir", &root, "dir2").unwrap();
+    let dir = root.find("dir2").unwrap();
+    assert_eq!(inode_id(&dir), id);
+    assert_eq!(inode_id(&dir.find("..").unwrap()), inode_id(&root));
+    if env.features().dir_nlinks {
+        assert_eq!(nlinks(&root), 3, "nlinks is unchanged by rename");
+    }
+    fs.sync().unwrap();
+}
+
+pub fn move_to_dir(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let dir1 = root.create("dir1", FileType::Dir, 0o755).unwrap();
+    let dir2 = root.create("dir2", FileType::Dir, 0o755).unwrap();
+    let file = dir1.create("file", FileType::File, 0o644).unwrap();
+    let id = inode_id(&file);
+    dir1.move_("file", &dir2, "file").unwrap();
+    assert_eq!(err(dir1.find("file")), Some(FsError::EntryNotFound));
+    assert_eq!(inode_id(&dir2.find("file").unwrap()), id);
+
+    let sub = dir1.create("sub", FileType::Dir, 0o755).unwrap();
+    sub.create("file", FileType::File, 0o644).unwrap();
+    let id = inode_id(&sub);
+    dir1.move_("sub", &dir2, "sub2").unwrap();
+    let sub = dir2.find("sub2").unwrap();
+    assert_eq!(inode_id(&sub), id);
+    assert_eq!(
+        inode_id(&sub.find("..").unwrap()),
+        inode_id(&dir2),
+        ".. of a moved directory is the new parent"
+    );
+    assert!(root.lookup("dir2/sub2/file").is_ok());
+    assert!(root.lookup("dir2/sub2/../file").is_ok());
+    if env.features().dir_nlinks {
+        assert_eq!(nlinks(&dir1), 2, "nlinks of the old parent");
+        assert_eq!(nlinks(&dir2), 3, "nlinks of the new parent");
+        assert_eq!(nlinks(&sub), 2, "nlinks of the moved directory");
+    }
+    fs.sync().unwrap();
+}
+
+/// Move onto an existing name replaces it
+pub fn move_replace(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let file1 = root.create("file1", FileType::File, 0o644).unwrap();
+    let file2 = root.create("file2", FileType::File, 0o644).unwrap();
+    file1.write_at(0, b"one").unwrap();
+    file2.write_at(0, b"two").unwrap();
+    root.move_("file1", &root, "file2").unwrap();
+    assert_eq!(children(&root), ["file2"], "the old entry is replaced");
+    assert_eq!(read_all(&root.find("file2").unwrap()), b"one");
+    drop(file2);
+
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    let id = inode_id(&dir);
+    let full = root.create("full", FileType::Dir, 0o755).unwrap();
+    full.create("file", FileType::File, 0o644).unwrap();
+    assert_eq!(
+        err(root.move_("file2", &root, "dir")),
+        Some(FsError::IsDir),
+        "move a file onto a directory"
+    );
+    assert_eq!(
+        err(root.move_("dir", &root, "file2")),
+        Some(FsError::NotDir),
+        "move a directory onto a file"
+    );
+    assert_eq!(
+        err(root.move_("dir", &root, "full")),
+        Some(FsError::DirNotEmpty),
+        "move a directory onto a non-empty directory"
+    );
+    assert_eq!(children(&root), ["dir", "file2", "full"]);
+
+    full.unlink("file").unwrap();
+    root.move_("dir", &root, "full").unwrap();
+    assert_eq!(children(&root), ["file2", "full"]);
+    assert_eq!(inode_id(&root.find("full").unwrap()), id);
+    if env.features().dir_nlinks {
+        assert_eq!(nlinks(&root), 3, "nlinks after replacing a directory");
+    }
+    fs.sync().unwrap();
+}
+
+/// A directory can not be moved into itself or its own subtree
+pub fn move_into_subtree(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let a = root.create("a", FileType::Dir, 0o755).unwrap();
+    let b = a.create("b", FileType::Dir, 0o755).unwrap();
+    assert_eq!(
+        err(root.move_("a", &b, "a")),
+        Some(FsError::InvalidParam),
+        "move a directory into its subdirectory"
+    );
+    assert_eq!(
+        err(root.move_("a", &a, "a")),
+        Some(FsError::InvalidParam),
+        "move a directory into itself"
+    );
+    assert_eq!(children(&root), ["a"]);
+    assert_eq!(children(&a), ["b"]);
+    assert!(root.lookup("a/b").is_ok());
+    fs.sync().unwrap();
+}
+
+pub fn symlink(env: &Env) {
+    if !env.features().symlink {
+        return;
+    }
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    let file = dir.create("file", FileType::File, 0o644).unwrap();
+    let link = root.create("link", FileType::SymLink, 0o777).unwrap();
+    link.write_at(0, b"dir").unwrap();
+    assert_eq!(link.metadata().unwrap().type_, FileType::SymLink);
+    assert_eq!(read_all(&link), b"dir");
+
+    assert_eq!(inode_id(&root.lookup("link").unwrap()), inode_id(&link));
+    assert_eq!(
+        inode_id(&root.lookup_follow("link/file", 1).unwrap()),
+        inode_id(&file)
+    );
+    assert_eq!(
+        err(root.lookup("link/file")),
+        Some(FsError::NotDir),
+        "lookup without following symlinks"
+    );
+    fs.sync().unwrap();
+}
+
+pub fn lookup(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let dir1 = root.create("dir1", FileType::Dir, 0o755).unwrap();
+    let dir2 = dir1.create("dir2", FileType::Dir, 0o755).unwrap();
+    let file = dir2.create("file", FileType::File, 0o644).unwrap();
+    let id = inode_id(&file);
+    for path in [
+        "dir1/dir2/file",
+        "/dir1/dir2/file",
+        "./dir1/./dir2/file",
+        "dir1//dir2/file",
+        "dir1/dir2/../dir2/file",
+        "/../dir1/dir2/file",
+    ] {
+        assert_eq!(inode_id(&root.lookup(path).unwrap()), id, "lookup {}", path);
+    }
+    assert_eq!(inode_id(&dir2.lookup("file").unwrap()), id);
+    assert_eq!(inode_id(&dir2.lookup("/dir1/dir2/file").unwrap()), id);
+    assert_eq!(inode_id(&dir2.lookup("../../dir1/dir2/file").unwrap()), id);
+    assert_eq!(inode_id(&dir2.lookup("..").unwrap()), inode_id(&dir1));
+    assert_eq!(
+        err(root.lookup("dir1/nothing/file")),
+        Some(FsError::EntryNotFound)
+    );
+}
+
+pub fn get_entry_with_metadata(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    root.create("file", FileType::File, 0o644).unwrap();
+    root.create("dir", FileType::Dir, 0o755).unwrap();
+    let names = root.list().unwrap();
+    assert_eq!(names.len(), 4);
+    for (i, name) in names.iter().enumerate() {
+        let (info, entry_name) = root.get_entry_with_metadata(i).unwrap();
+        assert_eq!(&entry_name, name);
+        assert_eq!(info.inode, inode_id(&root.find(name).unwrap()));
+    }
+    assert_eq!(
+        err(root.get_entry(names.len())),
+        Some(FsError::EntryNotFound),
+        "get_entry after the last one"
+    );
+}
+
+pub fn many_entries(env: &Env) {
+    let fs = env.new_fs();
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    let mut names: Vec<String> = (0..100).map(|i| format!("file{:03}", i)).collect();
+    for name in names.iter() {
+        dir.create(name, FileType::File, 0o644).unwrap();
+    }
+    assert_eq!(children(&dir), names);
+    for name in names.iter().step_by(2) {
+        dir.unlink(name).unwrap();
+    }
+    names = names.into_iter().skip(1).step_by(2).collect();
+    assert_eq!(children(&dir), names);
+    for name in names.iter() {
+        assert!(dir.find(name).is_ok(), "find {}", name);
+    }
+    fs.sync().unwrap();
+}
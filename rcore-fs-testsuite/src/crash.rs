@@ -0,0 +1,497 @@
+//! Crash-consistency checking
+//!
+//! A workload is run on a `Recorder`, which logs every write to the storage.
+//! Then the storage states a crash could leave behind are enumerated:
+//! every prefix of the log, and at each barrier (flush), states in which
+//! some writes since the last barrier are lost, as if they were reordered.
+//! Each state is reopened and checked by a caller-provided function.
+//!
+//! Creating and removing files of the storage take effect immediately,
+//! writes and length changes of a file are persisted by flushing that file.
+
+use crate::Features;
+use rcore_fs::dev::{self, DevError, Device};
+use rcore_fs::vfs::{FileSystem, FileType, INode};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+/// Contents of all files in a storage, by file id
+pub type Image = BTreeMap<usize, Vec<u8>>;
+
+/// An operation on the storage
+#[derive(Debug, Clone)]
+pub enum Event {
+    Write {
+        file: usize,
+        offset: usize,
+        data: Vec<u8>,
+    },
+    SetLen {
+        file: usize,
+        len: usize,
+    },
+    Create {
+        file: usize,
+    },
+    Remove {
+        file: usize,
+    },
+    /// Barrier: all previous events on `file` are persisted
+    Flush {
+        file: usize,
+    },
+}
+
+impl Event {
+    fn apply(&self, image: &mut Image) {
+        match *self {
+            Event::Write {
+                file,
+                offset,
+                ref data,
+            } => {
+                let content = image.entry(file).or_default();
+                if content.len() < offset + data.len() {
+                    content.resize(offset + data.len(), 0);
+                }
+                content[offset..offset + data.len()].copy_from_slice(data);
+            }
+            Event::SetLen { file, len } => image.entry(file).or_default().resize(len, 0),
+            Event::Create { file } => {
+                image.entry(file).or_default();
+            }
+            Event::Remove { file } => {
+                image.remove(&file);
+            }
+            Event::Flush { .. } => {}
+        }
+    }
+
+    /// The file whose pending events are persisted by this event
+    fn flushed_file(&self) -> Option<usize> {
+        match *self {
+            Event::Flush { file } => Some(file),
+            _ => None,
+        }
+    }
+
+    /// Whether this event is not persisted until a flush
+    fn is_buffered(&self) -> bool {
+        matches!(self, Event::Write { .. } | Event::SetLen { .. })
+    }
+
+    fn file(&self) -> usize {
+        match *self {
+            Event::Write { file, .. }
+            | Event::SetLen { file, .. }
+            | Event::Create { file }
+            | Event::Remove { file }
+            | Event::Flush { file } => file,
+        }
+    }
+}
+
+/// A storage recording all operations on it.
+///
+/// As a `Device`, it is the file 0 of the storage, which always exists and
+/// reads zeros beyond the end like a disk.
+#[derive(Default)]
+pub struct Recorder {
+    image: Mutex<Image>,
+    events: Mutex<Vec<Event>>,
+}
+
+impl Recorder {
+    /// Create a recorder whose initial content is `image`
+    pub fn new(image: Image) -> Arc<Self> {
+        Arc::new(Recorder {
+            image: Mutex::new(image),
+            events: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn record(&self, event: Event) {
+        event.apply(&mut self.image.lock().unwrap());
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// Current content of all files
+    pub fn image(&self) -> Image {
+        self.image.lock().unwrap().clone()
+    }
+
+    /// Take the events recorded since the last call
+    pub fn take_events(&self) -> Vec<Event> {
+        core::mem::take(&mut self.events.lock().unwrap())
+    }
+
+    pub fn exists(&self, file: usize) -> bool {
+        self.image.lock().unwrap().contains_key(&file)
+    }
+
+    /// Read like `std::fs::File`: stop at the end of file
+    pub fn read_at(&self, file: usize, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let image = self.image.lock().unwrap();
+        let content = image.get(&file)?;
+        let begin = content.len().min(offset);
+        let end = content.len().min(offset + buf.len());
+        buf[..end - begin].copy_from_slice(&content[begin..end]);
+        Some(end - begin)
+    }
+
+    pub fn write_at(&self, file: usize, offset: usize, buf: &[u8]) -> Option<usize> {
+        if !self.exists(file) {
+            return None;
+        }
+        self.record(Event::Write {
+            file,
+            offset,
+            data: buf.to_vec(),
+        });
+        Some(buf.len())
+    }
+
+    pub fn set_len(&self, file: usize, len: usize) -> Option<()> {
+        if !self.exists(file) {
+            return None;
+        }
+        self.record(Event::SetLen { file, len });
+        Some(())
+    }
+
+    pub fn create(&self, file: usize) {
+        self.record(Event::Create { file });
+    }
+
+    pub fn remove(&self, file: usize) -> Option<()> {
+        if !self.exists(file) {
+            return None;
+        }
+        self.record(Event::Remove { file });
+        Some(())
+    }
+
+    pub fn flush(&self, file: usize) {
+        self.record(Event::Flush { file });
+    }
+}
+
+impl Device for Recorder {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> dev::Result<usize> {
+        let len = Recorder::read_at(self, 0, offset, buf).ok_or(DevError)?;
+        buf[len..].fill(0);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> dev::Result<usize> {
+        self.record(Event::Write {
+            file: 0,
+            offset,
+            data: buf.to_vec(),
+        });
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> dev::Result<()> {
+        self.flush(0);
+        Ok(())
+    }
+}
+
+/// A storage state after a crash
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CrashPoint {
+    /// Number of events issued before the crash
+    pub events: usize,
+    /// Index of issued but not persisted events which are lost
+    pub lost: Vec<usize>,
+    /// Whether all issued events are persisted
+    pub durable: bool,
+}
+
+impl CrashPoint {
+    /// Reconstruct the storage from the initial `base` and `events`
+    pub fn replay(&self, base: &Image, events: &[Event]) -> Image {
+        let mut image = base.clone();
+        for (i, event) in events[..self.events].iter().enumerate() {
+            if self.lost.binary_search(&i).is_err() {
+                event.apply(&mut image);
+            }
+        }
+        image
+    }
+}
+
+impl fmt::Display for CrashPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "after {} events", self.events)?;
+        if !self.lost.is_empty() {
+            write!(f, ", lost {:?}", self.lost)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Number of random sets of lost events at each barrier
+    pub subsets_per_barrier: usize,
+    /// Seed of the random sets
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            subsets_per_barrier: 8,
+            seed: 0,
+        }
+    }
+}
+
+/// A xorshift generator, good enough to pick subsets
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Enumerate the crash points of `events`
+pub fn crash_points(events: &[Event], config: &Config) -> Vec<CrashPoint> {
+    let mut points = BTreeSet::new();
+    let mut rng = Rng(config.seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1);
+    let mut pending: Vec<usize> = Vec::new();
+    for i in 0..=events.len() {
+        // in order
+        points.insert(CrashPoint {
+            events: i,
+            lost: Vec::new(),
+            durable: pending.is_empty(),
+        });
+        // reordered before a barrier or at the end
+        let barrier = events.get(i).is_none_or(|e| e.flushed_file().is_some());
+        if barrier && !pending.is_empty() {
+            for &p in pending.iter() {
+                points.insert(CrashPoint {
+                    events: i,
+                    lost: vec![p],
+                    durable: false,
+                });
+            }
+            for _ in 0..config.subsets_per_barrier {
+                let lost: Vec<usize> = pending
+                    .iter()
+                    .copied()
+                    .filter(|_| rng.next() & 1 == 0)
+                    .collect();
+                if !lost.is_empty() {
+                    points.insert(CrashPoint {
+                        events: i,
+                        lost,
+                        durable: false,
+                    });
+                }
+            }
+        }
+        if let Some(event) = events.get(i) {
+            if let Some(file) = event.flushed_file() {
+                pending.retain(|&p| events[p].file() != file);
+            } else if event.is_buffered() {
+                pending.push(i);
+            }
+        }
+    }
+    points.into_iter().collect()
+}
+
+/// A crash point which fails the check
+#[derive(Debug)]
+pub struct Failure {
+    pub point: CrashPoint,
+    pub error: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of checked crash points
+    pub points: usize,
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    /// Failures at crash points where all issued writes are persisted.
+    /// These are bugs even for a file system without crash consistency.
+    pub fn durable_failures(&self) -> impl Iterator<Item = &Failure> {
+        self.failures.iter().filter(|f| f.point.durable)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} of {} crash points are corrupted",
+            self.failures.len(),
+            self.points
+        )?;
+        for failure in self.failures.iter() {
+            writeln!(f, "  {}: {}", failure.point, failure.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check every crash point of `events` applied on `base`.
+///
+/// `check` should open the file system on the image and verify it.
+/// A panic in `check` is reported as a failure.
+pub fn check(
+    base: &Image,
+    events: &[Event],
+    config: &Config,
+    check: impl Fn(Image) -> Result<(), String>,
+) -> Report {
+    let points = crash_points(events, config);
+    let mut report = Report {
+        points: points.len(),
+        failures: Vec::new(),
+    };
+    for point in points {
+        let image = point.replay(base, events);
+        let result = catch_unwind(AssertUnwindSafe(|| check(image))).unwrap_or_else(|e| {
+            let msg = e
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            Err(format!("panic: {}", msg))
+        });
+        if let Err(error) = result {
+            report.failures.push(Failure { point, error });
+        }
+    }
+    report
+}
+
+/// Check invariants of the directory tree which should hold on any
+/// successfully opened file system:
+///
+/// * every entry can be found and has readable metadata and content
+/// * "." and ".." of directories are themselves and their parents
+/// * directories are linked only once, so there is no loop
+/// * link counts match the number of entries (as `features` declares)
+pub fn check_tree(fs: &dyn FileSystem, features: Features) -> Result<(), String> {
+    struct Walker {
+        /// inode id -> (type, nlinks, entries referring to it, subdirs)
+        inodes: BTreeMap<usize, (FileType, usize, usize, usize)>,
+    }
+    impl Walker {
+        fn walk(&mut self, path: &str, dir: &Arc<dyn INode>, parent: usize) -> Result<(), String> {
+            let err = |op: &str, e| format!("{}: {}: {:?}", path, op, e);
+            let id = dir.metadata().map_err(|e| err("metadata", e))?.inode;
+            let names = dir.list().map_err(|e| err("list", e))?;
+            let unique: BTreeSet<&String> = names.iter().collect();
+            if unique.len() != names.len() {
+                return Err(format!("{}: duplicated entries", path));
+            }
+            for name in names.iter() {
+                let child = dir.find(name).map_err(|e| err("find", e))?;
+                let info = child.metadata().map_err(|e| err("metadata", e))?;
+                match name.as_str() {
+                    "." if info.inode != id => return Err(format!("{}: . is not itself", path)),
+                    ".." if info.inode != parent => {
+                        return Err(format!("{}: .. is not the parent", path))
+                    }
+                    "." | ".." => continue,
+                    _ => {}
+                }
+                let child_path = format!("{}/{}", path, name);
+                let entry =
+                    self.inodes
+                        .entry(info.inode)
+                        .or_insert((info.type_, info.nlinks, 0, 0));
+                entry.2 += 1;
+                match info.type_ {
+                    FileType::Dir => {
+                        if entry.2 > 1 {
+                            return Err(format!("{}: directory is linked twice", child_path));
+                        }
+                        self.inodes.get_mut(&id).unwrap().3 += 1;
+                        self.walk(&child_path, &child, id)?;
+                    }
+                    FileType::File | FileType::SymLink => {
+                        let mut buf = vec![0u8; info.size];
+                        let len = child
+                            .read_at(0, &mut buf)
+                            .map_err(|e| format!("{}: read_at: {:?}", child_path, e))?;
+                        if len != info.size {
+                            return Err(format!(
+                                "{}: read {} bytes, size {}",
+                                child_path, len, info.size
+                            ));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let root = fs.root_inode();
+    let info = root.metadata().map_err(|e| format!("root: {:?}", e))?;
+    let mut walker = Walker {
+        inodes: BTreeMap::new(),
+    };
+    walker
+        .inodes
+        .insert(info.inode, (FileType::Dir, info.nlinks, 1, 0));
+    walker.walk("", &root, info.inode)?;
+    for (id, &(type_, nlinks, refs, subdirs)) in walker.inodes.iter() {
+        let expected = match type_ {
+            FileType::Dir if features.dir_nlinks => 2 + subdirs,
+            FileType::Dir => continue,
+            _ if features.hard_link => refs,
+            _ => continue,
+        };
+        if nlinks != expected {
+            return Err(format!(
+                "inode {}: nlinks {}, expect {}",
+                id, nlinks, expected
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A workload touching most kinds of metadata, with syncs in between
+pub fn workload(fs: &dyn FileSystem, features: Features) {
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+    let file = dir.create("file", FileType::File, 0o644).unwrap();
+    file.write_at(0, b"hello").unwrap();
+    fs.sync().unwrap();
+
+    let big = root.create("big", FileType::File, 0o644).unwrap();
+    let data: Vec<u8> = (0..60000u32).map(|i| i as u8).collect();
+    big.write_at(0, &data).unwrap();
+    if features.hard_link {
+        root.link("link", &file).unwrap();
+    }
+    root.create("sub", FileType::Dir, 0o755).unwrap();
+    fs.sync().unwrap();
+
+    root.move_("sub", &dir, "sub").unwrap();
+    dir.move_("file", &root, "file").unwrap();
+    big.resize(5000).unwrap();
+    root.unlink("big").unwrap();
+    drop(big);
+    fs.sync().unwrap();
+}
@@ -0,0 +1,147 @@
+//! Conformance test suite for `FileSystem` implementations.
+//!
+//! Every case takes a freshly created file system and checks one part of
+//! the `INode` contract, with the error each operation is expected to return.
+//!
+//! Use `vfs_tests!` in a test module to generate one `#[test]` for each case:
+//!
+//! ```ignore
+//! mod vfs {
+//!     use super::*;
+//!     rcore_fs_testsuite::vfs_tests!(RamFS::new());
+//! }
+//! ```
+//!
+//! The `model` module tests a file system with random operation sequences
+//! against a reference model, and the `crash` module checks the states a
+//! crash could leave on the storage.
+//!
+//! Ext2 and WondFS do not run the suite: ext2 does not implement `INode`,
+//! and WondFS can not create an empty file system with a root directory yet.
+
+use rcore_fs::vfs::FileSystem;
+use std::cell::RefCell;
+use std::sync::Arc;
+
+pub mod cases;
+pub mod crash;
+pub mod model;
+
+/// Optional features of a file system.
+/// Cases or checks for features which are not supported are skipped.
+#[derive(Debug, Copy, Clone)]
+pub struct Features {
+    /// Support creating `FileType::SymLink`
+    pub symlink: bool,
+    /// Support hard links to files by `link()`
+    pub hard_link: bool,
+    /// Keep POSIX link count for directories: 2 + number of subdirectories
+    pub dir_nlinks: bool,
+}
+
+impl Features {
+    /// All features are supported
+    pub const fn all() -> Self {
+        Features {
+            symlink: true,
+            hard_link: true,
+            dir_nlinks: true,
+        }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// A file system under test, with any resources it depends on.
+pub trait Fixture {
+    fn fs(&self) -> Arc<dyn FileSystem>;
+}
+
+impl<F: FileSystem + 'static> Fixture for Arc<F> {
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.clone()
+    }
+}
+
+/// A file system with a guard to be dropped after it, e.g. a temporary directory
+impl<F: FileSystem + 'static, G> Fixture for (Arc<F>, G) {
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.0.clone()
+    }
+}
+
+/// The environment of a test case
+pub struct Env {
+    features: Features,
+    make: Box<dyn Fn() -> Box<dyn Fixture>>,
+    fixtures: RefCell<Vec<Box<dyn Fixture>>>,
+}
+
+impl Env {
+    /// `make` should create a new empty file system each time it is called
+    pub fn new<T: Fixture + 'static>(make: impl Fn() -> T + 'static, features: Features) -> Self {
+        Env {
+            features,
+            make: Box::new(move || Box::new(make())),
+            fixtures: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Create a new empty file system, which is kept alive until `self` is dropped
+    pub fn new_fs(&self) -> Arc<dyn FileSystem> {
+        let fixture = (self.make)();
+        let fs = fixture.fs();
+        self.fixtures.borrow_mut().push(fixture);
+        fs
+    }
+
+    pub fn features(&self) -> &Features {
+        &self.features
+    }
+}
+
+/// Generate a `#[test]` for each case in `cases`.
+///
+/// `$fixture` is evaluated each time a new empty file system is required,
+/// and should give a `Fixture`.
+#[macro_export]
+macro_rules! vfs_tests {
+    ($fixture:expr) => {
+        $crate::vfs_tests!($fixture, $crate::Features::all());
+    };
+    ($fixture:expr, $features:expr) => {
+        $crate::vfs_tests!(@cases ($fixture) ($features)
+            root_dir,
+            create_file,
+            non_dir_operations,
+            read_write,
+            resize,
+            dir_io,
+            create_dir,
+            unlink_file,
+            unlink_dir,
+            hard_link,
+            cross_fs,
+            rename,
+            move_to_dir,
+            move_replace,
+            move_into_subtree,
+            symlink,
+            lookup,
+            get_entry_with_metadata,
+            many_entries,
+        );
+    };
+    (@cases ($fixture:expr) ($features:expr) $($case:ident,)*) => {
+        $(
+            #[test]
+            fn $case() {
+                $crate::cases::$case(&$crate::Env::new(|| $fixture, $features));
+            }
+        )*
+    };
+}
@@ -0,0 +1,543 @@
+//! Model-based randomized testing
+//!
+//! Random sequences of operations are applied both to a file system and to
+//! a simple in-memory reference model. After each step the whole tree of the
+//! file system is compared with the model. Failing sequences are shrunk by
+//! `proptest` to a minimal one before being reported.
+
+use crate::Features;
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestCaseError, TestError, TestRunner};
+use rcore_fs::vfs::{FileSystem, FileType, FsError, INode};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A file system under model test
+pub trait Target {
+    fn fs(&self) -> Arc<dyn FileSystem>;
+
+    /// Sync and open the file system again from its storage.
+    ///
+    /// File systems without a storage can keep the same instance.
+    fn reopen(&mut self) {}
+}
+
+impl<F: FileSystem + 'static> Target for Arc<F> {
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        self.clone()
+    }
+}
+
+/// Names used in generated paths. A small set makes collisions likely.
+const NAMES: &[&str] = &["a", "b", "c"];
+
+/// An operation on the file system. Paths are relative to the root.
+#[derive(Clone)]
+pub enum Op {
+    Create {
+        path: String,
+        dir: bool,
+    },
+    Write {
+        path: String,
+        offset: usize,
+        len: usize,
+        seed: u8,
+    },
+    Resize {
+        path: String,
+        len: usize,
+    },
+    Link {
+        old: String,
+        new: String,
+    },
+    Unlink {
+        path: String,
+    },
+    Move {
+        old: String,
+        new: String,
+    },
+    Sync,
+    Reopen,
+}
+
+impl fmt::Debug for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Create { path, dir: false } => write!(f, "create {}", path),
+            Op::Create { path, dir: true } => write!(f, "mkdir {}", path),
+            Op::Write {
+                path,
+                offset,
+                len,
+                seed,
+            } => write!(f, "write {} [{}, +{}) seed={}", path, offset, len, seed),
+            Op::Resize { path, len } => write!(f, "resize {} {}", path, len),
+            Op::Link { old, new } => write!(f, "link {} {}", old, new),
+            Op::Unlink { path } => write!(f, "unlink {}", path),
+            Op::Move { old, new } => write!(f, "move {} {}", old, new),
+            Op::Sync => write!(f, "sync"),
+            Op::Reopen => write!(f, "reopen"),
+        }
+    }
+}
+
+/// Content written by `Op::Write`
+fn pattern(offset: usize, len: usize, seed: u8) -> Vec<u8> {
+    (offset..offset + len)
+        .map(|i| (i % 251) as u8 ^ seed)
+        .collect()
+}
+
+/// Split a path into its parent and the last name
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => ("", path),
+    }
+}
+
+fn path_strategy() -> impl Strategy<Value = String> {
+    // shallow paths are more likely to exist
+    let depth = prop_oneof![4 => Just(1usize), 2 => Just(2usize), 1 => Just(3usize)];
+    depth
+        .prop_flat_map(|depth| prop::collection::vec(prop::sample::select(NAMES), depth))
+        .prop_map(|names| names.join("/"))
+}
+
+fn size_strategy() -> impl Strategy<Value = usize> {
+    // mostly within a few blocks, sometimes across indirect blocks
+    prop_oneof![2 => 0..600usize, 2 => 0..10000usize, 1 => 0..80000usize]
+}
+
+fn op_strategy(features: Features) -> impl Strategy<Value = Op> {
+    let link_weight = if features.hard_link { 2 } else { 0 };
+    prop_oneof![
+        4 => (path_strategy(), any::<bool>()).prop_map(|(path, dir)| Op::Create { path, dir }),
+        4 => (path_strategy(), size_strategy(), 0..3000usize, any::<u8>())
+            .prop_map(|(path, offset, len, seed)| Op::Write { path, offset, len, seed }),
+        2 => (path_strategy(), size_strategy()).prop_map(|(path, len)| Op::Resize { path, len }),
+        link_weight => (path_strategy(), path_strategy()).prop_map(|(old, new)| Op::Link { old, new }),
+        2 => path_strategy().prop_map(|path| Op::Unlink { path }),
+        2 => (path_strategy(), path_strategy()).prop_map(|(old, new)| Op::Move { old, new }),
+        1 => Just(Op::Sync),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+/// Strategy of operation sequences
+pub fn ops_strategy(features: Features, max_len: usize) -> impl Strategy<Value = Vec<Op>> {
+    prop::collection::vec(op_strategy(features), 1..max_len)
+}
+
+type NodeId = usize;
+
+enum Node {
+    File {
+        data: Vec<u8>,
+        nlinks: usize,
+    },
+    Dir {
+        parent: NodeId,
+        children: BTreeMap<String, NodeId>,
+    },
+}
+
+/// The reference file system
+struct Model {
+    nodes: Vec<Node>,
+}
+
+const ROOT: NodeId = 0;
+
+impl Model {
+    fn new() -> Self {
+        Model {
+            nodes: vec![Node::Dir {
+                parent: ROOT,
+                children: BTreeMap::new(),
+            }],
+        }
+    }
+
+    fn is_dir(&self, id: NodeId) -> bool {
+        matches!(self.nodes[id], Node::Dir { .. })
+    }
+
+    fn children(&self, id: NodeId) -> Option<&BTreeMap<String, NodeId>> {
+        match &self.nodes[id] {
+            Node::Dir { children, .. } => Some(children),
+            Node::File { .. } => None,
+        }
+    }
+
+    fn children_mut(&mut self, id: NodeId) -> &mut BTreeMap<String, NodeId> {
+        match &mut self.nodes[id] {
+            Node::Dir { children, .. } => children,
+            Node::File { .. } => unreachable!(),
+        }
+    }
+
+    fn lookup(&self, path: &str) -> Result<NodeId, FsError> {
+        let mut id = ROOT;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let children = self.children(id).ok_or(FsError::NotDir)?;
+            id = *children.get(name).ok_or(FsError::EntryNotFound)?;
+        }
+        Ok(id)
+    }
+
+    /// Lookup the parent of `path`, which may not be a directory
+    fn lookup_parent<'a>(&self, path: &'a str) -> Result<(NodeId, &'a str), FsError> {
+        let (parent, name) = split(path);
+        Ok((self.lookup(parent)?, name))
+    }
+
+    /// Entries of `id`, which should be a directory
+    fn entries(&self, id: NodeId) -> Result<&BTreeMap<String, NodeId>, FsError> {
+        self.children(id).ok_or(FsError::NotDir)
+    }
+
+    fn file_mut(&mut self, path: &str) -> Result<&mut Vec<u8>, FsError> {
+        let id = self.lookup(path)?;
+        match &mut self.nodes[id] {
+            Node::File { data, .. } => Ok(data),
+            Node::Dir { .. } => Err(FsError::NotFile),
+        }
+    }
+
+    fn is_ancestor(&self, ancestor: NodeId, mut id: NodeId) -> bool {
+        loop {
+            if id == ancestor {
+                return true;
+            }
+            match self.nodes[id] {
+                Node::Dir { parent, .. } if id != ROOT => id = parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Drop a link to `id`
+    fn release(&mut self, id: NodeId) {
+        if let Node::File { nlinks, .. } = &mut self.nodes[id] {
+            *nlinks -= 1;
+        }
+    }
+
+    /// Whether the result of `op` is not specified by the contract
+    fn is_unspecified(&self, op: &Op) -> bool {
+        match op {
+            // moving a directory into itself
+            Op::Move { old, new } => match (self.lookup(old), self.lookup_parent(new)) {
+                (Ok(id), Ok((dest, _))) => {
+                    self.is_dir(id) && self.is_dir(dest) && self.is_ancestor(id, dest)
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Apply `op` to the model
+    fn apply(&mut self, op: &Op) -> Result<(), FsError> {
+        match op {
+            Op::Create { path, dir } => {
+                let (parent, name) = self.lookup_parent(path)?;
+                if self.entries(parent)?.contains_key(name) {
+                    return Err(FsError::EntryExist);
+                }
+                let id = self.nodes.len();
+                self.nodes.push(match dir {
+                    true => Node::Dir {
+                        parent,
+                        children: BTreeMap::new(),
+                    },
+                    false => Node::File {
+                        data: Vec::new(),
+                        nlinks: 1,
+                    },
+                });
+                self.children_mut(parent).insert(String::from(name), id);
+            }
+            &Op::Write {
+                ref path,
+                offset,
+                len,
+                seed,
+            } => {
+                let data = self.file_mut(path)?;
+                if data.len() < offset + len {
+                    data.resize(offset + len, 0);
+                }
+                data[offset..offset + len].copy_from_slice(&pattern(offset, len, seed));
+            }
+            Op::Resize { path, len } => self.file_mut(path)?.resize(*len, 0),
+            Op::Link { old, new } => {
+                let id = self.lookup(old)?;
+                let (parent, name) = self.lookup_parent(new)?;
+                if self.entries(parent)?.contains_key(name) {
+                    return Err(FsError::EntryExist);
+                }
+                match &mut self.nodes[id] {
+                    Node::File { nlinks, .. } => *nlinks += 1,
+                    Node::Dir { .. } => return Err(FsError::IsDir),
+                }
+                self.children_mut(parent).insert(String::from(name), id);
+            }
+            Op::Unlink { path } => {
+                let (parent, name) = self.lookup_parent(path)?;
+                let id = *self
+                    .entries(parent)?
+                    .get(name)
+                    .ok_or(FsError::EntryNotFound)?;
+                if let Some(children) = self.children(id) {
+                    if !children.is_empty() {
+                        return Err(FsError::DirNotEmpty);
+                    }
+                }
+                self.children_mut(parent).remove(name);
+                self.release(id);
+            }
+            Op::Move { old, new } => {
+                let (src, old_name) = self.lookup_parent(old)?;
+                let (dest, new_name) = self.lookup_parent(new)?;
+                // both should be directories
+                self.entries(src)?;
+                self.entries(dest)?;
+                let id = *self
+                    .entries(src)?
+                    .get(old_name)
+                    .ok_or(FsError::EntryNotFound)?;
+                let is_dir = self.is_dir(id);
+                if let Some(&old_id) = self.entries(dest)?.get(new_name) {
+                    if old_id == id {
+                        return Ok(());
+                    }
+                    match (is_dir, self.children(old_id)) {
+                        (false, Some(_)) => return Err(FsError::IsDir),
+                        (true, None) => return Err(FsError::NotDir),
+                        (true, Some(children)) if !children.is_empty() => {
+                            return Err(FsError::DirNotEmpty)
+                        }
+                        _ => {}
+                    }
+                    self.release(old_id);
+                }
+                self.children_mut(src).remove(old_name);
+                self.children_mut(dest).insert(String::from(new_name), id);
+                if let Node::Dir { parent, .. } = &mut self.nodes[id] {
+                    *parent = dest;
+                }
+            }
+            Op::Sync | Op::Reopen => {}
+        }
+        Ok(())
+    }
+}
+
+/// Apply `op` to the file system
+fn apply(target: &mut dyn Target, op: &Op) -> Result<(), FsError> {
+    let root = target.fs().root_inode();
+    let lookup_parent = |path: &str| -> Result<(Arc<dyn INode>, String), FsError> {
+        let (parent, name) = split(path);
+        Ok((root.lookup(parent)?, String::from(name)))
+    };
+    match op {
+        Op::Create { path, dir } => {
+            let (parent, name) = lookup_parent(path)?;
+            let type_ = if *dir { FileType::Dir } else { FileType::File };
+            parent.create(&name, type_, 0o755)?;
+        }
+        &Op::Write {
+            ref path,
+            offset,
+            len,
+            seed,
+        } => {
+            let written = root
+                .lookup(path)?
+                .write_at(offset, &pattern(offset, len, seed))?;
+            assert_eq!(written, len, "write_at should write all data");
+        }
+        Op::Resize { path, len } => root.lookup(path)?.resize(*len)?,
+        Op::Link { old, new } => {
+            let other = root.lookup(old)?;
+            let (parent, name) = lookup_parent(new)?;
+            parent.link(&name, &other)?;
+        }
+        Op::Unlink { path } => {
+            let (parent, name) = lookup_parent(path)?;
+            parent.unlink(&name)?;
+        }
+        Op::Move { old, new } => {
+            let (src, old_name) = lookup_parent(old)?;
+            let (dest, new_name) = lookup_parent(new)?;
+            src.move_(&old_name, &dest, &new_name)?;
+        }
+        Op::Sync => target.fs().sync()?,
+        Op::Reopen => {
+            drop(root);
+            target.reopen();
+        }
+    }
+    Ok(())
+}
+
+/// Compare the tree of the file system with the model
+struct Checker<'a> {
+    model: &'a Model,
+    features: Features,
+    /// inode id of each model node
+    inodes: BTreeMap<NodeId, usize>,
+    /// model node of each inode id
+    nodes: BTreeMap<usize, NodeId>,
+}
+
+impl Checker<'_> {
+    fn check(&mut self, path: &str, inode: &Arc<dyn INode>, id: NodeId) -> Result<(), String> {
+        let info = inode
+            .metadata()
+            .map_err(|e| format!("{}: metadata: {:?}", path, e))?;
+        // the same model node should be the same inode
+        let known_inode = *self.inodes.entry(id).or_insert(info.inode);
+        let known_node = *self.nodes.entry(info.inode).or_insert(id);
+        if known_inode != info.inode || known_node != id {
+            return Err(format!(
+                "{}: inode {} is not linked as expected",
+                path, info.inode
+            ));
+        }
+        match &self.model.nodes[id] {
+            Node::File { data, nlinks } => {
+                if info.type_ != FileType::File {
+                    return Err(format!("{}: type {:?}, expect file", path, info.type_));
+                }
+                if info.size != data.len() {
+                    return Err(format!(
+                        "{}: size {}, expect {}",
+                        path,
+                        info.size,
+                        data.len()
+                    ));
+                }
+                if self.features.hard_link && info.nlinks != *nlinks {
+                    return Err(format!(
+                        "{}: nlinks {}, expect {}",
+                        path, info.nlinks, nlinks
+                    ));
+                }
+                let mut buf = vec![0u8; data.len()];
+                let len = inode
+                    .read_at(0, &mut buf)
+                    .map_err(|e| format!("{}: read_at: {:?}", path, e))?;
+                if len != data.len() || &buf != data {
+                    let pos = (0..len).find(|&i| buf[i] != data[i]).unwrap_or(len);
+                    return Err(format!("{}: content differs from offset {}", path, pos));
+                }
+            }
+            Node::Dir { parent, children } => {
+                if info.type_ != FileType::Dir {
+                    return Err(format!("{}: type {:?}, expect dir", path, info.type_));
+                }
+                let mut names = inode
+                    .list()
+                    .map_err(|e| format!("{}: list: {:?}", path, e))?;
+                names.retain(|name| name != "." && name != "..");
+                names.sort();
+                let expected: Vec<&String> = children.keys().collect();
+                if names.iter().collect::<Vec<_>>() != expected {
+                    return Err(format!(
+                        "{}: entries {:?}, expect {:?}",
+                        path, names, expected
+                    ));
+                }
+                let subdirs = children.values().filter(|&&c| self.model.is_dir(c)).count();
+                if self.features.dir_nlinks && info.nlinks != 2 + subdirs {
+                    return Err(format!(
+                        "{}: nlinks {}, expect {}",
+                        path,
+                        info.nlinks,
+                        2 + subdirs
+                    ));
+                }
+                let dotdot = inode
+                    .find("..")
+                    .and_then(|p| p.metadata())
+                    .map_err(|e| format!("{}: find ..: {:?}", path, e))?;
+                if self.inodes.get(parent) != Some(&dotdot.inode) {
+                    return Err(format!("{}: .. is not the parent", path));
+                }
+                for (name, &child) in children {
+                    let child_path = format!("{}/{}", path, name);
+                    let child_inode = inode
+                        .find(name)
+                        .map_err(|e| format!("{}: find: {:?}", child_path, e))?;
+                    self.check(&child_path, &child_inode, child)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn compare(model: &Model, fs: &Arc<dyn FileSystem>, features: Features) -> Result<(), String> {
+    let mut checker = Checker {
+        model,
+        features,
+        inodes: BTreeMap::new(),
+        nodes: BTreeMap::new(),
+    };
+    checker.check("", &fs.root_inode(), ROOT)
+}
+
+/// Apply `ops` to a new file system and the model, return the first mismatch
+pub fn run(target: &mut dyn Target, features: Features, ops: &[Op]) -> Result<(), String> {
+    let mut model = Model::new();
+    for (i, op) in ops.iter().chain([Op::Reopen].iter()).enumerate() {
+        if model.is_unspecified(op) {
+            continue;
+        }
+        let expected = model.apply(op);
+        let actual = apply(target, op);
+        if actual != expected {
+            return Err(format!(
+                "step {} {:?}: return {:?}, expect {:?}",
+                i, op, actual, expected
+            ));
+        }
+        compare(&model, &target.fs(), features)
+            .map_err(|e| format!("step {} {:?}: {}", i, op, e))?;
+    }
+    Ok(())
+}
+
+/// Run random operation sequences on file systems made by `make`.
+///
+/// Panic with the shrunk sequence if a mismatch is found.
+/// The number of sequences can be set by env `PROPTEST_CASES`.
+pub fn check<T: Target>(make: impl Fn() -> T, features: Features) {
+    let config = Config {
+        cases: std::env::var("PROPTEST_CASES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(256),
+        failure_persistence: None,
+        ..Config::default()
+    };
+    let mut runner = TestRunner::new(config);
+    let result = runner.run(&ops_strategy(features, 40), |ops| {
+        let mut target = make();
+        run(&mut target, features, &ops).map_err(TestCaseError::fail)
+    });
+    match result {
+        Ok(()) => {}
+        Err(TestError::Fail(reason, ops)) => {
+            panic!("model test failed: {}\nminimal ops: {:#?}", reason, ops)
+        }
+        Err(e) => panic!("model test failed: {}", e),
+    }
+}
@@ -22,6 +22,11 @@ impl vfs::FileSystem for WondFS {
             files: (sb.get_block_num() * sb.get_page_num_per_block()) as usize,  // inaccurate
             ffree: (sb.get_block_num() * sb.get_page_num_per_block()) as usize,  // inaccurate
             namemax: MAX_FNAME_LEN,
+            inline_blocks: 0,
         }
     }
+
+    fn name(&self) -> &'static str {
+        "wondfs"
+    }
 }
\ No newline at end of file
@@ -0,0 +1,725 @@
+//! A `Device` wrapper which transparently compresses data
+//!
+//! Logical blocks are grouped into fixed-size clusters. Each cluster is
+//! compressed independently and stored in the backing device through an
+//! indirection table, so overwriting a cluster relocates it to a new place.
+//!
+//! Layout of the backing device, in sectors:
+//!
+//! ```text
+//! | header | cluster table ... | data ... |
+//! ```
+use super::*;
+use crate::vfs::{self, FsError};
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+/// magic number for compressed device
+pub const MAGIC: u32 = 0x445a_4c43;
+/// log2( size of the allocation unit in backing device )
+pub const SECTOR_SIZE_LOG2: u8 = 9;
+/// size of the allocation unit in backing device
+pub const SECTOR_SIZE: usize = 1 << SECTOR_SIZE_LOG2;
+/// max log2( size of cluster )
+pub const MAX_CLUSTER_SIZE_LOG2: u8 = 16;
+/// size of one entry in the cluster table
+const ENTRY_SIZE: usize = 8;
+/// size of the encoded header
+const HEADER_SIZE: usize = 24;
+
+/// On-disk header
+#[derive(Debug, Eq, PartialEq)]
+struct Header {
+    /// log2( size of cluster )
+    cluster_size_log2: u8,
+    /// number of clusters, i.e. the logical capacity
+    clusters: usize,
+    /// number of sectors in backing device
+    sectors: usize,
+}
+
+impl Header {
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&(self.cluster_size_log2 as u32).to_le_bytes());
+        buf[8..16].copy_from_slice(&(self.clusters as u64).to_le_bytes());
+        buf[16..24].copy_from_slice(&(self.sectors as u64).to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; HEADER_SIZE]) -> Option<Self> {
+        if read_u32(&buf[0..4]) != MAGIC {
+            return None;
+        }
+        let cluster_size_log2 = read_u32(&buf[4..8]);
+        if !(SECTOR_SIZE_LOG2 as u32..=MAX_CLUSTER_SIZE_LOG2 as u32).contains(&cluster_size_log2) {
+            return None;
+        }
+        let header = Header {
+            cluster_size_log2: cluster_size_log2 as u8,
+            clusters: read_u64(&buf[8..16]) as usize,
+            sectors: read_u64(&buf[16..24]) as usize,
+        };
+        header.check()?;
+        Some(header)
+    }
+
+    /// Sizes in bytes must not overflow, and sectors must be addressable by the table
+    fn check(&self) -> Option<()> {
+        self.clusters.checked_mul(ENTRY_SIZE)?;
+        self.clusters.checked_mul(1 << self.cluster_size_log2)?;
+        self.sectors.checked_mul(SECTOR_SIZE)?;
+        if self.sectors > u32::MAX as usize {
+            return None;
+        }
+        Some(())
+    }
+
+    /// The first sector of data area
+    fn data_begin(&self) -> usize {
+        1 + (self.clusters * ENTRY_SIZE).div_ceil(SECTOR_SIZE)
+    }
+}
+
+/// Entry of the cluster table
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+struct ClusterEntry {
+    /// first sector of the stored data
+    sector: u32,
+    /// length of the stored data in bytes.
+    /// 0 if the cluster is not mapped (all zeros),
+    /// cluster size if the cluster is stored uncompressed.
+    len: u32,
+}
+
+impl ClusterEntry {
+    fn is_mapped(&self) -> bool {
+        self.len != 0
+    }
+
+    fn sectors(&self) -> usize {
+        (self.len as usize).div_ceil(SECTOR_SIZE)
+    }
+
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        buf[0..4].copy_from_slice(&self.sector.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.len.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        ClusterEntry {
+            sector: read_u32(&buf[0..4]),
+            len: read_u32(&buf[4..8]),
+        }
+    }
+}
+
+/// Statistics of a `CompressDevice`
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CompressStats {
+    /// Number of clusters holding data
+    pub clusters: usize,
+    /// Size of data in these clusters before compression
+    pub logical_bytes: usize,
+    /// Size of data in these clusters after compression
+    pub compressed_bytes: usize,
+    /// Space allocated in backing device for these clusters
+    pub allocated_bytes: usize,
+    /// Number of clusters moved by overwrites since opened
+    pub relocations: usize,
+}
+
+impl CompressStats {
+    /// Compression ratio: logical size / allocated size.
+    ///
+    /// Return 1.0 if there is no data.
+    pub fn ratio(&self) -> f64 {
+        if self.allocated_bytes == 0 {
+            return 1.0;
+        }
+        self.logical_bytes as f64 / self.allocated_bytes as f64
+    }
+}
+
+/// A device which compresses data on the backing device `T`
+pub struct CompressDevice<T: Device> {
+    device: T,
+    header: Header,
+    state: Mutex<State>,
+}
+
+struct State {
+    table: Vec<ClusterEntry>,
+    free_map: SectorMap,
+    /// where to start searching free sectors
+    next_fit: usize,
+    relocations: usize,
+}
+
+impl<T: Device> CompressDevice<T> {
+    /// Format `device` of `space` bytes as a compressed device,
+    /// which can hold `capacity` bytes in clusters of `1 << cluster_size_log2` bytes.
+    pub fn create(device: T, space: usize, capacity: usize, cluster_size_log2: u8) -> Result<Self> {
+        if !(SECTOR_SIZE_LOG2..=MAX_CLUSTER_SIZE_LOG2).contains(&cluster_size_log2) {
+            return Err(DevError);
+        }
+        let cluster_size = 1usize << cluster_size_log2;
+        let header = Header {
+            cluster_size_log2,
+            clusters: capacity.div_ceil(cluster_size),
+            sectors: space / SECTOR_SIZE,
+        };
+        header.check().ok_or(DevError)?;
+        let data_begin = header.data_begin();
+        if data_begin >= header.sectors {
+            return Err(DevError);
+        }
+        // clear the cluster table
+        static ZEROS: [u8; SECTOR_SIZE] = [0; SECTOR_SIZE];
+        for sector in 1..data_begin {
+            write_all(&device, sector * SECTOR_SIZE, &ZEROS)?;
+        }
+        write_all(&device, 0, &header.encode())?;
+        device.sync()?;
+
+        let state = State {
+            table: vec![ClusterEntry::default(); header.clusters],
+            free_map: SectorMap::new(header.sectors, data_begin),
+            next_fit: data_begin,
+            relocations: 0,
+        };
+        Ok(CompressDevice {
+            device,
+            header,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Load a compressed device from `device`.
+    ///
+    /// Return `WrongFs` if the header is corrupt or does not fit in `device`.
+    pub fn open(device: T) -> vfs::Result<Self> {
+        let mut buf = [0u8; HEADER_SIZE];
+        read_exact(&device, 0, &mut buf)?;
+        let header = Header::decode(&buf).ok_or(FsError::WrongFs)?;
+        let data_begin = header.data_begin();
+        if data_begin >= header.sectors {
+            return Err(FsError::WrongFs);
+        }
+        // the last sector must be there before allocating for all of them
+        read_exact(&device, (header.sectors - 1) * SECTOR_SIZE, &mut [0u8; 1])
+            .map_err(|_| FsError::WrongFs)?;
+
+        let mut raw = vec![0u8; header.clusters * ENTRY_SIZE];
+        read_exact(&device, SECTOR_SIZE, &mut raw)?;
+        let table: Vec<ClusterEntry> = raw.chunks(ENTRY_SIZE).map(ClusterEntry::decode).collect();
+
+        // rebuild the free map from the table
+        let cluster_size = 1usize << header.cluster_size_log2;
+        let mut free_map = SectorMap::new(header.sectors, data_begin);
+        for entry in table.iter().filter(|e| e.is_mapped()) {
+            let begin = entry.sector as usize;
+            let end = begin + entry.sectors();
+            if entry.len as usize > cluster_size || begin < data_begin || end > header.sectors {
+                return Err(FsError::WrongFs);
+            }
+            for sector in begin..end {
+                if !free_map.is_free(sector) {
+                    // two clusters overlap
+                    return Err(FsError::WrongFs);
+                }
+                free_map.set_free(sector, false);
+            }
+        }
+
+        let state = State {
+            table,
+            free_map,
+            next_fit: data_begin,
+            relocations: 0,
+        };
+        Ok(CompressDevice {
+            device,
+            header,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Size of the device before compression in bytes
+    pub fn capacity(&self) -> usize {
+        self.header.clusters << self.header.cluster_size_log2
+    }
+
+    /// Size of cluster in bytes
+    pub fn cluster_size(&self) -> usize {
+        1 << self.header.cluster_size_log2
+    }
+
+    /// Get the statistics of compression
+    pub fn stats(&self) -> CompressStats {
+        let state = self.state.lock();
+        let mut stats = CompressStats {
+            relocations: state.relocations,
+            ..CompressStats::default()
+        };
+        for entry in state.table.iter().filter(|e| e.is_mapped()) {
+            stats.clusters += 1;
+            stats.logical_bytes += self.cluster_size();
+            stats.compressed_bytes += entry.len as usize;
+            stats.allocated_bytes += entry.sectors() * SECTOR_SIZE;
+        }
+        stats
+    }
+
+    /// Get the backing device
+    pub fn into_inner(self) -> T {
+        self.device
+    }
+
+    /// Read and decompress cluster `id` into `buf`
+    fn read_cluster(&self, state: &State, id: usize, buf: &mut [u8]) -> Result<()> {
+        let entry = state.table[id];
+        let offset = entry.sector as usize * SECTOR_SIZE;
+        if !entry.is_mapped() {
+            buf.fill(0);
+        } else if entry.len as usize == self.cluster_size() {
+            read_exact(&self.device, offset, buf)?;
+        } else {
+            let mut data = vec![0u8; entry.len as usize];
+            read_exact(&self.device, offset, &mut data)?;
+            match lz4_flex::block::decompress_into(&data, buf) {
+                Ok(len) if len == buf.len() => {}
+                _ => return Err(DevError),
+            }
+        }
+        Ok(())
+    }
+
+    /// Compress and write cluster `id` from `buf`
+    fn write_cluster(&self, state: &mut State, id: usize, buf: &[u8]) -> Result<()> {
+        let old = state.table[id];
+        if buf.iter().all(|&b| b == 0) {
+            // all zeros: unmap the cluster
+            if old.is_mapped() {
+                self.write_entry(id, &ClusterEntry::default())?;
+                state.free(&old);
+                state.table[id] = ClusterEntry::default();
+            }
+            return Ok(());
+        }
+
+        let mut compressed = vec![0u8; lz4_flex::block::get_maximum_output_size(buf.len())];
+        let data = match lz4_flex::block::compress_into(buf, &mut compressed) {
+            Ok(len) if len < buf.len() => &compressed[..len],
+            // incompressible: store raw data
+            _ => buf,
+        };
+        let sectors = data.len().div_ceil(SECTOR_SIZE);
+
+        let new = match state.alloc(sectors) {
+            Some(sector) => ClusterEntry {
+                sector: sector as u32,
+                len: data.len() as u32,
+            },
+            // no space to relocate: overwrite in place if it fits
+            None if old.is_mapped() && sectors <= old.sectors() => ClusterEntry {
+                sector: old.sector,
+                len: data.len() as u32,
+            },
+            None => return Err(DevError),
+        };
+        // write data before the table, so that a crash leaves the old version intact
+        write_all(&self.device, new.sector as usize * SECTOR_SIZE, data)?;
+        self.write_entry(id, &new)?;
+        if old.is_mapped() {
+            if new.sector == old.sector {
+                // release the tail of the old extent
+                let tail = old.sector as usize + new.sectors();
+                for sector in tail..old.sector as usize + old.sectors() {
+                    state.free_map.set_free(sector, true);
+                }
+            } else {
+                state.free(&old);
+                state.relocations += 1;
+            }
+        }
+        state.table[id] = new;
+        Ok(())
+    }
+
+    fn write_entry(&self, id: usize, entry: &ClusterEntry) -> Result<()> {
+        write_all(&self.device, SECTOR_SIZE + id * ENTRY_SIZE, &entry.encode())
+    }
+}
+
+impl<T: Device> Device for CompressDevice<T> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let end = self
+            .capacity()
+            .min(offset.checked_add(buf.len()).ok_or(DevError)?);
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.header.cluster_size_log2,
+        };
+        let state = self.state.lock();
+        let mut cluster = vec![0u8; self.cluster_size()];
+
+        // For each cluster
+        for range in iter {
+            let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
+            if range.is_full() {
+                // Decompress to target buf directly
+                self.read_cluster(&state, range.block, buf)?;
+            } else {
+                self.read_cluster(&state, range.block, &mut cluster)?;
+                buf.copy_from_slice(&cluster[range.begin..range.end]);
+            }
+        }
+        Ok(end - offset)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = self
+            .capacity()
+            .min(offset.checked_add(buf.len()).ok_or(DevError)?);
+        if offset >= end {
+            return Ok(0);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end,
+            block_size_log2: self.header.cluster_size_log2,
+        };
+        let mut state = self.state.lock();
+        let mut cluster = vec![0u8; self.cluster_size()];
+
+        // For each cluster
+        for range in iter {
+            let buf = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            if range.is_full() {
+                self.write_cluster(&mut state, range.block, buf)?;
+            } else {
+                // Read-modify-write
+                self.read_cluster(&state, range.block, &mut cluster)?;
+                cluster[range.begin..range.end].copy_from_slice(buf);
+                self.write_cluster(&mut state, range.block, &cluster)?;
+            }
+        }
+        Ok(end - offset)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.device.sync()
+    }
+}
+
+impl State {
+    /// Allocate `count` contiguous sectors, return the first one
+    fn alloc(&mut self, count: usize) -> Option<usize> {
+        let begin = self.free_map.find_run(count, self.next_fit)?;
+        for sector in begin..begin + count {
+            self.free_map.set_free(sector, false);
+        }
+        self.next_fit = begin + count;
+        Some(begin)
+    }
+
+    /// Free sectors used by `entry`
+    fn free(&mut self, entry: &ClusterEntry) {
+        let begin = entry.sector as usize;
+        for sector in begin..begin + entry.sectors() {
+            self.free_map.set_free(sector, true);
+        }
+    }
+}
+
+/// Bitmap of free sectors in data area
+struct SectorMap {
+    bits: Vec<u64>,
+    /// first sector can be allocated
+    begin: usize,
+    /// number of sectors
+    end: usize,
+}
+
+impl SectorMap {
+    /// All sectors in `begin..end` are free
+    fn new(end: usize, begin: usize) -> Self {
+        let mut map = SectorMap {
+            bits: vec![0; end.div_ceil(64)],
+            begin,
+            end,
+        };
+        for sector in begin..end {
+            map.set_free(sector, true);
+        }
+        map
+    }
+
+    fn is_free(&self, sector: usize) -> bool {
+        self.bits[sector / 64] & (1 << (sector % 64)) != 0
+    }
+
+    fn set_free(&mut self, sector: usize, free: bool) {
+        if free {
+            self.bits[sector / 64] |= 1 << (sector % 64);
+        } else {
+            self.bits[sector / 64] &= !(1 << (sector % 64));
+        }
+    }
+
+    /// Find `count` contiguous free sectors, starting from `hint` and wrapping around
+    fn find_run(&self, count: usize, hint: usize) -> Option<usize> {
+        let hint = if hint < self.begin || hint >= self.end {
+            self.begin
+        } else {
+            hint
+        };
+        self.find_run_in(count, hint, self.end)
+            .or_else(|| self.find_run_in(count, self.begin, (hint + count).min(self.end)))
+    }
+
+    fn find_run_in(&self, count: usize, begin: usize, end: usize) -> Option<usize> {
+        let mut run = 0;
+        for sector in begin..end {
+            if !self.is_free(sector) {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run == count {
+                return Some(sector + 1 - count);
+            }
+        }
+        None
+    }
+}
+
+fn read_exact(device: &impl Device, offset: usize, buf: &mut [u8]) -> Result<()> {
+    match device.read_at(offset, buf)? {
+        len if len == buf.len() => Ok(()),
+        _ => Err(DevError),
+    }
+}
+
+fn write_all(device: &impl Device, offset: usize, buf: &[u8]) -> Result<()> {
+    match device.write_at(offset, buf)? {
+        len if len == buf.len() => Ok(()),
+        _ => Err(DevError),
+    }
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(buf);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(buf);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A device in memory, which can be shared after the `CompressDevice` is dropped
+    #[derive(Clone)]
+    struct MemDevice(Arc<Mutex<Vec<u8>>>);
+
+    impl MemDevice {
+        fn new(size: usize) -> Self {
+            MemDevice(Arc::new(Mutex::new(vec![0; size])))
+        }
+    }
+
+    impl Device for MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let offset = offset.min(data.len());
+            let len = buf.len().min(data.len() - offset);
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let offset = offset.min(data.len());
+            let len = buf.len().min(data.len() - offset);
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn text(len: usize) -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog\n"
+            .iter()
+            .cycle()
+            .take(len)
+            .cloned()
+            .collect()
+    }
+
+    /// Pseudo random data which can not be compressed
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn read_write() {
+        let dev = CompressDevice::create(MemDevice::new(0x10000), 0x10000, 0x20000, 12).unwrap();
+        assert_eq!(dev.capacity(), 0x20000);
+
+        // unwritten area reads zeros
+        let mut buf = vec![0xffu8; 0x1800];
+        assert_eq!(Device::read_at(&dev, 0x800, &mut buf), Ok(0x1800));
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // unaligned write across clusters
+        let data = text(0x2345);
+        assert_eq!(Device::write_at(&dev, 0x123, &data), Ok(data.len()));
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(Device::read_at(&dev, 0x123, &mut buf), Ok(data.len()));
+        assert_eq!(buf, data);
+
+        // partly outside
+        let mut buf = [0u8; 0x10];
+        assert_eq!(Device::read_at(&dev, 0x20000 - 8, &mut buf), Ok(8));
+        assert_eq!(Device::write_at(&dev, 0x20000, &buf), Ok(0));
+
+        // more logical data than physical space, thanks to compression
+        let stats = dev.stats();
+        assert_eq!(stats.clusters, 3);
+        assert!(stats.ratio() > 2.0);
+    }
+
+    #[test]
+    fn incompressible() {
+        let dev = CompressDevice::create(MemDevice::new(0x10000), 0x10000, 0x8000, 12).unwrap();
+        let data = noise(0x1000);
+        Device::write_at(&dev, 0x1000, &data).unwrap();
+        let mut buf = vec![0u8; 0x1000];
+        Device::read_at(&dev, 0x1000, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        let stats = dev.stats();
+        assert_eq!(stats.compressed_bytes, 0x1000);
+        assert_eq!(stats.allocated_bytes, 0x1000);
+    }
+
+    #[test]
+    fn overwrite_relocate() {
+        let dev = CompressDevice::create(MemDevice::new(0x10000), 0x10000, 0x8000, 12).unwrap();
+        Device::write_at(&dev, 0, &text(0x1000)).unwrap();
+        Device::write_at(&dev, 0, &noise(0x1000)).unwrap();
+        assert_eq!(dev.stats().relocations, 1);
+        let mut buf = vec![0u8; 0x1000];
+        Device::read_at(&dev, 0, &mut buf).unwrap();
+        assert_eq!(buf, noise(0x1000));
+
+        // writing zeros releases the space
+        Device::write_at(&dev, 0, &[0u8; 0x1000]).unwrap();
+        assert_eq!(
+            dev.stats(),
+            CompressStats {
+                relocations: 1,
+                ..CompressStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn no_space() {
+        // room for 4 raw clusters only
+        let space = SECTOR_SIZE * 2 + 0x4000;
+        let dev = CompressDevice::create(MemDevice::new(space), space, 0x8000, 12).unwrap();
+        for i in 0..4 {
+            Device::write_at(&dev, i * 0x1000, &noise(0x1000)).unwrap();
+        }
+        assert!(Device::write_at(&dev, 0x4000, &noise(0x1000)).is_err());
+        // overwrite in place when there is no space to relocate
+        Device::write_at(&dev, 0, &text(0x1000)).unwrap();
+        let mut buf = vec![0u8; 0x1000];
+        Device::read_at(&dev, 0, &mut buf).unwrap();
+        assert_eq!(buf, text(0x1000));
+        // then the freed space can be used
+        Device::write_at(&dev, 0x4000, &text(0x1000)).unwrap();
+    }
+
+    #[test]
+    fn reopen() {
+        let mem = MemDevice::new(0x10000);
+        let data = text(0x3000);
+        {
+            let dev = CompressDevice::create(mem.clone(), 0x10000, 0x8000, 13).unwrap();
+            Device::write_at(&dev, 0x800, &data).unwrap();
+            Device::write_at(&dev, 0x6000, &noise(0x100)).unwrap();
+        }
+        let dev = CompressDevice::open(mem).unwrap();
+        assert_eq!(dev.cluster_size(), 0x2000);
+        let mut buf = vec![0u8; data.len()];
+        Device::read_at(&dev, 0x800, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        let mut buf = vec![0u8; 0x100];
+        Device::read_at(&dev, 0x6000, &mut buf).unwrap();
+        assert_eq!(buf, noise(0x100));
+
+        assert!(CompressDevice::open(MemDevice::new(0x1000)).is_err());
+    }
+
+    #[test]
+    fn corrupt_header() {
+        let mem = MemDevice::new(0x10000);
+        CompressDevice::create(mem.clone(), 0x10000, 0x8000, 12).unwrap();
+        let header = |clusters: usize, sectors: usize| {
+            let header = Header {
+                cluster_size_log2: 12,
+                clusters,
+                sectors,
+            };
+            mem.0.lock().unwrap()[..HEADER_SIZE].copy_from_slice(&header.encode());
+            CompressDevice::open(mem.clone()).err()
+        };
+        // the cluster table overflows
+        assert_eq!(header(usize::MAX / 4, 0x80), Some(FsError::WrongFs));
+        // the cluster table is larger than the device
+        assert_eq!(header(0x1000_0000, 0x80), Some(FsError::WrongFs));
+        // more sectors than in the device
+        assert_eq!(header(8, 0x1000_0000), Some(FsError::WrongFs));
+        assert_eq!(header(8, 0x80), None);
+    }
+
+    #[test]
+    fn too_large() {
+        let mem = MemDevice::new(0x10000);
+        // more sectors than the table can address
+        let space = (u32::MAX as usize + 1) * SECTOR_SIZE;
+        assert!(CompressDevice::create(mem.clone(), space, 0x8000, 12).is_err());
+        // the capacity in bytes overflows
+        assert!(CompressDevice::create(mem.clone(), 0x10000, usize::MAX, 12).is_err());
+
+        let dev = CompressDevice::create(mem, 0x10000, 0x8000, 12).unwrap();
+        let mut buf = [0u8; 0x10];
+        assert!(Device::read_at(&dev, usize::MAX - 8, &mut buf).is_err());
+        assert!(Device::write_at(&dev, usize::MAX - 8, &buf).is_err());
+    }
+}
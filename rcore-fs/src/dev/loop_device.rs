@@ -0,0 +1,44 @@
+//! Use an `INode` as the backing storage of a `Device`
+use super::*;
+use crate::vfs::{FileType, FsError, INode};
+use alloc::sync::Arc;
+
+/// A loop device: forward R/W to a regular file or block device `INode`.
+///
+/// This makes it possible to open a file system image which itself lives
+/// as a file inside another file system, e.g. SFS image in RamFS.
+pub struct LoopDevice {
+    inode: Arc<dyn INode>,
+}
+
+impl LoopDevice {
+    /// Create a loop device backed by `inode`.
+    ///
+    /// Only regular files and block devices can be used as backing storage.
+    pub fn new(inode: Arc<dyn INode>) -> crate::vfs::Result<Self> {
+        match inode.metadata()?.type_ {
+            FileType::File | FileType::BlockDevice => Ok(LoopDevice { inode }),
+            FileType::Dir => Err(FsError::IsDir),
+            _ => Err(FsError::InvalidParam),
+        }
+    }
+
+    /// Get the backing INode
+    pub fn inode(&self) -> &Arc<dyn INode> {
+        &self.inode
+    }
+}
+
+impl Device for LoopDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inode.read_at(offset, buf).map_err(|_| DevError)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.inode.write_at(offset, buf).map_err(|_| DevError)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inode.sync_data().map_err(|_| DevError)
+    }
+}
@@ -1,6 +1,12 @@
-use crate::{util::*, vfs::Timespec};
+use crate::{
+    util::*,
+    vfs::{INode, Timespec},
+};
+use alloc::sync::Arc;
 
 pub mod block_cache;
+pub mod compress;
+pub mod loop_device;
 pub mod std_impl;
 
 /// A current time provider
@@ -8,6 +14,12 @@ pub trait TimeProvider: Send + Sync {
     fn current_time(&self) -> Timespec;
 }
 
+/// Resolves the device nodes stored in a FS to the devices
+pub trait DeviceRegistry: Send + Sync {
+    /// Get the device of number `rdev`, as made by `vfs::make_rdev`
+    fn get(&self, rdev: usize) -> Option<Arc<dyn INode>>;
+}
+
 /// Interface for FS to read & write
 pub trait Device: Send + Sync {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
@@ -5,6 +5,7 @@ extern crate alloc;
 pub mod dev;
 pub mod dirty;
 pub mod file;
+pub mod pipe;
 pub mod util;
 pub mod vfs;
 
@@ -0,0 +1,435 @@
+//! Pipes and FIFOs
+//!
+//! A `Pipe` is a bounded byte buffer shared by read ends and write ends.
+//! Reading from an empty pipe returns `Again` while there are writers, and
+//! end of file (0 bytes) after all writers are closed. Writing to a full pipe
+//! returns `Again`, and writing without any reader returns `BrokenPipe`.
+//! Writes of at most `PIPE_BUF` bytes are atomic: all written or `Again`.
+//! To block, wait for `async_poll` and then try again.
+
+use crate::vfs::*;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::any::Any;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// Default capacity of a pipe in bytes, same as Linux
+pub const PIPE_CAPACITY: usize = 0x10000;
+/// Max size of atomic writes in bytes, same as Linux
+pub const PIPE_BUF: usize = 0x1000;
+
+/// Shared buffer of a pipe
+pub struct Pipe {
+    /// Inode number of anonymous pipes
+    id: usize,
+    inner: Mutex<PipeInner>,
+}
+
+struct PipeInner {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    readers: usize,
+    writers: usize,
+    /// Tasks waiting for the state to change
+    wakers: Vec<Waker>,
+}
+
+impl PipeInner {
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Which end of a pipe
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PipeEnd {
+    Read,
+    Write,
+}
+
+/// An opened end of a pipe
+pub struct PipeINode {
+    pipe: Arc<Pipe>,
+    end: PipeEnd,
+    /// The FIFO node the pipe attached to, `None` for anonymous pipes
+    fifo: Option<Arc<dyn INode>>,
+}
+
+impl Pipe {
+    /// Create a pipe with no end opened
+    pub fn new(capacity: usize) -> Arc<Self> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+        Arc::new(Pipe {
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+            inner: Mutex::new(PipeInner {
+                buf: VecDeque::new(),
+                capacity,
+                readers: 0,
+                writers: 0,
+                wakers: Vec::new(),
+            }),
+        })
+    }
+
+    /// Create an anonymous pipe, return the read end and the write end
+    pub fn new_pair() -> (Arc<PipeINode>, Arc<PipeINode>) {
+        let pipe = Self::new(PIPE_CAPACITY);
+        let read = Self::open(&pipe, PipeEnd::Read, None);
+        let write = Self::open(&pipe, PipeEnd::Write, None);
+        (read, write)
+    }
+
+    /// Open an end of the pipe
+    pub fn open(self: &Arc<Self>, end: PipeEnd, fifo: Option<Arc<dyn INode>>) -> Arc<PipeINode> {
+        let mut inner = self.inner.lock();
+        match end {
+            PipeEnd::Read => inner.readers += 1,
+            PipeEnd::Write => inner.writers += 1,
+        }
+        inner.wake_all();
+        Arc::new(PipeINode {
+            pipe: self.clone(),
+            end,
+            fifo,
+        })
+    }
+
+    /// Number of opened read ends
+    pub fn readers(&self) -> usize {
+        self.inner.lock().readers
+    }
+
+    /// Number of opened write ends
+    pub fn writers(&self) -> usize {
+        self.inner.lock().writers
+    }
+
+    /// Number of bytes in the buffer
+    pub fn len(&self) -> usize {
+        self.inner.lock().buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl PipeINode {
+    /// Get the underlying pipe
+    pub fn pipe(&self) -> &Arc<Pipe> {
+        &self.pipe
+    }
+
+    pub fn end(&self) -> PipeEnd {
+        self.end
+    }
+
+    fn status(&self, inner: &PipeInner) -> PollStatus {
+        match self.end {
+            PipeEnd::Read => PollStatus {
+                read: !inner.buf.is_empty() || inner.writers == 0,
+                write: false,
+                error: false,
+            },
+            PipeEnd::Write => PollStatus {
+                read: false,
+                write: inner.buf.len() < inner.capacity,
+                error: inner.readers == 0,
+            },
+        }
+    }
+}
+
+impl Drop for PipeINode {
+    fn drop(&mut self) {
+        let mut inner = self.pipe.inner.lock();
+        match self.end {
+            PipeEnd::Read => inner.readers -= 1,
+            PipeEnd::Write => inner.writers -= 1,
+        }
+        inner.wake_all();
+    }
+}
+
+impl INode for PipeINode {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.end != PipeEnd::Read {
+            return Err(FsError::InvalidParam);
+        }
+        let mut inner = self.pipe.inner.lock();
+        if inner.buf.is_empty() {
+            if inner.writers == 0 || buf.is_empty() {
+                return Ok(0);
+            }
+            return Err(FsError::Again);
+        }
+        let len = buf.len().min(inner.buf.len());
+        for (dst, src) in buf.iter_mut().zip(inner.buf.drain(..len)) {
+            *dst = src;
+        }
+        inner.wake_all();
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        if self.end != PipeEnd::Write {
+            return Err(FsError::InvalidParam);
+        }
+        let mut inner = self.pipe.inner.lock();
+        if inner.readers == 0 {
+            return Err(FsError::BrokenPipe);
+        }
+        let free = inner.capacity - inner.buf.len();
+        // not interleaved with other writes, if it can ever fit
+        if buf.len() <= PIPE_BUF.min(inner.capacity) && buf.len() > free {
+            return Err(FsError::Again);
+        }
+        let len = buf.len().min(free);
+        if len == 0 && !buf.is_empty() {
+            return Err(FsError::Again);
+        }
+        inner.buf.extend(&buf[..len]);
+        inner.wake_all();
+        Ok(len)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(self.status(&self.pipe.inner.lock()))
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        struct PipeFuture<'a> {
+            inode: &'a PipeINode,
+        }
+
+        impl Future for PipeFuture<'_> {
+            type Output = Result<PollStatus>;
+
+            fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                let mut inner = self.inode.pipe.inner.lock();
+                let status = self.inode.status(&inner);
+                if status.read || status.write || status.error {
+                    return Poll::Ready(Ok(status));
+                }
+                // polled again before woken up
+                if !inner.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    inner.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+
+        Box::pin(PipeFuture { inode: self })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let size = self.pipe.len();
+        if let Some(fifo) = &self.fifo {
+            return Ok(Metadata {
+                size,
+                ..fifo.metadata()?
+            });
+        }
+        Ok(Metadata {
+            dev: 0,
+            inode: self.pipe.id,
+            size,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::NamedPipe,
+            mode: 0o600,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        match &self.fifo {
+            Some(fifo) => fifo.set_metadata(metadata),
+            None => Err(FsError::NotSupported),
+        }
+    }
+
+    fn fs(&self) -> Arc<dyn FileSystem> {
+        match &self.fifo {
+            Some(fifo) => fifo.fs(),
+            None => unimplemented!(),
+        }
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Pipes attached to opened FIFO nodes.
+///
+/// All opens of the same FIFO node share one pipe until every end is closed.
+/// Opening never blocks: a reader opened before any writer reads end of file,
+/// callers can wait for `Pipe::writers` or `Pipe::readers` for blocking opens.
+#[derive(Default)]
+pub struct FifoTable {
+    /// Map (address of file system, inode) to the pipe,
+    /// as file systems do not tell their devices apart
+    pipes: Mutex<BTreeMap<(usize, usize), Weak<Pipe>>>,
+}
+
+impl FifoTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open an end of the pipe attached to FIFO `inode`
+    pub fn open(&self, inode: &Arc<dyn INode>, end: PipeEnd) -> Result<Arc<PipeINode>> {
+        let metadata = inode.metadata()?;
+        if metadata.type_ != FileType::NamedPipe {
+            return Err(FsError::InvalidParam);
+        }
+        let mut pipes = self.pipes.lock();
+        pipes.retain(|_, pipe| pipe.strong_count() > 0);
+        let key = (
+            Arc::as_ptr(&inode.fs()) as *const () as usize,
+            metadata.inode,
+        );
+        let pipe = match pipes.get(&key).and_then(Weak::upgrade) {
+            Some(pipe) => pipe,
+            None => {
+                let pipe = Pipe::new(PIPE_CAPACITY);
+                pipes.insert(key, Arc::downgrade(&pipe));
+                pipe
+            }
+        };
+        Ok(pipe.open(end, Some(inode.clone())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::task::{RawWaker, RawWakerVTable};
+
+    fn counting_waker(count: &Arc<AtomicUsize>) -> Waker {
+        fn clone(data: *const ()) -> RawWaker {
+            unsafe { Arc::increment_strong_count(data as *const AtomicUsize) };
+            RawWaker::new(data, &VTABLE)
+        }
+        fn wake(data: *const ()) {
+            wake_by_ref(data);
+            drop_waker(data);
+        }
+        fn wake_by_ref(data: *const ()) {
+            unsafe { &*(data as *const AtomicUsize) }.fetch_add(1, Ordering::SeqCst);
+        }
+        fn drop_waker(data: *const ()) {
+            unsafe { Arc::decrement_strong_count(data as *const AtomicUsize) };
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+        let data = Arc::into_raw(count.clone()) as *const ();
+        unsafe { Waker::from_raw(RawWaker::new(data, &VTABLE)) }
+    }
+
+    #[test]
+    fn read_write() {
+        let (read, write) = Pipe::new_pair();
+        let mut buf = [0u8; 8];
+        assert_eq!(read.read_at(0, &mut buf), Err(FsError::Again));
+        assert_eq!(write.write_at(0, b"hello"), Ok(5));
+        assert_eq!(read.metadata().unwrap().size, 5);
+        assert_eq!(read.read_at(0, &mut buf[..3]), Ok(3));
+        assert_eq!(&buf[..3], b"hel");
+        assert_eq!(read.read_at(0, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(read.write_at(0, b"x"), Err(FsError::InvalidParam));
+        assert_eq!(write.read_at(0, &mut buf), Err(FsError::InvalidParam));
+
+        write.write_at(0, b"bye").unwrap();
+        drop(write);
+        assert_eq!(read.read_at(0, &mut buf), Ok(3));
+        assert_eq!(read.read_at(0, &mut buf), Ok(0), "end of file");
+    }
+
+    #[test]
+    fn full_and_broken() {
+        let pipe = Pipe::new(4);
+        let read = pipe.open(PipeEnd::Read, None);
+        let write = pipe.open(PipeEnd::Write, None);
+        assert_eq!(write.write_at(0, b"abcdef"), Ok(4));
+        assert_eq!(write.write_at(0, b"g"), Err(FsError::Again));
+        assert!(!write.poll().unwrap().write);
+        let mut buf = [0u8; 1];
+        read.read_at(0, &mut buf).unwrap();
+        assert!(write.poll().unwrap().write);
+        assert_eq!(write.write_at(0, b"gh"), Err(FsError::Again), "atomic");
+        assert_eq!(write.write_at(0, b"g"), Ok(1));
+
+        drop(read);
+        assert!(write.poll().unwrap().error);
+        assert_eq!(write.write_at(0, b"x"), Err(FsError::BrokenPipe));
+        assert_eq!(pipe.readers(), 0);
+        assert_eq!(pipe.writers(), 1);
+    }
+
+    #[test]
+    fn atomic_write() {
+        let (read, write) = Pipe::new_pair();
+        let data = [1u8; PIPE_CAPACITY];
+        assert_eq!(
+            write.write_at(0, &data[..PIPE_CAPACITY - 10]),
+            Ok(PIPE_CAPACITY - 10)
+        );
+        assert_eq!(write.write_at(0, &data[..11]), Err(FsError::Again));
+        assert_eq!(
+            write.write_at(0, &data[..PIPE_BUF + 1]),
+            Ok(10),
+            "large writes are split"
+        );
+        let mut buf = [0u8; PIPE_BUF];
+        assert_eq!(read.read_at(0, &mut buf), Ok(PIPE_BUF));
+        assert_eq!(write.write_at(0, &data[..PIPE_BUF]), Ok(PIPE_BUF));
+    }
+
+    #[test]
+    fn async_poll() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let waker = counting_waker(&count);
+        let mut cx = Context::from_waker(&waker);
+        let (read, write) = Pipe::new_pair();
+
+        let mut future = read.async_poll();
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(read.pipe().inner.lock().wakers.len(), 1);
+        write.write_at(0, b"x").unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok(status)) => assert!(status.read),
+            _ => panic!("pipe should be readable"),
+        }
+
+        // closing the write end wakes the reader
+        let mut buf = [0u8; 1];
+        read.read_at(0, &mut buf).unwrap();
+        let mut future = read.async_poll();
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        drop(write);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert!(future.as_mut().poll(&mut cx).is_ready());
+    }
+}
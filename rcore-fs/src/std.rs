@@ -24,6 +24,10 @@ impl From<std::io::Error> for FsError {
             ErrorKind::WouldBlock => FsError::Again,
             ErrorKind::InvalidInput => FsError::InvalidParam,
             ErrorKind::InvalidData => FsError::InvalidParam,
+            ErrorKind::IsADirectory => FsError::IsDir,
+            ErrorKind::NotADirectory => FsError::NotDir,
+            ErrorKind::DirectoryNotEmpty => FsError::DirNotEmpty,
+            ErrorKind::CrossesDevices => FsError::NotSameFs,
             // The host fs is the device here
             _ => FsError::DeviceError,
         }
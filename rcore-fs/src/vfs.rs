@@ -302,6 +302,8 @@ pub struct FsInfo {
     pub ffree: usize,
     /// Maximum filename length
     pub namemax: usize,
+    /// Number of blocks saved by storing small files inline, in units of `frsize`
+    pub inline_blocks: usize,
 }
 
 // Note: IOError/NoMemory always lead to a panic since it's hard to recover from it.
@@ -310,7 +312,7 @@ pub struct FsInfo {
 pub enum FsError {
     NotSupported,  // E_UNIMP, or E_INVAL
     NotFile,       // E_ISDIR
-    IsDir,         // E_ISDIR, used only in link
+    IsDir,         // E_ISDIR, used in link, unlink and move
     NotDir,        // E_NOTDIR
     EntryNotFound, // E_NOENT
     EntryExist,    // E_EXIST
@@ -327,6 +329,9 @@ pub enum FsError {
     SymLoop,     // E_LOOP
     Busy,        // E_BUSY
     Interrupted, // E_INTR
+    ReadOnly,    // E_ROFS
+    BrokenPipe,  // E_PIPE
+    FileTooBig,  // E_FBIG
 }
 
 impl fmt::Display for FsError {
@@ -353,6 +358,11 @@ pub trait FileSystem: Sync + Send {
 
     /// Get the file system information
     fn info(&self) -> FsInfo;
+
+    /// Get the type name of the file system, like "sfs"
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
 }
 
 pub fn make_rdev(major: usize, minor: usize) -> usize {